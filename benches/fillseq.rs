@@ -0,0 +1,29 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rleveldb::{LevelDB, MemEnv, Options};
+
+const ENTRIES: usize = 100_000;
+
+// writes the keys in order into a new db and flushes it, on a MemEnv so the
+// time is cpu only
+fn fillseq(disable_data_checksums: bool) {
+    let mut options = Options::default();
+    options.create_if_missing = true;
+    options.disable_data_checksums = disable_data_checksums;
+    let db = LevelDB::open(options, "/fillseq", MemEnv::new()).unwrap();
+    let value = vec![b'v'; 100];
+    for i in 0..ENTRIES {
+        db.write(format!("{:016}", i).as_bytes(), &value).unwrap();
+    }
+    db.live_files(true).unwrap();
+}
+
+fn bench_fillseq(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fillseq_100k");
+    group.sample_size(10);
+    group.bench_function("checksums", |b| b.iter(|| fillseq(false)));
+    group.bench_function("no_checksums", |b| b.iter(|| fillseq(true)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_fillseq);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rleveldb::{BloomFilterPolicy, DBIterator, LevelDB, MemEnv, Options, ReadOption};
+
+const ENTRIES: usize = 200_000;
+const GETS: usize = 1000;
+
+// Many more tables than the table cache holds, so a full scan evicts every
+// table the gets need.
+fn open(pin_table_metadata_in_cache: bool) -> LevelDB<MemEnv> {
+    let mut options = Options::default();
+    options.create_if_missing = true;
+    options.max_open_files = 74;
+    options.write_buffer_size = 64 * 1024;
+    options.max_file_size = 64 * 1024;
+    options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+    options.pin_table_metadata_in_cache = pin_table_metadata_in_cache;
+    let db = LevelDB::open(options, "/table_metadata", MemEnv::new()).unwrap();
+    let value = vec![b'v'; 100];
+    for i in 0..ENTRIES {
+        db.write(format!("{:016}", i).as_bytes(), &value).unwrap();
+    }
+    db.live_files(true).unwrap();
+    db
+}
+
+// the gets right after a scan, the scan isn't timed
+fn gets_after_scan(db: &LevelDB<MemEnv>, iters: u64) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iters {
+        let mut iter = db.iter(&ReadOption::default());
+        iter.seek_to_first();
+        while iter.valid() {
+            iter.next();
+        }
+        drop(iter);
+
+        let start = Instant::now();
+        for i in 0..GETS {
+            let key = format!("{:016}", i * (ENTRIES / GETS) + 7);
+            db.get(key.as_bytes()).unwrap().unwrap();
+        }
+        elapsed += start.elapsed();
+    }
+    elapsed
+}
+
+fn bench_table_metadata(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gets_after_scan");
+    group.sample_size(10);
+    for (name, pin) in [("single_tier", false), ("metadata_kept", true)] {
+        let db = open(pin);
+        group.bench_function(name, |b| b.iter_custom(|iters| gets_after_scan(&db, iters)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_table_metadata);
+criterion_main!(benches);
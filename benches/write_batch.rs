@@ -0,0 +1,78 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rleveldb::WriteBatch;
+
+// counts allocations, to show how often the batch had to grow
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ENTRIES: usize = 1_000_000;
+
+fn entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..ENTRIES)
+        .map(|i| (format!("{:016}", i).into_bytes(), vec![b'v'; 100]))
+        .collect()
+}
+
+fn put_loop(entries: &[(Vec<u8>, Vec<u8>)]) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for (k, v) in entries.iter() {
+        batch.put(k, v);
+    }
+    batch
+}
+
+fn put_many(entries: &[(Vec<u8>, Vec<u8>)]) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    batch.put_many(entries.iter().map(|(k, v)| (k, v)));
+    batch
+}
+
+fn allocations(f: impl FnOnce() -> WriteBatch) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let batch = f();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(batch);
+    after - before
+}
+
+fn bench_build(c: &mut Criterion) {
+    let entries = entries();
+    println!(
+        "allocations for {} puts: loop {}, put_many {}",
+        ENTRIES,
+        allocations(|| put_loop(&entries)),
+        allocations(|| put_many(&entries))
+    );
+
+    let mut group = c.benchmark_group("write_batch_1m");
+    group.sample_size(10);
+    group.bench_function("put_loop", |b| b.iter(|| put_loop(&entries)));
+    group.bench_function("put_many", |b| b.iter(|| put_many(&entries)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_build);
+criterion_main!(benches);
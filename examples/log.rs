@@ -2,7 +2,6 @@
 
 use std::str;
 
-use rleveldb::{LogReader, LogWriter, LookupKey, MemTable, ValueType};
 fn main() {
     let datas = &[
         "liu",
@@ -10,8 +9,8 @@ fn main() {
         "guojia",
         str::from_utf8(&[b'a'; 32 * 1024 * 2 + 20]).unwrap(),
     ];
-    println!("hello");
-    
+    println!("hello, {} records", datas.len());
+
     // let mut v = Vec::new();
     // let mut writer = LogWriter::new(&mut v);
     // for data in datas {
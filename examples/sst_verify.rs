@@ -0,0 +1,202 @@
+// Reads every entry of a table with checksums verified and checks that the keys
+// are strictly increasing bytewise. Also reports the table's filter, whose bytes
+// are only loaded for the builtin bloom filter.
+//
+//     sst_verify <table>
+//
+// Exits with 0 for a valid table, 1 on bad arguments or a file that can't be read
+// and 2 on a corrupted table.
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use rleveldb::{BloomFilterPolicy, DBIterator, DefaultEnv, Env, Error, Options, ReadOption, Table};
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_CORRUPTED: i32 = 2;
+
+#[derive(Debug, Default, PartialEq)]
+struct TableStats {
+    entries: u64,
+    key_bytes: u64,
+    value_bytes: u64,
+    file_bytes: u64,
+    // the filter policy name, and the filter size when the policy is known
+    filter: Option<(String, Option<usize>)>,
+}
+
+fn verify(path: &Path) -> Result<TableStats, (i32, String)> {
+    let io_error = |e: Error| (EXIT_USAGE, format!("{:?}", e));
+    let corrupted = |e: Error| (EXIT_CORRUPTED, format!("{:?}", e));
+
+    let env = DefaultEnv::default();
+    let file_bytes = env.file_size(path).map_err(|e| io_error(e.into()))? as u64;
+    let file = env
+        .new_random_access_file(path)
+        .map_err(|e| io_error(e.into()))?;
+    let mut options = Options::default();
+    options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+    let table = Table::open(Arc::new(options), file, file_bytes).map_err(corrupted)?;
+    let read_option = ReadOption::VERIFY;
+
+    let mut stats = TableStats {
+        file_bytes,
+        filter: table
+            .filter_policy_name()
+            .map(|name| (name, table.filter_data().map(|data| data.len()))),
+        ..Default::default()
+    };
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut iter = Arc::new(table).iter(&read_option);
+    iter.seek_to_first();
+    while iter.valid() {
+        let key = iter.key();
+        if last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err((
+                EXIT_CORRUPTED,
+                format!("entry {}: keys out of order", stats.entries + 1),
+            ));
+        }
+        stats.entries += 1;
+        stats.key_bytes += key.len() as u64;
+        stats.value_bytes += iter.value().len() as u64;
+        last_key = Some(key.to_vec());
+        iter.next();
+    }
+    iter.status().map_err(corrupted)?;
+    Ok(stats)
+}
+
+fn run(args: &[String], out: &mut impl Write) -> i32 {
+    let path = match args {
+        [path] => Path::new(path),
+        _ => {
+            writeln!(out, "usage: sst_verify <table>").unwrap();
+            return EXIT_USAGE;
+        }
+    };
+    match verify(path) {
+        Ok(stats) => {
+            writeln!(
+                out,
+                "{}: ok, {} entries, {} key bytes, {} value bytes, {} file bytes",
+                path.display(),
+                stats.entries,
+                stats.key_bytes,
+                stats.value_bytes,
+                stats.file_bytes
+            )
+            .unwrap();
+            match stats.filter {
+                Some((name, Some(size))) => writeln!(out, "filter: {}, {} bytes", name, size),
+                Some((name, None)) => writeln!(out, "filter: {}, unknown policy", name),
+                None => writeln!(out, "filter: none"),
+            }
+            .unwrap();
+            0
+        }
+        Err((code, msg)) => {
+            writeln!(out, "{}: {}", path.display(), msg).unwrap();
+            code
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = run(&args, &mut io::stdout());
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rleveldb::{FilterPolicy, TableBuiler};
+
+    use super::*;
+
+    // a table of 1000 entries over several data blocks
+    fn write_table(dir: &Path) -> PathBuf {
+        write_table_with_filter(dir, None)
+    }
+
+    fn write_table_with_filter(dir: &Path, filter: Option<Arc<dyn FilterPolicy>>) -> PathBuf {
+        let path = dir.join("000001.ldb");
+        let file = DefaultEnv::default().new_writable_file(&path).unwrap();
+        let mut options = Options::default();
+        options.filter_policy = filter;
+        let mut builder = TableBuiler::new(Arc::new(options), file);
+        for i in 0..1000 {
+            builder
+                .add(format!("key{:06}", i).as_bytes(), &[b'v'; 50])
+                .unwrap();
+        }
+        builder.finish(true).unwrap();
+        path
+    }
+
+    fn run_on(path: &Path) -> (i32, String) {
+        let mut out = Vec::new();
+        let code = run(&[path.to_str().unwrap().to_string()], &mut out);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_valid_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_table(dir.path());
+        let stats = verify(&path).unwrap();
+        assert_eq!(
+            (stats.entries, stats.key_bytes, stats.value_bytes),
+            (1000, 9000, 50000)
+        );
+        assert_eq!(stats.file_bytes, std::fs::metadata(&path).unwrap().len());
+        let (code, out) = run_on(&path);
+        assert_eq!(code, 0);
+        assert!(out.contains(": ok, 1000 entries"));
+        assert!(out.ends_with("filter: none\n"));
+    }
+
+    #[test]
+    fn test_filter_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_table_with_filter(dir.path(), Some(Arc::new(BloomFilterPolicy::new(10))));
+        let (name, size) = verify(&path).unwrap().filter.unwrap();
+        assert_eq!(name, "leveldb.BuiltinBloomFilter2");
+        assert!(size.unwrap() > 0);
+        let (code, out) = run_on(&path);
+        assert_eq!(code, 0);
+        assert!(out.contains("filter: leveldb.BuiltinBloomFilter2, "));
+    }
+
+    #[test]
+    fn test_corrupted_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_table(dir.path());
+        let data = std::fs::read(&path).unwrap();
+
+        // a flipped byte in the middle of the data blocks
+        let mut flipped = data.clone();
+        flipped[data.len() / 3] ^= 0x80;
+        std::fs::write(&path, &flipped).unwrap();
+        assert_eq!(run_on(&path).0, EXIT_CORRUPTED);
+
+        // the footer cut off
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+        assert_eq!(run_on(&path).0, EXIT_CORRUPTED);
+
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(run_on(&path).0, 0);
+    }
+
+    #[test]
+    fn test_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(run_on(&dir.path().join("missing.ldb")).0, EXIT_USAGE);
+        assert_eq!(run(&[], &mut Vec::new()), EXIT_USAGE);
+    }
+}
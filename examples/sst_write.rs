@@ -0,0 +1,159 @@
+// Writes a table from `key<TAB>value` lines read from stdin. Keys must come in
+// strictly increasing bytewise order.
+//
+//     sst_write <output> [--snappy] [--block-size <bytes>] [--restart-interval <n>]
+//
+// Exits with 0 once the table is written, 1 on bad arguments or io errors and 2
+// on malformed input.
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use rleveldb::{Compress, DefaultEnv, Env, Error, Options, TableBuiler};
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_BAD_INPUT: i32 = 2;
+
+fn parse_args(args: &[String]) -> Result<(String, Options), String> {
+    let mut output = None;
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| -> Result<usize, String> {
+            args.next()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .ok_or(format!("{} takes a positive number", name))
+        };
+        match arg.as_str() {
+            "--snappy" => options.compression_type = Compress::Snappy,
+            "--block-size" => options.block_size = number(arg)?,
+            "--restart-interval" => options.block_restart_interval = number(arg)? as u32,
+            _ if output.is_none() && !arg.starts_with("--") => output = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    let output = output.ok_or("missing output file")?;
+    Ok((output, options))
+}
+
+// Writes the table and returns the number of entries, `Err` carries the exit code.
+fn run(args: &[String], input: impl BufRead, out: &mut impl Write) -> Result<u64, i32> {
+    let (output, options) = parse_args(args).map_err(|e| {
+        writeln!(out, "{}", e).unwrap();
+        writeln!(
+            out,
+            "usage: sst_write <output> [--snappy] [--block-size <bytes>] [--restart-interval <n>]"
+        )
+        .unwrap();
+        EXIT_USAGE
+    })?;
+    let io_error = |out: &mut dyn Write, e: Error| {
+        writeln!(out, "{}: {:?}", output, e).unwrap();
+        EXIT_USAGE
+    };
+    let file = DefaultEnv::default()
+        .new_writable_file(Path::new(&output))
+        .map_err(|e| io_error(out, e.into()))?;
+    let mut builder = TableBuiler::new(Arc::new(options), file);
+
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut count = 0;
+    for (line_number, line) in input.split(b'\n').enumerate() {
+        let line = line.map_err(|e| io_error(out, e.into()))?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+        let bad_input = |out: &mut dyn Write, msg: &str| {
+            writeln!(out, "line {}: {}", line_number + 1, msg).unwrap();
+            EXIT_BAD_INPUT
+        };
+        let tab = match line.iter().position(|&b| b == b'\t') {
+            Some(tab) => tab,
+            None => return Err(bad_input(out, "expected key<TAB>value")),
+        };
+        let (key, value) = (&line[..tab], &line[tab + 1..]);
+        if last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(bad_input(out, "keys must be strictly increasing"));
+        }
+        builder.add(key, value).map_err(|e| io_error(out, e))?;
+        last_key = Some(key.to_vec());
+        count += 1;
+    }
+    let size = builder.finish(true).map_err(|e| io_error(out, e))?;
+    writeln!(out, "{}: {} entries, {} bytes", output, count, size).unwrap();
+    Ok(count)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdin = io::stdin();
+    if let Err(code) = run(&args, stdin.lock(), &mut io::stderr()) {
+        process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rleveldb::{DBIterator, ReadOption, Table};
+
+    use super::*;
+
+    fn write(args: &[&str], input: &str) -> (Result<u64, i32>, String) {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let mut out = Vec::new();
+        let res = run(&args, input.as_bytes(), &mut out);
+        (res, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_write_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.ldb");
+        let name = path.to_str().unwrap();
+        let input = "a\t1\nb\t\r\nc\tvalue\twith tab\n\n";
+        let (res, _) = write(&[name, "--snappy", "--block-size", "16"], input);
+        assert_eq!(res, Ok(3));
+
+        let env = DefaultEnv::default();
+        let file = env.new_random_access_file(&path).unwrap();
+        let size = env.file_size(&path).unwrap() as u64;
+        let table = Arc::new(Table::open(Arc::new(Options::default()), file, size).unwrap());
+        let mut iter = table.iter(&ReadOption::default());
+        iter.seek_to_first();
+        let mut entries = Vec::new();
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"".to_vec()),
+            (b"c".to_vec(), b"value\twith tab".to_vec()),
+        ];
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_bad_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = dir.path().join("000001.ldb");
+        let name = name.to_str().unwrap();
+
+        let (res, out) = write(&[name], "b\t1\na\t2\n");
+        assert_eq!(res, Err(EXIT_BAD_INPUT));
+        assert!(out.starts_with("line 2: "));
+        let (res, out) = write(&[name], "a\t1\nno tab\n");
+        assert_eq!(res, Err(EXIT_BAD_INPUT));
+        assert!(out.starts_with("line 2: "));
+
+        assert_eq!(write(&[], "").0, Err(EXIT_USAGE));
+        assert_eq!(write(&[name, "--block-size"], "").0, Err(EXIT_USAGE));
+        assert_eq!(write(&[name, "--block-size", "0"], "").0, Err(EXIT_USAGE));
+        let missing = dir.path().join("missing").join("000001.ldb");
+        assert_eq!(write(&[missing.to_str().unwrap()], "").0, Err(EXIT_USAGE));
+    }
+}
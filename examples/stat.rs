@@ -1,10 +1,9 @@
-use rleveldb::{LevelDB, Options, PosixEnv};
+use rleveldb::DbBuilder;
 
 fn main() {
-    let mut options = Options::default();
-    options.create_if_missing = true;
-    let db_name = "demo";
-    let env = PosixEnv {};
-    let db = LevelDB::open(options, db_name, env).unwrap();
+    let db = DbBuilder::new()
+        .create_if_missing(true)
+        .open("demo")
+        .unwrap();
     db.debug_print();
 }
@@ -1,13 +1,18 @@
-use rleveldb::{LevelDB, Options, PosixEnv};
+use rleveldb::{DbBuilder, WriteBatch, WriteOption};
 
 fn main() {
-    let mut options = Options::default();
-    options.create_if_missing = true;
-    let db_name = "demo";
-    let env = PosixEnv {};
-    let db = LevelDB::open(options, db_name, env).unwrap();
+    let db = DbBuilder::new()
+        .create_if_missing(true)
+        .open("demo")
+        .unwrap();
     for i in 0..20 {
         db.write(format!("liu{}", i).as_bytes(), "zhong".as_bytes())
             .unwrap();
     }
+
+    // bulk load with one batch
+    let keys: Vec<String> = (0..10000).map(|i| format!("bulk{:06}", i)).collect();
+    let mut batch = WriteBatch::new();
+    batch.put_many(keys.iter().map(|k| (k, "zhong")));
+    db.write_batch(&WriteOption::default(), batch).unwrap();
 }
@@ -1,12 +1,13 @@
-use std::{mem::forget, sync::Arc};
+use std::{mem::forget, path::Path, sync::Arc};
 
 use crate::{
     env::Env,
-    error::Result,
+    error::{Error, Result},
     filenames::table_file_name,
     format::InternalKey,
     iterator::DBIterator,
     options::{Options, ReadOption},
+    rate_limiter::IoPriority,
     sstable::{Table, TableBuiler},
     table_cache::TableCache,
     utils::release::DropRelease,
@@ -14,12 +15,13 @@ use crate::{
 };
 
 pub fn build_table<E: Env>(
-    db_name: &str,
+    db_name: &Path,
     env: E,
     options: &Arc<Options>,
     table_cache: TableCache<E>,
     mut iter: Box<dyn DBIterator>,
     meta: &mut FileMetaData,
+    priority: IoPriority,
 ) -> Result<()> {
     meta.file_size = 0;
     iter.seek_to_first();
@@ -30,25 +32,45 @@ pub fn build_table<E: Env>(
 
     if iter.valid() {
         let file = env.new_writable_file(&file_name)?;
-        let mut builder = TableBuiler::new(options.clone(), file);
-        let mut smallest = InternalKey::empty();
-
-        smallest.decode(iter.key());
-        meta.smallest = smallest;
-        meta.largest = InternalKey::empty();
+        // memtable outputs always start at level 0
+        let mut builder = TableBuiler::new(options.table_options_for_level(0), file);
+        builder.set_io_priority(priority, env.clone());
+        meta.smallest = InternalKey::empty();
+        meta.smallest.decode(iter.key());
 
+        let mut largest = Vec::new();
+        let mut entries = 0;
         while iter.valid() {
-            builder.add(iter.key(), iter.value()).unwrap();
-            meta.largest.decode(iter.key()); //optimize this??
+            // a memtable iterator finds corrupted entries as it goes
+            iter.status()?;
+            builder.add(iter.key(), iter.value())?;
+            largest.clear();
+            largest.extend_from_slice(iter.key());
+            entries += 1;
             iter.next();
         }
+        iter.status()?;
+        meta.largest = InternalKey::empty();
+        meta.largest.decode(&largest);
 
         meta.file_size = builder.finish(true)?;
 
-        // verify file
+        // the table must read back whole before it's installed
         let table = table_cache.find_table(meta.number, meta.file_size)?;
         let mut iter = Table::iter(table, &ReadOption::default());
+        iter.seek_to_first();
+        let mut read = 0;
+        while iter.valid() {
+            read += 1;
+            iter.next();
+        }
         iter.status()?;
+        if read != entries {
+            return Err(Error::Corruption(format!(
+                "table #{} holds {} of the {} entries written",
+                meta.number, read, entries
+            )));
+        }
     }
 
     // if write success ,not release file
@@ -58,3 +80,104 @@ pub fn build_table<E: Env>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmp::{BitWiseComparator, InternalKeyComparator},
+        memtable::MemTable,
+        types::ValueType,
+        MemEnv,
+    };
+
+    #[test]
+    fn test_build_table_from_memtable() {
+        let env = MemEnv::new();
+        let db_name = Path::new("/build_table");
+        env.create_dir(db_name).unwrap();
+        let icmp = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let options = Arc::new(Options {
+            comparator: Arc::new(icmp.clone()),
+            ..Default::default()
+        });
+        let mem = MemTable::new(icmp);
+        // added out of order, with a deletion and a key written twice
+        for i in (0..300u64).rev() {
+            let key = format!("key{:04}", i);
+            mem.add(i + 1, ValueType::Value, key.as_bytes(), key.as_bytes());
+        }
+        mem.add(
+            301,
+            ValueType::Deletetion,
+            b"key0100".as_ref(),
+            b"".as_ref(),
+        );
+        mem.add(302, ValueType::Value, b"key0299".as_ref(), b"new".as_ref());
+
+        let table_cache = TableCache::new(db_name.to_path_buf(), options.clone(), env.clone(), 10);
+        let mut meta = FileMetaData {
+            number: 7,
+            ..Default::default()
+        };
+        build_table(
+            db_name,
+            env.clone(),
+            &options,
+            table_cache.clone(),
+            mem.iter(),
+            &mut meta,
+            IoPriority::Foreground,
+        )
+        .unwrap();
+        assert_eq!(
+            meta.smallest.encode(),
+            InternalKey::new(b"key0000", 1, ValueType::Value).encode()
+        );
+        // the oldest entry of the last key sorts last
+        assert_eq!(
+            meta.largest.encode(),
+            InternalKey::new(b"key0299", 300, ValueType::Value).encode()
+        );
+        assert_eq!(
+            meta.file_size,
+            env.file_size(&table_file_name(db_name, 7)).unwrap() as u64
+        );
+
+        let table = table_cache.find_table(7, meta.file_size).unwrap();
+        let mut iter = Table::iter(table, &ReadOption::default());
+        iter.seek_to_first();
+        let mut mem_iter = mem.iter();
+        mem_iter.seek_to_first();
+        let mut n = 0;
+        while mem_iter.valid() {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), mem_iter.key());
+            assert_eq!(iter.value(), mem_iter.value());
+            iter.next();
+            mem_iter.next();
+            n += 1;
+        }
+        assert!(!iter.valid());
+        assert_eq!(n, 302);
+
+        // an empty memtable writes no table
+        let mut meta = FileMetaData {
+            number: 8,
+            ..Default::default()
+        };
+        let empty = MemTable::new(InternalKeyComparator::new(Arc::new(BitWiseComparator {})));
+        build_table(
+            db_name,
+            env.clone(),
+            &options,
+            table_cache,
+            empty.iter(),
+            &mut meta,
+            IoPriority::Foreground,
+        )
+        .unwrap();
+        assert_eq!(meta.file_size, 0);
+        assert!(!env.file_exists(&table_file_name(db_name, 8)));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
-    codec::VarIntReader,
-    format::{extract_sequence_key, extract_user_key},
+    codec,
+    format::{extract_tag, extract_user_key},
+    types::{Tag, ValueType, MAX_SEQUENCE_NUMBER},
 };
 
 use std::{
@@ -8,16 +9,35 @@ use std::{
     sync::Arc,
 };
 
-pub trait Comparator {
+pub trait Comparator: Send + Sync {
     fn compare(&self, left: &[u8], right: &[u8]) -> Ordering;
 
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
+
+    // The name of the comparator ordering the user keys, what a table and the
+    // manifest record. A wrapper around a user comparator gives the name of the
+    // one it wraps.
+    fn user_comparator_name(&self) -> &str {
+        self.name()
+    }
 
     fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]);
 
     fn find_shortest_successor(&self, key: &mut Vec<u8>);
 }
 
+// The name leveldb gives its bytewise comparator, so dbs are shared with it
+pub const BYTEWISE_COMPARATOR_NAME: &str = "leveldb.BytewiseComparator";
+// the misspelled name this crate used to record for the same order
+const LEGACY_BYTEWISE_COMPARATOR_NAME: &str = "Leveldb.BitwiseComaparator";
+
+// Whether a db or table recorded as written with the comparator `stored` can be
+// read with the comparator named `name`.
+pub fn is_same_comparator(stored: &str, name: &str) -> bool {
+    stored == name
+        || (name == BYTEWISE_COMPARATOR_NAME && stored == LEGACY_BYTEWISE_COMPARATOR_NAME)
+}
+
 #[derive(Clone, Copy)]
 pub struct BitWiseComparator {}
 
@@ -27,7 +47,7 @@ impl Comparator for BitWiseComparator {
     }
 
     fn name(&self) -> &'static str {
-        "Leveldb.BitwiseComaparator"
+        BYTEWISE_COMPARATOR_NAME
     }
 
     fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]) {
@@ -46,28 +66,29 @@ impl Comparator for BitWiseComparator {
     }
 
     fn find_shortest_successor(&self, key: &mut Vec<u8>) {
-        let mut truncate_len = 0;
-        for (_, byte) in key.iter_mut().enumerate() {
-            if *byte != 0xff {
-                *byte += 1;
-                truncate_len += 1;
-                break;
-            }
-        }
-        if truncate_len != 0 {
-            key.truncate(truncate_len)
+        // a key of only 0xff bytes has no shorter successor
+        if let Some(i) = key.iter().position(|&byte| byte != 0xff) {
+            key[i] += 1;
+            key.truncate(i + 1);
         }
     }
 }
 
+// Its name is only for telling comparators apart in memory. What is persisted
+// is `user_comparator_name`, so a db doesn't depend on how keys are wrapped.
 #[derive(Clone)]
 pub struct InternalKeyComparator {
     user_comparator: Arc<dyn Comparator>,
+    name: Arc<str>,
 }
 
 impl InternalKeyComparator {
     pub fn new(user_comparator: Arc<dyn Comparator>) -> Self {
-        InternalKeyComparator { user_comparator }
+        let name = format!("internal:{}", user_comparator.name()).into();
+        InternalKeyComparator {
+            user_comparator,
+            name,
+        }
     }
     pub fn user_comparator(&self) -> Arc<dyn Comparator> {
         self.user_comparator.clone()
@@ -87,23 +108,46 @@ impl Comparator for InternalKeyComparator {
             Ordering::Less => Ordering::Less,
             Ordering::Greater => Ordering::Greater,
             Ordering::Equal => {
-                let left_seq = extract_sequence_key(left);
-                let right_seq = extract_sequence_key(right);
-                right_seq.cmp(&left_seq)
+                let left_tag = extract_tag(left);
+                let right_tag = extract_tag(right);
+                right_tag.cmp(&left_tag)
             }
         }
     }
 
-    fn name(&self) -> &'static str {
-        "leveldb.InternalKeyComparator"
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &[u8]) {
-        todo!()
+    fn user_comparator_name(&self) -> &str {
+        self.user_comparator.name()
+    }
+
+    // Only the user key is shortened. A user key made shorter and larger gets the
+    // largest tag, so it sorts before every entry of that user key.
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]) {
+        let user_start = extract_user_key(start);
+        let mut tmp = user_start.to_vec();
+        self.user_comparator
+            .find_shortest_separator(&mut tmp, extract_user_key(limit));
+        if tmp.len() < user_start.len()
+            && self.user_comparator.compare(user_start, &tmp) == Ordering::Less
+        {
+            Tag::new(MAX_SEQUENCE_NUMBER, ValueType::Value).encode_to(&mut tmp);
+            *start = tmp;
+        }
     }
 
-    fn find_shortest_successor(&self, _key: &mut Vec<u8>) {
-        todo!()
+    fn find_shortest_successor(&self, key: &mut Vec<u8>) {
+        let user_key = extract_user_key(key);
+        let mut tmp = user_key.to_vec();
+        self.user_comparator.find_shortest_successor(&mut tmp);
+        if tmp.len() < user_key.len()
+            && self.user_comparator.compare(user_key, &tmp) == Ordering::Less
+        {
+            Tag::new(MAX_SEQUENCE_NUMBER, ValueType::Value).encode_to(&mut tmp);
+            *key = tmp;
+        }
     }
 }
 
@@ -137,10 +181,9 @@ impl Comparator for KeyComparator {
     }
 }
 
-pub fn get_length_prefixed_slice(mut buf: &[u8]) -> &[u8] {
-    let (len,_) = buf.read_var_u32().unwrap();
-    // assert!(len as usize == buf.len());
-    &buf[..len as usize]
+pub fn get_length_prefixed_slice(buf: &[u8]) -> &[u8] {
+    let (len, len_size) = codec::decode_var_u32(buf).unwrap();
+    &buf[len_size..len_size + len as usize]
 }
 
 #[cfg(test)]
@@ -193,6 +236,125 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_find_shortest_successor() {
+        let tests: Vec<(&[u8], &[u8])> = vec![
+            (b"abc", b"b"),
+            (&[0xff, 0xff, 1, 7], &[0xff, 0xff, 2]),
+            (&[0xff, 0xff], &[0xff, 0xff]),
+            (&[], &[]),
+        ];
+        let comparator = BitWiseComparator {};
+        for &(key, expect) in tests.iter() {
+            let mut successor = Vec::from(key);
+            comparator.find_shortest_successor(&mut successor);
+            assert_eq!(&successor, expect);
+            assert!(comparator.compare(key, &successor) != Ordering::Greater);
+        }
+    }
+
+    fn ikey(user_key: &[u8], seq: u64, t: ValueType) -> Vec<u8> {
+        let mut key = user_key.to_vec();
+        Tag::new(seq, t).encode_to(&mut key);
+        key
+    }
+
+    fn shorten(start: Vec<u8>, limit: Vec<u8>) -> Vec<u8> {
+        let icmp = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let mut separator = start.clone();
+        icmp.find_shortest_separator(&mut separator, &limit);
+        assert!(icmp.compare(&start, &separator) != Ordering::Greater);
+        separator
+    }
+
+    #[test]
+    fn test_internal_key_short_separator() {
+        let foo = ikey(b"foo", 100, ValueType::Value);
+
+        // when the user keys are the same
+        for limit in [
+            ikey(b"foo", 99, ValueType::Value),
+            ikey(b"foo", 101, ValueType::Value),
+            ikey(b"foo", 100, ValueType::Value),
+            ikey(b"foo", 100, ValueType::Deletetion),
+        ] {
+            assert_eq!(shorten(foo.clone(), limit), foo);
+        }
+
+        // when the user keys are misordered
+        assert_eq!(
+            shorten(foo.clone(), ikey(b"bar", 99, ValueType::Value)),
+            foo
+        );
+
+        // when the user keys are different, but correctly ordered
+        assert_eq!(
+            shorten(foo.clone(), ikey(b"hello", 200, ValueType::Value)),
+            ikey(b"g", MAX_SEQUENCE_NUMBER, ValueType::Value)
+        );
+
+        // when the start user key is a prefix of the limit user key
+        assert_eq!(
+            shorten(foo.clone(), ikey(b"foobar", 200, ValueType::Value)),
+            foo
+        );
+
+        // when the limit user key is a prefix of the start user key
+        let foobar = ikey(b"foobar", 100, ValueType::Value);
+        assert_eq!(
+            shorten(foobar.clone(), ikey(b"foo", 200, ValueType::Value)),
+            foobar
+        );
+    }
+
+    #[test]
+    fn test_internal_key_shortest_successor() {
+        let icmp = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let tests = [
+            (
+                ikey(b"foo", 100, ValueType::Value),
+                ikey(b"g", MAX_SEQUENCE_NUMBER, ValueType::Value),
+            ),
+            (
+                ikey(&[0xff, 0xff], 100, ValueType::Value),
+                ikey(&[0xff, 0xff], 100, ValueType::Value),
+            ),
+        ];
+        for (key, expect) in tests {
+            let mut successor = key.clone();
+            icmp.find_shortest_successor(&mut successor);
+            assert_eq!(successor, expect);
+            assert!(icmp.compare(&key, &successor) != Ordering::Greater);
+        }
+    }
+
+    #[test]
+    fn test_comparator_names() {
+        let icmp = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        assert_eq!(icmp.name(), "internal:leveldb.BytewiseComparator");
+        assert_eq!(icmp.user_comparator_name(), BYTEWISE_COMPARATOR_NAME);
+        assert_eq!(
+            BitWiseComparator {}.user_comparator_name(),
+            BYTEWISE_COMPARATOR_NAME
+        );
+
+        assert!(is_same_comparator(
+            BYTEWISE_COMPARATOR_NAME,
+            BYTEWISE_COMPARATOR_NAME
+        ));
+        assert!(is_same_comparator(
+            "Leveldb.BitwiseComaparator",
+            BYTEWISE_COMPARATOR_NAME
+        ));
+        // the alias is only for the bytewise comparator, and never the other way
+        assert!(!is_same_comparator("Leveldb.BitwiseComaparator", "other"));
+        assert!(!is_same_comparator(
+            BYTEWISE_COMPARATOR_NAME,
+            "Leveldb.BitwiseComaparator"
+        ));
+        assert!(!is_same_comparator(icmp.name(), BYTEWISE_COMPARATOR_NAME));
+    }
+
     #[test]
     fn test_bit_wise_comparator_cmp() {
         let _tests: Vec<(&[u8], &[u8], Ordering)> = vec![
@@ -72,10 +72,13 @@ pub trait VarIntWriter: NumberWriter {
         }
     }
     fn write_var_u64(&mut self, mut v: u64) -> Result<usize> {
+        if v <= u64::from(u32::MAX) {
+            return self.write_var_u32(v as u32);
+        }
         let mut len = 0;
         while v >= u64::from(MASK) {
             let n = (v | u64::from(MASK)) & 0xFF;
-            self.write_u8(n as u8).unwrap();
+            self.write_u8(n as u8)?;
             v >>= 7;
             len += 1;
         }
@@ -88,6 +91,8 @@ impl VarIntWriter for &mut [u8] {}
 impl VarIntWriter for Vec<u8> {}
 
 pub trait VarIntReader: NumberReader {
+    // Fails on truncated input, and on a varint longer than 5 bytes or with bits
+    // beyond the 32nd.
     fn read_var_u32(&mut self) -> Result<(u32, usize)> {
         let mut shift = 0;
         let mut result = 0;
@@ -95,6 +100,9 @@ pub trait VarIntReader: NumberReader {
         while shift <= 28 {
             let byte = self.read_u8()?;
             len += 1;
+            if shift == 28 && byte > 0x0f {
+                break;
+            }
             if u32::from(byte) & MASK == 0 {
                 result |= (u32::from(byte)) << shift;
                 return Ok((result, len));
@@ -109,6 +117,7 @@ pub trait VarIntReader: NumberReader {
         ))
     }
 
+    // like read_var_u32, at most 10 bytes
     fn read_var_u64(&mut self) -> Result<(u64, usize)> {
         let mut shift = 0;
         let mut result = 0;
@@ -116,6 +125,9 @@ pub trait VarIntReader: NumberReader {
         while shift <= 63 {
             let byte = self.read_u8()?;
             len += 1;
+            if shift == 63 && byte > 1 {
+                break;
+            }
             if u64::from(byte) & u64::from(MASK) == 0 {
                 result |= (u64::from(byte)) << shift;
                 return Ok((result, len));
@@ -133,18 +145,20 @@ pub trait VarIntReader: NumberReader {
 
 impl VarIntReader for &[u8] {}
 
-pub fn required_space(data: u64) -> usize {
-    let mut data = data;
-    if data == 0 {
-        return 1;
-    }
+// Decodes the varint32 at the start of `src`, along with its length.
+pub fn decode_var_u32(mut src: &[u8]) -> Result<(u32, usize)> {
+    src.read_var_u32()
+}
 
-    let mut logcounter = 0;
-    while data > 0 {
-        logcounter += 1;
-        data >>= 7;
-    }
-    logcounter
+pub fn decode_var_u64(mut src: &[u8]) -> Result<(u64, usize)> {
+    src.read_var_u64()
+}
+
+// the length of the varint of `data`
+pub fn required_space(data: u64) -> usize {
+    // 7 bits a byte, 0 takes one byte too
+    let bits = 64 - (data | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
 }
 
 pub fn write_length_prefixed_slice(target: &mut Vec<u8>, data: &[u8]) -> Result<()> {
@@ -166,3 +180,89 @@ pub fn read_length_prefixed_slice<'a>(data: &mut &'a [u8]) -> Result<&'a [u8]> {
     *data = right;
     Ok(left)
 }
+
+#[cfg(test)]
+mod tests {
+    use integer_encoding::VarInt;
+    use rand::Rng;
+
+    use super::*;
+
+    fn sample() -> Vec<u64> {
+        let mut rng = rand::thread_rng();
+        let mut values = vec![0, 1, u64::MAX];
+        for shift in 0..64 {
+            let bit = 1u64 << shift;
+            values.extend([bit - 1, bit, bit + 1]);
+        }
+        values.extend((0..10000).map(|_| rng.gen::<u64>() >> rng.gen_range(0..64)));
+        values
+    }
+
+    // the encoding is the one of the integer-encoding crate the codec replaced
+    #[test]
+    fn test_against_integer_encoding() {
+        for v in sample() {
+            let mut encoded = Vec::new();
+            let len = encoded.write_var_u64(v).unwrap();
+            assert_eq!(encoded, v.encode_var_vec(), "{}", v);
+            assert_eq!(len, encoded.len());
+            assert_eq!(required_space(v), v.required_space());
+            assert_eq!(decode_var_u64(&encoded).unwrap(), (v, len));
+
+            let v = v as u32;
+            let mut encoded = Vec::new();
+            let len = encoded.write_var_u32(v).unwrap();
+            assert_eq!(encoded, v.encode_var_vec(), "{}", v);
+            assert_eq!(decode_var_u32(&encoded).unwrap(), (v, len));
+            assert_eq!(u32::decode_var(&encoded), Some((v, len)));
+        }
+    }
+
+    #[test]
+    fn test_malformed_varints() {
+        for v in sample() {
+            let mut encoded = Vec::new();
+            encoded.write_var_u64(v).unwrap();
+            // a varint followed by more data only takes its own bytes
+            let mut more = encoded.clone();
+            more.extend_from_slice(&[0xff, 0x01]);
+            assert_eq!(decode_var_u64(&more).unwrap(), (v, encoded.len()));
+            for cut in 0..encoded.len() {
+                assert!(decode_var_u64(&encoded[..cut]).is_err());
+            }
+            if v > u64::from(u32::MAX) {
+                assert!(decode_var_u32(&encoded).is_err(), "{}", v);
+            }
+
+            // into a slice that is too short
+            let mut buf = vec![0; encoded.len() - 1];
+            assert!((&mut buf[..]).write_var_u64(v).is_err());
+        }
+
+        // too long, or with bits past the end of the type
+        let cases: [(&[u8], bool, bool); 5] = [
+            (&[0xff, 0xff, 0xff, 0xff, 0x0f], true, true),
+            (&[0xff, 0xff, 0xff, 0xff, 0x10], false, true),
+            (&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], false, true),
+            (
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01],
+                false,
+                true,
+            ),
+            (
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02],
+                false,
+                false,
+            ),
+        ];
+        for (data, u32_ok, u64_ok) in cases {
+            assert_eq!(decode_var_u32(data).is_ok(), u32_ok, "{:?}", data);
+            assert_eq!(decode_var_u64(data).is_ok(), u64_ok, "{:?}", data);
+        }
+        let overlong: [u8; 11] = [
+            0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0,
+        ];
+        assert!(decode_var_u64(&overlong).is_err());
+    }
+}
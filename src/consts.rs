@@ -2,6 +2,10 @@ pub const NUM_LEVELS: usize = 7;
 // Level-0 compaction is started when we hit this many files.
 pub const L0_COMPACTION_TRIGGER: usize = 4;
 
+// Largest `Options::block_restart_interval` a db uses, larger values are
+// clamped to it.
+pub const MAX_BLOCK_RESTART_INTERVAL: u32 = 1024;
+
 // Soft limit on number of level-0 files.  We slow down writes at this point.
 pub const L0_SLOW_DOWN_WRITES_TRIGGER: usize = 8;
 
@@ -17,4 +21,21 @@ pub const L0_STOP_WRITES_TRIGGER: usize = 12;
 pub const MAX_MEM_COMPACT_LEVEL: usize = 2;
 
 // Approximate gap in bytes between samples of data read during iteration.
-pub const READ_BYTES_PERIOD: usize = 1048576;
\ No newline at end of file
+pub const READ_BYTES_PERIOD: usize = 1048576;
+// The names of the entries of a table's meta index block. Readers look up the
+// entries they know by exact name and skip the rest, so a table written by a
+// later version still opens. Every name in use or set aside for a planned block
+// is listed here, pick new ones so they don't collide with these.
+pub const FILTER_META_KEY_PREFIX: &str = "filter";
+pub const PROPERTIES_META_KEY: &str = "rleveldb.properties";
+// set aside, not written yet
+pub const RANGE_DEL_META_KEY: &str = "rleveldb.range-del";
+pub const PREFIX_FILTER_META_KEY_PREFIX: &str = "rleveldb.prefix-filter.";
+
+// each name, or prefix of names, of a meta index entry
+pub const RESERVED_META_KEYS: [&str; 4] = [
+    FILTER_META_KEY_PREFIX,
+    PROPERTIES_META_KEY,
+    RANGE_DEL_META_KEY,
+    PREFIX_FILTER_META_KEY_PREFIX,
+];
@@ -78,7 +78,7 @@ pub fn parse_test_data(input: &str, source: &str) -> Result<Vec<TestData>> {
     loop {
         let mut data = TestData::default();
         if let Some((line_no, line)) = iter.next() {
-            if line.starts_with("#") {
+            if line.starts_with('#') {
                 continue;
             }
             let fields = split_directive(line);
@@ -89,7 +89,7 @@ pub fn parse_test_data(input: &str, source: &str) -> Result<Vec<TestData>> {
             data.pos = format!("{}:{}", source, line_no);
             data.cmd = fields[0].clone();
             for arg in &fields[1..] {
-                if let Some(idx) = arg.find("=") {
+                if let Some(idx) = arg.find('=') {
                     let key = arg[0..idx].to_owned();
                     let val = &arg[idx + 1..];
 
@@ -99,7 +99,7 @@ pub fn parse_test_data(input: &str, source: &str) -> Result<Vec<TestData>> {
                         && val.as_bytes()[val.len() - 1] == b')'
                     {
                         vals = val[1..val.len() - 1]
-                            .split(",")
+                            .split(',')
                             .map(|s| s.trim().to_owned())
                             .collect();
                     } else {
@@ -115,30 +115,22 @@ pub fn parse_test_data(input: &str, source: &str) -> Result<Vec<TestData>> {
             }
             let mut buf = String::new();
             let mut seperator = false;
-            loop {
-                if let Some((_, line)) = iter.next() {
-                    if line == "----" {
-                        seperator = true;
-                        break;
-                    }
-                    write!(&mut buf, "{}\n", line).unwrap();
-                } else {
+            for (_, line) in iter.by_ref() {
+                if line == "----" {
+                    seperator = true;
                     break;
                 }
+                writeln!(&mut buf, "{}", line).unwrap();
             }
             data.input = buf.trim().to_owned();
             if seperator {
                 let mut buf = String::new();
-                loop {
-                    if let Some((_, line)) = iter.next() {
-                        let line = line.trim();
-                        if line.is_empty() {
-                            break;
-                        }
-                        write!(&mut buf, "{}\n", line).unwrap();
-                    } else {
+                for (_, line) in iter.by_ref() {
+                    let line = line.trim();
+                    if line.is_empty() {
                         break;
                     }
+                    writeln!(&mut buf, "{}", line).unwrap();
                 }
                 data.expected = buf;
             }
@@ -155,10 +147,10 @@ const PATTERN: &str = r"^ *[a-zA-Z0-9_/,-\.]+(|=[-a-zA-Z0-9_@]+|=\([^)]*\))( |$)
 fn split_directive(mut line: &str) -> Vec<String> {
     let p = Regex::new(PATTERN).unwrap();
     let mut results = Vec::new();
-    while line != "" {
+    while !line.is_empty() {
         let m = p
             .find(line)
-            .expect(&format!("cannot parse directive: {}", line));
+            .unwrap_or_else(|| panic!("cannot parse directive: {}", line));
         let v = m.as_str();
         line = &line[v.len()..];
         results.push(v.trim().to_string());
@@ -1 +1,297 @@
+use std::{path::Path, sync::Arc};
 
+use crate::{
+    db_impl::{destroy_db, LevelDB},
+    env::{DefaultEnv, Env},
+    error::Result,
+    filter::BloomFilterPolicy,
+    options::{invalid_options, Compress, Options, OptionsError, MAX_FILTER_BITS_PER_KEY},
+    repair::repair_db,
+    utils::cache::ShardLruCache,
+};
+
+// Collects the options of a db and then opens, repairs or destroys it. The
+// settings are only checked by the method finishing the builder, which reports
+// all invalid ones at once.
+//
+//     let db = DbBuilder::new().create_if_missing(true).open("demo")?;
+#[derive(Clone)]
+pub struct DbBuilder<E: Env = DefaultEnv> {
+    options: Options,
+    filter_bits_per_key: Option<usize>,
+    block_cache_capacity: Option<usize>,
+    env: E,
+}
+
+impl DbBuilder<DefaultEnv> {
+    pub fn new() -> Self {
+        DbBuilder {
+            options: Options::default(),
+            filter_bits_per_key: None,
+            block_cache_capacity: None,
+            env: DefaultEnv::default(),
+        }
+    }
+}
+
+impl Default for DbBuilder<DefaultEnv> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Env> DbBuilder<E> {
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.options.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.options.error_if_exists = error_if_exists;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: u64) -> Self {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    // keep everything in memory and the WAL, see `Options::memtable_only`
+    pub fn memtable_only(mut self, memtable_only: bool) -> Self {
+        self.options.memtable_only = memtable_only;
+        self
+    }
+
+    // keep appending to the last WAL on open, see `Options::reuse_log`
+    pub fn reuse_log(mut self, reuse_log: bool) -> Self {
+        self.options.reuse_log = reuse_log;
+        self
+    }
+
+    // 0 to flush and compact on the writing threads, see `Options::background_threads`
+    pub fn background_threads(mut self, background_threads: u32) -> Self {
+        self.options.background_threads = background_threads;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compress) -> Self {
+        self.options.compression_type = compression;
+        self
+    }
+
+    // a bloom filter with this many bits per key for every table
+    pub fn filter_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.filter_bits_per_key = Some(bits_per_key);
+        self
+    }
+
+    // an lru cache of uncompressed data blocks charging at most `capacity` bytes
+    pub fn block_cache_capacity(mut self, capacity: usize) -> Self {
+        self.block_cache_capacity = Some(capacity);
+        self
+    }
+
+    pub fn env<F: Env>(self, env: F) -> DbBuilder<F> {
+        DbBuilder {
+            options: self.options,
+            filter_bits_per_key: self.filter_bits_per_key,
+            block_cache_capacity: self.block_cache_capacity,
+            env,
+        }
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<LevelDB<E>> {
+        LevelDB::open(self.build_options()?, path, self.env.clone())
+    }
+
+    pub fn open_read_only(&self, path: impl AsRef<Path>) -> Result<LevelDB<E>> {
+        LevelDB::open_read_only(self.build_options()?, path, self.env.clone())
+    }
+
+    pub fn repair(&self, path: impl AsRef<Path>) -> Result<()> {
+        repair_db(self.build_options()?, path.as_ref(), self.env.clone())
+    }
+
+    pub fn destroy(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.build_options()?;
+        destroy_db(path.as_ref(), self.env.clone())
+    }
+
+    fn build_options(&self) -> Result<Options> {
+        let mut options = self.options.clone();
+        let mut errors = options.validate().err().unwrap_or_default();
+        if let Some(bits_per_key) = self.filter_bits_per_key {
+            if (1..=MAX_FILTER_BITS_PER_KEY).contains(&bits_per_key) {
+                options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(bits_per_key)));
+            } else {
+                errors.push(OptionsError::FilterBitsOutOfRange {
+                    min: 1,
+                    max: MAX_FILTER_BITS_PER_KEY,
+                    got: bits_per_key,
+                });
+            }
+        }
+        if let Some(capacity) = self.block_cache_capacity {
+            if capacity == 0 {
+                errors.push(OptionsError::BlockCacheCapacityZero);
+            } else {
+                options.block_cache = Some(Arc::new(ShardLruCache::new(capacity as u64)));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(invalid_options(&errors));
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::error::Error;
+
+    fn listing(path: &Path) -> Vec<(PathBuf, u64)> {
+        let mut files: Vec<_> = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path(), entry.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let dir = tempfile::Builder::new()
+            .prefix("builder")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("db");
+
+        assert!(DbBuilder::new().open_read_only(&path).is_err());
+        assert!(!path.exists());
+        assert!(DbBuilder::new().open(&path).is_err());
+
+        let builder = DbBuilder::new()
+            .create_if_missing(true)
+            .write_buffer_size(1 << 20)
+            .compression(Compress::NO)
+            .filter_bits_per_key(10)
+            .block_cache_capacity(1 << 20);
+        {
+            let db = builder.open(&path).unwrap();
+            db.write(b"key", b"value").unwrap();
+        }
+        assert!(matches!(
+            builder.clone().error_if_exists(true).open(&path),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // a read only db doesn't touch any file
+        let files = listing(&path);
+        {
+            let db = builder.open_read_only(&path).unwrap();
+            assert!(matches!(
+                db.write(b"key", b"other"),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert_eq!(listing(&path), files);
+
+        builder.repair(&path).unwrap();
+        builder.open(&path).unwrap();
+
+        // the files repair moved aside are kept
+        builder.destroy(&path).unwrap();
+        let left: Vec<_> = listing(&path).into_iter().map(|(f, _)| f).collect();
+        assert_eq!(left, vec![path.join("lost")]);
+        std::fs::remove_dir_all(&path).unwrap();
+        // nothing left to destroy
+        builder.destroy(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validation_errors_at_terminal_method() {
+        let dir = tempfile::Builder::new()
+            .prefix("builder_validation")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("db");
+        let builders = [
+            DbBuilder::new().write_buffer_size(0),
+            DbBuilder::new().filter_bits_per_key(0),
+            DbBuilder::new().block_cache_capacity(0),
+        ];
+        for builder in builders {
+            let builder = builder.create_if_missing(true);
+            assert!(matches!(
+                builder.open(&path),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(matches!(
+                builder.open_read_only(&path),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(matches!(
+                builder.repair(&path),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(matches!(
+                builder.destroy(&path),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert!(!path.exists());
+
+        // every invalid setting is reported, not just the first
+        let builder = DbBuilder::new()
+            .write_buffer_size(0)
+            .filter_bits_per_key(MAX_FILTER_BITS_PER_KEY + 1)
+            .block_cache_capacity(0);
+        let msg = match builder.open(&path) {
+            Err(Error::InvalidArgument(msg)) => msg,
+            res => panic!("{:?}", res.err()),
+        };
+        assert_eq!(
+            msg,
+            "invalid options: write_buffer_size 0 is below the minimum of 65536; \
+             filter_bits_per_key 65 is out of [1, 64]; block_cache_capacity must not be 0"
+        );
+
+        let options = DbBuilder::new()
+            .filter_bits_per_key(10)
+            .block_cache_capacity(1024)
+            .build_options()
+            .unwrap();
+        assert_eq!(
+            options.filter_policy.unwrap().name(),
+            "leveldb.BuiltinBloomFilter2"
+        );
+        assert!(options.block_cache.is_some());
+    }
+
+    #[test]
+    fn test_destroy_open_db() {
+        let dir = tempfile::Builder::new()
+            .prefix("builder_destroy")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let db = builder.open(dir.path()).unwrap();
+        assert!(matches!(
+            builder.destroy(dir.path()),
+            Err(Error::AlreadyExist(_))
+        ));
+        assert!(matches!(
+            builder.repair(dir.path()),
+            Err(Error::AlreadyExist(_))
+        ));
+        drop(db);
+        builder.destroy(dir.path()).unwrap();
+        assert!(!dir.path().exists());
+    }
+}
@@ -1,65 +1,246 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::ffi::OsString;
 
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
-// use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
-use std::thread;
-
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use crate::builder::build_table;
 use crate::cmp::{Comparator, InternalKeyComparator};
+use crate::consts::{
+    L0_SLOW_DOWN_WRITES_TRIGGER, L0_STOP_WRITES_TRIGGER, MAX_BLOCK_RESTART_INTERVAL, NUM_LEVELS,
+};
+use crate::db_iter::DBIter;
 use crate::env::WritableFile;
 use crate::error::{Error, Result};
 use crate::filenames::{
-    current_file_name, descriptor_file_name, log_file_name, parse_file_name, set_current_file,
-    FileType,
+    current_file_name, descriptor_file_name, lock_file_name, log_file_name, parse_file_name,
+    set_current_file, sst_table_file_name, table_file_name, FileType,
 };
-use crate::iterator::DBIterator;
-use crate::options::{ReadOption, WriteOption};
+use crate::filter::InternalFilterPolicy;
+use crate::format::{extract_user_key, InternalKey, ParsedInternalKey};
+use crate::iterator::{DBIterator, PinnedIterator};
+use crate::listener::{CompactionInfo, CompactionKind, TableFileInfo};
+use crate::memtable::MemGet;
+use crate::merge::MergingIterator;
+use crate::options::{invalid_options, ReadOption, ReadTier, WriteOption};
+use crate::rate_limiter::IoPriority;
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::sstable::{Table, TableBuiler};
+use crate::stats::{level_stats_property, CompactionStats, WriteStats};
 use crate::table_cache::TableCache;
-use crate::types::SequenceNumber;
-use crate::version::{FileMetaData, Version};
+use crate::trace::trace_span;
+use crate::types::{SequenceNumber, ValueType, MAX_SEQUENCE_NUMBER};
+use crate::version::{CompactionDebt, FileMetaData, GetStats, Version};
 use crate::version_edit::VersionEdit;
-use crate::version_set::VersionSet;
-use crate::{env::Env, options::Options, write_batch::WriteBatch};
-use crate::{Forward, LogReader, LogWriter, MemTable};
+use crate::version_set::{Compaction, VersionSet};
+use crate::{
+    env::{DefaultEnv, Env},
+    options::Options,
+    write_batch::WriteBatch,
+};
+use crate::{LogReader, LogWriter, LookupKey, MemTable};
 
 pub struct LevelDB<E: Env> {
     inner: Arc<DBImplInner<E>>,
+    // joined by the drop, none without background threads
+    compaction_worker: Option<JoinHandle<()>>,
+    // dropped after `inner`
+    _registration: OpenDb,
+}
+
+// The files of a db at one point, see `LevelDB::live_files`. A copy of the db
+// needs a CURRENT file naming the manifest, and only the first `manifest_size`
+// bytes of it: the manifest grows with every later change.
+#[derive(Debug)]
+pub struct LiveFilesInfo {
+    pub manifest: PathBuf,
+    pub manifest_size: u64,
+    // the log of the writes after the tables, none in a read only db
+    pub wal: Option<PathBuf>,
+    // the writes up to this sequence made it to the disk
+    pub wal_synced_sequence: SequenceNumber,
+    pub tables: Vec<LiveTableFile>,
+}
+
+#[derive(Debug)]
+pub struct LiveTableFile {
+    pub level: usize,
+    pub number: u64,
+    pub name: PathBuf,
+    pub size: u64,
+    pub smallest_user_key: Vec<u8>,
+    pub largest_user_key: Vec<u8>,
+}
+
+// The memtable bytes waiting to be written to tables, see
+// `LevelDB::pending_flush`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PendingFlush {
+    pub memtable_bytes: u64,
+    pub immutable_memtable_bytes: u64,
+}
+
+impl PendingFlush {
+    pub fn total_bytes(&self) -> u64 {
+        self.memtable_bytes + self.immutable_memtable_bytes
+    }
+}
+
+// Canonical paths of the databases open in this process, by the file system of
+// their env. A second open of the same path would interleave its WAL and manifest
+// writes with the first one, the LOCK file only keeps other processes out.
+static OPEN_DBS: Mutex<BTreeSet<(u64, PathBuf)>> = Mutex::new(BTreeSet::new());
+
+pub(crate) struct OpenDb((u64, PathBuf));
+
+impl OpenDb {
+    // the directory has to exist to be canonicalized
+    pub(crate) fn register<E: Env>(env: &E, db_name: &Path) -> Result<Self> {
+        let key = (env.fs_id(), env.canonicalize(db_name)?);
+        if !OPEN_DBS.lock().unwrap().insert(key.clone()) {
+            return Err(Error::AlreadyExist(format!(
+                "db {:?} is already open in this process",
+                key.1
+            )));
+        }
+        Ok(OpenDb(key))
+    }
+}
+
+impl Drop for OpenDb {
+    fn drop(&mut self) {
+        OPEN_DBS.lock().unwrap().remove(&self.0);
+    }
+}
+
+// The flush of the immutable memtable is waited for, a level compaction in
+// flight stops at its next key and the tables it wrote are deleted by the next
+// open. The WAL is synced, so no write is lost. The LOCK file goes before the
+// registration, no other open of this process finds it held
+impl<E: Env> Drop for LevelDB<E> {
+    fn drop(&mut self) {
+        self.inner.wait_for_imm_flush();
+        self.inner.shutdown.store(true, Ordering::Release);
+        // closing the channel wakes the worker, it ends after the round it's in
+        self.inner.compaction_trigger.lock().unwrap().take();
+        if let Some(worker) = self.compaction_worker.take() {
+            let _ = worker.join();
+        }
+        self.inner.close();
+        self.inner.release_lock();
+    }
+}
+
+impl LevelDB<DefaultEnv> {
+    // opens the db with the env of the platform, see `DefaultEnv`
+    pub fn open_default(options: Options, db_name: impl AsRef<Path>) -> Result<Self> {
+        Self::open(options, db_name, DefaultEnv::default())
+    }
 }
 
 impl<E: Env> LevelDB<E> {
-    pub fn open(options: Options, db_name: impl Into<String>, env: E) -> Result<Self> {
-        let db_name = db_name.into();
-        let db = DBImplInner::new(options, &db_name, env.clone());
+    pub fn open(options: Options, db_name: impl AsRef<Path>, env: E) -> Result<Self> {
+        Self::open_inner(options, db_name.as_ref(), env, false)
+    }
+
+    // Opens an existing db without changing any of its files. The WAL is replayed
+    // into the memtable instead of level-0 tables, and every write fails.
+    pub fn open_read_only(options: Options, db_name: impl AsRef<Path>, env: E) -> Result<Self> {
+        Self::open_inner(options, db_name.as_ref(), env, true)
+    }
+
+    fn open_inner(options: Options, db_name: &Path, env: E, read_only: bool) -> Result<Self> {
+        options
+            .validate()
+            .map_err(|errors| invalid_options(&errors))?;
+        if read_only {
+            if !env.file_exists(&current_file_name(db_name)) {
+                return Err(Error::InvalidArgument("db not exists".into()));
+            }
+        } else {
+            let _ = env.create_dir(db_name);
+        }
+        let start_micros = env.now_micros();
+        let registration = OpenDb::register(&env, db_name)?;
+        let mut db = DBImplInner::new(options, db_name, env.clone());
+        db.read_only = read_only;
         let mut edit = VersionEdit::default();
         let mut save_manifest = false;
-        db.recovery(&mut edit, &mut save_manifest)?;
+        // the one listing of the directory during the open
+        let mut file_names = Vec::new();
+        db.recovery(&mut edit, &mut save_manifest, &mut file_names)?;
 
-        {
+        if read_only {
+            let mut mem = db.mem.write().unwrap();
+            if mem.is_none() {
+                *mem = Some(db.new_memtable());
+            }
+        } else {
             let mut mem = db.mem.write().unwrap();
             let mut versions = db.versions.lock().unwrap();
             let mut wal = db.wal.lock().unwrap();
-            if mem.is_none() {
+            if mem.is_none() || db.options.memtable_only {
                 let new_log_number = versions.new_file_number();
-                let file = env.new_writable_file(&log_file_name(&db_name, new_log_number))?;
+                let file = env.new_writable_file(&log_file_name(db_name, new_log_number))?;
+                let mut log = db.new_log_writer(file, 0);
+                let new_mem = db.new_memtable();
+                // a memtable only db keeps the replayed logs in its memtable. Their live
+                // entries are moved to the new log, which replaces them
+                if let Some(old) = mem.take() {
+                    let mut batch = live_entries(&old)?;
+                    if batch.count() > 0 {
+                        let sequence = versions.last_sequence() + 1;
+                        batch.set_sequence(sequence);
+                        versions.set_last_sequence(sequence + batch.count() as u64 - 1);
+                        log.add_record(batch.content())?;
+                        log.sync()?;
+                        batch.insert_into(new_mem.clone())?;
+                    }
+                }
                 wal.log_file_number = new_log_number;
-                wal.log = Some(LogWriter::new(file));
-                *mem = Some(Arc::new(MemTable::new(db.internal_comparator.clone())));
+                wal.log = Some(log);
+                *mem = Some(new_mem);
+                save_manifest |= db.options.memtable_only;
             }
+            // what recovery read back is on disk already
+            wal.synced_sequence = versions.last_sequence();
 
             if save_manifest {
                 edit.set_prev_log_number(0);
                 edit.set_log_number(wal.log_file_number);
                 versions.log_and_apply(&mut edit)?;
             }
+            if db.options.memtable_only {
+                db.delete_logs_before(wal.log_file_number, &file_names)?;
+            }
+            drop((mem, versions, wal));
+            db.delete_obsolete_files_listed(&file_names);
         }
+        db.open_stats = OpenStats {
+            micros: env.now_micros().saturating_sub(start_micros),
+            files_listed: file_names.len(),
+        };
 
-        Ok(LevelDB {
+        let mut db = LevelDB {
             inner: Arc::new(db),
-        })
+            compaction_worker: None,
+            _registration: registration,
+        };
+        // without background threads the writers do the work, and dump the stats
+        let threads = db.inner.options.background_threads > 0;
+        if threads && !read_only && !db.inner.options.memtable_only {
+            db.compaction_worker = Some(db.run_compaction_worker());
+        }
+        match db.inner.options.stats_dump_period {
+            Some(period) if threads => db.run_stats_dump_worker(period.as_micros() as u64),
+            _ => (),
+        }
+        Ok(db)
     }
 
     pub fn write(&self, key: &[u8], value: &[u8]) -> Result<()> {
@@ -69,15 +250,131 @@ impl<E: Env> LevelDB<E> {
         self.inner.write(&write_option, Some(batch))
     }
 
-    fn run_compaction_worker(&self) {
-        let inner = self.inner.clone();
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.inner.write(&WriteOption::default(), Some(batch))
+    }
+
+    // The value of `key`, `None` if it was never written or is deleted.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_with_options(&ReadOption::default(), key)
+    }
+
+    // `get` at the snapshot and read tier of `options`
+    pub fn get_with_options(&self, options: &ReadOption, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(options, key) {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::NotFoundError(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Iterates the user keys and values of the db as of the snapshot of `options`,
+    // or else as of now. The tables read stay on disk until the iterator is
+    // dropped.
+    pub fn iter(&self, options: &ReadOption) -> DBIter {
+        let (iter, sequence) = self.inner.new_internal_iterator(options);
+        DBIter::new(self.user_comparator(), iter, sequence)
+    }
+
+    // A read view of the db as it is now, for `ReadOption::snapshot`. Compactions
+    // keep what it reads until it and its clones are dropped.
+    pub fn get_snapshot(&self) -> Snapshot {
+        self.inner.get_snapshot()
+    }
+
+    // same as dropping the snapshot
+    pub fn release_snapshot(&self, snapshot: Snapshot) {
+        self.inner.release_snapshot(snapshot)
+    }
+
+    // applies all updates of the batch at once
+    pub fn write_batch(&self, options: &WriteOption, batch: WriteBatch) -> Result<()> {
+        self.inner.write(options, Some(batch))
+    }
+
+    // Makes every write so far durable, for writers that don't sync each write
+    // but want to pick their own points of durability.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.inner.sync_wal()
+    }
+
+    // The writes up to this sequence survive a crash: synced writes, and those
+    // before a `sync_wal`.
+    pub fn last_durable_sequence(&self) -> SequenceNumber {
+        self.inner.wal.lock().unwrap().synced_sequence
+    }
+
+    // The files holding the current state of the db, for backups. Flushing first
+    // leaves the least data in the WAL. The files listed may be deleted by the db
+    // once they are obsolete: call disable_file_deletions before and
+    // enable_file_deletions after copying them.
+    pub fn live_files(&self, flush_before: bool) -> Result<LiveFilesInfo> {
+        self.inner.live_files(flush_before)
+    }
+
+    // Disables deleting obsolete files until enable_file_deletions was called as
+    // often as this.
+    pub fn disable_file_deletions(&self) {
+        self.inner.disable_file_deletions()
+    }
+
+    pub fn enable_file_deletions(&self) {
+        self.inner.enable_file_deletions()
+    }
+
+    pub(crate) fn user_comparator(&self) -> Arc<dyn Comparator> {
+        self.inner.internal_comparator.user_comparator()
+    }
+
+    // see `DBImplInner::new_internal_iterator`
+    pub(crate) fn new_internal_iterator(
+        &self,
+        options: &ReadOption,
+    ) -> (Box<dyn DBIterator>, SequenceNumber) {
+        self.inner.new_internal_iterator(options)
+    }
+
+    // Like the stats worker it only holds a weak reference. The drop of the db
+    // closes the trigger channel, which ends the worker after its round.
+    fn run_compaction_worker(&self) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let trigger = self
+            .inner
+            .compaction_wakeups
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap();
         thread::Builder::new()
             .name("compaction".to_string())
             .spawn(move || {
-                while inner.compaction_trigger.1.recv().is_ok() {
-                    if inner.shutdown.load(Ordering::Acquire) {
-                        break;
+                while trigger.recv().is_ok() {
+                    match Weak::upgrade(&inner) {
+                        Some(inner) if !inner.shutdown.load(Ordering::Acquire) => {
+                            inner.run_background_work()
+                        }
+                        _ => break,
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    // the worker only holds a weak reference, it exits once the db is dropped
+    fn run_stats_dump_worker(&self, period_micros: u64) {
+        let inner = Arc::downgrade(&self.inner);
+        let env = self.inner.env.clone();
+        thread::Builder::new()
+            .name("stats-dump".to_string())
+            .spawn(move || loop {
+                env.sleep_for_micros(period_micros);
+                match Weak::upgrade(&inner) {
+                    Some(inner) if !inner.shutdown.load(Ordering::Acquire) => {
+                        inner.maybe_dump_stats()
                     }
+                    _ => break,
                 }
             })
             .unwrap();
@@ -86,32 +383,258 @@ impl<E: Env> LevelDB<E> {
     pub fn debug_print(&self) {
         self.inner.debug_print();
     }
+
+    // Zeroes the compaction and write amplification counters, the file counts and
+    // sizes reported by `leveldb.stats` describe the current version and stay.
+    pub fn reset_stats(&self) {
+        self.inner.reset_stats();
+    }
+
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        self.inner.get_property(property)
+    }
+
+    pub fn is_empty(&self, options: &ReadOption) -> Result<bool> {
+        self.inner.is_empty(options)
+    }
+
+    // About the bytes of the tables holding the keys of each (start, limit) range,
+    // limit excluded. Keys only in the memtables count for nothing.
+    pub fn get_approximate_sizes(&self, ranges: &[(&[u8], &[u8])]) -> Vec<u64> {
+        self.inner.get_approximate_sizes(ranges)
+    }
+
+    // How far compaction is behind, for shedding load before writes stall. Cheap
+    // enough to call on every request, also the `leveldb.compaction-debt-bytes`
+    // property.
+    pub fn compaction_debt(&self) -> CompactionDebt {
+        self.inner.compaction_debt()
+    }
+
+    pub fn compaction_debt_bytes(&self) -> u64 {
+        self.compaction_debt().total_bytes
+    }
+
+    // the memory the memtables use, also the `leveldb.pending-flush-bytes` property
+    pub fn pending_flush(&self) -> PendingFlush {
+        self.inner.pending_flush()
+    }
+
+    pub fn pending_flush_bytes(&self) -> u64 {
+        self.pending_flush().total_bytes()
+    }
+
+    // Compacts the keys in [begin, end] down to the last level holding any of
+    // them, the memtable included. `None` is before the first key or after the
+    // last. Deleted and overwritten entries are dropped on the way, which is how
+    // the space of bulk deletes is reclaimed. Returns once it's done.
+    pub fn compact_range(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        self.inner.compact_range(begin, end)
+    }
+
+    // Compacts every table written more than `age` ago into the next level. Tables
+    // whose creation time is unknown, and those of the last level, are left alone.
+    pub fn compact_files_older_than(&self, age: Duration) -> Result<()> {
+        self.inner.compact_files_older_than(age)
+    }
+}
+
+// The newest entry of every key of `mem` that isn't a deletion, as a batch.
+fn live_entries(mem: &MemTable) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut iter = mem.iter();
+    iter.seek_to_first();
+    while iter.valid() {
+        let ikey = ParsedInternalKey::parse(iter.key());
+        // entries of a key are ordered newest first
+        if last_key.as_deref() != Some(ikey.user_key) {
+            if ikey.val_type == ValueType::Value {
+                batch.put(ikey.user_key, iter.value());
+            }
+            last_key = Some(ikey.user_key.to_vec());
+        }
+        iter.next();
+    }
+    iter.status()?;
+    Ok(batch)
+}
+
+// A batch applied with its own sequence may not go back to sequences the db has
+// already used, readers would see its entries reordered with older ones.
+fn check_preserved_sequence(batch: &WriteBatch, last_sequence: SequenceNumber) -> Result<()> {
+    let sequence = batch.sequence();
+    if sequence <= last_sequence {
+        return Err(Error::InvalidArgument(format!(
+            "batch sequence {} is not after the last sequence {}",
+            sequence, last_sequence
+        )));
+    }
+    let end = sequence.checked_add(batch.count() as u64);
+    if end.is_none_or(|end| end > MAX_SEQUENCE_NUMBER + 1) {
+        return Err(Error::InvalidArgument(format!(
+            "batch sequence {} with {} entries overflows the sequence numbers",
+            sequence,
+            batch.count()
+        )));
+    }
+    Ok(())
+}
+
+// Deletes the files of the db at `db_name`, and the directory if nothing else is
+// left in it. Fails if the db is open in this process, or locked by another.
+pub fn destroy_db<E: Env>(db_name: &Path, env: E) -> Result<()> {
+    if !env.file_exists(db_name) {
+        return Ok(());
+    }
+    let _registration = OpenDb::register(&env, db_name)?;
+    let lock_name = lock_file_name(db_name);
+    let lock = env.lock_file(&lock_name)?;
+    let mut files = Vec::new();
+    env.get_children(db_name, &mut files)?;
+    let mut res = Ok(());
+    for f in files.iter() {
+        match parse_file_name(f) {
+            Ok((_, FileType::DBLock)) | Err(_) => {}
+            Ok(_) => {
+                if let Err(e) = env.delete_file(&db_name.join(f)) {
+                    res = Err(e.into());
+                }
+            }
+        }
+    }
+    let _ = env.unlock_file(lock);
+    let _ = env.delete_file(&lock_name);
+    let _ = env.delete_dir(db_name);
+    res
+}
+
+// How the open went: its duration and the directory entries it listed, also the
+// `leveldb.open-micros` and `leveldb.open-files-listed` properties.
+#[derive(Clone, Copy, Debug, Default)]
+struct OpenStats {
+    micros: u64,
+    files_listed: usize,
 }
 
 struct Wal<W: WritableFile> {
     pub log_file_number: u64,
     pub log: Option<LogWriter<W>>,
+    // the writes up to this sequence are durable
+    pub synced_sequence: SequenceNumber,
 }
 
 const NUM_NON_TABLE_CACHE_FILES: u64 = 10;
+const MIN_MAX_OPEN_FILES: u64 = 64 + NUM_NON_TABLE_CACHE_FILES;
+const MAX_MAX_OPEN_FILES: u64 = 50000;
+
+// the longest a writer waits for background work before checking again
+const BACKGROUND_WORK_WAIT: Duration = Duration::from_secs(1);
 
 fn table_cache_size(sanitized_options: &Arc<Options>) -> u64 {
     sanitized_options.max_open_files - NUM_NON_TABLE_CACHE_FILES
 }
 
+// The options the tables of a db are written and read with. They hold internal
+// keys, ordered by the internal key comparator and filtered on the user key.
+pub(crate) fn sanitize_options(options: Options) -> Options {
+    let block_restart_interval = options
+        .block_restart_interval
+        .clamp(1, MAX_BLOCK_RESTART_INTERVAL);
+    if block_restart_interval != options.block_restart_interval {
+        if let Some(logger) = options.info_log.as_ref() {
+            logger.log(&format!(
+                "block_restart_interval {} is out of [1, {}], using {}",
+                options.block_restart_interval, MAX_BLOCK_RESTART_INTERVAL, block_restart_interval
+            ));
+        }
+    }
+    let max_open_files = options
+        .max_open_files
+        .clamp(MIN_MAX_OPEN_FILES, MAX_MAX_OPEN_FILES);
+    if max_open_files != options.max_open_files {
+        if let Some(logger) = options.info_log.as_ref() {
+            logger.log(&format!(
+                "max_open_files {} is out of [{}, {}], using {}",
+                options.max_open_files, MIN_MAX_OPEN_FILES, MAX_MAX_OPEN_FILES, max_open_files
+            ));
+        }
+    }
+    let filter_policy_per_level = options.filter_policy_per_level.map(|policies| {
+        policies
+            .into_iter()
+            .map(|p| p.map(InternalFilterPolicy::wrap))
+            .collect()
+    });
+    Options {
+        comparator: Arc::new(InternalKeyComparator::new(options.comparator)),
+        filter_policy: options.filter_policy.map(InternalFilterPolicy::wrap),
+        filter_policy_per_level,
+        block_restart_interval,
+        max_open_files,
+        ..options
+    }
+}
+
+// A write waiting in the queue. The writer at the front writes its batch along
+// with those of the writers behind it, they get the result on `notifier`.
 struct Writer {
+    // tells a writer its own entry in the queue
+    id: u64,
     batch: Option<WriteBatch>,
     notifier: Sender<Result<()>>,
     sync: bool,
+    preserve_sequence: bool,
+}
+
+// A compaction of the keys in [begin, end] at `level` asked for by
+// `compact_range`. The worker runs it ahead of size and seek triggered
+// compactions, a step at a time: each step moves the first overlapping files to
+// the next level and continues after them, until no file of the level overlaps.
+struct ManualCompaction {
+    level: usize,
+    begin: Option<InternalKey>,
+    end: Option<InternalKey>,
+    done: bool,
+}
+
+// What `leveldb.compaction-state` shows of the level compactions.
+#[derive(Default)]
+struct CompactionStatus {
+    running: Option<RunningCompaction>,
+    // finished or failed since the db was opened
+    completed: u64,
+}
+
+struct RunningCompaction {
+    level: usize,
+    input_files: Vec<u64>,
+    // Env::now_micros when it started
+    started_micros: u64,
 }
 
-enum BatchTask {
-    Write(Writer),
-    Close,
+// A user key for a property, escaped and cut after 32 bytes.
+fn escape_key(key: &[u8]) -> String {
+    let mut escaped: String = key
+        .iter()
+        .take(32)
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect();
+    if key.len() > 32 {
+        escaped.push_str("...");
+    }
+    escaped
 }
 
+// Largest group of batches written as one log record.
+const MAX_GROUP_BYTES: usize = 1 << 20;
+// A small batch is only grouped with a little more, so its write isn't slowed
+// down much by the others.
+const SMALL_BATCH_BYTES: usize = 128 << 10;
+
 pub struct DBImplInner<E: Env> {
-    db_name: String,
+    db_name: PathBuf,
     env: E,
     internal_comparator: InternalKeyComparator,
     options: Arc<Options>,
@@ -121,33 +644,60 @@ pub struct DBImplInner<E: Env> {
     imm: RwLock<Option<Arc<MemTable>>>,
 
     versions: Mutex<VersionSet<E>>,
+    // locked after `versions` when both are needed
+    snapshots: Arc<Mutex<SnapshotList>>,
 
-    batch_write_queue: Mutex<VecDeque<BatchTask>>,
+    // the writers in the order of their writes, the front one is writing
+    batch_write_queue: Mutex<VecDeque<Writer>>,
     batch_write_cond: Condvar,
+    next_writer_id: AtomicU64,
 
-    //background_error: RwLock<Option<Error>>,
+    // a permanent write error, every later write fails with it
+    background_error: RwLock<Option<Error>>,
     shutdown: AtomicBool,
 
-    compaction_trigger: (Sender<()>, Receiver<()>),
+    // none without background threads
+    compaction_trigger: Mutex<Option<Sender<()>>>,
+    // the receiving end, taken by the compaction worker
+    compaction_wakeups: Mutex<Option<Receiver<()>>>,
     background_work_finish: Condvar,
+    // held by a writer doing the background work itself, without background threads
+    inline_work: Mutex<()>,
+    // locked after `versions` when both are needed
+    manual_compaction: Mutex<Option<ManualCompaction>>,
+    compaction_status: RwLock<CompactionStatus>,
 
     wal: Mutex<Wal<E::WritableFile>>,
-}
+    read_only: bool,
+    // the LOCK file, held from the recovery on. A read only db doesn't take it
+    db_lock: Mutex<Option<E::FileLock>>,
+    // delete_obsoleted_files does nothing while this is above 0
+    file_deletions_disabled: AtomicUsize,
 
-unsafe impl<E: Env> Send for DBImplInner<E> {}
-unsafe impl<E: Env> Sync for DBImplInner<E> {}
+    stats: WriteStats,
+    compaction_stats: Mutex<[CompactionStats; NUM_LEVELS]>,
+    last_stats_dump_micros: AtomicU64,
+    open_stats: OpenStats,
+}
 
 impl<E: Env> DBImplInner<E> {
-    pub fn new(options: Options, db_name: impl Into<String>, env: E) -> Self {
-        let db_name = db_name.into();
-        let options = Arc::new(options);
+    pub fn new(options: Options, db_name: impl AsRef<Path>, env: E) -> Self {
+        let db_name = db_name.as_ref().to_path_buf();
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let options = Arc::new(sanitize_options(options));
         let table_cache = TableCache::new(
             db_name.clone(),
             options.clone(),
             env.clone(),
             table_cache_size(&options),
         );
-        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let now_micros = env.now_micros();
+        let (trigger, wakeups) = if options.background_threads > 0 {
+            let (trigger, wakeups) = channel();
+            (Some(trigger), Some(wakeups))
+        } else {
+            (None, None)
+        };
         DBImplInner {
             internal_comparator: icmp.clone(),
             db_name: db_name.clone(),
@@ -157,48 +707,273 @@ impl<E: Env> DBImplInner<E> {
             mem: RwLock::new(None),
             imm: RwLock::new(None),
             versions: Mutex::new(VersionSet::new(env, db_name, options, table_cache, icmp)),
+            snapshots: Default::default(),
             batch_write_queue: Mutex::new(VecDeque::new()),
             batch_write_cond: Condvar::new(),
+            next_writer_id: AtomicU64::new(0),
+            background_error: RwLock::new(None),
             shutdown: AtomicBool::new(false),
-            compaction_trigger: unbounded(),
+            compaction_trigger: Mutex::new(trigger),
+            compaction_wakeups: Mutex::new(wakeups),
             background_work_finish: Condvar::new(),
+            inline_work: Mutex::new(()),
+            manual_compaction: Mutex::new(None),
+            compaction_status: RwLock::new(CompactionStatus::default()),
             wal: Mutex::new(Wal {
                 log_file_number: 0,
                 log: None,
+                synced_sequence: 0,
             }),
+            read_only: false,
+            db_lock: Mutex::new(None),
+            file_deletions_disabled: AtomicUsize::new(0),
+            stats: WriteStats::default(),
+            compaction_stats: Mutex::new(Default::default()),
+            last_stats_dump_micros: AtomicU64::new(now_micros),
+            open_stats: OpenStats::default(),
         }
     }
 
-    fn make_room_for_write(&self, _force: bool) {
-        // let mut allow_delay = !force;
-        // let mut versions = self.versions.lock().unwrap();
-        // loop {
-        //     // process error
-        // }
+    fn new_memtable(&self) -> Arc<MemTable> {
+        let icmp = self.internal_comparator.clone();
+        Arc::new(MemTable::with_checksums(
+            icmp,
+            self.options.paranoid_memory_checks,
+        ))
     }
 
-    pub fn write(&self, options: &WriteOption, updates: Option<WriteBatch>) -> Result<()> {
-        // let (sender, receiver) = unbounded();
-        // let task = BatchTask::Write(Writer {
-        //     batch: updates,
-        //     notifier: sender,
-        //     sync: options.sync,
-        // });
+    // a writer of the WAL `file`, which is `dest_len` bytes long
+    fn new_log_writer(&self, file: E::WritableFile, dest_len: u64) -> LogWriter<E::WritableFile> {
+        let log = LogWriter::new_with_dest_len(file, dest_len);
+        if self.options.disable_data_checksums {
+            log.without_checksums()
+        } else {
+            log
+        }
+    }
+
+    // Waits until the write can go to the current memtable. A full memtable becomes
+    // the immutable one once the previous one is flushed, `force` switches even if
+    // it isn't full. Writes are delayed by 1ms each once level 0 has many files and
+    // stop until it's compacted once it has too many. A memtable only db never
+    // switches.
+    fn make_room_for_write(&self, mut force: bool) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let mut versions = self.versions.lock().unwrap();
+        let mut allow_delay = !force;
+        loop {
+            self.check_background_error()?;
+            let usage = self
+                .mem
+                .read()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .approximate_memory_usage();
+            if self.options.memtable_only {
+                return match self.options.memtable_only_max_bytes {
+                    Some(max) if usage >= max => Err(Error::CustomError(format!(
+                        "memtable is full, {} of {} bytes used",
+                        usage, max
+                    ))),
+                    _ => Ok(()),
+                };
+            }
+            let level0_files = versions.current().map_or(0, |v| v.files[0].len());
+            if allow_delay && level0_files >= L0_SLOW_DOWN_WRITES_TRIGGER {
+                // spreads the wait for the level-0 compaction over many writes
+                // instead of stopping one for seconds. The compaction needs neither
+                // lock
+                drop(versions);
+                drop(wal);
+                if self.options.background_threads > 0 {
+                    self.env.sleep_for_micros(1000);
+                } else {
+                    // nothing compacts while the writer sleeps
+                    self.maybe_schedule_compaction();
+                }
+                allow_delay = false;
+                wal = self.wal.lock().unwrap();
+                versions = self.versions.lock().unwrap();
+            } else if !force && usage as u64 <= self.options.write_buffer_size {
+                return Ok(());
+            } else if self.imm.read().unwrap().is_some() {
+                // the previous memtable is still being flushed
+                versions = self.wait_for_background_work(versions)?;
+            } else if level0_files >= L0_STOP_WRITES_TRIGGER {
+                drop(versions);
+                self.maybe_schedule_compaction();
+                versions = self.wait_for_background_work(self.versions.lock().unwrap())?;
+            } else {
+                self.switch_memtable_locked(&mut wal, &mut versions)?;
+                drop(versions);
+                self.maybe_schedule_compaction();
+                versions = self.versions.lock().unwrap();
+                force = false;
+            }
+        }
+    }
+
+    // Makes the memtable the immutable one and continues with a new memtable and
+    // log. The old log becomes the prev_log of the manifest: recovery replays it
+    // before the new one until the flush of the immutable memtable is installed.
+    fn switch_memtable(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let mut versions = self.versions.lock().unwrap();
+        self.switch_memtable_locked(&mut wal, &mut versions)
+    }
 
-        // self.batch_write_queue.lock().unwrap().push_back(task);
-        // self.batch_write_cond.notify_all();
-        // //
-        // receiver.recv()?;
-        if updates.is_some() {
-            self.write_inner(&mut updates.unwrap(), options)?;
+    fn switch_memtable_locked(
+        &self,
+        wal: &mut Wal<E::WritableFile>,
+        versions: &mut VersionSet<E>,
+    ) -> Result<()> {
+        assert!(self.imm.read().unwrap().is_none());
+        let new_log_number = versions.new_file_number();
+        let log_name = log_file_name(&self.db_name, new_log_number);
+        let file = self.env.new_writable_file(&log_name)?;
+        let mut edit = VersionEdit::default();
+        edit.set_prev_log_number(wal.log_file_number);
+        edit.set_log_number(new_log_number);
+        if let Err(err) = versions.log_and_apply(&mut edit) {
+            let _ = self.env.delete_file(&log_name);
+            return Err(err);
         }
 
+        wal.log_file_number = new_log_number;
+        wal.log = Some(self.new_log_writer(file, 0));
+        let new_mem = self.new_memtable();
+        let mem = self.mem.write().unwrap().replace(new_mem);
+        *self.imm.write().unwrap() = mem;
         Ok(())
     }
 
-    pub fn recovery(&self, edit: &mut VersionEdit, save_manifest: &mut bool) -> Result<()> {
-        let db_path = Path::new(&self.db_name);
-        let _ = self.env.create_dir(db_path);
+    // Waits for the background worker to finish a round of work. The wait is
+    // bounded, so a lost wakeup only delays the caller's recheck, and it fails once
+    // the worker has failed or the db shuts down. Without background threads the
+    // caller does the round itself.
+    fn wait_for_background_work<'a>(
+        &'a self,
+        versions: MutexGuard<'a, VersionSet<E>>,
+    ) -> Result<MutexGuard<'a, VersionSet<E>>> {
+        let versions = if self.options.background_threads > 0 {
+            self.background_work_finish
+                .wait_timeout(versions, BACKGROUND_WORK_WAIT)
+                .unwrap()
+                .0
+        } else {
+            drop(versions);
+            self.maybe_schedule_compaction();
+            self.versions.lock().unwrap()
+        };
+        if self.shutdown.load(Ordering::Acquire) {
+            return Err(Error::CustomError("db is shutting down".into()));
+        }
+        self.check_background_error()?;
+        Ok(versions)
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        match self.background_error.read().unwrap().as_ref() {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&self, options: &WriteOption, updates: Option<WriteBatch>) -> Result<()> {
+        let _span = trace_span!(
+            "db.write",
+            batch_count = updates.as_ref().map_or(0, |b| b.count()),
+            batch_bytes = updates.as_ref().map_or(0, |b| b.content().len()),
+            sync = options.sync,
+        );
+        if self.read_only {
+            return Err(Error::InvalidArgument("db is opened read only".into()));
+        }
+        let batch = match updates {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        // the batch doesn't know the options, its sizes are checked here before
+        // anything reaches the log
+        batch.check_sizes(self.options.max_key_size, self.options.max_value_size)?;
+
+        let id = self.next_writer_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = channel();
+        let mut queue = self.batch_write_queue.lock().unwrap();
+        queue.push_back(Writer {
+            id,
+            batch: Some(batch),
+            notifier: sender,
+            sync: options.sync,
+            preserve_sequence: options.preserve_sequence,
+        });
+        // until an earlier writer has written the batch or this one is the front
+        while queue.front().unwrap().id != id {
+            queue = self.batch_write_cond.wait(queue).unwrap();
+            if let Ok(res) = receiver.try_recv() {
+                return res;
+            }
+        }
+
+        let (mut batch, group_size) = Self::build_batch_group(&mut queue);
+        drop(queue);
+        let res = self.write_inner(&mut batch, options);
+
+        let mut queue = self.batch_write_queue.lock().unwrap();
+        for writer in queue.drain(..group_size).skip(1) {
+            let _ = writer.notifier.send(res.clone());
+        }
+        self.batch_write_cond.notify_all();
+        res
+    }
+
+    // Takes the batch of the front writer and those of the writers behind it that
+    // can go to the log with it: none needing a sync unless the front one syncs,
+    // none with its own sequence and not past the group size. Returns the merged
+    // batch and the number of writers in the group.
+    fn build_batch_group(queue: &mut VecDeque<Writer>) -> (WriteBatch, usize) {
+        let front = &mut queue[0];
+        let mut batch = front.batch.take().unwrap();
+        if front.preserve_sequence {
+            return (batch, 1);
+        }
+        let sync = front.sync;
+        let mut max_size = MAX_GROUP_BYTES;
+        if batch.approximate_size() <= SMALL_BATCH_BYTES {
+            max_size = batch.approximate_size() + SMALL_BATCH_BYTES;
+        }
+
+        let mut group_size = 1;
+        for writer in queue.iter_mut().skip(1) {
+            if (writer.sync && !sync) || writer.preserve_sequence {
+                break;
+            }
+            let next = writer.batch.as_ref().unwrap();
+            if batch.approximate_size() + next.approximate_size() > max_size {
+                break;
+            }
+            batch.append(next);
+            writer.batch = None;
+            group_size += 1;
+        }
+        (batch, group_size)
+    }
+
+    // Replays the logs the manifest doesn't cover. The directory is listed once,
+    // into `file_names` for the rest of the open, and tables are only checked
+    // to be there by name: they are opened on their first read.
+    pub fn recovery(
+        &self,
+        edit: &mut VersionEdit,
+        save_manifest: &mut bool,
+        file_names: &mut Vec<OsString>,
+    ) -> Result<()> {
+        let db_path = self.db_name.as_path();
+        // another process writing the db would interleave its edits with ours
+        if !self.read_only {
+            *self.db_lock.lock().unwrap() = Some(self.env.lock_file(&lock_file_name(db_path))?);
+        }
         if !self.env.file_exists(&current_file_name(db_path)) {
             if self.options.create_if_missing {
                 self.new_db()?;
@@ -210,27 +985,43 @@ impl<E: Env> DBImplInner<E> {
         }
 
         let mut versions = self.versions.lock().unwrap();
-        *save_manifest = versions.recover()?;
+        *save_manifest = versions.recover(self.options.reuse_log && !self.read_only)?;
 
-        // recovery logs TODO
+        // The logs from the log number on, and the prev_log holding an immutable
+        // memtable whose flush wasn't installed. A prev_log of 0 is no log
         let min_log = versions.log_number();
         let prev_log = versions.prev_log_number();
-        let mut file_names = Vec::new();
-        self.env.get_children(db_path, &mut file_names)?;
-        let mut expect = HashSet::new();
-        versions.live_files(&mut expect);
+        self.env.get_children(db_path, file_names)?;
+        // The files the manifest refers to, a file of another type with the same
+        // number doesn't stand in for one. `recover` already read the manifest
+        let mut live = HashSet::new();
+        versions.live_files(&mut live);
+        let mut expect: HashSet<_> = live
+            .into_iter()
+            .map(|number| (number, FileType::Table))
+            .collect();
 
         let mut logs = Vec::new();
         for f in file_names.iter() {
             if let Ok((number, file_type)) = parse_file_name(f) {
-                expect.remove(&number);
-                if file_type == FileType::Log && (number >= min_log || number == prev_log) {
+                expect.remove(&(number, file_type));
+                let is_prev_log = prev_log != 0 && number == prev_log;
+                if file_type == FileType::Log && (number >= min_log || is_prev_log) {
                     logs.push(number);
                 }
             }
         }
         if !expect.is_empty() {
-            return Err(Error::Corruption(format!("missing files: {:?}", expect)));
+            let mut missing: Vec<_> = expect.into_iter().collect();
+            missing.sort_unstable_by_key(|(number, _)| *number);
+            let names: Vec<_> = missing
+                .into_iter()
+                .map(|(number, _)| table_file_name(db_path, number).display().to_string())
+                .collect();
+            return Err(Error::Corruption(format!(
+                "missing files: {}",
+                names.join(", ")
+            )));
         }
         drop(versions);
 
@@ -267,127 +1058,439 @@ impl<E: Env> DBImplInner<E> {
     ) -> Result<()> {
         let fname = log_file_name(&self.db_name, log_number);
         let file = self.env.new_sequential_file(&fname)?;
-        let mut log_reader = LogReader::new(file, true);
+        // Damaged records are logged and skipped, like the write torn by a crash.
+        // With paranoid checks the first one fails the recovery
+        let corruption = Arc::new(Mutex::new(None));
+        let reporter = {
+            let corruption = corruption.clone();
+            let logger = self.options.info_log.clone();
+            let name = fname.file_name().unwrap().to_string_lossy().into_owned();
+            Box::new(move |bytes: usize, reason: &str| {
+                if let Some(logger) = logger.as_ref() {
+                    logger.log(&format!("{}: dropping {} bytes; {}", name, bytes, reason));
+                }
+                corruption
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| Error::Corruption(format!("{}: {}", name, reason)));
+            })
+        };
+        let mut log_reader = LogReader::new_with_reporter(file, true, reporter);
 
-        let mut mem = None;
+        // a read only or memtable only db keeps all the logs in its memtable
+        let keep_in_memtable = self.read_only || self.options.memtable_only;
+        let mut mem = if keep_in_memtable {
+            self.mem.read().unwrap().clone()
+        } else {
+            None
+        };
 
         let buffer_size = self.options.write_buffer_size;
-        // let paranoid_checks = self.options.paranoid_checks;
-        let mut compaction = 0;
+        let mut compactions = 0;
         loop {
-            let mut batch = WriteBatch::new();
             let mut record = Vec::with_capacity(1024);
 
-            //finish read
-            if log_reader.read_record(&mut record)?.is_none() {
+            let read = log_reader.read_record(&mut record)?;
+            if self.options.paranoid_checks {
+                if let Some(err) = corruption.lock().unwrap().take() {
+                    return Err(err);
+                }
+            }
+            if read.is_none() {
                 break;
-            };
+            }
 
-            batch.set_content(record);
+            let batch = match WriteBatch::from_content(record) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    if self.options.paranoid_checks {
+                        return Err(err);
+                    }
+                    if let Some(logger) = self.options.info_log.as_ref() {
+                        logger.log(&format!("log #{}: dropping record: {}", log_number, err));
+                    }
+                    continue;
+                }
+            };
+            // A record's sequences come after those of every record before it, in
+            // this log and the earlier ones. Replaying one that doesn't would change
+            // which write of a key wins
+            if batch.count() > 0 && batch.sequence() <= *max_sequence {
+                let msg = format!(
+                    "log #{}: record at sequence {} follows sequence {}",
+                    log_number,
+                    batch.sequence(),
+                    max_sequence
+                );
+                if self.options.paranoid_checks {
+                    return Err(Error::Corruption(msg));
+                }
+                if let Some(logger) = self.options.info_log.as_ref() {
+                    logger.log(&msg);
+                }
+            }
 
             if mem.is_none() {
-                mem.replace(Arc::new(MemTable::new(self.internal_comparator.clone())));
+                mem.replace(self.new_memtable());
             }
             let memtable = mem.as_ref().unwrap();
             batch.insert_into(memtable.clone())?;
 
-            let last_sequence = batch.sequence() + batch.count() as SequenceNumber - 1;
+            let last_sequence =
+                (batch.sequence() + batch.count() as SequenceNumber).saturating_sub(1);
             if last_sequence > *max_sequence {
                 *max_sequence = last_sequence;
             }
 
-            if memtable.approximate_memory_usage() > buffer_size as usize {
-                compaction += 1;
+            if !keep_in_memtable && memtable.approximate_memory_usage() > buffer_size as usize {
                 *save_manifest = true;
-                self.write_level0_table(memtable.clone(), edit, None)?;
+                self.write_level0_table(memtable.clone(), edit, None, IoPriority::Foreground)?;
+                compactions += 1;
                 mem = None;
             }
         }
 
-        //todo resuse log
-        // if res.is_ok() && self.options.reuse_log && last_log && compaction == 0 {
-        //     let mut inner_mem = self.mem.write().unwrap();
-        //     assert!(inner_mem.is_none());
-
-        //     let log_file = self.env.new_appendable_file(&fname);
-        //     let lfile_size = self.env.get_file_size(&fname);
-        //     if let (Ok(log), Ok(s)) = (log_file, lfile_size) {
-        //         let mut wal = self.wal.lock().unwrap();
-        //         assert!(wal.log.is_none());
-        //         let writer = LogWriter::new_with_dest_len(log, s);
-        //         wal.log = Some(writer);
-        //         wal.log_file_number = log_number;
-        //         if mem.is_some() {
-        //             inner_mem.get_or_insert(mem.take().unwrap());
-        //         } else {
-        //             inner_mem
-        //                 .get_or_insert(Arc::new(MemTable::new(self.internal_comparator.clone())));
-        //         }
-        //     }
-        // }
+        // The last log stays the WAL if its memtable wasn't written out. Appending
+        // after damage, like a torn last record, would make the new records part
+        // of it, such a log is left for a new one.
+        let damaged = corruption.lock().unwrap().is_some();
+        if self.options.reuse_log && last_log && compactions == 0 && !keep_in_memtable && !damaged {
+            let reused = self.env.file_size(&fname).and_then(|size| {
+                let file = self.env.new_appendable_file(&fname)?;
+                Ok(self.new_log_writer(file, size as u64))
+            });
+            if let Ok(log) = reused {
+                let mut wal = self.wal.lock().unwrap();
+                wal.log = Some(log);
+                wal.log_file_number = log_number;
+                *self.mem.write().unwrap() =
+                    Some(mem.take().unwrap_or_else(|| self.new_memtable()));
+                return Ok(());
+            }
+        }
 
         if let Some(m) = mem {
-            self.write_level0_table(m, edit, None)?;
-            *save_manifest = true;
+            if keep_in_memtable {
+                *self.mem.write().unwrap() = Some(m);
+            } else {
+                self.write_level0_table(m, edit, None, IoPriority::Foreground)?;
+                *save_manifest = true;
+            }
         }
         Ok(())
     }
 
-    pub fn get(&self, _option: &ReadOption, _key: &[u8], _value: &mut [u8]) -> Result<()> {
-        // let snapshot = self.versions.lock().unwrap().last_sequence();
-        // let lookup_key = LookupKey::new(key, snapshot, ValueType::Value);
-        // let memtable = self.mem.read().unwrap().as_ref().unwrap();
-        // if let Some(v) = memtable.
-        // Ok(())
-        todo!()
-    }
-
-    fn write_inner(&self, batch: &mut WriteBatch, options: &WriteOption) -> Result<()> {
-        let versions: std::sync::MutexGuard<VersionSet<E>> = self.versions.lock().unwrap();
+    // The value of `key`, looked up in the memtable, the immutable memtable and
+    // then the tables of the current version, as of the snapshot of `options` or
+    // else the last sequence. The newest entry of the key decides, a key never
+    // written or deleted fails with `Error::NotFoundError`. With
+    // `ReadTier::MemtableOnly` a key the memtables don't have fails with
+    // `Error::Incomplete` instead.
+    pub fn get(&self, options: &ReadOption, key: &[u8]) -> Result<Vec<u8>> {
+        let span = trace_span!(
+            "db.get",
+            key_len = key.len(),
+            found = tracing::field::Empty,
+            level_served = tracing::field::Empty,
+        );
+        let versions = self.versions.lock().unwrap();
         let last_sequence = versions.last_sequence();
-        batch.set_sequence(last_sequence + 1);
-        // last_sequence += batch.count() as u64;
+        let sequence = options
+            .snapshot
+            .as_ref()
+            .map_or(last_sequence, |s| s.sequence().min(last_sequence));
+        let mems = [&self.mem, &self.imm].map(|mem| mem.read().unwrap().clone());
+        let current = versions.current().unwrap();
         drop(versions);
 
+        let lookup = LookupKey::new(key, sequence, ValueType::Value);
+        let mut found = Err(Error::NotFoundError("no key".into()));
+        for mem in mems.iter().flatten() {
+            let value = match mem.get(LookupKey::new(key, sequence, ValueType::Value))? {
+                MemGet::Hit(value) => Some(value),
+                MemGet::Deleted => None,
+                MemGet::Miss => continue,
+            };
+            span.record("level_served", "memtable");
+            found = Ok(value);
+            break;
+        }
+        if matches!(found, Err(Error::NotFoundError(_))) {
+            if options.read_tier == ReadTier::MemtableOnly {
+                return Err(Error::Incomplete(
+                    "key is not in the memtables and tables may not be read".into(),
+                ));
+            }
+            let mut stats = GetStats::default();
+            found = current.get(options, &lookup, &mut stats);
+            if let Some(level) = stats.level_served {
+                span.record("level_served", level);
+            }
+            if current.update_stats(&stats) && !self.read_only {
+                self.maybe_schedule_compaction();
+            }
+        }
+        let value =
+            found.and_then(|v| v.ok_or_else(|| Error::NotFoundError("key is deleted".into())));
+        span.record("found", value.is_ok());
+        value
+    }
+
+    fn write_inner(&self, batch: &mut WriteBatch, options: &WriteOption) -> Result<()> {
+        self.make_room_for_write(false)?;
+        // Writers are serialized by the log. A batch's sequences are published only
+        // once it's in the log and the memtable, a failed write leaves no trace
+        // for readers or later writers.
         let mut wal = self.wal.lock().unwrap();
-        let log_writter = wal.log.as_mut().unwrap();
-        log_writter.add_record(batch.content())?;
+        let last_sequence = self.versions.lock().unwrap().last_sequence();
+        if options.preserve_sequence {
+            check_preserved_sequence(batch, last_sequence)?;
+        } else {
+            batch.set_sequence(last_sequence + 1);
+        }
+        let new_last_sequence = (batch.sequence() + batch.count() as u64).saturating_sub(1);
 
-        if options.sync {
-            let res = log_writter.sync();
-            if res.is_err() {
-                // record sync error
+        let log_writter = wal.log.as_mut().unwrap();
+        let log_offset = log_writter.bytes_written();
+        let mut res = log_writter.add_record(batch.content());
+        // a failed append may still have written part of the record
+        self.stats
+            .add_wal_bytes(log_writter.bytes_written() - log_offset);
+        if res.is_ok() && options.sync {
+            res = log_writter.sync();
+            if res.is_ok() {
+                wal.synced_sequence = new_last_sequence;
             }
-            res?;
+        }
+        if let Err(err) = res {
+            // the log may end in a partial record, even after a transient error, and a
+            // record appended after it could be lost with it on recovery
+            self.record_background_error(&err);
+            return Err(err);
         }
 
         let mem = self.mem.read().unwrap();
         let mem = mem.as_ref().unwrap();
-        batch.insert_into(mem.clone())?;
+        let user_bytes = batch.insert_into(mem.clone())?;
+        self.stats.add_user_bytes(user_bytes);
 
+        self.versions
+            .lock()
+            .unwrap()
+            .set_last_sequence(new_last_sequence);
         Ok(())
     }
 
-    pub fn write_batch_task(&mut self) {
-        // let mut queue = self.batch_write_queue.lock().unwrap();
-        todo!();
-    }
-
-    pub fn delete_obsoleted_files(&self) {}
-
-    fn write_level0_table(
+    pub fn sync_wal(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        // with the wal held, every write in the log is in the last sequence
+        let last_sequence = self.versions.lock().unwrap().last_sequence();
+        let log = match wal.log.as_mut() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let res = log.flush().and_then(|_| log.sync());
+        match res {
+            Ok(()) => {
+                wal.synced_sequence = last_sequence;
+                Ok(())
+            }
+            Err(err) => {
+                // like a failed sync write, the log may not hold what it was told
+                self.record_background_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn record_background_error(&self, err: &Error) {
+        let mut background_error = self.background_error.write().unwrap();
+        if background_error.is_none() {
+            *background_error = Some(err.clone());
+        }
+    }
+
+    pub fn delete_obsoleted_files(&self) {
+        if self.file_deletions_disabled.load(Ordering::Acquire) > 0 || self.read_only {
+            return;
+        }
+        let mut file_names = Vec::new();
+        if self
+            .env
+            .get_children(&self.db_name, &mut file_names)
+            .is_err()
+        {
+            return;
+        }
+        self.delete_obsolete_files_listed(&file_names);
+    }
+
+    // The files must be listed before the live set is taken: a table written
+    // after the listing isn't in it, one written before is in the set, a
+    // version or pending_outputs.
+    fn delete_obsolete_files_listed(&self, file_names: &[OsString]) {
+        if self.file_deletions_disabled.load(Ordering::Acquire) > 0 || self.read_only {
+            return;
+        }
+        let mut versions = self.versions.lock().unwrap();
+        versions.remove_unused_versions();
+        let mut live = versions.pending_outputs.clone();
+        versions.live_files(&mut live);
+        let (log_number, prev_log_number) = (versions.log_number(), versions.prev_log_number());
+        let current_manifest = versions.current_manifest_number();
+        drop(versions);
+
+        for f in file_names.iter() {
+            let obsolete = match parse_file_name(f) {
+                // the log of the memtable and the one of the immutable memtable
+                Ok((number, FileType::Log)) => number < log_number && number != prev_log_number,
+                Ok((number, FileType::Table)) => !live.contains(&number),
+                // the manifests older than the one CURRENT names
+                Ok((number, FileType::Descriptor)) => number < current_manifest,
+                _ => false,
+            };
+            if obsolete {
+                if let Ok((number, FileType::Table)) = parse_file_name(f) {
+                    self.table_cache.evict(number);
+                }
+                let _ = self.env.delete_file(&self.db_name.join(f));
+            }
+        }
+    }
+
+    pub fn disable_file_deletions(&self) {
+        self.file_deletions_disabled.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // undoes one disable_file_deletions
+    pub fn enable_file_deletions(&self) {
+        let _ = self.file_deletions_disabled.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |disabled| disabled.checked_sub(1),
+        );
+    }
+
+    // Writes the memtable to a table and continues with a new log, so the data
+    // written so far no longer needs the old one. Writers wait until it's done.
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::InvalidArgument("db is opened read only".into()));
+        }
+        if self.options.memtable_only {
+            return Err(Error::InvalidArgument("db is memtable only".into()));
+        }
+        let mut wal = self.wal.lock().unwrap();
+        let mut versions = self.versions.lock().unwrap();
+        // the immutable memtable goes to a table first, its log is still needed
+        while self.imm.read().unwrap().is_some() {
+            versions = self.wait_for_background_work(versions)?;
+        }
+        let new_log_number = versions.new_file_number();
+        let base = versions.current();
+        drop(versions);
+
+        let file = self
+            .env
+            .new_writable_file(&log_file_name(&self.db_name, new_log_number))?;
+        let mem = self.mem.read().unwrap().clone().unwrap();
+        let mut edit = VersionEdit::default();
+        self.write_level0_table(mem, &mut edit, base, IoPriority::Foreground)?;
+        edit.set_prev_log_number(0);
+        edit.set_log_number(new_log_number);
+        let mut versions = self.versions.lock().unwrap();
+        versions.log_and_apply(&mut edit)?;
+        wal.synced_sequence = versions.last_sequence();
+        let needs_compaction = versions.needs_compaction();
+        drop(versions);
+
+        wal.log_file_number = new_log_number;
+        wal.log = Some(self.new_log_writer(file, 0));
+        *self.mem.write().unwrap() = Some(self.new_memtable());
+        drop(wal);
+        self.delete_obsoleted_files();
+        if needs_compaction {
+            self.maybe_schedule_compaction();
+        }
+        Ok(())
+    }
+
+    pub fn live_files(&self, flush_before: bool) -> Result<LiveFilesInfo> {
+        if flush_before {
+            self.flush()?;
+        }
+        let wal = self.wal.lock().unwrap();
+        let versions = self.versions.lock().unwrap();
+        let manifest = descriptor_file_name(&self.db_name, versions.current_manifest_number());
+        let manifest_size = self.env.file_size(&manifest)? as u64;
+        let current = versions.current().unwrap();
+        drop(versions);
+
+        let mut tables = Vec::new();
+        for (level, files) in current.files.iter().enumerate() {
+            for f in files.iter() {
+                let mut name = table_file_name(&self.db_name, f.number);
+                if !self.env.file_exists(&name) {
+                    name = sst_table_file_name(&self.db_name, f.number);
+                }
+                tables.push(LiveTableFile {
+                    level,
+                    number: f.number,
+                    name,
+                    size: f.file_size,
+                    smallest_user_key: f.smallest.user_key().to_vec(),
+                    largest_user_key: f.largest.user_key().to_vec(),
+                });
+            }
+        }
+        Ok(LiveFilesInfo {
+            manifest,
+            manifest_size,
+            wal: wal
+                .log
+                .as_ref()
+                .map(|_| log_file_name(&self.db_name, wal.log_file_number)),
+            wal_synced_sequence: wal.synced_sequence,
+            tables,
+        })
+    }
+
+    fn delete_logs_before(&self, log_number: u64, file_names: &[OsString]) -> Result<()> {
+        for f in file_names.iter() {
+            if let Ok((number, FileType::Log)) = parse_file_name(f) {
+                if number < log_number {
+                    self.env
+                        .delete_file(&log_file_name(&self.db_name, number))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_level0_table(
         &self,
         mem: Arc<MemTable>,
         edit: &mut VersionEdit,
         base: Option<Arc<Version<E>>>,
+        priority: IoPriority,
     ) -> Result<()> {
+        // duration is in micros
+        let span = trace_span!(
+            "db.flush",
+            file_number = tracing::field::Empty,
+            bytes = tracing::field::Empty,
+            duration = tracing::field::Empty,
+        );
+        let start_micros = self.env.now_micros();
         let mut versions = self.versions.lock().unwrap();
         let mut meta = FileMetaData {
             number: versions.new_file_number(),
+            creation_time: start_micros,
             ..Default::default()
         };
         versions.pending_outputs.insert(meta.number);
         drop(versions);
+        span.record("file_number", meta.number);
 
         let iter = mem.iter();
         let res = build_table(
@@ -397,6 +1500,7 @@ impl<E: Env> DBImplInner<E> {
             self.table_cache.clone(),
             iter,
             &mut meta,
+            priority,
         );
 
         let mut versions = self.versions.lock().unwrap();
@@ -404,6 +1508,7 @@ impl<E: Env> DBImplInner<E> {
 
         let mut level = 0;
         if res.is_ok() && meta.file_size > 0 {
+            self.stats.add_flush_bytes(meta.file_size);
             let smallest_user_key = meta.smallest.user_key();
             let largest_user_key = meta.largest.user_key();
             if let Some(v) = base {
@@ -418,23 +1523,526 @@ impl<E: Env> DBImplInner<E> {
                 meta.file_size,
                 meta.smallest.clone(),
                 meta.largest.clone(),
+                meta.creation_time,
             );
+            drop(versions);
+            self.notify_table_file_created(&meta, level);
         }
 
-        //TODO add stats
+        let stats = CompactionStats {
+            micros: self.env.now_micros().saturating_sub(start_micros),
+            bytes_read: 0,
+            bytes_written: meta.file_size,
+        };
+        span.record("bytes", meta.file_size);
+        span.record("duration", stats.micros);
+        self.compaction_stats.lock().unwrap()[level].add(&stats);
         res
     }
 
+    // One round of background work. A panic becomes the background error instead
+    // of ending the worker, and waiting writers are woken either way.
+    fn run_background_work(&self) {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| self.background_compaction()));
+        if let Err(payload) = res {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".into());
+            self.record_background_error(&Error::CustomError(format!(
+                "background work panicked: {}",
+                msg
+            )));
+        }
+        // taken so a writer can't miss the wakeup between its check and its wait,
+        // a panic may have poisoned it
+        let versions = self.versions.lock();
+        self.background_work_finish.notify_all();
+        // one compaction may leave work for another, like level 1 overfull
+        // after a level-0 compaction
+        let more = matches!(&versions, Ok(versions) if versions.needs_compaction())
+            || self.manual_compaction_pending();
+        drop(versions);
+        // a writer doing the work itself stops after a round
+        let worker = self.options.background_threads > 0;
+        if worker && more && self.background_error.read().unwrap().is_none() {
+            self.maybe_schedule_compaction();
+        }
+    }
+
     fn background_compaction(&self) {
         if self.imm.read().unwrap().is_some() {
             self.compaction_memtable();
+            return;
+        }
+        let res = if self.manual_compaction_pending() {
+            self.do_manual_compaction()
+        } else {
+            self.do_compaction()
+        };
+        if let Err(err) = res {
+            self.record_background_error(&err);
+        }
+    }
+
+    fn manual_compaction_pending(&self) -> bool {
+        matches!(self.manual_compaction.lock().unwrap().as_ref(), Some(m) if !m.done)
+    }
+
+    // Runs a step of the manual compaction. The range is copied out, the
+    // compaction holds neither lock.
+    fn do_manual_compaction(&self) -> Result<()> {
+        let (level, begin, end) = match self.manual_compaction.lock().unwrap().as_ref() {
+            Some(m) if !m.done => (m.level, m.begin.clone(), m.end.clone()),
+            _ => return Ok(()),
+        };
+        let mut versions = self.versions.lock().unwrap();
+        let c = versions.compact_range(level, &begin, &end);
+        // the inputs are in key order above level 0, the next step starts after them
+        let next = c
+            .as_ref()
+            .map(|c| c.inputs[0].last().unwrap().largest.clone());
+        let res = match c {
+            Some(c) => self.run_compaction(versions, c),
+            None => Ok(()),
+        };
+        if let Some(m) = self.manual_compaction.lock().unwrap().as_mut() {
+            match next {
+                Some(largest) if res.is_ok() => m.begin = Some(largest),
+                _ => m.done = true,
+            }
+        }
+        res
+    }
+
+    // Flushes the memtable, then compacts the files overlapping the range of each
+    // level into the next one, down to the last level holding such files. The
+    // worker does the compactions, this waits for them.
+    pub fn compact_range(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> Result<()> {
+        if self.read_only {
+            return Err(Error::InvalidArgument("db is opened read only".into()));
+        }
+        if self.options.memtable_only {
+            return Err(Error::InvalidArgument("db is memtable only".into()));
+        }
+        self.compact_memtable()?;
+
+        let current = self.versions.lock().unwrap().current().unwrap();
+        let max_level_with_files = (1..NUM_LEVELS)
+            .filter(|&level| current.overlap_in_level(level, &begin, &end))
+            .max()
+            .unwrap_or(1);
+        drop(current);
+        // the smallest and largest internal keys of the user keys
+        let begin = begin.map(|k| InternalKey::new(k, MAX_SEQUENCE_NUMBER, ValueType::Value));
+        let end = end.map(|k| InternalKey::new(k, 0, ValueType::Deletetion));
+        for level in 0..max_level_with_files {
+            self.run_manual_compaction(level, &begin, &end)?;
+        }
+        Ok(())
+    }
+
+    // Switches to a new memtable and waits until the old one is flushed.
+    fn compact_memtable(&self) -> Result<()> {
+        self.make_room_for_write(true)?;
+        let mut versions = self.versions.lock().unwrap();
+        while self.imm.read().unwrap().is_some() {
+            versions = self.wait_for_background_work(versions)?;
+        }
+        Ok(())
+    }
+
+    fn run_manual_compaction(
+        &self,
+        level: usize,
+        begin: &Option<InternalKey>,
+        end: &Option<InternalKey>,
+    ) -> Result<()> {
+        let mut versions = self.versions.lock().unwrap();
+        // one manual compaction at a time
+        while self.manual_compaction.lock().unwrap().is_some() {
+            versions = self.wait_for_background_work(versions)?;
+        }
+        *self.manual_compaction.lock().unwrap() = Some(ManualCompaction {
+            level,
+            begin: begin.clone(),
+            end: end.clone(),
+            done: false,
+        });
+        drop(versions);
+        self.maybe_schedule_compaction();
+
+        let mut versions = self.versions.lock().unwrap();
+        let res = loop {
+            if !self.manual_compaction_pending() {
+                break Ok(());
+            }
+            match self.wait_for_background_work(versions) {
+                Ok(v) => versions = v,
+                Err(err) => break Err(err),
+            }
+        };
+        *self.manual_compaction.lock().unwrap() = None;
+        res
+    }
+
+    // Runs one level compaction, if the current version needs one.
+    fn do_compaction(&self) -> Result<()> {
+        let mut versions = self.versions.lock().unwrap();
+        match versions.pick_compaction() {
+            Some(c) => self.run_compaction(versions, c),
+            None => Ok(()),
+        }
+    }
+
+    pub fn compact_files_older_than(&self, age: Duration) -> Result<()> {
+        if self.read_only {
+            return Err(Error::InvalidArgument("db is opened read only".into()));
+        }
+        if self.options.memtable_only {
+            return Err(Error::InvalidArgument("db is memtable only".into()));
+        }
+        let cutoff = self.env.now_micros().saturating_sub(age.as_micros() as u64);
+        let current = self.versions.lock().unwrap().current().unwrap();
+        let old_files: Vec<u64> = current.files[..NUM_LEVELS - 1]
+            .iter()
+            .flatten()
+            .filter(|f| f.creation_time != 0 && f.creation_time < cutoff)
+            .map(|f| f.number)
+            .collect();
+
+        for number in old_files {
+            let mut versions = self.versions.lock().unwrap();
+            let current = versions.current().unwrap();
+            // an earlier compaction may already have taken the file as an input
+            let found = (0..NUM_LEVELS - 1).find_map(|level| {
+                let f = current.files[level].iter().find(|f| f.number == number)?;
+                Some((level, f.clone()))
+            });
+            if let Some(c) = found.and_then(|(level, f)| versions.compact_file(level, f)) {
+                self.run_compaction(versions, c)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs `c`, which is shown as running by `leveldb.compaction-state` until it's
+    // done or failed.
+    fn run_compaction(&self, versions: MutexGuard<VersionSet<E>>, c: Compaction<E>) -> Result<()> {
+        self.compaction_status.write().unwrap().running = Some(RunningCompaction {
+            level: c.level,
+            input_files: c.inputs.iter().flatten().map(|f| f.number).collect(),
+            started_micros: self.env.now_micros(),
+        });
+        let res = self.move_or_merge(versions, c);
+        let mut status = self.compaction_status.write().unwrap();
+        status.running = None;
+        status.completed += 1;
+        res
+    }
+
+    fn move_or_merge(
+        &self,
+        mut versions: MutexGuard<VersionSet<E>>,
+        mut c: Compaction<E>,
+    ) -> Result<()> {
+        if c.is_trivial_move() {
+            let f = c.inputs[0][0].clone();
+            c.edit.add_delete_file(c.level as u32, f.number);
+            c.edit.add_new_file(
+                c.level as u32 + 1,
+                f.number,
+                f.file_size,
+                f.smallest.clone(),
+                f.largest.clone(),
+                f.creation_time,
+            );
+            versions.log_and_apply(&mut c.edit)?;
+            drop(versions);
+            self.delete_obsoleted_files();
+
+            if let Some(listener) = self.options.listener.as_ref() {
+                listener.on_compaction_completed(&CompactionInfo {
+                    kind: CompactionKind::Moved,
+                    level: c.level,
+                    output_level: c.level + 1,
+                    input_files: vec![f.number],
+                    output_files: vec![f.number],
+                    bytes_read: 0,
+                    bytes_written: 0,
+                });
+            }
+        } else {
+            let smallest_snapshot = self.oldest_snapshot_sequence(&versions);
+            drop(versions);
+            self.do_compaction_work(&mut c, smallest_snapshot)?;
+        }
+
+        Ok(())
+    }
+
+    // Merges the inputs of `c` into new tables at the next level, dropping the
+    // entries no read at or after `smallest_snapshot` can see.
+    fn do_compaction_work(
+        &self,
+        c: &mut Compaction<E>,
+        smallest_snapshot: SequenceNumber,
+    ) -> Result<()> {
+        let span = trace_span!(
+            "db.compaction",
+            level = c.level,
+            input_files = c.inputs.iter().flatten().count(),
+            output_files = tracing::field::Empty,
+            bytes_read = tracing::field::Empty,
+            bytes_written = tracing::field::Empty,
+        );
+        let start_micros = self.env.now_micros();
+        let mut input = self.versions.lock().unwrap().make_input_iterator(c)?;
+        let user_comparator = self.internal_comparator.user_comparator();
+
+        let mut outputs: Vec<FileMetaData> = Vec::new();
+        let mut builder = None;
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+        let mut res = Ok(());
+
+        input.seek_to_first();
+        while input.valid() {
+            if self.shutdown.load(Ordering::Acquire) {
+                res = Err(Error::CustomError("deleting db during compaction".into()));
+                break;
+            }
+
+            let key = input.key();
+            if builder.is_some() && c.should_stop_before(key) {
+                res = self.finish_compaction_output(&mut builder, outputs.last_mut().unwrap());
+                if res.is_err() {
+                    break;
+                }
+            }
+
+            let ikey = ParsedInternalKey::parse(key);
+            if current_user_key.is_none()
+                || user_comparator.compare(ikey.user_key, current_user_key.as_ref().unwrap())
+                    != std::cmp::Ordering::Equal
+            {
+                // first occurrence of this user key
+                current_user_key = Some(ikey.user_key.to_vec());
+                last_sequence_for_key = MAX_SEQUENCE_NUMBER;
+            }
+
+            // a newer entry of the key is already visible to every reader, or this is
+            // a deletion nobody can see past with nothing older left to shadow
+            let drop_entry = last_sequence_for_key <= smallest_snapshot
+                || (ikey.val_type == ValueType::Deletetion
+                    && ikey.sequence <= smallest_snapshot
+                    && c.is_base_level_for_key(ikey.user_key));
+            last_sequence_for_key = ikey.sequence;
+
+            if !drop_entry {
+                if builder.is_none() {
+                    match self.open_compaction_output(c) {
+                        Ok((b, meta)) => {
+                            builder = Some(b);
+                            outputs.push(meta);
+                        }
+                        Err(e) => {
+                            res = Err(e);
+                            break;
+                        }
+                    }
+                }
+                let meta = outputs.last_mut().unwrap();
+                if meta.smallest.encode().is_empty() {
+                    meta.smallest.decode(key);
+                }
+                meta.largest.decode(key);
+                let b = builder.as_mut().unwrap();
+                res = b.add(key, input.value());
+                if res.is_err() {
+                    break;
+                }
+
+                if b.file_size() >= c.max_output_file_size {
+                    res = self.finish_compaction_output(&mut builder, meta);
+                    if res.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            input.next();
+        }
+
+        if res.is_ok() && builder.is_some() {
+            res = self.finish_compaction_output(&mut builder, outputs.last_mut().unwrap());
+        }
+        if res.is_ok() {
+            res = input.status();
+        }
+        drop(input);
+
+        let bytes_read: u64 = c.inputs.iter().flatten().map(|f| f.file_size).sum();
+        let bytes_written: u64 = outputs.iter().map(|f| f.file_size).sum();
+        let output_level = c.level + 1;
+        span.record("output_files", outputs.len());
+        span.record("bytes_read", bytes_read);
+        span.record("bytes_written", bytes_written);
+
+        let mut versions = self.versions.lock().unwrap();
+        for meta in outputs.iter() {
+            versions.pending_outputs.remove(&meta.number);
+        }
+        // the outputs of a failed compaction are left for delete_obsoleted_files
+        res?;
+        self.install_compaction_results(&mut versions, c, &outputs)?;
+        drop(versions);
+
+        for meta in outputs.iter() {
+            self.notify_table_file_created(meta, output_level);
+        }
+
+        self.stats.add_compaction_bytes(bytes_written);
+        let stats = CompactionStats {
+            micros: self.env.now_micros().saturating_sub(start_micros),
+            bytes_read,
+            bytes_written,
+        };
+        self.compaction_stats.lock().unwrap()[output_level].add(&stats);
+
+        if let Some(listener) = self.options.listener.as_ref() {
+            listener.on_compaction_completed(&CompactionInfo {
+                kind: CompactionKind::Rewritten,
+                level: c.level,
+                output_level,
+                input_files: c.inputs.iter().flatten().map(|f| f.number).collect(),
+                output_files: outputs.iter().map(|f| f.number).collect(),
+                bytes_read,
+                bytes_written,
+            });
+        }
+        // the inputs are only garbage once the edit dropping them is durable
+        c.release_inputs();
+        self.delete_obsoleted_files();
+
+        Ok(())
+    }
+
+    // Swaps the inputs of `c` for its outputs. A crash at any point must leave the
+    // data in either of them, which takes this order:
+    //   1. the outputs are complete and synced, finish_compaction_output did that
+    //   2. the edit is synced to the manifest, so it never names a table that may
+    //      still be lost
+    //   3. after this returns the inputs can be deleted, the reopened db won't
+    //      read them anymore
+    fn install_compaction_results(
+        &self,
+        versions: &mut VersionSet<E>,
+        c: &mut Compaction<E>,
+        outputs: &[FileMetaData],
+    ) -> Result<()> {
+        let output_level = c.level + 1;
+        c.add_input_deletions();
+        for meta in outputs.iter() {
+            debug_assert_eq!(
+                self.env
+                    .file_size(&table_file_name(&self.db_name, meta.number))
+                    .ok(),
+                Some(meta.file_size as usize),
+                "compaction output #{} isn't complete",
+                meta.number
+            );
+            c.edit.add_new_file(
+                output_level as u32,
+                meta.number,
+                meta.file_size,
+                meta.smallest.clone(),
+                meta.largest.clone(),
+                meta.creation_time,
+            );
+        }
+        // log_and_apply syncs the manifest before the new version is installed
+        versions.log_and_apply(&mut c.edit)
+    }
+
+    fn open_compaction_output(
+        &self,
+        c: &Compaction<E>,
+    ) -> Result<(TableBuiler<E::WritableFile>, FileMetaData)> {
+        let mut versions = self.versions.lock().unwrap();
+        let meta = FileMetaData {
+            number: versions.new_file_number(),
+            creation_time: self.env.now_micros(),
+            ..Default::default()
+        };
+        versions.pending_outputs.insert(meta.number);
+        drop(versions);
+
+        let file = self
+            .env
+            .new_writable_file(&table_file_name(&self.db_name, meta.number))?;
+        let options = self.options.table_options_for_level(c.level + 1);
+        let mut builder = TableBuiler::new(options, file);
+        builder.set_io_priority(IoPriority::Background, self.env.clone());
+        Ok((builder, meta))
+    }
+
+    fn finish_compaction_output(
+        &self,
+        builder: &mut Option<TableBuiler<E::WritableFile>>,
+        meta: &mut FileMetaData,
+    ) -> Result<()> {
+        let builder = builder.take().unwrap();
+        let entries = builder.num_entries();
+        meta.file_size = builder.finish(true)?;
+
+        // the table must read back whole before it's installed
+        let table = self.table_cache.find_table(meta.number, meta.file_size)?;
+        let mut iter = Table::iter(table, &ReadOption::default());
+        iter.seek_to_first();
+        let mut read = 0;
+        let mut smallest = Vec::new();
+        let mut largest = Vec::new();
+        while iter.valid() {
+            if read == 0 {
+                smallest.extend_from_slice(iter.key());
+            }
+            largest.clear();
+            largest.extend_from_slice(iter.key());
+            read += 1;
+            iter.next();
+        }
+        iter.status()?;
+        if read != entries {
+            return Err(Error::Corruption(format!(
+                "table #{} holds {} of the {} entries written",
+                meta.number, read, entries
+            )));
+        }
+        if smallest != meta.smallest.encode() || largest != meta.largest.encode() {
+            return Err(Error::Corruption(format!(
+                "table #{} doesn't span the keys written to it",
+                meta.number
+            )));
+        }
+        Ok(())
+    }
+
+    fn notify_table_file_created(&self, meta: &FileMetaData, level: usize) {
+        if let Some(listener) = self.options.listener.as_ref() {
+            listener.on_table_file_created(&TableFileInfo {
+                number: meta.number,
+                level,
+                file_size: meta.file_size,
+                creation_time: meta.creation_time,
+            });
         }
     }
 
     pub fn compaction_memtable(&self) {
-        if self.do_compaction_memtable().is_err() {
-            //TODO
-            panic!("error in compaction table");
+        if let Err(err) = self.do_compaction_memtable() {
+            self.record_background_error(&err);
         }
     }
 
@@ -443,47 +2051,71 @@ impl<E: Env> DBImplInner<E> {
         let mut edit = VersionEdit::default();
         let current = self.versions.lock().unwrap().current();
 
-        self.write_level0_table(imm, &mut edit, current)?;
+        self.write_level0_table(imm, &mut edit, current, IoPriority::Background)?;
         if self.shutdown.load(Ordering::Acquire) {
             return Err(Error::CustomError(
                 "deleting db during memtable compaction".into(),
             ));
         }
 
+        // The log number is the one switch_memtable set, the prev_log holding the
+        // immutable memtable is no longer needed. The wal isn't locked: flush
+        // holds it while it waits for this
         edit.set_prev_log_number(0);
-        edit.set_log_number(self.wal.lock().unwrap().log_file_number);
-
-        let mut imm = self.imm.write().unwrap();
-        *imm = None;
+        let mut versions = self.versions.lock().unwrap();
+        versions.log_and_apply(&mut edit)?;
+        *self.imm.write().unwrap() = None;
+        drop(versions);
 
         self.delete_obsoleted_files();
 
         Ok(())
     }
 
+    // Wakes the compaction worker. Without background threads the caller flushes
+    // the immutable memtable and does at most one level compaction instead, it
+    // must not hold the versions.
     fn maybe_schedule_compaction(&self) {
         if self.shutdown.load(Ordering::Acquire) {
             return;
         }
 
-        self.compaction_trigger.0.send(()).unwrap();
+        if let Some(trigger) = self.compaction_trigger.lock().unwrap().as_ref() {
+            // the worker is gone only once the db shuts down
+            let _ = trigger.send(());
+            return;
+        }
+        if self.read_only || self.options.memtable_only {
+            return;
+        }
+        let _round = self.inline_work.lock().unwrap();
+        if self.imm.read().unwrap().is_some() {
+            self.run_background_work();
+        }
+        let more =
+            self.versions.lock().unwrap().needs_compaction() || self.manual_compaction_pending();
+        if more && self.background_error.read().unwrap().is_none() {
+            self.run_background_work();
+        }
+        self.maybe_dump_stats();
     }
 
     fn new_db(&self) -> Result<()> {
         let mut edit = VersionEdit::default();
-        let comparator = self.internal_comparator.user_comparator();
-        edit.set_comparator(comparator.name());
+        edit.set_comparator(self.internal_comparator.user_comparator_name());
         edit.set_log_number(0);
         edit.set_next_file_number(2);
         edit.set_last_sequence(0);
 
+        // a manifest left by an open cut off before CURRENT is overwritten
         let manifest = descriptor_file_name(&self.db_name, 1);
         let file = self.env.new_writable_file(&manifest)?;
         let mut log = LogWriter::new(file);
         let mut record = Vec::new();
         edit.encode(&mut record);
 
-        let mut res = log.add_record(record);
+        // CURRENT must not name a manifest a crash can empty
+        let mut res = log.add_record(record).and_then(|_| log.sync());
         if res.is_ok() {
             res = set_current_file(self.env.clone(), &self.db_name, 1);
         } else {
@@ -492,42 +2124,4600 @@ impl<E: Env> DBImplInner<E> {
         res
     }
 
-    pub fn new_internal_iterator(&self, _options: ReadOption) {}
-
-    pub fn debug_print(&self) {
-        println!("{:#?}", self.versions);
+    // A read view at the last sequence, held until it is dropped.
+    pub fn get_snapshot(&self) -> Snapshot {
+        let versions = self.versions.lock().unwrap();
+        Snapshot::acquire(&self.snapshots, versions.last_sequence())
     }
-}
 
-pub struct DBIter<E: Env> {
-    db: Arc<DBImplInner<E>>,
-    user_comparator: Arc<dyn Comparator>,
-    iter: Box<dyn DBIterator>,
-    sequence: SequenceNumber,
-    status: Option<Error>,
-    saved_key: Vec<u8>,
-    saved_value: Vec<u8>,
-    direction: Forward,
-    valid: bool,
-}
+    pub fn release_snapshot(&self, snapshot: Snapshot) {
+        drop(snapshot)
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::env::posix::PosixEnv;
+    // The oldest sequence a read can still ask for: that of the oldest live
+    // snapshot, or the last sequence when there is none. Compactions keep the
+    // newest entry of a key at or below it and everything above it.
+    fn oldest_snapshot_sequence(&self, versions: &VersionSet<E>) -> SequenceNumber {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .oldest()
+            .unwrap_or_else(|| versions.last_sequence())
+    }
 
-    use super::*;
+    // Merges the memtables and all tables of the current version, along with the
+    // sequence reads through it should see: the snapshot of `options`, or else the
+    // last sequence at the time it was created.
+    pub fn new_internal_iterator(
+        &self,
+        options: &ReadOption,
+    ) -> (Box<dyn DBIterator>, SequenceNumber) {
+        let versions = self.versions.lock().unwrap();
+        let mut iters = Vec::new();
+        for mem in [&self.mem, &self.imm] {
+            if let Some(mem) = mem.read().unwrap().as_ref() {
+                iters.push(mem.iter());
+            }
+        }
+        let current = versions.current().unwrap();
+        current.add_iterators(options, &mut iters);
+        let iter = MergingIterator::new(self.internal_comparator.clone(), iters);
+        // the tables of the version are not deleted while the iterator reads them
+        let iter = PinnedIterator::new(Box::new(iter), current);
+        let last_sequence = versions.last_sequence();
+        let sequence = options
+            .snapshot
+            .as_ref()
+            .map_or(last_sequence, |s| s.sequence().min(last_sequence));
+        (Box::new(iter), sequence)
+    }
 
-    #[test]
-    fn test_base_insert() {
-        let mut options = Options::default();
-        options.create_if_missing = true;
-        let db_name = "demo";
-        let env = PosixEnv {};
-        let db = LevelDB::open(options, db_name, env).unwrap();
-        for i in 0..20 {
-            let key = format!("liu{}", i);
-            eprintln!("{} {:?}", i, key.as_bytes());
-            db.write(key.as_bytes(), "zhong".as_bytes()).unwrap();
+    // Whether no key is visible, which is what a db iterator positioned at its
+    // first entry would tell.
+    pub fn is_empty(&self, options: &ReadOption) -> Result<bool> {
+        let (mut iter, sequence) = self.new_internal_iterator(options);
+        let user_comparator = self.internal_comparator.user_comparator();
+        // entries older than a visible deletion of their key are hidden by it
+        let mut deleted: Option<Vec<u8>> = None;
+        iter.seek_to_first();
+        while iter.valid() {
+            let ikey = ParsedInternalKey::parse(iter.key());
+            let hidden = deleted.as_ref().is_some_and(|k| {
+                user_comparator.compare(k, ikey.user_key) == std::cmp::Ordering::Equal
+            });
+            if ikey.sequence <= sequence && !hidden {
+                match ikey.val_type {
+                    ValueType::Value => return Ok(false),
+                    ValueType::Deletetion => deleted = Some(ikey.user_key.to_vec()),
+                }
+            }
+            iter.next();
         }
+        iter.status()?;
+        Ok(true)
+    }
+
+    pub fn get_approximate_sizes(&self, ranges: &[(&[u8], &[u8])]) -> Vec<u64> {
+        let current = self.versions.lock().unwrap().current().unwrap();
+        let offset_of = |key: &[u8]| {
+            let ikey = InternalKey::new(key, MAX_SEQUENCE_NUMBER, ValueType::Value);
+            VersionSet::approximate_offset_of(&current, ikey.encode())
+        };
+        ranges
+            .iter()
+            .map(|(start, limit)| offset_of(limit).saturating_sub(offset_of(start)))
+            .collect()
+    }
+
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        let name = property.strip_prefix("leveldb.")?;
+        match name {
+            "write-amplification" => Some(self.stats.write_amplification_property()),
+            "stats" => Some(self.stats_property()),
+            "sstables" => {
+                let current = self.versions.lock().unwrap().current().unwrap();
+                Some(current.sstables_property())
+            }
+            "compaction-debt-bytes" => Some(self.compaction_debt().total_bytes.to_string()),
+            "pending-flush-bytes" => Some(self.pending_flush().total_bytes().to_string()),
+            "pinned-tables" => Some(self.table_cache.pinned_count().to_string()),
+            "table-metadata" => Some(self.table_cache.metadata_property()),
+            "compaction-state" => Some(self.compaction_state_property()),
+            "open-micros" => Some(self.open_stats.micros.to_string()),
+            "open-files-listed" => Some(self.open_stats.files_listed.to_string()),
+            _ => None,
+        }
+    }
+
+    // The compaction running now and the manual one queued, then the score of each
+    // level and the user key its next size triggered compaction starts after.
+    fn compaction_state_property(&self) -> String {
+        let versions = self.versions.lock().unwrap();
+        let scores = versions.level_scores();
+        let pointers = versions.compact_pointers().clone();
+        drop(versions);
+
+        let mut out = String::new();
+        let status = self.compaction_status.read().unwrap();
+        match status.running.as_ref() {
+            Some(running) => {
+                let inputs: Vec<String> =
+                    running.input_files.iter().map(|n| n.to_string()).collect();
+                out.push_str(&format!(
+                    "running: level {}, inputs {}, started at {}\n",
+                    running.level,
+                    inputs.join(","),
+                    running.started_micros
+                ));
+            }
+            None => out.push_str("running: none\n"),
+        }
+        out.push_str(&format!("completed: {}\n", status.completed));
+        drop(status);
+        match self.manual_compaction.lock().unwrap().as_ref() {
+            Some(m) => {
+                let bound = |key: &Option<InternalKey>, none: &str| {
+                    key.as_ref().map_or(none.to_string(), |k| {
+                        format!("'{}'", escape_key(k.user_key()))
+                    })
+                };
+                out.push_str(&format!(
+                    "manual: level {}, {} .. {}\n",
+                    m.level,
+                    bound(&m.begin, "first"),
+                    bound(&m.end, "last")
+                ));
+            }
+            None => out.push_str("manual: none\n"),
+        }
+        for (level, pointer) in pointers.iter().enumerate().take(NUM_LEVELS - 1) {
+            let pointer = if pointer.is_empty() {
+                "none".to_string()
+            } else {
+                format!("'{}'", escape_key(extract_user_key(pointer)))
+            };
+            out.push_str(&format!(
+                "level {}: score {:.2}, pointer {}\n",
+                level, scores[level], pointer
+            ));
+        }
+        out
+    }
+
+    // computed from the current version, the lock is only held to pin it
+    fn compaction_debt(&self) -> CompactionDebt {
+        let current = self.versions.lock().unwrap().current().unwrap();
+        current.compaction_debt()
+    }
+
+    fn pending_flush(&self) -> PendingFlush {
+        let usage = |mem: &RwLock<Option<Arc<MemTable>>>| {
+            mem.read()
+                .unwrap()
+                .as_ref()
+                .map_or(0, |m| m.approximate_memory_usage() as u64)
+        };
+        PendingFlush {
+            memtable_bytes: usage(&self.mem),
+            immutable_memtable_bytes: usage(&self.imm),
+        }
+    }
+
+    fn stats_property(&self) -> String {
+        // snapshot everything first, the versions lock is only held to clone the Arc
+        let current = self.versions.lock().unwrap().current().unwrap();
+        let stats = *self.compaction_stats.lock().unwrap();
+        let files: Vec<_> = (0..NUM_LEVELS)
+            .map(|level| {
+                (
+                    current.files[level].len(),
+                    current.level_total_file_size(level),
+                )
+            })
+            .collect();
+        level_stats_property(&files, &stats)
+    }
+
+    pub fn reset_stats(&self) {
+        *self.compaction_stats.lock().unwrap() = Default::default();
+        self.stats.reset();
+    }
+
+    // Writes `leveldb.stats` to the info log if `stats_dump_period` passed since
+    // the last dump.
+    fn maybe_dump_stats(&self) {
+        let (period, logger) = match (self.options.stats_dump_period, &self.options.info_log) {
+            (Some(period), Some(logger)) => (period.as_micros() as u64, logger),
+            _ => return,
+        };
+        let now = self.env.now_micros();
+        let last = self.last_stats_dump_micros.load(Ordering::Acquire);
+        if now.saturating_sub(last) < period {
+            return;
+        }
+        // another caller won the race for this period
+        if self
+            .last_stats_dump_micros
+            .compare_exchange(last, now, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+        logger.log(&format!(
+            "------- DUMPING STATS -------\n{}",
+            self.stats_property()
+        ));
+    }
+
+    // The log of the immutable memtable may not be synced. A failed worker leaves
+    // the memtable to the replay of the next open
+    fn wait_for_imm_flush(&self) {
+        if self.imm.read().unwrap().is_none() {
+            return;
+        }
+        self.maybe_schedule_compaction();
+        let mut versions = match self.versions.lock() {
+            Ok(versions) => versions,
+            Err(_) => return,
+        };
+        while self.imm.read().unwrap().is_some() && self.check_background_error().is_ok() {
+            versions = match self.wait_for_background_work(versions) {
+                Ok(versions) => versions,
+                Err(_) => return,
+            };
+        }
+    }
+
+    // Syncs the WAL and closes the manifest, once no background work runs. A
+    // panic of that work may have poisoned the locks
+    fn close(&self) {
+        if let Ok(mut wal) = self.wal.lock() {
+            if let Some(log) = wal.log.as_mut() {
+                if let Err(err) = log.flush().and_then(|_| log.sync()) {
+                    if let Some(logger) = self.options.info_log.as_ref() {
+                        logger.log(&format!("syncing the log on close failed: {}", err));
+                    }
+                }
+            }
+        }
+        if let Ok(mut versions) = self.versions.lock() {
+            versions.close_manifest();
+        }
+    }
+
+    fn release_lock(&self) {
+        if let Some(lock) = self.db_lock.lock().unwrap().take() {
+            if let Err(err) = self.env.unlock_file(lock) {
+                if let Some(logger) = self.options.info_log.as_ref() {
+                    logger.log(&format!("unlock failed: {}", err));
+                }
+            }
+        }
+    }
+
+    pub fn debug_print(&self) {
+        println!("{:#?}", self.versions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::cmp::{BitWiseComparator, BYTEWISE_COMPARATOR_NAME};
+    use crate::consts::{L0_COMPACTION_TRIGGER, NUM_LEVELS};
+    use crate::env::Logger;
+    use crate::env::{faulty::FaultyEnv, DefaultEnv, IoResult, SequencialFile};
+    use crate::filter::{BloomFilterPolicy, FilterPolicy};
+    use crate::listener::EventListener;
+    use crate::slice::UnsafeSlice;
+    use crate::snapshot::Snapshot;
+    use crate::version::max_bytes_for_level;
+    use crate::{DbBuilder, MemEnv, RateLimiter};
+
+    use super::*;
+
+    #[test]
+    fn test_base_insert() {
+        let dir = tempfile::Builder::new().prefix("demo").tempdir().unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..20 {
+            let key = format!("liu{}", i);
+            eprintln!("{} {:?}", i, key.as_bytes());
+            db.write(key.as_bytes(), "zhong".as_bytes()).unwrap();
+        }
+    }
+
+    // writes the memtable to a level-0 table and installs it, continuing with a
+    // new log like flush does, so recovery doesn't replay the flushed data
+    fn flush_memtable<E: Env>(db: &LevelDB<E>) {
+        let inner = &db.inner;
+        let mut wal = inner.wal.lock().unwrap();
+        let new_log_number = inner.versions.lock().unwrap().new_file_number();
+        let file = inner
+            .env
+            .new_writable_file(&log_file_name(&inner.db_name, new_log_number))
+            .unwrap();
+        let new_mem = inner.new_memtable();
+        let mem = inner.mem.write().unwrap().replace(new_mem).unwrap();
+        let mut edit = VersionEdit::default();
+        inner
+            .write_level0_table(mem, &mut edit, None, IoPriority::Foreground)
+            .unwrap();
+        edit.set_log_number(new_log_number);
+        inner
+            .versions
+            .lock()
+            .unwrap()
+            .log_and_apply(&mut edit)
+            .unwrap();
+        wal.log_file_number = new_log_number;
+        wal.log = Some(inner.new_log_writer(file, 0));
+    }
+
+    fn dir_size(dir: &Path, file_type: FileType) -> u64 {
+        let mut size = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            if matches!(parse_file_name(entry.file_name()), Ok((_, t)) if t == file_type) {
+                size += entry.metadata().unwrap().len();
+            }
+        }
+        size
+    }
+
+    #[test]
+    fn test_write_amplification() {
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("write_amp")
+            .tempdir()
+            .unwrap();
+        let db =
+            LevelDB::open(options, dir.path().to_str().unwrap(), DefaultEnv::default()).unwrap();
+        assert!(db.get_property("leveldb.unknown").is_none());
+        let property = db.get_property("leveldb.write-amplification").unwrap();
+        assert!(property.starts_with("write-amplification: 0.000\n"));
+
+        let value = vec![b'x'; 1000];
+        let mut user_bytes = 0;
+        // every round overwrites the keys of the one before, so the tables overlap
+        for _ in 0..4 {
+            for i in 0..500 {
+                let key = format!("key{:06}", i);
+                db.write(key.as_bytes(), &value).unwrap();
+                user_bytes += (key.len() + value.len()) as u64;
+            }
+            flush_memtable(&db);
+        }
+
+        let stats = &db.inner.stats;
+        assert_eq!(stats.user_bytes(), user_bytes);
+        assert_eq!(stats.wal_bytes(), dir_size(dir.path(), FileType::Log));
+        assert_eq!(stats.flush_bytes(), dir_size(dir.path(), FileType::Table));
+        assert_eq!(stats.compaction_bytes(), 0);
+
+        // every byte goes to the WAL once and to a level-0 table once
+        let amp = stats.write_amplification();
+        assert!(
+            (amp - 2.0).abs() < 2.0 * 0.05,
+            "write amplification {}",
+            amp
+        );
+        let property = db.get_property("leveldb.write-amplification").unwrap();
+        assert!(property.contains(&format!("user-bytes: {}\n", user_bytes)));
+
+        // a full compaction merges the level-0 tables into one level, and every
+        // table left is one of its outputs
+        let wal_bytes = dir_size(dir.path(), FileType::Log);
+        let flush_bytes = dir_size(dir.path(), FileType::Table);
+        db.compact_range(None, None).unwrap();
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert!(current.files[0].is_empty());
+        drop(current);
+        let compaction_bytes = dir_size(dir.path(), FileType::Table);
+        assert!(compaction_bytes > 0);
+        assert_eq!(stats.compaction_bytes(), compaction_bytes);
+        let expected = (wal_bytes + flush_bytes + compaction_bytes) as f64 / user_bytes as f64;
+        let compacted = stats.write_amplification();
+        assert!(compacted > amp, "{} after {}", compacted, amp);
+        assert!(
+            (compacted - expected).abs() < expected * 0.05,
+            "write amplification {}, expected {}",
+            compacted,
+            expected
+        );
+    }
+
+    // counts the table files created and opened through the env, the directory
+    // listings and the sleeps, and runs on a manual clock once `clock_micros` is set
+    #[derive(Clone, Default)]
+    struct CountingEnv<E> {
+        env: E,
+        tables_created: Arc<AtomicUsize>,
+        tables_opened: Arc<AtomicUsize>,
+        listings: Arc<AtomicUsize>,
+        sleeps: Arc<AtomicUsize>,
+        clock_micros: Arc<AtomicU64>,
+        // see `fake_sleeps`
+        sleeps_faked: Arc<AtomicBool>,
+        slept: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    type TestEnv = CountingEnv<DefaultEnv>;
+
+    impl<E: Env> CountingEnv<E> {
+        fn advance_clock(&self, d: Duration) {
+            self.clock_micros
+                .fetch_add(d.as_micros() as u64, Ordering::SeqCst);
+        }
+
+        // sleeps advance the clock instead and are recorded with the name of the
+        // sleeping thread
+        fn fake_sleeps(&self) {
+            self.sleeps_faked.store(true, Ordering::SeqCst);
+        }
+
+        fn slept(&self) -> Vec<(String, u64)> {
+            self.slept.lock().unwrap().clone()
+        }
+    }
+
+    impl<E: Env> Env for CountingEnv<E> {
+        type RandomAccessFile = E::RandomAccessFile;
+        type WritableFile = E::WritableFile;
+        type SequencialFile = E::SequencialFile;
+        type FileLock = E::FileLock;
+
+        fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile> {
+            if name.extension().is_some_and(|ext| ext == "ldb") {
+                self.tables_opened.fetch_add(1, Ordering::SeqCst);
+            }
+            self.env.new_random_access_file(name)
+        }
+        fn new_writable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+            if name.extension().is_some_and(|ext| ext == "ldb") {
+                self.tables_created.fetch_add(1, Ordering::SeqCst);
+            }
+            self.env.new_writable_file(name)
+        }
+        fn new_appendable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+            self.env.new_appendable_file(name)
+        }
+        fn new_sequential_file(&self, name: &Path) -> IoResult<Self::SequencialFile> {
+            self.env.new_sequential_file(name)
+        }
+        fn file_size(&self, path: &Path) -> IoResult<usize> {
+            self.env.file_size(path)
+        }
+        fn file_exists(&self, path: &Path) -> bool {
+            self.env.file_exists(path)
+        }
+        fn delete_file(&self, path: &Path) -> IoResult<()> {
+            self.env.delete_file(path)
+        }
+        fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()> {
+            self.env.rename_file(from, to)
+        }
+        fn create_dir(&self, path: &Path) -> IoResult<()> {
+            self.env.create_dir(path)
+        }
+        fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+            self.env.canonicalize(path)
+        }
+        fn fs_id(&self) -> u64 {
+            self.env.fs_id()
+        }
+        fn delete_dir(&self, path: &Path) -> IoResult<()> {
+            self.env.delete_dir(path)
+        }
+        fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()> {
+            self.listings.fetch_add(1, Ordering::SeqCst);
+            self.env.get_children(path, files)
+        }
+        fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock> {
+            self.env.lock_file(path)
+        }
+        fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()> {
+            self.env.unlock_file(lock)
+        }
+        fn now_micros(&self) -> u64 {
+            match self.clock_micros.load(Ordering::SeqCst) {
+                0 => self.env.now_micros(),
+                now => now,
+            }
+        }
+        fn sleep_for_micros(&self, micros: u64) {
+            self.sleeps.fetch_add(1, Ordering::SeqCst);
+            if self.sleeps_faked.load(Ordering::SeqCst) {
+                let name = thread::current().name().unwrap_or_default().to_string();
+                self.slept.lock().unwrap().push((name, micros));
+                self.advance_clock(Duration::from_micros(micros));
+                return;
+            }
+            self.env.sleep_for_micros(micros)
+        }
+    }
+
+    #[derive(Default)]
+    struct CompactionRecorder {
+        events: Mutex<Vec<CompactionInfo>>,
+        tables: Mutex<Vec<TableFileInfo>>,
+    }
+
+    impl EventListener for CompactionRecorder {
+        fn on_compaction_completed(&self, info: &CompactionInfo) {
+            self.events.lock().unwrap().push(info.clone());
+        }
+        fn on_table_file_created(&self, info: &TableFileInfo) {
+            self.tables.lock().unwrap().push(info.clone());
+        }
+    }
+
+    fn file_level<E: Env>(db: &LevelDB<E>, number: u64) -> Option<usize> {
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        (0..NUM_LEVELS).find(|&level| current.files[level].iter().any(|f| f.number == number))
+    }
+
+    // flushes `count` keys with the given prefix, returns the new level-0 file
+    fn flush_keys<E: Env>(db: &LevelDB<E>, prefix: &str, count: usize, value_size: usize) -> u64 {
+        let value = vec![b'v'; value_size];
+        for i in 0..count {
+            db.write(format!("{}{:04}", prefix, i).as_bytes(), &value)
+                .unwrap();
+        }
+        flush_memtable(db);
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        current.files[0].iter().map(|f| f.number).max().unwrap()
+    }
+
+    // marks the file as seek compaction candidate and runs one compaction
+    fn compact_file<E: Env>(db: &LevelDB<E>, number: u64) {
+        let level = file_level(db, number).unwrap();
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let f = current.files[level]
+            .iter()
+            .find(|f| f.number == number)
+            .unwrap()
+            .clone();
+        *current.file_to_compact.write().unwrap() = Some((f, level));
+        db.inner.do_compaction().unwrap();
+    }
+
+    #[test]
+    fn test_trivial_move() {
+        let listener = Arc::new(CompactionRecorder::default());
+        let options = Options {
+            create_if_missing: true,
+            // grandparent overlap limit of 10KB
+            max_file_size: 1024,
+            listener: Some(listener.clone()),
+            ..Default::default()
+        };
+        let env = TestEnv::default();
+        let dir = tempfile::Builder::new()
+            .prefix("trivial_move")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options, dir.path().to_str().unwrap(), env.clone()).unwrap();
+
+        // a file without any overlap moves all the way to the last level, but no further
+        let file = flush_keys(&db, "a", 100, 10);
+        let tables = env.tables_created.load(Ordering::SeqCst);
+        for level in 0..NUM_LEVELS - 1 {
+            assert_eq!(file_level(&db, file), Some(level));
+            compact_file(&db, file);
+        }
+        assert_eq!(file_level(&db, file), Some(NUM_LEVELS - 1));
+        compact_file(&db, file);
+        assert_eq!(file_level(&db, file), Some(NUM_LEVELS - 1));
+        assert!(db
+            .inner
+            .versions
+            .lock()
+            .unwrap()
+            .pick_compaction()
+            .is_none());
+
+        let events = listener.events.lock().unwrap().clone();
+        assert_eq!(events.len(), NUM_LEVELS - 1);
+        for (level, e) in events.iter().enumerate() {
+            assert_eq!(e.kind, CompactionKind::Moved);
+            assert_eq!((e.level, e.output_level), (level, level + 1));
+            assert_eq!(e.input_files, vec![file]);
+            assert_eq!(e.output_files, vec![file]);
+        }
+
+        // a small file and a large one at level 3
+        let small = flush_keys(&db, "m", 10, 10);
+        let large = flush_keys(&db, "x", 20, 1024);
+        for _ in 0..3 {
+            compact_file(&db, small);
+            compact_file(&db, large);
+        }
+        assert_eq!(file_level(&db, small), Some(3));
+        assert_eq!(file_level(&db, large), Some(3));
+        listener.events.lock().unwrap().clear();
+
+        // stops right above the overlapping file, and is merged with it from there
+        let overlap = flush_keys(&db, "m", 5, 10);
+        for _ in 0..2 {
+            compact_file(&db, overlap);
+        }
+        assert_eq!(file_level(&db, overlap), Some(2));
+        compact_file(&db, overlap);
+        assert_eq!(file_level(&db, overlap), None);
+        assert_eq!(file_level(&db, small), None);
+
+        // stops where the grandparent overlap exceeds the limit, and is rewritten
+        let grandparent = flush_keys(&db, "x", 5, 10);
+        compact_file(&db, grandparent);
+        assert_eq!(file_level(&db, grandparent), Some(1));
+        compact_file(&db, grandparent);
+        assert_eq!(file_level(&db, grandparent), None);
+        assert_eq!(file_level(&db, large), Some(3));
+
+        let events = listener.events.lock().unwrap().clone();
+        let kinds: Vec<_> = events.iter().map(|e| e.kind).collect();
+        use CompactionKind::*;
+        assert_eq!(kinds, vec![Moved, Moved, Rewritten, Moved, Rewritten]);
+        assert_eq!(events[2].input_files, vec![overlap, small]);
+        assert_eq!((events[2].level, events[2].output_level), (2, 3));
+        assert_eq!(events[4].input_files, vec![grandparent]);
+        assert_eq!((events[4].level, events[4].output_level), (1, 2));
+
+        // the flushes and the two rewrites wrote tables
+        assert_eq!(env.tables_created.load(Ordering::SeqCst), tables + 4 + 2);
+    }
+
+    #[test]
+    fn test_no_filter_for_last_level() {
+        let bloom: Arc<dyn FilterPolicy> = Arc::new(BloomFilterPolicy::new(10));
+        let mut per_level = vec![Some(bloom.clone()); NUM_LEVELS - 1];
+        per_level.push(None);
+        let options = Options {
+            create_if_missing: true,
+            filter_policy: Some(bloom),
+            filter_policy_per_level: Some(per_level),
+            ..Default::default()
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("last_level_filter")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options, dir.path().to_str().unwrap(), TestEnv::default()).unwrap();
+        let table = |f: &FileMetaData| {
+            db.inner
+                .table_cache
+                .find_table(f.number, f.file_size)
+                .unwrap()
+        };
+        let has_filter = |number| {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            let f = current.files.iter().flatten().find(|f| f.number == number);
+            table(f.unwrap()).filter_data().is_some()
+        };
+
+        // flushed with a filter, which a move to the last level keeps
+        let moved = flush_keys(&db, "key", 100, 10);
+        assert!(has_filter(moved));
+        for _ in 0..NUM_LEVELS - 1 {
+            compact_file(&db, moved);
+        }
+        assert_eq!(file_level(&db, moved), Some(NUM_LEVELS - 1));
+        assert!(has_filter(moved));
+
+        // overwritten, flushed and moved down until it's merged with it
+        let newer = flush_keys(&db, "key", 100, 20);
+        assert!(has_filter(newer));
+        for _ in 0..NUM_LEVELS - 1 {
+            compact_file(&db, newer);
+        }
+        assert_eq!(file_level(&db, newer), None);
+        assert_eq!(file_level(&db, moved), None);
+
+        // the tables written to the last level have no filter, and reads don't need one
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert!(!current.files[NUM_LEVELS - 1].is_empty());
+        for f in current.files[NUM_LEVELS - 1].iter() {
+            let table = table(f);
+            assert!(table.filter_data().is_none());
+            assert_eq!(table.filter_policy_name(), None);
+        }
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(vec![b'v'; 20]));
+        }
+        assert_eq!(db.get(b"key0100").unwrap(), None);
+    }
+
+    // Looks the key up the way a get walks the version: the level-0 files holding it
+    // newest first, then the file holding it in each level.
+    fn get_from_tables<E: Env>(db: &LevelDB<E>, key: &[u8]) -> Option<Vec<u8>> {
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let ucmp = db.inner.internal_comparator.user_comparator();
+        // the largest tag sorts before every entry of the key
+        let target = InternalKey::new(key, MAX_SEQUENCE_NUMBER, ValueType::Value);
+        for level in 0..NUM_LEVELS {
+            let mut files: Vec<_> = current.files[level]
+                .iter()
+                .filter(|f| {
+                    ucmp.compare(key, f.smallest.user_key()) != std::cmp::Ordering::Less
+                        && ucmp.compare(key, f.largest.user_key()) != std::cmp::Ordering::Greater
+                })
+                .collect();
+            files.sort_by_key(|f| std::cmp::Reverse(f.number));
+            for f in files {
+                let mut found = None;
+                db.inner
+                    .table_cache
+                    .get(&ReadOption::default(), f, target.encode(), |k, v| {
+                        if extract_user_key(k) == key {
+                            found = Some(v.to_vec());
+                        }
+                    })
+                    .unwrap();
+                if found.is_some() {
+                    return found;
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_compact_many_small_level0_files() {
+        let listener = Arc::new(CompactionRecorder::default());
+        let options = Options {
+            create_if_missing: true,
+            listener: Some(listener.clone()),
+            ..Default::default()
+        };
+        let env = TestEnv::default();
+        let dir = tempfile::Builder::new()
+            .prefix("many_level0")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options, dir.path().to_str().unwrap(), env.clone()).unwrap();
+
+        // every flush overwrites most of the keys of the previous few
+        let mut expected = BTreeMap::new();
+        for i in 0..50 {
+            for j in 0..100 {
+                let key = format!("key{:06}", i * 20 + j);
+                let value = format!("value{}-{}", i, j);
+                db.write(key.as_bytes(), value.as_bytes()).unwrap();
+                expected.insert(key.into_bytes(), value.into_bytes());
+            }
+            flush_memtable(&db);
+        }
+
+        // far too small for a size triggered compaction, the file count triggers it
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert_eq!(current.files[0].len(), 50);
+        assert!((current.level_total_file_size(0) as f64) < max_bytes_for_level(1));
+        assert_eq!(current.compaction_level, 0);
+        assert!(current.compaction_score >= 1.0);
+        drop(current);
+
+        let tables = env.tables_created.load(Ordering::SeqCst);
+        db.inner.do_compaction().unwrap();
+        // the inputs were pinned for the compaction only
+        assert_eq!(db.get_property("leveldb.pinned-tables").unwrap(), "0");
+
+        // all of them are merged into one table at level 1
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert!(current.files[0].len() <= L0_COMPACTION_TRIGGER);
+        assert_eq!(current.files[1].len(), 1);
+        assert_eq!(env.tables_created.load(Ordering::SeqCst), tables + 1);
+        let events = listener.events.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, CompactionKind::Rewritten);
+        assert_eq!((events[0].level, events[0].output_level), (0, 1));
+        assert_eq!(events[0].input_files.len(), 50);
+        assert_eq!(events[0].output_files, vec![current.files[1][0].number]);
+
+        // only the newest value of each key is kept, and reads only need the one
+        // table, cached since table_entries opened it
+        assert_eq!(
+            table_entries(&db),
+            expected.clone().into_iter().collect::<Vec<_>>()
+        );
+        let opened = env.tables_opened.load(Ordering::SeqCst);
+        for (key, value) in expected.iter() {
+            assert_eq!(get_from_tables(&db, key).as_ref(), Some(value));
+        }
+        assert_eq!(env.tables_opened.load(Ordering::SeqCst), opened);
+        assert_eq!(get_from_tables(&db, b"key999999"), None);
+    }
+
+    #[derive(Default)]
+    struct CapturingLogger {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Logger for CapturingLogger {
+        fn log(&self, msg: &str) {
+            self.lines.lock().unwrap().push(msg.to_string());
+        }
+    }
+
+    #[test]
+    fn test_sanitize_block_restart_interval() {
+        let logger = Arc::new(CapturingLogger::default());
+        let sanitized = |block_restart_interval| {
+            sanitize_options(Options {
+                block_restart_interval,
+                info_log: Some(logger.clone()),
+                ..Default::default()
+            })
+            .block_restart_interval
+        };
+        assert_eq!(sanitized(16), 16);
+        assert_eq!(
+            sanitized(MAX_BLOCK_RESTART_INTERVAL),
+            MAX_BLOCK_RESTART_INTERVAL
+        );
+        assert!(logger.lines.lock().unwrap().is_empty());
+
+        assert_eq!(sanitized(0), 1);
+        assert_eq!(sanitized(u32::MAX), MAX_BLOCK_RESTART_INTERVAL);
+        let lines = logger.lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "block_restart_interval 0 is out of [1, 1024], using 1"
+        );
+
+        // a db opened with an interval of 0 writes tables instead of aborting
+        let dir = tempfile::Builder::new()
+            .prefix("restart_interval")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            block_restart_interval: 0,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        flush_keys(&db, "a", 100, 10);
+        assert_eq!(table_entries(&db).len(), 100);
+    }
+
+    #[test]
+    fn test_sanitize_max_open_files() {
+        let logger = Arc::new(CapturingLogger::default());
+        let sanitized = |max_open_files| {
+            sanitize_options(Options {
+                max_open_files,
+                info_log: Some(logger.clone()),
+                ..Default::default()
+            })
+            .max_open_files
+        };
+        assert_eq!(sanitized(1000), 1000);
+        assert!(logger.lines.lock().unwrap().is_empty());
+
+        // too few files used to leave no room for the table cache
+        assert_eq!(sanitized(5), MIN_MAX_OPEN_FILES);
+        assert_eq!(sanitized(u64::MAX), MAX_MAX_OPEN_FILES);
+        let lines = logger.lines.lock().unwrap();
+        assert_eq!(
+            *lines,
+            vec![
+                "max_open_files 5 is out of [74, 50000], using 74".to_string(),
+                format!(
+                    "max_open_files {} is out of [74, 50000], using 50000",
+                    u64::MAX
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stats_dump_and_reset() {
+        let logger = Arc::new(CapturingLogger::default());
+        let options = Options {
+            create_if_missing: true,
+            info_log: Some(logger.clone()),
+            // long enough for the dump thread to stay out of the way
+            stats_dump_period: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let env = TestEnv::default();
+        env.advance_clock(Duration::from_secs(1000));
+        let dir = tempfile::Builder::new()
+            .prefix("stats_dump")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options, dir.path().to_str().unwrap(), env.clone()).unwrap();
+        flush_keys(&db, "a", 100, 100);
+        assert!(db.inner.compaction_stats.lock().unwrap()[0].bytes_written > 0);
+
+        db.inner.maybe_dump_stats();
+        assert!(logger.lines.lock().unwrap().is_empty());
+
+        env.advance_clock(Duration::from_secs(3600));
+        db.inner.maybe_dump_stats();
+        db.inner.maybe_dump_stats();
+        {
+            let lines = logger.lines.lock().unwrap();
+            assert_eq!(lines.len(), 1);
+            assert!(lines[0].starts_with("------- DUMPING STATS -------\n"));
+            assert!(lines[0].ends_with(&db.get_property("leveldb.stats").unwrap()));
+            assert!(lines[0].contains("\n  0        1 "));
+        }
+
+        db.reset_stats();
+        assert_eq!(
+            db.inner.compaction_stats.lock().unwrap()[0],
+            CompactionStats::default()
+        );
+        assert_eq!(db.inner.stats.disk_bytes(), 0);
+        env.advance_clock(Duration::from_secs(3600));
+        db.inner.maybe_dump_stats();
+        let lines = logger.lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        // the file is still there, the counters are gone
+        assert!(lines[1].contains("\n  0        1        0         0        0         0\n"));
+    }
+
+    #[test]
+    fn test_stats_dump_worker() {
+        let logger = Arc::new(CapturingLogger::default());
+        let options = Options {
+            create_if_missing: true,
+            info_log: Some(logger.clone()),
+            stats_dump_period: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("stats_dump_worker")
+            .tempdir()
+            .unwrap();
+        let db =
+            LevelDB::open(options, dir.path().to_str().unwrap(), DefaultEnv::default()).unwrap();
+        for _ in 0..100 {
+            if !logger.lines.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!logger.lines.lock().unwrap().is_empty());
+        drop(db);
+    }
+
+    // every entry of every table in the current version, as (user key, value)
+    fn table_entries<E: Env>(db: &LevelDB<E>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let mut entries = Vec::new();
+        for f in current.files.iter().flatten() {
+            let table = db
+                .inner
+                .table_cache
+                .find_table(f.number, f.file_size)
+                .unwrap();
+            let mut iter = Table::iter(table, &ReadOption::default());
+            iter.seek_to_first();
+            while iter.valid() {
+                let user_key = crate::format::extract_user_key(iter.key());
+                entries.push((user_key.to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_keys_and_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let datas: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![0xff, 0xfe, 0x00, 0x01], vec![0xc3, 0x28]),
+            (b"key\0with\0nul".to_vec(), b"\0".to_vec()),
+            (vec![0x80], vec![0xed, 0xa0, 0x80, 0x00, 0xff]),
+            (vec![0x00], vec![]),
+        ];
+        let mut expected = datas.clone();
+        expected.sort();
+
+        let tmp = tempfile::Builder::new()
+            .prefix("non_utf8")
+            .tempdir()
+            .unwrap();
+        // the db directory itself is not utf-8, and neither is a stray file in it
+        let db_path = tmp.path().join(OsStr::from_bytes(b"db-\xff\xfe"));
+        std::fs::create_dir(&db_path).unwrap();
+        std::fs::write(db_path.join(OsStr::from_bytes(b"stray-\xc3\x28")), b"").unwrap();
+
+        let options = || Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        {
+            let db = LevelDB::open(options(), &db_path, DefaultEnv::default()).unwrap();
+            for (k, v) in datas.iter() {
+                db.write(k, v).unwrap();
+            }
+            flush_memtable(&db);
+            assert_eq!(table_entries(&db), expected);
+            for (k, v) in datas.iter() {
+                db.write(&[k.as_slice(), b"\xff"].concat(), v).unwrap();
+            }
+        }
+
+        // replaying the WAL writes the second round of data to a table
+        let db = LevelDB::open(options(), &db_path, DefaultEnv::default()).unwrap();
+        let mut all: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| ([k.as_slice(), b"\xff"].concat(), v.clone()))
+            .chain(expected.iter().cloned())
+            .collect();
+        all.sort();
+        assert_eq!(table_entries(&db), all);
+    }
+
+    #[test]
+    fn test_open_twice_in_process() {
+        let dir = tempfile::Builder::new()
+            .prefix("open_twice")
+            .tempdir()
+            .unwrap();
+        let options = || Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let path = dir.path().to_path_buf();
+                thread::spawn(move || {
+                    barrier.wait();
+                    LevelDB::open(options(), path, DefaultEnv::default())
+                })
+            })
+            .collect();
+        let mut results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        results.sort_by_key(|r| r.is_err());
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::AlreadyExist(_))));
+
+        // another name of the same directory is the same db
+        let other_name = dir.path().join(".");
+        assert!(matches!(
+            LevelDB::open(options(), &other_name, DefaultEnv::default()),
+            Err(Error::AlreadyExist(_))
+        ));
+
+        results.clear();
+        let db = LevelDB::open(options(), &other_name, DefaultEnv::default()).unwrap();
+        db.write(b"k", b"v").unwrap();
+    }
+
+    #[test]
+    fn test_open_same_path_in_separate_mem_envs() {
+        let builder = DbBuilder::new().create_if_missing(true);
+        let env = MemEnv::new();
+        let db = builder.clone().env(env.clone()).open("/db").unwrap();
+        db.write(b"k", b"first").unwrap();
+        // a clone shares the files, it's the same db
+        assert!(matches!(
+            builder.clone().env(env).open("/db"),
+            Err(Error::AlreadyExist(_))
+        ));
+
+        let other = builder.clone().env(MemEnv::new()).open("/db").unwrap();
+        other.write(b"k", b"second").unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(other.get(b"k").unwrap(), Some(b"second".to_vec()));
+
+        // nor does a path in memory hold the same one on disk
+        let dir = tempfile::Builder::new()
+            .prefix("mem_and_disk")
+            .tempdir()
+            .unwrap();
+        let _in_memory = builder.clone().env(MemEnv::new()).open(dir.path()).unwrap();
+        builder.open(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let dir = tempfile::Builder::new()
+            .prefix("lock_file")
+            .tempdir()
+            .unwrap();
+        let env = DefaultEnv::default();
+        let lock_name = lock_file_name(dir.path());
+        let builder = DbBuilder::new().create_if_missing(true);
+        let db = builder.open(dir.path()).unwrap();
+        db.write(b"k", b"v").unwrap();
+        // an flock of another open of the file fails like one of another process
+        let err = env.lock_file(&lock_name).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        drop(db);
+
+        let lock = env.lock_file(&lock_name).unwrap();
+        let err = builder.open(dir.path()).err().unwrap();
+        assert_eq!(err.io_kind(), Some(std::io::ErrorKind::WouldBlock));
+        assert!(err.to_string().contains("lock held"), "{}", err);
+        assert!(destroy_db(dir.path(), env).is_err());
+        // a read only db doesn't take the lock
+        let db = builder.open_read_only(dir.path()).unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v".to_vec()));
+        drop(db);
+
+        env.unlock_file(lock).unwrap();
+        let db = builder.open(dir.path()).unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v".to_vec()));
+        drop(db);
+        destroy_db(dir.path(), env).unwrap();
+        assert!(!dir.path().exists());
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_torn_manifest_tail() {
+        let dir = tempfile::Builder::new()
+            .prefix("torn_manifest")
+            .tempdir()
+            .unwrap();
+        let options = |paranoid_checks| Options {
+            create_if_missing: true,
+            paranoid_checks,
+            ..Default::default()
+        };
+
+        // every flush appends an edit to the manifest, the WAL keeps all the data
+        let mut expected = BTreeMap::new();
+        {
+            let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+            for round in 0..3 {
+                for i in 0..50 {
+                    let key = format!("key{:02}{:04}", round, i).into_bytes();
+                    let value = format!("value{}", i).into_bytes();
+                    db.write(&key, &value).unwrap();
+                    expected.insert(key, value);
+                }
+                flush_memtable(&db);
+            }
+        }
+        let expected: Vec<_> = expected.into_iter().collect();
+
+        let current = std::fs::read_to_string(current_file_name(dir.path())).unwrap();
+        let manifest_name = current.trim_end();
+        let manifest = std::fs::read(dir.path().join(manifest_name)).unwrap();
+        // the snapshot and the edit a manifest is created with are synced before
+        // CURRENT names it, those two are never torn
+        let record_end = |start: usize| {
+            let length = u16::from_le_bytes([manifest[start + 4], manifest[start + 5]]);
+            start + crate::log::HEADER_SIZE + length as usize
+        };
+        let first_end = record_end(record_end(0));
+        assert!(first_end < manifest.len());
+
+        let open_cut = |data: &[u8], paranoid_checks| {
+            let copy = tempfile::Builder::new()
+                .prefix("torn_manifest_copy")
+                .tempdir()
+                .unwrap();
+            copy_dir(dir.path(), copy.path());
+            std::fs::write(copy.path().join(manifest_name), data).unwrap();
+            let res = LevelDB::open(options(paranoid_checks), copy.path(), DefaultEnv::default());
+            res.map(|db| table_entries(&db))
+        };
+
+        // the lost edits only referenced tables, the WAL has everything again
+        for cut in first_end..=manifest.len() {
+            let entries = open_cut(&manifest[..cut], false).unwrap();
+            assert_eq!(entries, expected, "manifest cut at {}", cut);
+        }
+
+        // paranoid checks don't accept a torn record
+        assert!(matches!(
+            open_cut(&manifest[..manifest.len() - 3], true),
+            Err(Error::Corruption(_))
+        ));
+
+        // damage followed by valid records is not a torn write
+        let mut damaged = manifest.clone();
+        damaged[first_end + crate::log::HEADER_SIZE] ^= 0xff;
+        assert!(matches!(
+            open_cut(&damaged, false),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    // writes three flushed rounds of keys and one round left in the WAL
+    fn write_rounds<E: Env>(db: &LevelDB<E>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut expected = Vec::new();
+        for round in 0..4 {
+            for i in 0..50 {
+                let key = format!("key{:02}{:04}", round, i).into_bytes();
+                let value = format!("value{}", i).into_bytes();
+                db.write(&key, &value).unwrap();
+                expected.push((key, value));
+            }
+            if round < 3 {
+                flush_memtable(db);
+            }
+        }
+        expected
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::Builder::new()
+            .prefix("read_only")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let expected = write_rounds(&builder.open(dir.path()).unwrap());
+
+        let db = builder.open_read_only(dir.path()).unwrap();
+        // the WAL went to the memtable rather than a new table
+        assert_eq!(table_entries(&db), expected[..150]);
+        let mem = db.inner.mem.read().unwrap().clone().unwrap();
+        let mut iter = mem.iter();
+        iter.seek_to_first();
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        // only the round written after the last flush is in the log
+        assert_eq!(count, expected.len() - 150);
+        assert_eq!(
+            db.inner.versions.lock().unwrap().last_sequence(),
+            expected.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_repair() {
+        let dir = tempfile::Builder::new().prefix("repair").tempdir().unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let expected = write_rounds(&builder.open(dir.path()).unwrap());
+
+        // lose the manifest, and leave a table nobody can read
+        for f in std::fs::read_dir(dir.path()).unwrap() {
+            let f = f.unwrap();
+            if matches!(
+                parse_file_name(f.file_name()),
+                Ok((_, FileType::Descriptor | FileType::Current))
+            ) {
+                std::fs::remove_file(f.path()).unwrap();
+            }
+        }
+        std::fs::write(dir.path().join("000099.ldb"), b"not a table").unwrap();
+        assert!(DbBuilder::new().open(dir.path()).is_err());
+
+        builder.repair(dir.path()).unwrap();
+        assert!(dir.path().join("lost").join("000099.ldb").exists());
+        assert!(dir.path().join("lost").join("000003.log").exists());
+
+        let db = builder.open(dir.path()).unwrap();
+        // repair replays every log it finds, the first one holds the flushed first
+        // round again
+        let mut entries = table_entries(&db);
+        entries.dedup();
+        assert_eq!(entries, expected);
+        let versions = db.inner.versions.lock().unwrap();
+        assert_eq!(versions.last_sequence(), expected.len() as u64);
+        // new files don't reuse the numbers of the lost ones
+        assert!(versions.manifest_file_number() > 99);
+    }
+
+    fn creation_times<E: Env>(db: &LevelDB<E>) -> Vec<(usize, u64, u64)> {
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let mut files = Vec::new();
+        for (level, level_files) in current.files.iter().enumerate() {
+            for f in level_files.iter() {
+                files.push((level, f.number, f.creation_time));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_rate_limited_flush() {
+        let limiter = Arc::new(RateLimiter::new(1 << 20));
+        let options = Options {
+            create_if_missing: true,
+            write_buffer_size: 64 << 20,
+            rate_limiter: Some(limiter.clone()),
+            ..Default::default()
+        };
+        let env = CountingEnv::<MemEnv>::default();
+        env.advance_clock(Duration::from_secs(1000));
+        env.fake_sleeps();
+        let db = LevelDB::open(options, "/rate_limited", env.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..10 * 1024 {
+            db.write(format!("key{:06}", i).as_bytes(), &value).unwrap();
+        }
+        // 10MB go to a table in the background while the writes go on
+        db.inner.make_room_for_write(true).unwrap();
+        for i in 0..1000 {
+            db.write(format!("new{:06}", i).as_bytes(), &value).unwrap();
+        }
+        while db.inner.imm.read().unwrap().is_some() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // only the table was charged, the writes never waited
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let tables: Vec<_> = current.files.iter().flatten().collect();
+        assert_eq!(tables.len(), 1);
+        let table_size = tables[0].file_size;
+        assert!(table_size > 10 << 20);
+        assert_eq!(limiter.total_bytes_through(), table_size);
+        let slept = env.slept();
+        assert!(
+            slept.iter().all(|(name, _)| name == "compaction"),
+            "{:?}",
+            slept
+        );
+        let total: u64 = slept.iter().map(|(_, micros)| micros).sum();
+        let expected = table_size * 1_000_000 / (1 << 20);
+        assert!(
+            total.abs_diff(expected) < expected / 100,
+            "{} {}",
+            total,
+            expected
+        );
+        assert_eq!(db.get(b"new000999").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_approximate_sizes() {
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("approximate_sizes")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        // a table per prefix, fewer than trigger a compaction
+        for prefix in ["a", "b", "c"] {
+            flush_keys(&db, prefix, 1000, 200);
+        }
+        for i in 0..100 {
+            db.write(format!("m{:04}", i).as_bytes(), &[b'v'; 200])
+                .unwrap();
+        }
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let total: u64 = current.files[0].iter().map(|f| f.file_size).sum();
+        let table = total / 3;
+
+        let limits = [
+            "", "a", "a0250", "a0500", "b", "b0999", "c", "c0500", "d", "z",
+        ];
+        let ranges: Vec<(&[u8], &[u8])> = limits
+            .iter()
+            .map(|l| (b"".as_ref(), l.as_bytes()))
+            .collect();
+        let sizes = db.get_approximate_sizes(&ranges);
+        assert!(sizes.windows(2).all(|w| w[0] <= w[1]), "{:?}", sizes);
+        assert_eq!(sizes[0], 0);
+        assert_eq!(sizes[1], 0);
+
+        // sizes follow the data written, to a block or so
+        let near = |size: u64, expected: u64| size.abs_diff(expected) < 8 * 1024;
+        let size = |start: &str, limit: &str| {
+            db.get_approximate_sizes(&[(start.as_bytes(), limit.as_bytes())])[0]
+        };
+        assert!(
+            near(size("a", "a0500"), table / 2),
+            "{}",
+            size("a", "a0500")
+        );
+        assert!(near(size("a", "b"), table));
+        assert!(near(size("b", "c0500"), table * 3 / 2));
+        assert!(near(size("a", "d"), total));
+        assert!(near(size("", "z"), total));
+        // the memtable counts for nothing, nor does an empty or reversed range
+        assert_eq!(size("m", "n"), 0);
+        assert_eq!(size("b", "b"), 0);
+        assert_eq!(size("c", "a"), 0);
+    }
+
+    #[test]
+    fn test_compact_range() {
+        for background_threads in [1, 0] {
+            let mut options = Options {
+                create_if_missing: true,
+                write_buffer_size: 64 << 10,
+                max_file_size: 64 << 10,
+                ..Default::default()
+            };
+            options.background_threads = background_threads;
+            let db = LevelDB::open(options, "/compact_range", MemEnv::new()).unwrap();
+            let value = [b'v'; 200];
+            for i in 0..3000 {
+                db.write(format!("key{:05}", i).as_bytes(), &value).unwrap();
+            }
+            for i in 0..2500 {
+                db.delete(format!("key{:05}", i).as_bytes()).unwrap();
+            }
+            let all: [(&[u8], &[u8]); 1] = [(b"", b"z")];
+            db.compact_range(None, None).unwrap();
+            let size = db.get_approximate_sizes(&all)[0];
+
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.check_invariants().unwrap();
+            assert!(current.files[0].is_empty());
+            // only the live keys are left, in a few tables at one level
+            let levels: BTreeSet<usize> = (0..NUM_LEVELS)
+                .filter(|&level| !current.files[level].is_empty())
+                .collect();
+            assert_eq!(levels.len(), 1, "{:?}", current);
+            assert!(size < 500 * 250, "{}", size);
+            assert!(size > 500 * 200, "{}", size);
+            assert_eq!(db.get(b"key00000").unwrap(), None);
+            assert_eq!(db.get(b"key02499").unwrap(), None);
+            assert_eq!(db.get(b"key02500").unwrap().unwrap(), value);
+            assert_eq!(db.get(b"key02999").unwrap().unwrap(), value);
+            assert!(db.inner.manual_compaction.lock().unwrap().is_none());
+        }
+    }
+
+    // records the compaction state property as each compaction completes
+    #[derive(Default)]
+    struct CompactionStateProbe {
+        db: Mutex<Weak<DBImplInner<CountingEnv<MemEnv>>>>,
+        states: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for CompactionStateProbe {
+        fn on_compaction_completed(&self, _info: &CompactionInfo) {
+            if let Some(db) = self.db.lock().unwrap().upgrade() {
+                let state = db.get_property("leveldb.compaction-state").unwrap();
+                self.states.lock().unwrap().push(state);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_state_property() {
+        let probe = Arc::new(CompactionStateProbe::default());
+        let options = Options {
+            create_if_missing: true,
+            listener: Some(probe.clone()),
+            ..Default::default()
+        };
+        let env = CountingEnv::<MemEnv>::default();
+        env.advance_clock(Duration::from_secs(10));
+        let db = LevelDB::open(options, "/compaction_state", env).unwrap();
+        *probe.db.lock().unwrap() = Arc::downgrade(&db.inner);
+        let state = || db.get_property("leveldb.compaction-state").unwrap();
+        let lines = |state: &str| state.lines().map(String::from).collect::<Vec<_>>();
+
+        let idle = lines(&state());
+        assert_eq!(idle.len(), 3 + NUM_LEVELS - 1);
+        assert_eq!(
+            idle[..4],
+            [
+                "running: none",
+                "completed: 0",
+                "manual: none",
+                "level 0: score 0.00, pointer none"
+            ]
+        );
+
+        let a = flush_keys(&db, "a", 100, 100);
+        let b = flush_keys(&db, "b", 100, 100);
+        assert_eq!(lines(&state())[3], "level 0: score 0.50, pointer none");
+
+        // a manual compaction merges both into level 1
+        db.compact_range(None, None).unwrap();
+        let states = std::mem::take(&mut *probe.states.lock().unwrap());
+        assert_eq!(states.len(), 1);
+        let during = lines(&states[0]);
+        assert_eq!(
+            during[..3],
+            [
+                format!("running: level 0, inputs {},{}, started at 10000000", a, b),
+                "completed: 0".to_string(),
+                "manual: level 0, first .. last".to_string()
+            ]
+        );
+        let after = lines(&state());
+        assert_eq!(
+            after[..3],
+            ["running: none", "completed: 1", "manual: none"]
+        );
+        assert_eq!(after[3], "level 0: score 0.00, pointer 'b0099'");
+
+        // the pointer of a level follows the files compacted from it
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let output = current.files[1][0].number;
+        drop(current);
+        compact_file(&db, output);
+        let after = lines(&state());
+        assert_eq!(after[1], "completed: 2");
+        assert_eq!(after[4], "level 1: score 0.00, pointer 'b0099'");
+
+        let c = flush_keys(&db, "c", 100, 100);
+        db.compact_range(Some(b"c"), None).unwrap();
+        assert_eq!(file_level(&db, c), Some(1));
+        compact_file(&db, c);
+        let after = lines(&state());
+        assert_eq!(after[..2], ["running: none", "completed: 4"]);
+        assert_eq!(after[3], "level 0: score 0.00, pointer 'c0099'");
+        assert_eq!(after[4], "level 1: score 0.00, pointer 'c0099'");
+        let states = probe.states.lock().unwrap();
+        assert_eq!(states.len(), 3);
+        assert!(states[2].starts_with(&format!("running: level 1, inputs {},", c)));
+    }
+
+    #[test]
+    fn test_escape_key() {
+        assert_eq!(escape_key(b"key\x01\n"), "key\\x01\\n");
+        assert_eq!(escape_key(&[b'k'; 32]), "k".repeat(32));
+        assert_eq!(escape_key(&[b'k'; 40]), format!("{}...", "k".repeat(32)));
+    }
+
+    #[test]
+    fn test_overlapping_edit_is_refused() {
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let env = MemEnv::new();
+        let db = LevelDB::open(options.clone(), "/overlap", env.clone()).unwrap();
+        let a = flush_keys(&db, "a", 100, 100);
+        db.compact_range(None, None).unwrap();
+        assert_eq!(file_level(&db, a), Some(1));
+
+        // a file at level 1 within the range of `a`
+        let mut versions = db.inner.versions.lock().unwrap();
+        let before = versions.current().unwrap();
+        let mut edit = VersionEdit::default();
+        let number = versions.new_file_number();
+        edit.add_new_file(
+            1,
+            number,
+            100,
+            InternalKey::new(b"a0010", 1000, ValueType::Value),
+            InternalKey::new(b"a0020", 1000, ValueType::Value),
+            0,
+        );
+        let err = versions.log_and_apply(&mut edit).unwrap_err();
+        let expected = format!("files #{} and #{} overlap at level 1", a, number);
+        assert!(
+            matches!(&err, Error::Corruption(msg) if *msg == expected),
+            "{:?}",
+            err
+        );
+        // nothing was installed or logged
+        assert!(Arc::ptr_eq(&versions.current().unwrap(), &before));
+        drop(versions);
+        drop(before);
+        db.write(b"b", b"after").unwrap();
+        drop(db);
+
+        let db = LevelDB::open(options, "/overlap", env).unwrap();
+        assert_eq!(file_level(&db, a), Some(1));
+        assert_eq!(db.get(b"b").unwrap().unwrap(), b"after");
+        assert!(db.get(b"a0015").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_compact_range_of_some_files() {
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, "/compact_some", MemEnv::new()).unwrap();
+        let a = flush_keys(&db, "a", 100, 100);
+        let b = flush_keys(&db, "b", 100, 100);
+        let c = flush_keys(&db, "c", 100, 100);
+
+        db.compact_range(Some(b"b"), Some(b"b0050")).unwrap();
+        assert_eq!(file_level(&db, a), Some(0));
+        assert_eq!(file_level(&db, b), Some(1));
+        assert_eq!(file_level(&db, c), Some(0));
+
+        // from the first key, the a file joins the b file
+        db.compact_range(None, Some(b"a0000")).unwrap();
+        assert_eq!(file_level(&db, a), Some(1));
+        assert_eq!(file_level(&db, c), Some(0));
+        // to the last key, the c file goes down to level 1
+        db.compact_range(Some(b"c0099"), None).unwrap();
+        assert_eq!(file_level(&db, c), Some(1));
+        // a range no file holds moves nothing
+        db.compact_range(Some(b"d"), Some(b"e")).unwrap();
+        for number in [a, b, c] {
+            assert_eq!(file_level(&db, number), Some(1));
+        }
+
+        for i in 0..100 {
+            for prefix in ["a", "b", "c"] {
+                let key = format!("{}{:04}", prefix, i);
+                assert!(db.get(key.as_bytes()).unwrap().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_files_older_than() {
+        let listener = Arc::new(CompactionRecorder::default());
+        let options = Options {
+            create_if_missing: true,
+            listener: Some(listener.clone()),
+            ..Default::default()
+        };
+        let env = TestEnv::default();
+        let secs = |s: u64| s * 1_000_000;
+        env.advance_clock(Duration::from_secs(1000));
+        let dir = tempfile::Builder::new()
+            .prefix("older_than")
+            .tempdir()
+            .unwrap();
+        let db = LevelDB::open(options.clone(), dir.path(), env.clone()).unwrap();
+
+        let a = flush_keys(&db, "a", 100, 100);
+        env.advance_clock(Duration::from_secs(100));
+        let b = flush_keys(&db, "b", 100, 100);
+        env.advance_clock(Duration::from_secs(100));
+        let tables: Vec<_> = listener
+            .tables
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|t| (t.number, t.level, t.creation_time))
+            .collect();
+        assert_eq!(tables, vec![(a, 0, secs(1000)), (b, 0, secs(1100))]);
+
+        // only `a` is older than 150s, it's moved down and keeps its age
+        db.compact_files_older_than(Duration::from_secs(150))
+            .unwrap();
+        assert_eq!(
+            creation_times(&db),
+            vec![(0, b, secs(1100)), (1, a, secs(1000))]
+        );
+
+        // overlaps `a` without overwriting any of its keys
+        let c = flush_keys(&db, "a00", 100, 50);
+        env.advance_clock(Duration::from_secs(100));
+        db.compact_files_older_than(Duration::from_secs(50))
+            .unwrap();
+        // `c` and `a` were merged into a new table, `b` was moved
+        let files = creation_times(&db);
+        assert_eq!(files.len(), 2);
+        assert!(files
+            .iter()
+            .all(|&(level, number, _)| level == 1 && number != a && number != c));
+        assert!(files.contains(&(1, b, secs(1100))));
+        let (_, merged, created) = *files.iter().find(|f| f.1 != b).unwrap();
+        assert_eq!(created, secs(1300));
+        assert_eq!(
+            listener
+                .tables
+                .lock()
+                .unwrap()
+                .last()
+                .map(|t| (t.number, t.level)),
+            Some((merged, 1))
+        );
+        let sstables = db.get_property("leveldb.sstables").unwrap();
+        assert!(sstables.starts_with("--- level 0 ---\n--- level 1 ---\n"));
+        assert!(sstables.contains(&format!(
+            " {}:{}['a0000' @ ",
+            merged,
+            db.inner.versions.lock().unwrap().current().unwrap().files[1][0].file_size
+        )));
+        assert!(sstables.contains(&format!("] created {}\n", secs(1300))));
+
+        // the table written just now isn't older than now
+        let events = listener.events.lock().unwrap().len();
+        db.compact_files_older_than(Duration::ZERO).unwrap();
+        assert_eq!(listener.events.lock().unwrap().len(), events + 1);
+        assert_eq!(file_level(&db, b), Some(2));
+        assert_eq!(file_level(&db, merged), Some(1));
+
+        // creation times survive the manifest
+        let before = creation_times(&db);
+        drop(db);
+        let db = LevelDB::open(options, dir.path(), env).unwrap();
+        // next to the table the WAL was replayed into
+        let after = creation_times(&db);
+        assert!(before.iter().all(|f| after.contains(f)));
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let env = TestEnv::default();
+        let dir = tempfile::Builder::new()
+            .prefix("is_empty")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .env(env.clone())
+            .open(dir.path())
+            .unwrap();
+        let read_option = ReadOption::default();
+        assert!(db.is_empty(&read_option).unwrap());
+
+        db.write(b"key1", b"value1").unwrap();
+        assert!(!db.is_empty(&read_option).unwrap());
+
+        db.write(b"key2", b"value2").unwrap();
+        flush_memtable(&db);
+        db.write(b"key3", b"value3").unwrap();
+        flush_memtable(&db);
+        // the tables are only opened once the iterator is positioned, they're
+        // dropped from the cache that has them since they were written
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        for f in current.files[0].iter() {
+            db.inner.table_cache.evict(f.number);
+        }
+        let opened = env.tables_opened.load(Ordering::SeqCst);
+        let (mut iter, _) = db.inner.new_internal_iterator(&read_option);
+        assert_eq!(env.tables_opened.load(Ordering::SeqCst), opened);
+        iter.seek_to_first();
+        assert_eq!(env.tables_opened.load(Ordering::SeqCst), opened + 2);
+        drop(iter);
+
+        let delete = |key: &[u8]| {
+            let mut batch = WriteBatch::new();
+            batch.delete(key);
+            db.inner
+                .write(&WriteOption::default(), Some(batch))
+                .unwrap();
+        };
+        // the first key is gone, the later ones are still there
+        delete(b"key1");
+        assert!(!db.is_empty(&read_option).unwrap());
+        delete(b"key2");
+        delete(b"key3");
+        assert!(db.is_empty(&read_option).unwrap());
+
+        db.write(b"key2", b"again").unwrap();
+        assert!(!db.is_empty(&read_option).unwrap());
+    }
+
+    #[test]
+    fn test_read_at_snapshot() {
+        let dir = tempfile::Builder::new()
+            .prefix("snapshot_read")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let at = |sequence| ReadOption {
+            snapshot: Some(Snapshot::new(sequence)),
+            ..Default::default()
+        };
+
+        db.write(b"key1", b"value1").unwrap();
+        flush_memtable(&db);
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key1");
+        db.inner
+            .write(&WriteOption::default(), Some(batch))
+            .unwrap();
+
+        assert!(db.is_empty(&ReadOption::default()).unwrap());
+        // before the delete, and before the first write
+        assert!(!db.is_empty(&at(1)).unwrap());
+        assert!(db.is_empty(&at(0)).unwrap());
+        // a sequence the db hasn't reached reads the latest state
+        assert!(db.is_empty(&at(1000)).unwrap());
+        db.write(b"key2", b"value2").unwrap();
+        assert!(!db.is_empty(&at(1000)).unwrap());
+        assert_eq!(db.inner.new_internal_iterator(&at(1000)).1, 3);
+    }
+
+    #[test]
+    fn test_wal_write_errors() {
+        let dir = tempfile::Builder::new()
+            .prefix("wal_errors")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .env(env.clone())
+            .open(dir.path())
+            .unwrap();
+        let put = |key: &[u8], sync| {
+            let mut batch = WriteBatch::new();
+            batch.put(key, b"value");
+            db.write_batch(
+                &WriteOption {
+                    sync,
+                    ..Default::default()
+                },
+                batch,
+            )
+        };
+        put(b"key1", true).unwrap();
+
+        // a full disk at sync time fails every later write
+        env.fail_writes(|| std::io::Error::from_raw_os_error(28), 1);
+        let err = put(b"key3", true).unwrap_err();
+        assert!(!err.is_retryable());
+        assert!(err.to_string().contains(".log: "));
+        env.clear_faults();
+        for sync in [false, true] {
+            let again = put(b"key4", sync).unwrap_err();
+            assert_eq!(again.io_kind(), err.io_kind());
+            assert_eq!(again.to_string(), err.to_string());
+        }
+    }
+
+    #[test]
+    fn test_wal_transient_error_is_sticky() {
+        let dir = tempfile::Builder::new()
+            .prefix("wal_transient")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .env(env.clone())
+            .open(dir.path())
+            .unwrap();
+        db.write(b"key1", b"value").unwrap();
+
+        // even an interrupted append may have left part of the record in the log
+        env.fail_writes(|| std::io::ErrorKind::Interrupted.into(), 1);
+        let err = db.write(b"key2", b"value").unwrap_err();
+        assert!(err.is_retryable());
+        env.clear_faults();
+        let again = db.write(b"key3", b"value").unwrap_err();
+        assert_eq!(again.to_string(), err.to_string());
+        drop(db);
+
+        let db = DbBuilder::new().open(dir.path()).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"key3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_full_disk() {
+        let dir = tempfile::Builder::new()
+            .prefix("wal_full_disk")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        let db = builder.open(dir.path()).unwrap();
+        let put = |db: &LevelDB<_>, key: &[u8]| {
+            let mut batch = WriteBatch::new();
+            batch.put(key, &[b'v'; 100]);
+            db.write_batch(
+                &WriteOption {
+                    sync: true,
+                    ..Default::default()
+                },
+                batch,
+            )
+        };
+        // the keys of every entry in the memtable or a table, whatever its sequence
+        let all_keys = |db: &LevelDB<_>| {
+            let (mut iter, _) = db.inner.new_internal_iterator(&ReadOption::default());
+            let mut keys = Vec::new();
+            iter.seek_to_first();
+            while iter.valid() {
+                keys.push(extract_user_key(iter.key()).to_vec());
+                iter.next();
+            }
+            keys
+        };
+        put(&db, b"key1").unwrap();
+        put(&db, b"key2").unwrap();
+        let last_sequence = db.inner.versions.lock().unwrap().last_sequence();
+
+        // the disk fills up in the middle of the next record
+        env.fail_writes_after(|| std::io::Error::from_raw_os_error(28), 50);
+        let err = put(&db, b"key3").unwrap_err();
+        assert!(!err.is_retryable());
+        assert_eq!(
+            db.inner.versions.lock().unwrap().last_sequence(),
+            last_sequence
+        );
+        assert_eq!(read_at(&db, b"key3", &ReadOption::default()), None);
+        assert_eq!(all_keys(&db), [b"key1", b"key2"]);
+        env.clear_faults();
+        assert!(put(&db, b"key4").is_err());
+        drop(db);
+
+        // the torn record at the end of the log is dropped
+        let db = builder.open(dir.path()).unwrap();
+        assert_eq!(all_keys(&db), [b"key1", b"key2"]);
+        assert_eq!(
+            db.inner.versions.lock().unwrap().last_sequence(),
+            last_sequence
+        );
+        put(&db, b"key5").unwrap();
+        assert_eq!(all_keys(&db), [b"key1", b"key2", b"key5"]);
+    }
+
+    #[test]
+    fn test_failed_flush_fails_writes() {
+        let dir = tempfile::Builder::new()
+            .prefix("failed_flush")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .env(env.clone())
+            .write_buffer_size(64 << 10)
+            .open(dir.path())
+            .unwrap();
+        db.write(b"key1", b"value").unwrap();
+
+        // the flush of the immutable memtable fails. The memtable is full, so the
+        // write waits for the flush
+        let inner = &db.inner;
+        let new_mem = Some(inner.new_memtable());
+        let mem = std::mem::replace(&mut *inner.mem.write().unwrap(), new_mem);
+        *inner.imm.write().unwrap() = mem;
+        db.write(b"big", &vec![b'x'; 64 << 10]).unwrap();
+        env.fail_writes(|| std::io::Error::from_raw_os_error(28), usize::MAX);
+        inner.maybe_schedule_compaction();
+
+        let start = Instant::now();
+        let err = db.write(b"key2", b"value").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!err.is_retryable());
+        assert!(err.to_string().contains(".ldb: "));
+        env.clear_faults();
+        let again = db.write(b"key3", b"value").unwrap_err();
+        assert_eq!(again.to_string(), err.to_string());
+        drop(db);
+    }
+
+    // a bug in user code the worker runs
+    struct PanickingFilterPolicy;
+
+    impl FilterPolicy for PanickingFilterPolicy {
+        fn name(&self) -> &'static str {
+            "test.PanickingFilter"
+        }
+
+        fn create_filter(&self, _keys: &[UnsafeSlice], _dst: &mut Vec<u8>) {
+            panic!("filter policy bug");
+        }
+
+        fn key_match(&self, _key: &[u8], _filter: &[u8]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_background_panic_fails_writes() {
+        let dir = tempfile::Builder::new()
+            .prefix("background_panic")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            write_buffer_size: 64 << 10,
+            filter_policy: Some(Arc::new(PanickingFilterPolicy)),
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        // spans several filter blocks, so the flushed table gets a filter
+        db.write(b"key1", &vec![b'v'; 8 << 10]).unwrap();
+
+        // building the filter of the flushed table panics the worker. The memtable
+        // is full, so the write waits for the flush
+        let inner = &db.inner;
+        let new_mem = Some(inner.new_memtable());
+        let mem = std::mem::replace(&mut *inner.mem.write().unwrap(), new_mem);
+        *inner.imm.write().unwrap() = mem;
+        db.write(b"big", &vec![b'x'; 64 << 10]).unwrap();
+        inner.maybe_schedule_compaction();
+
+        let start = Instant::now();
+        let err = db.write(b"key2", b"value").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(&err, Error::CustomError(msg)
+            if msg == "background work panicked: filter policy bug"));
+        assert!(matches!(db.write(b"key3", b"value"),
+            Err(Error::CustomError(msg)) if msg.contains("panicked")));
+        drop(db);
+    }
+
+    #[test]
+    fn test_paranoid_table_key_range() {
+        let dir = tempfile::Builder::new()
+            .prefix("table_key_range")
+            .tempdir()
+            .unwrap();
+        let options = |paranoid_checks| Options {
+            create_if_missing: true,
+            paranoid_checks,
+            ..Default::default()
+        };
+        {
+            let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+            for key in [b"k1", b"k2", b"k3"] {
+                db.write(key, b"value").unwrap();
+            }
+            flush_memtable(&db);
+
+            // the manifest claims a wider range than the table holds
+            let mut versions = db.inner.versions.lock().unwrap();
+            let f = versions.current().unwrap().files[0][0].clone();
+            let mut edit = VersionEdit::default();
+            edit.add_delete_file(0, f.number);
+            edit.add_new_file(
+                0,
+                f.number,
+                f.file_size,
+                InternalKey::new(b"a", 1, ValueType::Value),
+                f.largest.clone(),
+                f.creation_time,
+            );
+            versions.log_and_apply(&mut edit).unwrap();
+        }
+
+        let get = |db: &LevelDB<DefaultEnv>| {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            let target = InternalKey::new(b"k2", MAX_SEQUENCE_NUMBER, ValueType::Value);
+            let mut found = None;
+            db.inner
+                .table_cache
+                .get(
+                    &ReadOption::default(),
+                    &current.files[0][0],
+                    target.encode(),
+                    |k, v| found = Some((extract_user_key(k).to_vec(), v.to_vec())),
+                )
+                .map(|_| found)
+        };
+        let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+        assert_eq!(get(&db).unwrap(), Some((b"k2".to_vec(), b"value".to_vec())));
+        drop(db);
+        let db = LevelDB::open(options(true), dir.path(), DefaultEnv::default()).unwrap();
+        assert!(matches!(get(&db), Err(Error::Corruption(msg)) if msg.contains("key range")));
+    }
+
+    #[test]
+    fn test_memtable_only() {
+        let dir = tempfile::Builder::new()
+            .prefix("memtable_only")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true).memtable_only(true);
+        let only_logs_and_manifests = || {
+            std::fs::read_dir(dir.path()).unwrap().all(|entry| {
+                let (_, file_type) = parse_file_name(entry.unwrap().file_name()).unwrap();
+                matches!(
+                    file_type,
+                    FileType::Log | FileType::Descriptor | FileType::Current | FileType::DBLock
+                )
+            })
+        };
+        let key = |i: usize| format!("key{:03}", i).into_bytes();
+        let value = |round: usize| vec![b'a' + round as u8; 100];
+        let memory_usage = |db: &LevelDB<DefaultEnv>| {
+            let mem = db.inner.mem.read().unwrap();
+            mem.as_ref().unwrap().approximate_memory_usage()
+        };
+
+        for round in 0..3 {
+            let db = builder.open(dir.path()).unwrap();
+            // the live entries of the last round are all that's kept
+            if round > 0 {
+                let read = |i| read_at(&db, &key(i), &ReadOption::default());
+                assert_eq!(read(0), None);
+                for i in 1..100 {
+                    assert_eq!(read(i), Some(value(round - 1)));
+                }
+                let data_size = 99 * (key(0).len() + value(0).len());
+                let usage = memory_usage(&db);
+                assert!(data_size <= usage && usage < 2 * data_size, "{}", usage);
+            }
+            for i in 0..100 {
+                db.write(&key(i), &value(round)).unwrap();
+            }
+            let mut batch = WriteBatch::new();
+            batch.delete(&key(0));
+            db.write_batch(&WriteOption::default(), batch).unwrap();
+            assert!(matches!(
+                db.compact_files_older_than(Duration::ZERO),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(only_logs_and_manifests());
+        }
+
+        let options = Options {
+            create_if_missing: true,
+            memtable_only: true,
+            memtable_only_max_bytes: Some(1000),
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path().join("full"), DefaultEnv::default()).unwrap();
+        let res: Result<()> = (0..20).try_for_each(|i| db.write(&key(i), &value(0)));
+        assert!(matches!(res, Err(Error::CustomError(msg)) if msg.starts_with("memtable is full")));
+        assert!(memory_usage(&db) < 1000 + key(0).len() + value(0).len() + 10);
+    }
+
+    // the value of `key` a read with `options` sees
+    fn read_at<E: Env>(db: &LevelDB<E>, key: &[u8], options: &ReadOption) -> Option<Vec<u8>> {
+        let (mut iter, sequence) = db.inner.new_internal_iterator(options);
+        iter.seek(InternalKey::new(key, sequence, ValueType::Value).encode());
+        if !iter.valid() {
+            return None;
+        }
+        let ikey = ParsedInternalKey::parse(iter.key());
+        (ikey.user_key == key && ikey.val_type == ValueType::Value).then(|| iter.value().to_vec())
+    }
+
+    #[test]
+    fn test_snapshot_survives_compaction() {
+        let dir = tempfile::Builder::new()
+            .prefix("snapshot_compaction")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let versions_of = |key: &[u8]| -> Vec<Vec<u8>> {
+            table_entries(&db)
+                .into_iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .collect()
+        };
+        let compact_level0 = || {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            compact_file(&db, current.files[0][0].number);
+        };
+
+        db.write(b"key", b"v0").unwrap();
+        db.write(b"gone", b"x").unwrap();
+        db.write(b"key", b"v1").unwrap();
+        let snapshot = db.inner.get_snapshot();
+        let at_snapshot = ReadOption {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        for i in 2..=20 {
+            db.write(b"key", format!("v{}", i).as_bytes()).unwrap();
+            if i % 5 == 0 {
+                flush_memtable(&db);
+            }
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(b"gone");
+        db.write_batch(&WriteOption::default(), batch).unwrap();
+        flush_memtable(&db);
+
+        compact_level0();
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert!(current.files[0].is_empty());
+        drop(current);
+        assert_eq!(read_at(&db, b"key", &at_snapshot), Some(b"v1".to_vec()));
+        assert_eq!(read_at(&db, b"gone", &at_snapshot), Some(b"x".to_vec()));
+        assert_eq!(
+            read_at(&db, b"key", &ReadOption::default()),
+            Some(b"v20".to_vec())
+        );
+        assert_eq!(read_at(&db, b"gone", &ReadOption::default()), None);
+        // v0 was already overwritten when the snapshot was taken
+        assert_eq!(versions_of(b"key").len(), 20);
+        assert!(!versions_of(b"key").contains(&b"v0".to_vec()));
+        assert_eq!(versions_of(b"gone").len(), 2);
+
+        // the read options hold a clone of the snapshot
+        drop(at_snapshot);
+        db.inner.release_snapshot(snapshot);
+        db.write(b"key", b"v21").unwrap();
+        flush_memtable(&db);
+        compact_level0();
+        assert_eq!(versions_of(b"key"), vec![b"v21".to_vec()]);
+        assert!(versions_of(b"gone").is_empty());
+    }
+
+    #[test]
+    fn test_recover_mixed_width_logs() {
+        let dir = tempfile::Builder::new()
+            .prefix("mixed_width_logs")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let db = builder.open(dir.path()).unwrap();
+        db.write(b"key", b"v0").unwrap();
+        drop(db);
+
+        // 1000000.log sorts before 999999.log by name
+        let write_log = |number: u64, sequence: u64, value: &[u8]| {
+            let file = DefaultEnv::default()
+                .new_writable_file(&log_file_name(dir.path(), number))
+                .unwrap();
+            let mut log = LogWriter::new(file);
+            let mut batch = WriteBatch::new();
+            batch.put(b"key", value);
+            batch.put(value, b"");
+            batch.set_sequence(sequence);
+            log.add_record(batch.content().clone()).unwrap();
+            log.sync().unwrap();
+        };
+        write_log(999_999, 100, b"v1");
+        write_log(1_000_000, 200, b"v2");
+
+        let db = builder.open(dir.path()).unwrap();
+        assert_eq!(
+            read_at(&db, b"key", &ReadOption::default()),
+            Some(b"v2".to_vec())
+        );
+        assert!(db.inner.wal.lock().unwrap().log_file_number > 1_000_000);
+        assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), 201);
+        db.write(b"key", b"v3").unwrap();
+        drop(db);
+
+        let db = builder.open(dir.path()).unwrap();
+        for key in [b"v1", b"v2"] {
+            assert_eq!(read_at(&db, key, &ReadOption::default()), Some(vec![]));
+        }
+        assert_eq!(
+            read_at(&db, b"key", &ReadOption::default()),
+            Some(b"v3".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_recover_sequence_regression() {
+        let env = MemEnv::new();
+        let db_name = Path::new("/regression");
+        let logger = Arc::new(CapturingLogger::default());
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        drop(builder.open(db_name).unwrap());
+
+        // the second record of the first log goes back in time, and so does the
+        // first record of the second log
+        let write_log = |number: u64, records: &[(u64, &[u8])]| {
+            let file = env
+                .new_writable_file(&log_file_name(db_name, number))
+                .unwrap();
+            let mut log = LogWriter::new(file);
+            for &(sequence, value) in records {
+                let mut batch = WriteBatch::new();
+                batch.put(b"key", value);
+                batch.set_sequence(sequence);
+                log.add_record(batch.content().clone()).unwrap();
+            }
+            log.sync().unwrap();
+        };
+        write_log(100, &[(10, b"first"), (5, b"second")]);
+        write_log(101, &[(8, b"third")]);
+
+        let open = |paranoid_checks| {
+            let options = Options {
+                create_if_missing: true,
+                paranoid_checks,
+                info_log: Some(logger.clone()),
+                ..Default::default()
+            };
+            LevelDB::open(options, db_name, env.clone())
+        };
+        let err = open(true).err().unwrap();
+        assert!(
+            matches!(&err, Error::Corruption(msg)
+                if msg == "log #100: record at sequence 5 follows sequence 10"),
+            "{:?}",
+            err
+        );
+        assert!(logger.lines.lock().unwrap().is_empty());
+
+        // without paranoid checks the records are replayed, with a warning for each
+        let db = open(false).unwrap();
+        let lines = logger.lines.lock().unwrap().clone();
+        let warnings: Vec<_> = lines.iter().filter(|l| l.starts_with("log #")).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "log #100: record at sequence 5 follows sequence 10",
+                "log #101: record at sequence 8 follows sequence 10",
+            ]
+        );
+        // each log became a level-0 table, the newer one is searched first
+        // whatever its sequences
+        assert_eq!(db.get(b"key").unwrap(), Some(b"third".to_vec()));
+        assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), 10);
+    }
+
+    #[test]
+    fn test_missing_table_not_covered_by_log() {
+        let dir = tempfile::Builder::new()
+            .prefix("missing_table")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let db = builder.open(dir.path()).unwrap();
+        db.write(b"key", b"value").unwrap();
+        flush_memtable(&db);
+        let number = {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.files[0][0].number
+        };
+        drop(db);
+
+        // a log with the number of the table doesn't make up for it
+        let table = table_file_name(dir.path(), number);
+        std::fs::remove_file(&table).unwrap();
+        std::fs::write(log_file_name(dir.path(), number), b"").unwrap();
+        let err = builder.open(dir.path()).err().unwrap();
+        let expected = format!("missing files: {}", table.display());
+        assert!(
+            matches!(&err, Error::Corruption(msg) if *msg == expected),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_power_cut_during_compaction_install() {
+        let dir = tempfile::Builder::new()
+            .prefix("compaction_power_cut")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+
+        for ops in 0.. {
+            let path = dir.path().join(ops.to_string());
+            let db = builder.open(&path).unwrap();
+            // overlapping level 0 tables hold all of the data, the WAL isn't synced
+            // and is lost in the cut
+            let mut expected = BTreeMap::new();
+            for round in 0..L0_COMPACTION_TRIGGER {
+                for i in round * 100..round * 100 + 200 {
+                    let value = format!("value{}-{}", round, i).into_bytes();
+                    db.write(&key(i), &value).unwrap();
+                    expected.insert(key(i), value);
+                }
+                flush_memtable(&db);
+            }
+
+            // the manifest open replaced is gone before the cut
+            db.inner.delete_obsoleted_files();
+            env.cut_power_after(ops);
+            let res = db.inner.do_compaction();
+            let power_cut = env.power_cut();
+            // a cut while deleting the inputs fails nothing, the outputs are
+            // installed by then
+            assert!(res.is_ok() || power_cut);
+            drop(db);
+            env.drop_unsynced_data().unwrap();
+            env.clear_faults();
+
+            // served by the inputs or by the outputs, but never by neither
+            let db = builder.open(&path).unwrap();
+            for (key, value) in expected.iter() {
+                assert_eq!(
+                    read_at(&db, key, &ReadOption::default()).as_ref(),
+                    Some(value)
+                );
+            }
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.check_invariants().unwrap();
+            let compacted = current.files[0].is_empty();
+            assert!(compacted || current.files[1].is_empty());
+            if !power_cut {
+                assert!(compacted);
+                assert!(ops > 0);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_power_cut_during_first_open() {
+        let dir = tempfile::Builder::new()
+            .prefix("first_open_power_cut")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new()
+            .create_if_missing(true)
+            .background_threads(0)
+            .env(env.clone());
+        let check_empty = |path: &Path| {
+            let db = builder.open(path).unwrap();
+            let mut iter = db.iter(&ReadOption::default());
+            iter.seek_to_first();
+            assert!(!iter.valid());
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.check_invariants().unwrap();
+            assert_eq!(current.files.iter().flatten().count(), 0);
+            assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), 0);
+            db.write(b"k", b"v").unwrap();
+            drop(db);
+            let db = builder.open(path).unwrap();
+            assert_eq!(db.get(b"k").unwrap(), Some(b"v".to_vec()));
+        };
+
+        // a manifest longer than the one the open writes, left without a CURRENT
+        let path = dir.path().join("stale");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(descriptor_file_name(&path, 1), vec![b'x'; 1 << 16]).unwrap();
+        check_empty(&path);
+
+        for ops in 0.. {
+            let path = dir.path().join(ops.to_string());
+            env.cut_power_after(ops);
+            let res = builder.open(&path);
+            let power_cut = env.power_cut();
+            assert!(res.is_ok() || power_cut);
+            drop(res);
+            env.drop_unsynced_data().unwrap();
+            env.clear_faults();
+
+            check_empty(&path);
+            if !power_cut {
+                assert!(ops > 0);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_large_directory() {
+        let mem_env = MemEnv::new();
+        let db_name = Path::new("/large");
+        let builder = DbBuilder::new().create_if_missing(true);
+        let num_tables = 5000;
+        {
+            let db = builder.clone().env(mem_env.clone()).open(db_name).unwrap();
+            // tables of the last level that are never read, and never compacted
+            let mut edit = VersionEdit::default();
+            let mut versions = db.inner.versions.lock().unwrap();
+            for _ in 0..num_tables {
+                let number = versions.new_file_number();
+                let key = |c| {
+                    InternalKey::new(
+                        format!("{:06}{}", number, c).as_bytes(),
+                        1,
+                        ValueType::Value,
+                    )
+                };
+                edit.add_new_file(NUM_LEVELS as u32 - 1, number, 100, key('a'), key('b'), 0);
+                mem_env
+                    .new_writable_file(&table_file_name(db_name, number))
+                    .unwrap();
+            }
+            versions.log_and_apply(&mut edit).unwrap();
+        }
+
+        let env = CountingEnv {
+            env: mem_env,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let db = builder.env(env.clone()).open(db_name).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(env.listings.load(Ordering::SeqCst), 1);
+        assert_eq!(env.tables_opened.load(Ordering::SeqCst), 0);
+        let listed: usize = db
+            .get_property("leveldb.open-files-listed")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(listed > num_tables);
+        assert!(db.get_property("leveldb.open-micros").is_some());
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        assert_eq!(current.files[NUM_LEVELS - 1].len(), num_tables);
+    }
+
+    #[test]
+    fn test_reuse_log() {
+        let dir = tempfile::Builder::new()
+            .prefix("reuse_log")
+            .tempdir()
+            .unwrap();
+        let builder = DbBuilder::new().create_if_missing(true).reuse_log(true);
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let db = builder.open(dir.path()).unwrap();
+        let log_number = db.inner.wal.lock().unwrap().log_file_number;
+        for i in 0..100 {
+            db.write(&key(i), &key(i)).unwrap();
+        }
+        drop(db);
+
+        for round in 1..3 {
+            let db = builder.open(dir.path()).unwrap();
+            assert_eq!(db.inner.wal.lock().unwrap().log_file_number, log_number);
+            assert_eq!(file_numbers(dir.path(), FileType::Log), vec![log_number]);
+            assert!(file_numbers(dir.path(), FileType::Table).is_empty());
+            for i in 0..100 * round {
+                assert_eq!(db.get(&key(i)).unwrap(), Some(key(i)));
+            }
+            // the new records go after the old ones, in the block they ended in
+            for i in 100 * round..100 * (round + 1) {
+                db.write(&key(i), &key(i)).unwrap();
+            }
+        }
+
+        // without the option the entries go to a table and a new log
+        let db = DbBuilder::new().open(dir.path()).unwrap();
+        assert!(db.inner.wal.lock().unwrap().log_file_number > log_number);
+        assert_eq!(file_numbers(dir.path(), FileType::Table).len(), 1);
+        for i in 0..300 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(key(i)));
+        }
+    }
+
+    #[test]
+    fn test_reuse_manifest() {
+        let dir = tempfile::Builder::new()
+            .prefix("reuse_manifest")
+            .tempdir()
+            .unwrap();
+        let mut options = Options {
+            create_if_missing: true,
+            reuse_log: true,
+            ..Default::default()
+        };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let open = |options: &Options| {
+            LevelDB::open(options.clone(), dir.path(), DefaultEnv::default()).unwrap()
+        };
+        let db = open(&options);
+        db.write(&key(0), &key(0)).unwrap();
+        flush_memtable(&db);
+        let manifest = db.inner.versions.lock().unwrap().current_manifest_number();
+        drop(db);
+
+        // the edits of the flushes are appended to the manifest of the first open
+        for round in 1..4 {
+            let db = open(&options);
+            db.write(&key(round), &key(round)).unwrap();
+            flush_memtable(&db);
+            let versions = db.inner.versions.lock().unwrap();
+            assert_eq!(versions.current_manifest_number(), manifest);
+            assert_eq!(versions.recovered_edits(), round + 1);
+            drop(versions);
+            assert_eq!(file_numbers(dir.path(), FileType::Descriptor), [manifest]);
+        }
+
+        // one larger than `max_file_size` is replaced on the first edit
+        options.max_file_size = 64;
+        let db = open(&options);
+        assert_eq!(
+            db.inner.versions.lock().unwrap().current_manifest_number(),
+            manifest
+        );
+        db.write(&key(4), &key(4)).unwrap();
+        flush_memtable(&db);
+        db.inner.delete_obsoleted_files();
+        let replaced = db.inner.versions.lock().unwrap().current_manifest_number();
+        assert!(replaced > manifest);
+        assert_eq!(file_numbers(dir.path(), FileType::Descriptor), [replaced]);
+        drop(db);
+
+        options.max_file_size = Options::default().max_file_size;
+        let db = open(&options);
+        for i in 0..5 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(key(i)));
+        }
+        // the snapshot and the edit after it
+        assert_eq!(db.inner.versions.lock().unwrap().recovered_edits(), 2);
+        db.write(&key(5), &key(5)).unwrap();
+        flush_memtable(&db);
+        assert_eq!(file_numbers(dir.path(), FileType::Descriptor), [replaced]);
+    }
+
+    #[test]
+    fn test_sync_wal() {
+        let dir = tempfile::Builder::new()
+            .prefix("sync_wal")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        let db = builder.open(dir.path()).unwrap();
+        for i in 0..1000 {
+            db.write(&key(i), &key(i)).unwrap();
+        }
+        assert_eq!(db.last_durable_sequence(), 0);
+        db.sync_wal().unwrap();
+        let durable = db.last_durable_sequence();
+        assert_eq!(durable, db.inner.versions.lock().unwrap().last_sequence());
+        for i in 1000..1500 {
+            db.write(&key(i), &key(i)).unwrap();
+        }
+        assert_eq!(db.last_durable_sequence(), durable);
+        // a crash, the drop can't sync the log
+        env.cut_power_after(0);
+        drop(db);
+        env.clear_faults();
+        env.drop_unsynced_data().unwrap();
+
+        let db = builder.open(dir.path()).unwrap();
+        for i in 0..1000 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(key(i)));
+        }
+        // the crash took the writes after the sync
+        assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), durable);
+        assert_eq!(db.get(&key(1000)).unwrap(), None);
+
+        // a synced write moves the durable sequence along
+        db.write(&key(2000), &key(2000)).unwrap();
+        let options = WriteOption {
+            sync: true,
+            ..Default::default()
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(&key(2001), &key(2001));
+        db.write_batch(&options, batch).unwrap();
+        assert_eq!(db.last_durable_sequence(), durable + 2);
+    }
+
+    #[test]
+    fn test_drop_closes_db() {
+        let dir = tempfile::Builder::new()
+            .prefix("drop_closes")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new()
+            .create_if_missing(true)
+            .write_buffer_size(64 << 10)
+            .env(env.clone());
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        let value = |round: usize| format!("value{}", round).repeat(20).into_bytes();
+
+        for round in 0..5usize {
+            let db = builder.open(dir.path()).unwrap();
+            assert!(db.inner.background_error.read().unwrap().is_none());
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.check_invariants().unwrap();
+            for i in 0..2000 {
+                let expected = round.checked_sub(1).map(value);
+                assert_eq!(db.get(&key(i)).unwrap(), expected);
+            }
+            // the flushes and compactions these start are still running at the drop
+            for i in 0..2000 {
+                db.write(&key(i), &value(round)).unwrap();
+            }
+            let inner = Arc::downgrade(&db.inner);
+            drop(db);
+            // the worker is joined, nothing else holds the db
+            assert!(inner.upgrade().is_none());
+            // and the unsynced writes were synced by the drop
+            env.drop_unsynced_data().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backup_live_files() {
+        let dir = tempfile::Builder::new()
+            .prefix("live_files")
+            .tempdir()
+            .unwrap();
+        let env = DefaultEnv::default();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let db = builder.open(dir.path().join("db")).unwrap();
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        for i in 0..500 {
+            db.write(&key(i), &key(i + 1)).unwrap();
+        }
+        flush_memtable(&db);
+        for i in 250..1000 {
+            db.write(&key(i), &key(i + 2)).unwrap();
+        }
+        let last_sequence = db.inner.versions.lock().unwrap().last_sequence();
+
+        db.disable_file_deletions();
+        let info = db.live_files(true).unwrap();
+        assert_eq!(info.wal_synced_sequence, last_sequence);
+        assert_eq!(info.tables.len(), 2);
+        let mut ranges: Vec<_> = info
+            .tables
+            .iter()
+            .map(|t| (t.smallest_user_key.clone(), t.largest_user_key.clone()))
+            .collect();
+        ranges.sort();
+        assert_eq!(ranges, [(key(0), key(499)), (key(250), key(999))]);
+
+        // copy the files through the env, the manifest only up to its listed size
+        let backup = dir.path().join("backup");
+        env.create_dir(&backup).unwrap();
+        let copy = |from: &Path, len: u64| {
+            let mut data = vec![0; len as usize];
+            let mut src = env.new_sequential_file(from).unwrap();
+            src.read_exact(&mut data).unwrap();
+            let to = backup.join(from.file_name().unwrap());
+            let mut dst = env.new_writable_file(&to).unwrap();
+            dst.append(&data).unwrap();
+            dst.sync().unwrap();
+        };
+        copy(&info.manifest, info.manifest_size);
+        for table in info.tables.iter() {
+            copy(&table.name, table.size);
+        }
+        let wal = info.wal.unwrap();
+        copy(&wal, env.file_size(&wal).unwrap() as u64);
+        let manifest = info.manifest.file_name().unwrap().to_str().unwrap();
+        std::fs::write(backup.join("CURRENT"), format!("{}\n", manifest)).unwrap();
+        db.enable_file_deletions();
+
+        // writes after the backup aren't in it
+        db.write(b"later", b"value").unwrap();
+        flush_memtable(&db);
+        drop(db);
+        let copy = builder.open(&backup).unwrap();
+        for i in 0..1000 {
+            let expected = if i < 250 { key(i + 1) } else { key(i + 2) };
+            assert_eq!(
+                read_at(&copy, &key(i), &ReadOption::default()),
+                Some(expected)
+            );
+        }
+        assert_eq!(read_at(&copy, b"later", &ReadOption::default()), None);
+    }
+
+    #[test]
+    fn test_crash_before_immutable_flush_installed() {
+        let dir = tempfile::Builder::new()
+            .prefix("prev_log")
+            .tempdir()
+            .unwrap();
+        let env = FaultyEnv::new();
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        let sync = WriteOption {
+            sync: true,
+            ..Default::default()
+        };
+        let write = |db: &LevelDB<FaultyEnv>, key: &str, value: &str| {
+            let mut batch = WriteBatch::new();
+            batch.put(key.as_bytes(), value.as_bytes());
+            db.write_batch(&sync, batch).unwrap();
+        };
+
+        for ops in 0.. {
+            let path = dir.path().join(ops.to_string());
+            let db = builder.open(&path).unwrap();
+            for i in 0..100 {
+                write(&db, &format!("key{:03}", i), "old");
+            }
+            db.inner.switch_memtable().unwrap();
+            let (old_log, new_log) = {
+                let versions = db.inner.versions.lock().unwrap();
+                (versions.prev_log_number(), versions.log_number())
+            };
+            assert!(old_log != 0 && old_log < new_log);
+            // the later writes to the new log overwrite half of the old ones. Writes
+            // wait for the immutable memtable's flush, it's set aside meanwhile
+            let imm = db.inner.imm.write().unwrap().take();
+            for i in 50..150 {
+                write(&db, &format!("key{:03}", i), "new");
+            }
+            *db.inner.imm.write().unwrap() = imm;
+
+            db.inner.delete_obsoleted_files();
+            env.cut_power_after(ops);
+            let res = db.inner.do_compaction_memtable();
+            let power_cut = env.power_cut();
+            // a cut while deleting the obsolete log fails nothing, the flush is
+            // installed by then
+            assert!(res.is_ok() || power_cut);
+            drop(db);
+            env.drop_unsynced_data().unwrap();
+            env.clear_faults();
+
+            let db = builder.open(&path).unwrap();
+            let versions = db.inner.versions.lock().unwrap();
+            assert_eq!(versions.prev_log_number(), 0);
+            versions.current().unwrap().check_invariants().unwrap();
+            drop(versions);
+            // every write is there once, the newest one visible
+            let (mut iter, _) = db.inner.new_internal_iterator(&ReadOption::default());
+            let mut entries = Vec::new();
+            iter.seek_to_first();
+            while iter.valid() {
+                let ikey = ParsedInternalKey::parse(iter.key());
+                entries.push((ikey.user_key.to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+            assert_eq!(entries.len(), 200);
+            for i in 0..150 {
+                let key = format!("key{:03}", i).into_bytes();
+                let expected = if i < 50 { "old" } else { "new" };
+                assert_eq!(
+                    read_at(&db, &key, &ReadOption::default()),
+                    Some(expected.as_bytes().to_vec())
+                );
+            }
+            if !power_cut {
+                assert!(ops > 0);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_memtable_only_tier() {
+        let dir = tempfile::Builder::new()
+            .prefix("read_tier")
+            .tempdir()
+            .unwrap();
+        let env = TestEnv::default();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .env(env.clone())
+            .open(dir.path())
+            .unwrap();
+        db.write(b"on_disk", b"value").unwrap();
+        flush_memtable(&db);
+        db.write(b"in_memory", b"value").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete(b"deleted");
+        db.write_batch(&WriteOption::default(), batch).unwrap();
+
+        let opened = env.tables_opened.load(Ordering::SeqCst);
+        let memtable_only = ReadOption {
+            read_tier: ReadTier::MemtableOnly,
+            ..Default::default()
+        };
+        assert_eq!(
+            db.inner.get(&memtable_only, b"in_memory").unwrap(),
+            b"value"
+        );
+        // a deletion in the memtable is an answer too
+        assert!(matches!(
+            db.inner.get(&memtable_only, b"deleted"),
+            Err(Error::NotFoundError(_))
+        ));
+        for key in [&b"on_disk"[..], b"missing"] {
+            assert!(matches!(
+                db.inner.get(&memtable_only, key),
+                Err(Error::Incomplete(_))
+            ));
+        }
+        assert_eq!(env.tables_opened.load(Ordering::SeqCst), opened);
+
+        // an older snapshot doesn't see the memtable's value
+        let before = ReadOption {
+            snapshot: Some(Snapshot::new(1)),
+            ..memtable_only
+        };
+        assert!(matches!(
+            db.inner.get(&before, b"in_memory"),
+            Err(Error::Incomplete(_))
+        ));
+        assert_eq!(format!("{:?}", ReadOption::VERIFY.read_tier), "All");
+        assert_eq!(ReadOption::internal(true), ReadOption::VERIFY);
+    }
+
+    #[test]
+    fn test_manifest_rotation() {
+        let dir = tempfile::Builder::new()
+            .prefix("manifest_rotation")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            max_manifest_file_size: 4096,
+            ..Default::default()
+        };
+        let manifests = || {
+            let mut numbers: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .filter_map(|entry| match parse_file_name(entry.unwrap().file_name()) {
+                    Ok((number, FileType::Descriptor)) => Some(number),
+                    _ => None,
+                })
+                .collect();
+            numbers.sort_unstable();
+            numbers
+        };
+
+        let db = LevelDB::open(options.clone(), dir.path(), DefaultEnv::default()).unwrap();
+        let mut seen = vec![];
+        for i in 0..500 {
+            db.write(format!("key{}", i % 10).as_bytes(), b"value")
+                .unwrap();
+            db.inner.flush().unwrap();
+            db.inner.do_compaction().unwrap();
+            let number = db.inner.versions.lock().unwrap().current_manifest_number();
+            if seen.last() != Some(&number) {
+                assert!(seen.last() < Some(&number));
+                seen.push(number);
+            }
+            // the replaced manifests are deleted, the current one stays small
+            assert_eq!(manifests(), [number]);
+            let size = std::fs::metadata(descriptor_file_name(dir.path(), number))
+                .unwrap()
+                .len();
+            assert!(size < 2 * 4096, "{}", size);
+        }
+        assert!(seen.len() > 10, "{:?}", seen);
+        drop(db);
+
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        // far fewer than the thousand and more edits written
+        let replayed = db.inner.versions.lock().unwrap().recovered_edits();
+        assert!(replayed < 100, "{}", replayed);
+        assert_eq!(
+            read_at(&db, b"key9", &ReadOption::default()),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_compaction_debt_under_load() {
+        let dir = tempfile::Builder::new()
+            .prefix("compaction_debt")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(db.compaction_debt_bytes(), 0);
+        assert_eq!(db.pending_flush().immutable_memtable_bytes, 0);
+        let empty = db.pending_flush_bytes();
+
+        // level-0 tables pile up while nothing compacts them
+        let mut debts = vec![];
+        for round in 0..L0_COMPACTION_TRIGGER + 4 {
+            flush_keys(&db, &format!("round{}", round), 1000, 100);
+            assert_eq!(db.pending_flush_bytes(), empty);
+            debts.push(db.compaction_debt_bytes());
+        }
+        assert!(debts[..L0_COMPACTION_TRIGGER].iter().all(|&d| d == 0));
+        assert!(debts[L0_COMPACTION_TRIGGER..]
+            .windows(2)
+            .all(|w| w[0] < w[1]));
+        let debt = db.compaction_debt();
+        assert_eq!(debt.total_bytes, debt.levels[0]);
+        assert_eq!(
+            db.get_property("leveldb.compaction-debt-bytes"),
+            Some(debt.total_bytes.to_string())
+        );
+
+        db.write(b"key", b"value").unwrap();
+        assert!(db.pending_flush_bytes() > empty);
+        assert_eq!(
+            db.get_property("leveldb.pending-flush-bytes"),
+            Some(db.pending_flush_bytes().to_string())
+        );
+
+        // compacting until no level needs it pays the debt off
+        loop {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            if current.compaction_score < 1.0 {
+                break;
+            }
+            db.inner.do_compaction().unwrap();
+        }
+        assert_eq!(db.compaction_debt_bytes(), 0);
+    }
+
+    // Databases written by earlier versions, each must keep opening with the same
+    // contents. A change to the on-disk format adds a new directory, see
+    // `regenerate_golden_db`, and leaves the old ones alone. v0 records the
+    // misspelled comparator name of the first versions, v1 leveldb's.
+    const GOLDEN_DBS: [&str; 2] = ["v0", "v1"];
+
+    fn golden_db_dir(version: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/dbs")
+            .join(version)
+    }
+
+    // the visible contents of a golden db, `None` for a deleted key
+    fn golden_db_entries() -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        let mut entries = BTreeMap::new();
+        for (round, keys) in [0..20, 10..30, 20..40].into_iter().enumerate() {
+            for i in keys {
+                let key = format!("key{:03}", i).into_bytes();
+                entries.insert(key, Some(format!("value{}-{}", round, i).into_bytes()));
+            }
+        }
+        entries.insert(b"key000".to_vec(), None);
+        entries
+    }
+
+    // Two tables, the later one overwriting part of the first, and a WAL with more
+    // overwrites and a deletion. Returns the files of the db.
+    fn write_golden_db(path: &Path) -> Vec<PathBuf> {
+        let db = DbBuilder::new().create_if_missing(true).open(path).unwrap();
+        for (round, keys) in [0..20, 10..30, 20..40].into_iter().enumerate() {
+            let mut batch = WriteBatch::new();
+            for i in keys {
+                let key = format!("key{:03}", i);
+                batch.put(key.as_bytes(), format!("value{}-{}", round, i).as_bytes());
+            }
+            if round == 2 {
+                batch.delete(b"key000");
+            }
+            db.write_batch(&WriteOption::default(), batch).unwrap();
+            if round < 2 {
+                db.inner.flush().unwrap();
+            }
+        }
+        let live = db.live_files(false).unwrap();
+        assert_eq!(live.tables.len(), 2);
+        drop(db);
+
+        let mut files = vec![path.join("CURRENT"), live.manifest, live.wal.unwrap()];
+        files.extend(live.tables.into_iter().map(|t| t.name));
+        files
+    }
+
+    // REWRITE=1 cargo test regenerate_golden_db -- --ignored
+    #[test]
+    #[ignore]
+    fn regenerate_golden_db() {
+        let dir = tempfile::Builder::new()
+            .prefix("golden_db")
+            .tempdir()
+            .unwrap();
+        let files = write_golden_db(dir.path());
+        if std::env::var("REWRITE").is_err() {
+            return;
+        }
+        let golden = golden_db_dir(GOLDEN_DBS[GOLDEN_DBS.len() - 1]);
+        let _ = std::fs::remove_dir_all(&golden);
+        std::fs::create_dir_all(&golden).unwrap();
+        for file in files {
+            std::fs::copy(&file, golden.join(file.file_name().unwrap())).unwrap();
+        }
+    }
+
+    fn dir_contents(path: &Path) -> BTreeMap<OsString, Vec<u8>> {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), std::fs::read(entry.path()).unwrap())
+            })
+            .collect()
+    }
+
+    fn check_golden_db_entries<E: Env>(db: &LevelDB<E>) {
+        for (key, value) in golden_db_entries() {
+            assert_eq!(
+                read_at(db, &key, &ReadOption::default()),
+                value,
+                "{:?}",
+                String::from_utf8_lossy(&key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_golden_dbs() {
+        for version in GOLDEN_DBS {
+            let dir = tempfile::Builder::new()
+                .prefix("golden_dbs")
+                .tempdir()
+                .unwrap();
+            copy_dir(&golden_db_dir(version), dir.path());
+            let contents = dir_contents(dir.path());
+            let names: Vec<_> = contents.keys().map(|name| name.to_str().unwrap()).collect();
+            assert!(
+                names.iter().filter(|name| name.ends_with(".ldb")).count() == 2
+                    && names.iter().filter(|name| name.ends_with(".log")).count() == 1
+                    && names.iter().any(|name| name.starts_with("MANIFEST-"))
+                    && names.contains(&"CURRENT"),
+                "{}: {:?}",
+                version,
+                names
+            );
+
+            let db = DbBuilder::new().open_read_only(dir.path()).unwrap();
+            check_golden_db_entries(&db);
+            drop(db);
+            assert!(
+                dir_contents(dir.path()) == contents,
+                "{} was modified",
+                version
+            );
+
+            let db = DbBuilder::new().open(dir.path()).unwrap();
+            check_golden_db_entries(&db);
+            db.write(b"key000", b"again").unwrap();
+            assert_eq!(
+                read_at(&db, b"key000", &ReadOption::default()),
+                Some(b"again".to_vec())
+            );
+        }
+    }
+
+    // a manifest with a field this version doesn't know is refused, without
+    // touching the db
+    #[test]
+    fn test_unknown_manifest_field_refused() {
+        let dir = tempfile::Builder::new()
+            .prefix("unknown_field")
+            .tempdir()
+            .unwrap();
+        copy_dir(&golden_db_dir(GOLDEN_DBS[0]), dir.path());
+
+        let mut record = Vec::new();
+        let mut edit = VersionEdit::default();
+        edit.set_comparator(crate::cmp::BitWiseComparator {}.name());
+        edit.set_log_number(0);
+        edit.set_next_file_number(1000);
+        edit.set_last_sequence(0);
+        edit.encode(&mut record);
+        // an unknown tag and its value, as a future version might write
+        record.extend_from_slice(&[100, 1]);
+        let manifest = descriptor_file_name(dir.path(), 999);
+        let mut log = LogWriter::new(DefaultEnv::default().new_writable_file(&manifest).unwrap());
+        log.add_record(record).unwrap();
+        log.sync().unwrap();
+        drop(log);
+        crate::filenames::set_current_file(DefaultEnv::default(), dir.path(), 999).unwrap();
+        let contents = dir_contents(dir.path());
+
+        let builder = DbBuilder::new();
+        for res in [builder.open_read_only(dir.path()), builder.open(dir.path())] {
+            match res {
+                Err(Error::Corruption(msg)) => assert!(msg.contains("unknown tag"), "{}", msg),
+                Err(err) => panic!("unexpected error {:?}", err),
+                Ok(_) => panic!("opened a manifest with an unknown field"),
+            }
+            // only the LOCK file is left behind, as by any open
+            let mut after = dir_contents(dir.path());
+            after.remove(std::ffi::OsStr::new("LOCK"));
+            assert!(after == contents);
+        }
+    }
+
+    // the visible entries, the way a db iterator would walk them
+    fn visible_entries<E: Env>(db: &LevelDB<E>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let (mut iter, sequence) = db.inner.new_internal_iterator(&ReadOption::default());
+        let mut entries = Vec::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        iter.seek_to_first();
+        while iter.valid() {
+            let ikey = ParsedInternalKey::parse(iter.key());
+            if ikey.sequence <= sequence && last_key.as_deref() != Some(ikey.user_key) {
+                if ikey.val_type == ValueType::Value {
+                    entries.push((ikey.user_key.to_vec(), iter.value().to_vec()));
+                }
+                last_key = Some(ikey.user_key.to_vec());
+            }
+            iter.next();
+        }
+        iter.status().unwrap();
+        entries
+    }
+
+    // A batch writing and deleting the same key ends as its last operation says,
+    // the later operation gets the higher sequence. Checked in the memtable, in the
+    // table both entries are flushed to, after compaction and after recovery.
+    #[test]
+    fn test_put_and_delete_in_one_batch() {
+        for put_last in [false, true] {
+            for flush in [false, true] {
+                let dir = tempfile::Builder::new()
+                    .prefix("batch_put_delete")
+                    .tempdir()
+                    .unwrap();
+                let builder = DbBuilder::new().create_if_missing(true);
+                let db = builder.open(dir.path()).unwrap();
+                db.write(b"key", b"old").unwrap();
+                db.write(b"other", b"value").unwrap();
+                if flush {
+                    db.inner.flush().unwrap();
+                }
+                let before = ReadOption {
+                    snapshot: Some(db.inner.get_snapshot()),
+                    ..Default::default()
+                };
+                let mut batch = WriteBatch::new();
+                if put_last {
+                    batch.delete(b"key");
+                    batch.put(b"key", b"new");
+                } else {
+                    batch.put(b"key", b"new");
+                    batch.delete(b"key");
+                }
+                db.write_batch(&WriteOption::default(), batch).unwrap();
+
+                let expected = put_last.then(|| b"new".to_vec());
+                let mut visible = vec![(b"other".to_vec(), b"value".to_vec())];
+                if put_last {
+                    visible.insert(0, (b"key".to_vec(), b"new".to_vec()));
+                }
+                let check = |db: &LevelDB<DefaultEnv>, flushed: bool| {
+                    let case = format!("put_last {} flushed {}", put_last, flushed);
+                    assert_eq!(db.get(b"key").unwrap(), expected, "{}", case);
+                    assert_eq!(
+                        read_at(db, b"key", &ReadOption::default()),
+                        expected,
+                        "{}",
+                        case
+                    );
+                    assert_eq!(visible_entries(db), visible, "{}", case);
+                    let memtable_only = ReadOption {
+                        read_tier: ReadTier::MemtableOnly,
+                        ..Default::default()
+                    };
+                    match db.get_with_options(&memtable_only, b"key") {
+                        Ok(value) => assert!(!flushed && value == expected, "{}", case),
+                        Err(Error::Incomplete(_)) => assert!(flushed, "{}", case),
+                        Err(err) => panic!("{}: {:?}", case, err),
+                    }
+                };
+                check(&db, false);
+                // the batch is newer than the snapshot, whichever entry comes first
+                assert_eq!(read_at(&db, b"key", &before), Some(b"old".to_vec()));
+                assert_eq!(
+                    db.get_with_options(&before, b"key").unwrap(),
+                    Some(b"old".to_vec())
+                );
+                db.inner.release_snapshot(before.snapshot.unwrap());
+
+                if flush {
+                    db.inner.flush().unwrap();
+                    // both entries of the batch are in the new table
+                    let current = db.inner.versions.lock().unwrap().current().unwrap();
+                    let f = current.files.iter().flatten().max_by_key(|f| f.number);
+                    let f = f.unwrap().clone();
+                    drop(current);
+                    let table = db.inner.table_cache.find_table(f.number, f.file_size);
+                    let mut iter = Table::iter(table.unwrap(), &ReadOption::default());
+                    let mut types = Vec::new();
+                    iter.seek_to_first();
+                    while iter.valid() {
+                        types.push(ParsedInternalKey::parse(iter.key()).val_type);
+                        iter.next();
+                    }
+                    if put_last {
+                        assert_eq!(types, [ValueType::Value, ValueType::Deletetion]);
+                    } else {
+                        assert_eq!(types, [ValueType::Deletetion, ValueType::Value]);
+                    }
+                    check(&db, true);
+
+                    // merged with the older table, and with nothing below, only
+                    // what the batch left stays
+                    compact_file(&db, f.number);
+                    let keys: Vec<_> = table_entries(&db).into_iter().map(|(k, _)| k).collect();
+                    if put_last {
+                        assert_eq!(keys, vec![b"key".to_vec(), b"other".to_vec()]);
+                    } else {
+                        assert_eq!(keys, vec![b"other".to_vec()]);
+                    }
+                    check(&db, true);
+                }
+
+                // recovery applies the batch as a whole, and flushes the log
+                drop(db);
+                let db = builder.open(dir.path()).unwrap();
+                check(&db, true);
+            }
+        }
+    }
+
+    // a damaged record costs the rest of its log block, unless paranoid checks
+    // make it fail the open
+    #[test]
+    fn test_recover_damaged_log() {
+        let dir = tempfile::Builder::new()
+            .prefix("damaged_log")
+            .tempdir()
+            .unwrap();
+        let logger = Arc::new(CapturingLogger::default());
+        let options = |paranoid_checks| Options {
+            create_if_missing: true,
+            paranoid_checks,
+            info_log: Some(logger.clone()),
+            ..Default::default()
+        };
+        let key = |i: usize| format!("key{:03}", i).into_bytes();
+        let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+        for i in 0..100 {
+            db.write(&key(i), &[b'v'; 1000]).unwrap();
+        }
+        let log = db.live_files(false).unwrap().wal.unwrap();
+        drop(db);
+        let mut data = std::fs::read(&log).unwrap();
+        data[5000] ^= 0x01;
+        std::fs::write(&log, &data).unwrap();
+
+        match LevelDB::open(options(true), dir.path(), DefaultEnv::default()) {
+            Err(Error::Corruption(msg)) => assert!(msg.ends_with("digest check failed"), "{}", msg),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+        let found: Vec<bool> = (0..100)
+            .map(|i| db.get(&key(i)).unwrap().is_some())
+            .collect();
+        // the records before the damaged one and those of the next blocks
+        assert!(found[..4].iter().all(|&f| f));
+        assert!(found[4..31].iter().all(|&f| !f));
+        assert!(found[33..].iter().all(|&f| f));
+        let lines = logger.lines.lock().unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("dropping") && line.ends_with("digest check failed")));
+    }
+
+    // a batch cut short in the log is dropped whole at recovery, never half applied
+    #[test]
+    fn test_torn_batch_dropped_whole() {
+        for put_last in [false, true] {
+            let dir = tempfile::Builder::new()
+                .prefix("torn_batch")
+                .tempdir()
+                .unwrap();
+            let builder = DbBuilder::new().create_if_missing(true);
+            let db = builder.open(dir.path()).unwrap();
+            db.write(b"key", b"old").unwrap();
+            let mut batch = WriteBatch::new();
+            if put_last {
+                batch.delete(b"key");
+                batch.put(b"key", b"new");
+            } else {
+                batch.put(b"key", b"new");
+                batch.delete(b"key");
+            }
+            db.write_batch(&WriteOption::default(), batch).unwrap();
+            let log = db.live_files(false).unwrap().wal.unwrap();
+            drop(db);
+
+            let data = std::fs::read(&log).unwrap();
+            std::fs::write(&log, &data[..data.len() - 3]).unwrap();
+            let db = builder.open(dir.path()).unwrap();
+            assert_eq!(
+                read_at(&db, b"key", &ReadOption::default()),
+                Some(b"old".to_vec())
+            );
+        }
+    }
+
+    // a table the manifest lists but the directory lacks is reported as corruption
+    // naming the file, by lookups and scans alike
+    #[test]
+    fn test_missing_live_table() {
+        let dir = tempfile::Builder::new()
+            .prefix("missing_table")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let number = flush_keys(&db, "key", 10, 10);
+        std::fs::remove_file(table_file_name(dir.path(), number)).unwrap();
+        // the table was opened when it was written, the cache would still serve it
+        db.inner.table_cache.evict(number);
+        let expected = format!(
+            "missing table file {:06} (looked for {:06}.ldb and {:06}.sst)",
+            number, number, number
+        );
+        let check = |res: Result<()>| match res {
+            Err(Error::Corruption(msg)) => assert_eq!(msg, expected),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let f = current.files[0][0].clone();
+        let key = InternalKey::new(b"key0001", MAX_SEQUENCE_NUMBER, ValueType::Value);
+        check(
+            db.inner
+                .table_cache
+                .get(&ReadOption::default(), &f, key.encode(), |_, _| {}),
+        );
+        let (mut iter, _) = db.inner.new_internal_iterator(&ReadOption::default());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        check(iter.status());
+        check(db.is_empty(&ReadOption::default()).map(|_| ()));
+    }
+
+    #[test]
+    fn test_get_across_levels() {
+        let dir = tempfile::Builder::new()
+            .prefix("get_levels")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        for key in [b"a", b"b", b"c"] {
+            db.write(key, b"1").unwrap();
+        }
+        db.inner.flush().unwrap();
+        let first = ReadOption {
+            snapshot: Some(db.inner.get_snapshot()),
+            ..Default::default()
+        };
+        let mut batch = WriteBatch::new();
+        batch.delete(b"b");
+        batch.put(b"c", b"2");
+        db.write_batch(&WriteOption::default(), batch).unwrap();
+        db.inner.flush().unwrap();
+        // the two tables are on different levels
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let levels: Vec<_> = (0..NUM_LEVELS)
+            .filter(|&level| !current.files[level].is_empty())
+            .collect();
+        assert_eq!(levels.len(), 2, "{:?}", current);
+        drop(current);
+        db.write(b"a", b"3").unwrap();
+
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+        // the newer deletion hides the older value
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"d").unwrap(), None);
+        assert!(matches!(
+            db.inner.get(&ReadOption::default(), b"d"),
+            Err(Error::NotFoundError(_))
+        ));
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(
+                db.get_with_options(&first, key).unwrap(),
+                Some(b"1".to_vec())
+            );
+        }
+
+        // level-0 files overlap, the newest holding the key decides
+        for value in [b"4", b"5"] {
+            db.write(b"c", value).unwrap();
+            flush_memtable(&db);
+        }
+        db.write(b"x", b"unrelated").unwrap();
+        flush_memtable(&db);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(
+            db.get_with_options(&first, b"c").unwrap(),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_max_key_and_value_size() {
+        let dir = tempfile::Builder::new()
+            .prefix("max_sizes")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let (max_key, max_value) = (options.max_key_size, options.max_value_size);
+        let db = LevelDB::open(options, dir.path().join("limits"), DefaultEnv::default()).unwrap();
+        let invalid = |res: Result<()>, limit: &str| {
+            assert!(
+                matches!(&res, Err(Error::InvalidArgument(msg)) if msg.contains(limit)),
+                "{:?}",
+                res
+            );
+        };
+
+        let key = vec![b'k'; max_key];
+        db.write(&key, b"v").unwrap();
+        let long_key = vec![b'k'; max_key + 1];
+        invalid(db.write(&long_key, b"v"), "max_key_size");
+        invalid(db.delete(&long_key), "max_key_size");
+        db.delete(&key).unwrap();
+        assert_eq!(db.get(&key).unwrap(), None);
+
+        // a rejected batch writes none of its entries
+        let mut batch = WriteBatch::new();
+        batch.put(b"fine", b"v");
+        batch.put(b"large", &vec![0; max_value + 1]);
+        invalid(
+            db.write_batch(&WriteOption::default(), batch),
+            "max_value_size",
+        );
+        assert_eq!(db.get(b"fine").unwrap(), None);
+
+        let value: Vec<u8> = (0..32 << 20).map(|i: u32| (i % 251) as u8).collect();
+        db.write(b"large", &value).unwrap();
+        db.inner.flush().unwrap();
+        assert!(table_entries(&db).iter().any(|(k, _)| k == b"large"));
+        assert_eq!(db.get(b"large").unwrap(), Some(value));
+        drop(db);
+
+        // 0 turns the limits off
+        let options = Options {
+            create_if_missing: true,
+            max_key_size: 0,
+            max_value_size: 0,
+            ..Default::default()
+        };
+        let db =
+            LevelDB::open(options, dir.path().join("no_limits"), DefaultEnv::default()).unwrap();
+        db.write(&long_key, b"v").unwrap();
+        assert_eq!(db.get(&long_key).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_values_larger_than_block() {
+        let dir = tempfile::Builder::new()
+            .prefix("large_values")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            block_size: 1024,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        let key = |i: usize| format!("key{:03}", i).into_bytes();
+        let value = |i: usize| vec![i as u8; 1024 + i * 100];
+        for i in 0..20 {
+            db.write(&key(i), &value(i)).unwrap();
+        }
+        db.write(b"small", b"v").unwrap();
+        flush_memtable(&db);
+
+        // every large value fills a block of its own
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let f = current.files[0][0].clone();
+        drop(current);
+        let table = db
+            .inner
+            .table_cache
+            .find_table(f.number, f.file_size)
+            .unwrap();
+        let ranges = table.block_ranges().unwrap();
+        assert_eq!(ranges.len(), 21);
+        for (i, (_, handle)) in ranges.iter().take(20).enumerate() {
+            assert!(handle.size() as usize > value(i).len());
+        }
+
+        for i in 0..20 {
+            assert_eq!(db.get(&key(i)).unwrap(), Some(value(i)));
+        }
+        assert_eq!(db.get(b"key0205").unwrap(), None);
+        let mut expected: Vec<_> = (0..20).map(|i| (key(i), value(i))).collect();
+        expected.push((b"small".to_vec(), b"v".to_vec()));
+        assert_eq!(visible_entries(&db), expected);
+        assert_eq!(read_at(&db, &key(7), &ReadOption::VERIFY), Some(value(7)));
+    }
+
+    #[test]
+    fn test_seeks_trigger_compaction() {
+        let dir = tempfile::Builder::new()
+            .prefix("seek_compaction")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        for key in [b"a", b"b", b"c"] {
+            db.write(key, b"old").unwrap();
+        }
+        flush_memtable(&db);
+        // the newer file spans "b" without holding it
+        db.write(b"a", b"new").unwrap();
+        db.write(b"c", b"new").unwrap();
+        flush_memtable(&db);
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let newer = current.files[0]
+            .iter()
+            .max_by_key(|f| f.number)
+            .unwrap()
+            .clone();
+        drop(current);
+        let allowed_seeks = newer.allowed_seeks.load(Ordering::Relaxed);
+        assert_eq!(allowed_seeks, 100);
+
+        // a get answered by the first file it reads costs no seek
+        assert_eq!(db.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(newer.allowed_seeks.load(Ordering::Relaxed), 100);
+        for _ in 0..allowed_seeks - 1 {
+            assert_eq!(db.get(b"b").unwrap(), Some(b"old".to_vec()));
+        }
+        assert_eq!(newer.allowed_seeks.load(Ordering::Relaxed), 1);
+        assert_eq!(file_level(&db, newer.number), Some(0));
+
+        // the last seek makes it the file to compact
+        assert_eq!(db.get(b"b").unwrap(), Some(b"old".to_vec()));
+        for _ in 0..100 {
+            if file_level(&db, newer.number).is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(file_level(&db, newer.number), None);
+        for (key, value) in [(b"a", b"new"), (b"b", b"old"), (b"c", b"new")] {
+            assert_eq!(db.get(key).unwrap(), Some(value.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_background_level_compaction() {
+        let dir = tempfile::Builder::new()
+            .prefix("background_compaction")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let level_files = |db: &LevelDB<DefaultEnv>| {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current
+                .files
+                .iter()
+                .map(|files| files.len())
+                .collect::<Vec<_>>()
+        };
+
+        // every flush overlaps the ones before, so they all stay at level 0 until
+        // the worker compacts them
+        let mut expected = BTreeMap::new();
+        for round in 0..L0_COMPACTION_TRIGGER * 2 {
+            for i in 0..100 {
+                let key = format!("key{:04}", i * 3 + round);
+                let value = format!("value{}", round);
+                db.write(key.as_bytes(), value.as_bytes()).unwrap();
+                expected.insert(key.into_bytes(), value.into_bytes());
+            }
+            db.live_files(true).unwrap();
+        }
+        for _ in 0..500 {
+            if level_files(&db)[0] < L0_COMPACTION_TRIGGER {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let files = level_files(&db);
+        assert!(files[0] < L0_COMPACTION_TRIGGER, "{:?}", files);
+        assert!(files[1] > 0, "{:?}", files);
+        assert!(db.inner.background_error.read().unwrap().is_none());
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        current.check_invariants().unwrap();
+        drop(current);
+
+        assert_eq!(
+            visible_entries(&db),
+            expected.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    // the comparator names recorded by the edits of the current manifest
+    fn manifest_comparators(dir: &Path) -> Vec<String> {
+        let current = std::fs::read_to_string(current_file_name(dir)).unwrap();
+        let file = DefaultEnv::default()
+            .new_sequential_file(&dir.join(current.trim()))
+            .unwrap();
+        let mut reader = LogReader::new(file, true);
+        let mut names = Vec::new();
+        let mut record = Vec::new();
+        while reader.read_record(&mut record).unwrap().is_some() {
+            let mut edit = VersionEdit::default();
+            edit.decode(&record).unwrap();
+            names.extend(edit.comparator);
+            record.clear();
+        }
+        names
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, left: &[u8], right: &[u8]) -> std::cmp::Ordering {
+            right.cmp(left)
+        }
+
+        fn name(&self) -> &'static str {
+            "rleveldb.test.ReverseComparator"
+        }
+
+        fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &[u8]) {}
+
+        fn find_shortest_successor(&self, _key: &mut Vec<u8>) {}
+    }
+
+    // the manifest records the user comparator, never the internal key wrapper
+    #[test]
+    fn test_manifest_comparator_name() {
+        let dir = tempfile::Builder::new()
+            .prefix("comparator_name")
+            .tempdir()
+            .unwrap();
+        let comparators: [Arc<dyn Comparator>; 2] =
+            [Arc::new(BitWiseComparator {}), Arc::new(ReverseComparator)];
+        for (i, comparator) in comparators.into_iter().enumerate() {
+            let name = comparator.name().to_string();
+            let path = dir.path().join(i.to_string());
+            let options = || Options {
+                create_if_missing: true,
+                comparator: comparator.clone(),
+                ..Default::default()
+            };
+            let db = LevelDB::open(options(), &path, DefaultEnv::default()).unwrap();
+            db.write(b"key", b"value").unwrap();
+            db.live_files(true).unwrap();
+            drop(db);
+            let names = manifest_comparators(&path);
+            assert!(!names.is_empty());
+            assert!(names.iter().all(|n| *n == name), "{:?}", names);
+
+            // and a reopen, which writes a new manifest, keeps it
+            let db = LevelDB::open(options(), &path, DefaultEnv::default()).unwrap();
+            assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+            drop(db);
+            assert_eq!(manifest_comparators(&path), vec![name]);
+        }
+    }
+
+    // A manifest with the name leveldb gives the bytewise comparator opens, and so
+    // does one with the misspelled name this crate used to write, which the next
+    // manifest replaces. Another comparator is refused.
+    #[test]
+    fn test_recover_comparator_names() {
+        let dir = tempfile::Builder::new()
+            .prefix("recover_comparator")
+            .tempdir()
+            .unwrap();
+        let write_manifest = |path: &Path, comparator: &str| {
+            std::fs::create_dir(path).unwrap();
+            let mut edit = VersionEdit::default();
+            edit.set_comparator(comparator);
+            edit.set_log_number(0);
+            edit.set_next_file_number(2);
+            edit.set_last_sequence(0);
+            let mut record = Vec::new();
+            edit.encode(&mut record);
+            let manifest = descriptor_file_name(path, 1);
+            let mut log =
+                LogWriter::new(DefaultEnv::default().new_writable_file(&manifest).unwrap());
+            log.add_record(record).unwrap();
+            log.sync().unwrap();
+            crate::filenames::set_current_file(DefaultEnv::default(), path, 1).unwrap();
+        };
+
+        for stored in ["leveldb.BytewiseComparator", "Leveldb.BitwiseComaparator"] {
+            let path = dir.path().join(stored);
+            write_manifest(&path, stored);
+            let db = DbBuilder::new().open(&path).unwrap();
+            db.write(b"key", b"value").unwrap();
+            drop(db);
+            assert_eq!(manifest_comparators(&path), vec![BYTEWISE_COMPARATOR_NAME]);
+            let db = DbBuilder::new().open(&path).unwrap();
+            assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+        }
+
+        let path = dir.path().join("reverse");
+        write_manifest(&path, ReverseComparator.name());
+        assert!(matches!(
+            DbBuilder::new().open(&path),
+            Err(Error::InvalidArgument(msg)) if msg.contains("comparator")
+        ));
+    }
+
+    // the numbers of the files of `file_type` in the db directory
+    fn file_numbers(dir: &Path, file_type: FileType) -> Vec<u64> {
+        let mut numbers: Vec<u64> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| match parse_file_name(entry.unwrap().file_name()) {
+                Ok((number, t)) if t == file_type => Some(number),
+                _ => None,
+            })
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    #[test]
+    fn test_delete_obsolete_files() {
+        let dir = tempfile::Builder::new()
+            .prefix("obsolete_files")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let live_tables = |db: &LevelDB<DefaultEnv>| {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            let mut numbers: Vec<u64> = current.files.iter().flatten().map(|f| f.number).collect();
+            numbers.sort_unstable();
+            numbers
+        };
+
+        // the flush of the immutable memtable makes its log obsolete
+        for i in 0..100 {
+            db.write(format!("key{:03}", i).as_bytes(), b"old").unwrap();
+        }
+        let old_log = db.inner.wal.lock().unwrap().log_file_number;
+        db.inner.switch_memtable().unwrap();
+        let new_log = db.inner.wal.lock().unwrap().log_file_number;
+        assert_eq!(
+            file_numbers(dir.path(), FileType::Log),
+            vec![old_log, new_log]
+        );
+        db.inner.do_compaction_memtable().unwrap();
+        assert_eq!(file_numbers(dir.path(), FileType::Log), vec![new_log]);
+        assert_eq!(file_numbers(dir.path(), FileType::Descriptor).len(), 1);
+
+        // the inputs of a compaction are deleted, unless an iterator still reads
+        // the version holding them
+        for round in 0..L0_COMPACTION_TRIGGER {
+            for i in 0..100 {
+                let value = format!("new{}", round);
+                db.write(format!("key{:03}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+            flush_memtable(&db);
+        }
+        let before = live_tables(&db);
+        let (mut iter, _) = db.inner.new_internal_iterator(&ReadOption::default());
+        db.inner.do_compaction().unwrap();
+        let outputs = live_tables(&db);
+        assert!(before.iter().any(|n| !outputs.contains(n)));
+        let mut expected: Vec<u64> = before.iter().chain(outputs.iter()).copied().collect();
+        expected.sort_unstable();
+        expected.dedup();
+        assert_eq!(file_numbers(dir.path(), FileType::Table), expected);
+
+        iter.seek_to_first();
+        let mut entries = 0;
+        while iter.valid() {
+            entries += 1;
+            iter.next();
+        }
+        iter.status().unwrap();
+        assert_eq!(entries, 100 * (L0_COMPACTION_TRIGGER + 1));
+        drop(iter);
+        db.inner.delete_obsoleted_files();
+        assert_eq!(file_numbers(dir.path(), FileType::Table), outputs);
+
+        // without a reader the compaction deletes its inputs itself, the compaction
+        // doesn't keep their version alive
+        for round in 0..L0_COMPACTION_TRIGGER {
+            for i in 0..100 {
+                let value = format!("new{}", round);
+                db.write(format!("key{:03}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+            flush_memtable(&db);
+        }
+        db.inner.do_compaction().unwrap();
+        let outputs = live_tables(&db);
+        assert_eq!(file_numbers(dir.path(), FileType::Table), outputs);
+
+        // and a reopen finds no file to delete once the log holding the writes
+        // above is flushed too
+        db.live_files(true).unwrap();
+        assert_eq!(live_tables(&db), outputs);
+        drop(db);
+        let files = dir_contents(dir.path()).into_keys().collect::<Vec<_>>();
+        let db = DbBuilder::new().open(dir.path()).unwrap();
+        assert_eq!(live_tables(&db), outputs);
+        let newest = format!("new{}", L0_COMPACTION_TRIGGER - 1);
+        assert_eq!(db.get(b"key000").unwrap(), Some(newest.into_bytes()));
+        // only the manifest the reopen replaced is gone
+        let reopened = dir_contents(dir.path()).into_keys().collect::<Vec<_>>();
+        for f in files.iter().filter(|f| !reopened.contains(f)) {
+            assert!(
+                matches!(parse_file_name(f), Ok((_, FileType::Descriptor))),
+                "{:?}",
+                f
+            );
+        }
+    }
+
+    #[test]
+    fn test_paranoid_memory_checks() {
+        let dir = tempfile::Builder::new()
+            .prefix("memory_checks")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            paranoid_memory_checks: true,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            db.write(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        assert_eq!(db.get(b"key3").unwrap(), Some(b"key3".to_vec()));
+
+        let mem = db.inner.mem.read().unwrap().clone().unwrap();
+        mem.corrupt_value(b"key3");
+        let res = db.get(b"key3");
+        assert!(matches!(res, Err(Error::Corruption(_))), "{:?}", res);
+        assert_eq!(db.get(b"key4").unwrap(), Some(b"key4".to_vec()));
+
+        // the flush fails rather than writing the flipped value to a table
+        let res = db.inner.flush();
+        assert!(matches!(res, Err(Error::Corruption(_))), "{:?}", res);
+        assert!(file_numbers(dir.path(), FileType::Table).is_empty());
+    }
+
+    #[test]
+    fn test_disable_data_checksums() {
+        let dir = tempfile::Builder::new()
+            .prefix("no_checksums")
+            .tempdir()
+            .unwrap();
+        let options = |disable_data_checksums| Options {
+            create_if_missing: true,
+            paranoid_checks: true,
+            disable_data_checksums,
+            ..Default::default()
+        };
+        let key = |i: usize| format!("key{:04}", i).into_bytes();
+        {
+            let db = LevelDB::open(options(true), dir.path(), DefaultEnv::default()).unwrap();
+            for i in 0..1000 {
+                db.write(&key(i), &key(i)).unwrap();
+            }
+            db.live_files(true).unwrap();
+            for i in 1000..1100 {
+                db.write(&key(i), &key(i)).unwrap();
+            }
+        }
+        let log = file_numbers(dir.path(), FileType::Log).pop().unwrap();
+        let log = std::fs::read(log_file_name(dir.path(), log)).unwrap();
+        assert_eq!(&log[..4], &[0; 4]);
+
+        // read back by a db writing checksums, whose own files are verified
+        let db = LevelDB::open(options(false), dir.path(), DefaultEnv::default()).unwrap();
+        let verify = ReadOption {
+            verify_checksum: true,
+            ..Default::default()
+        };
+        for i in 0..1100 {
+            assert_eq!(db.get_with_options(&verify, &key(i)).unwrap(), Some(key(i)));
+        }
+        db.live_files(true).unwrap();
+        let mut iter = db.iter(&verify);
+        iter.seek_to_first();
+        let mut n = 0;
+        while iter.valid() {
+            n += 1;
+            iter.next();
+        }
+        iter.status().unwrap();
+        assert_eq!(n, 1100);
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = tempfile::Builder::new().prefix("delete").tempdir().unwrap();
+        let options = || Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let open = || LevelDB::open(options(), dir.path(), DefaultEnv::default()).unwrap();
+        {
+            let db = open();
+            db.write(b"flushed", b"v1").unwrap();
+            db.write(b"logged", b"v1").unwrap();
+            db.live_files(true).unwrap();
+            // the tombstones shadow the values in the table
+            db.delete(b"flushed").unwrap();
+            db.delete(b"logged").unwrap();
+            db.delete(b"never").unwrap();
+            assert_eq!(db.get(b"flushed").unwrap(), None);
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"va");
+            batch.delete(b"a");
+            batch.put(b"b", b"vb");
+            db.write_batch(&WriteOption::default(), batch).unwrap();
+            db.write(b"flushed", b"v2").unwrap();
+            db.delete(b"flushed").unwrap();
+        }
+
+        // the deletes are replayed from the WAL
+        let db = open();
+        let check = |db: &LevelDB<DefaultEnv>| {
+            assert_eq!(db.get(b"flushed").unwrap(), None);
+            assert_eq!(db.get(b"logged").unwrap(), None);
+            assert_eq!(db.get(b"never").unwrap(), None);
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"b").unwrap(), Some(b"vb".to_vec()));
+        };
+        check(&db);
+        db.live_files(true).unwrap();
+        check(&db);
+        let tables = file_numbers(dir.path(), FileType::Table);
+        for number in tables {
+            if file_level(&db, number).is_some() {
+                compact_file(&db, number);
+            }
+        }
+        check(&db);
+        drop(db);
+        check(&open());
+    }
+
+    #[test]
+    fn test_get_snapshot() {
+        let dir = tempfile::Builder::new()
+            .prefix("snapshots")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        let oldest = || db.inner.snapshots.lock().unwrap().oldest();
+        db.write(b"k", b"v1").unwrap();
+        let snapshot = db.get_snapshot();
+        assert_eq!(oldest(), Some(1));
+        db.write(b"k", b"v2").unwrap();
+        db.delete(b"k1").unwrap();
+
+        let at_snapshot = ReadOption {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(
+            db.get_with_options(&at_snapshot, b"k").unwrap(),
+            Some(b"v1".to_vec())
+        );
+        db.live_files(true).unwrap();
+        assert_eq!(
+            db.get_with_options(&at_snapshot, b"k").unwrap(),
+            Some(b"v1".to_vec())
+        );
+
+        // the read options hold a clone, the snapshot lives until both are gone
+        db.release_snapshot(snapshot);
+        assert_eq!(oldest(), Some(1));
+        drop(at_snapshot);
+        assert_eq!(oldest(), None);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        use crate::trace::testing::SpanCollector;
+
+        let dir = tempfile::Builder::new()
+            .prefix("tracing")
+            .tempdir()
+            .unwrap();
+        let collector = SpanCollector::default();
+        // only the spans of this thread are collected, the background worker's
+        // compactions are not
+        tracing::subscriber::with_default(collector.clone(), || {
+            let options = Options {
+                create_if_missing: true,
+                ..Default::default()
+            };
+            let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"va");
+            batch.put(b"b", b"vb");
+            let sync = WriteOption {
+                sync: true,
+                ..Default::default()
+            };
+            db.write_batch(&sync, batch).unwrap();
+            assert!(db.get(b"a").unwrap().is_some());
+            flush_memtable(&db);
+            db.write(b"a", b"new").unwrap();
+            flush_memtable(&db);
+            assert!(db.get(b"b").unwrap().is_some());
+            assert!(db.get(b"missing").unwrap().is_none());
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            compact_file(&db, current.files[0][0].number);
+        });
+
+        let field = |span: &crate::trace::testing::SpanRecord, name: &str| {
+            span.fields.get(name).cloned().unwrap_or_default()
+        };
+        let writes = collector.spans("db.write");
+        assert_eq!(field(&writes[0], "batch_count"), "2");
+        assert_eq!(field(&writes[0], "sync"), "true");
+        assert!(field(&writes[0], "batch_bytes").parse::<usize>().unwrap() > 0);
+        assert_eq!(field(&writes[1], "batch_count"), "1");
+        assert_eq!(field(&writes[1], "sync"), "false");
+
+        let gets = collector.spans("db.get");
+        assert_eq!(gets.len(), 3);
+        assert_eq!(field(&gets[0], "key_len"), "1");
+        assert_eq!(field(&gets[0], "found"), "true");
+        assert_eq!(field(&gets[0], "level_served"), "memtable");
+        assert_eq!(field(&gets[1], "found"), "true");
+        assert_eq!(field(&gets[1], "level_served"), "0");
+        assert_eq!(field(&gets[2], "found"), "false");
+        assert_eq!(field(&gets[2], "level_served"), "");
+
+        let flushes = collector.spans("db.flush");
+        assert_eq!(flushes.len(), 2);
+        for flush in flushes.iter() {
+            assert!(field(flush, "file_number").parse::<u64>().unwrap() > 0);
+            assert!(field(flush, "bytes").parse::<u64>().unwrap() > 0);
+            assert!(field(flush, "duration").parse::<u64>().is_ok());
+        }
+
+        let compactions = collector.spans("db.compaction");
+        assert_eq!(compactions.len(), 1);
+        let compaction = &compactions[0];
+        assert_eq!(field(compaction, "level"), "0");
+        assert_eq!(field(compaction, "input_files"), "2");
+        assert_eq!(field(compaction, "output_files"), "1");
+        assert!(field(compaction, "bytes_read").parse::<u64>().unwrap() > 0);
+        assert!(field(compaction, "bytes_written").parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_apply_replicated_batches() {
+        let dir = tempfile::Builder::new()
+            .prefix("replicated")
+            .tempdir()
+            .unwrap();
+        let (leader_dir, follower_dir) = (dir.path().join("leader"), dir.path().join("follower"));
+        let options = || Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let leader = LevelDB::open(options(), &leader_dir, DefaultEnv::default()).unwrap();
+        for i in 0..20 {
+            let mut batch = WriteBatch::new();
+            for j in 0..i % 4 {
+                batch.put(
+                    format!("key{}", i + j).as_bytes(),
+                    format!("v{}", i).as_bytes(),
+                );
+            }
+            if i % 3 == 0 {
+                batch.delete(format!("key{}", i / 2).as_bytes());
+            }
+            leader.write_batch(&WriteOption::default(), batch).unwrap();
+        }
+        // the log of a db, synced so it can be read while the db is open
+        let synced_log = |db: &LevelDB<DefaultEnv>, dir: &Path| {
+            let mut wal = db.inner.wal.lock().unwrap();
+            wal.log.as_mut().unwrap().sync().unwrap();
+            log_file_name(dir, wal.log_file_number)
+        };
+        let leader_log = synced_log(&leader, &leader_dir);
+
+        // tail the leader's log into a fresh follower
+        let follower = LevelDB::open(options(), &follower_dir, DefaultEnv::default()).unwrap();
+        let replicated = WriteOption {
+            preserve_sequence: true,
+            ..Default::default()
+        };
+        let file = DefaultEnv::default()
+            .new_sequential_file(&leader_log)
+            .unwrap();
+        let mut reader = LogReader::new(file, true);
+        let mut first = None;
+        let mut record = Vec::new();
+        while reader.read_record(&mut record).unwrap().is_some() {
+            first.get_or_insert_with(|| record.clone());
+            let batch = WriteBatch::from_content(std::mem::take(&mut record)).unwrap();
+            follower.write_batch(&replicated, batch).unwrap();
+        }
+
+        let last_sequence =
+            |db: &LevelDB<DefaultEnv>| db.inner.versions.lock().unwrap().last_sequence();
+        assert_eq!(last_sequence(&follower), last_sequence(&leader));
+        for i in 0..25 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                follower.get(key.as_bytes()).unwrap(),
+                leader.get(key.as_bytes()).unwrap()
+            );
+        }
+        let follower_log = synced_log(&follower, &follower_dir);
+        assert_eq!(
+            std::fs::read(&follower_log).unwrap(),
+            std::fs::read(&leader_log).unwrap()
+        );
+
+        // a batch replayed twice is rejected, nothing reaches the log
+        let batch = WriteBatch::from_content(first.unwrap()).unwrap();
+        let res = follower.write_batch(&replicated, batch);
+        assert!(matches!(res, Err(Error::InvalidArgument(_))), "{:?}", res);
+        assert_eq!(last_sequence(&follower), last_sequence(&leader));
+        assert_eq!(
+            std::fs::read(&follower_log).unwrap(),
+            std::fs::read(&leader_log).unwrap()
+        );
+
+        // the follower's own writes continue after the replicated sequences
+        follower.write(b"own", b"v").unwrap();
+        assert_eq!(last_sequence(&follower), last_sequence(&leader) + 1);
+    }
+
+    #[test]
+    fn test_switch_memtable_when_full() {
+        let dir = tempfile::Builder::new()
+            .prefix("switch_memtable")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            write_buffer_size: 64 << 10,
+            ..Default::default()
+        };
+        let value = vec![b'v'; 1000];
+        {
+            let db = LevelDB::open(options.clone(), dir.path(), DefaultEnv::default()).unwrap();
+            let first_log = db.inner.wal.lock().unwrap().log_file_number;
+            for i in 0..300 {
+                db.write(format!("key{:03}", i).as_bytes(), &value).unwrap();
+                let usage = db
+                    .inner
+                    .mem
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap()
+                    .approximate_memory_usage();
+                assert!(usage <= (64 << 10) + 2 * value.len());
+            }
+            assert!(db.inner.wal.lock().unwrap().log_file_number > first_log);
+            // the full memtables went to tables in the background
+            let mut versions = db.inner.versions.lock().unwrap();
+            while db.inner.imm.read().unwrap().is_some() {
+                versions = db.inner.wait_for_background_work(versions).unwrap();
+            }
+            let current = versions.current().unwrap();
+            assert!(current.files.iter().map(|files| files.len()).sum::<usize>() > 0);
+            drop(versions);
+            for i in 0..300 {
+                assert_eq!(
+                    db.get(format!("key{:03}", i).as_bytes()).unwrap(),
+                    Some(value.clone())
+                );
+            }
+        }
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        for i in 0..300 {
+            assert_eq!(
+                db.get(format!("key{:03}", i).as_bytes()).unwrap(),
+                Some(value.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_level0_write_stalls() {
+        let dir = tempfile::Builder::new()
+            .prefix("write_stalls")
+            .tempdir()
+            .unwrap();
+        let env = TestEnv::default();
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), env.clone()).unwrap();
+        let level0_files = |db: &LevelDB<TestEnv>| {
+            let current = db.inner.versions.lock().unwrap().current().unwrap();
+            current.files[0].len()
+        };
+        let add_level0_files = |db: &LevelDB<TestEnv>, count: usize| {
+            for _ in 0..count {
+                db.write(b"key", b"value").unwrap();
+                flush_memtable(db);
+            }
+        };
+
+        // below the trigger writes aren't delayed
+        add_level0_files(&db, L0_SLOW_DOWN_WRITES_TRIGGER);
+        assert_eq!(env.sleeps.load(Ordering::SeqCst), 0);
+        assert_eq!(level0_files(&db), L0_SLOW_DOWN_WRITES_TRIGGER);
+        // each write is delayed once
+        db.write(b"key", b"value").unwrap();
+        db.write(b"key", b"value").unwrap();
+        assert_eq!(env.sleeps.load(Ordering::SeqCst), 2);
+
+        // at the stop trigger a write waits for the level-0 compaction
+        for _ in L0_SLOW_DOWN_WRITES_TRIGGER..L0_STOP_WRITES_TRIGGER {
+            flush_keys(&db, "key", 1, 5);
+        }
+        assert_eq!(level0_files(&db), L0_STOP_WRITES_TRIGGER);
+        db.inner.make_room_for_write(true).unwrap();
+        assert!(level0_files(&db) < L0_STOP_WRITES_TRIGGER);
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_memtable_output_keeps_levels_disjoint() {
+        let dir = tempfile::Builder::new()
+            .prefix("memtable_output_level")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        // flushes keys in [start, end) the way a memtable compaction does
+        let flush = |start: usize, end: usize| {
+            for i in start..end {
+                db.write(format!("key{:04}", i).as_bytes(), b"v").unwrap();
+            }
+            let inner = &db.inner;
+            let base = inner.versions.lock().unwrap().current();
+            let mem = inner
+                .mem
+                .write()
+                .unwrap()
+                .replace(inner.new_memtable())
+                .unwrap();
+            let mut edit = VersionEdit::default();
+            inner
+                .write_level0_table(mem, &mut edit, base, IoPriority::Foreground)
+                .unwrap();
+            let mut versions = inner.versions.lock().unwrap();
+            versions.log_and_apply(&mut edit).unwrap();
+            let current = versions.current().unwrap();
+            current.check_invariants().unwrap();
+            current
+        };
+        let current = flush(50, 150);
+        assert_eq!(current.files[2].len(), 1);
+        // starts before the file at level 2 but ends inside it
+        let current = flush(0, 100);
+        assert_eq!(current.files[1].len(), 1);
+        assert_eq!(current.files[2].len(), 1);
+        let current = flush(0, 100);
+        assert_eq!(current.files[0].len(), 1);
+        for i in 0..150 {
+            assert_eq!(
+                db.get(format!("key{:04}", i).as_bytes()).unwrap(),
+                Some(b"v".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_iterator_keeps_compacted_tables() {
+        let dir = tempfile::Builder::new()
+            .prefix("iterator_keeps_tables")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let first = flush_keys(&db, "key", 100, 10);
+        let mut iter = db.iter(&ReadOption::default());
+        for i in 0..100 {
+            db.write(format!("key{:04}", i).as_bytes(), b"new").unwrap();
+        }
+        flush_memtable(&db);
+        let current = db.inner.versions.lock().unwrap().current().unwrap();
+        let second = current.files[0].iter().map(|f| f.number).max().unwrap();
+        drop(current);
+        // both tables are merged away and collected while the iterator pins them
+        compact_file(&db, first);
+        for number in [first, second] {
+            assert_eq!(file_level(&db, number), None);
+        }
+        db.inner.delete_obsoleted_files();
+        let tables = file_numbers(dir.path(), FileType::Table);
+        assert!(tables.contains(&first));
+
+        iter.seek(b"key0050");
+        assert!(iter.valid());
+        assert_eq!(iter.key(), b"key0050");
+        assert_eq!(iter.value(), vec![b'v'; 10].as_slice());
+        drop(iter);
+
+        db.inner.delete_obsoleted_files();
+        assert!(!file_numbers(dir.path(), FileType::Table).contains(&first));
+        assert_eq!(db.get(b"key0050").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = tempfile::Builder::new()
+            .prefix("concurrent_writers")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            write_buffer_size: 64 << 10,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options.clone(), dir.path(), DefaultEnv::default()).unwrap();
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let db = &db;
+                scope.spawn(move || {
+                    let sync = WriteOption {
+                        sync: t % 2 == 0,
+                        ..Default::default()
+                    };
+                    for i in 0..200 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(format!("t{}-{:03}", t, i).as_bytes(), &[b'v'; 100]);
+                        db.write_batch(&sync, batch).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(db.inner.batch_write_queue.lock().unwrap().is_empty());
+        // every write got its own sequence
+        assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), 8 * 200);
+        drop(db);
+
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        for t in 0..8 {
+            for i in 0..200 {
+                let key = format!("t{}-{:03}", t, i);
+                assert_eq!(db.get(key.as_bytes()).unwrap(), Some(vec![b'v'; 100]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_batch_group() {
+        let writer = |size: usize, sync: bool, preserve_sequence: bool| {
+            let mut batch = WriteBatch::new();
+            batch.put(b"key", &vec![b'v'; size]);
+            Writer {
+                id: 0,
+                batch: Some(batch),
+                notifier: channel().0,
+                sync,
+                preserve_sequence,
+            }
+        };
+        let group = |writers: Vec<Writer>| {
+            let mut queue: VecDeque<Writer> = writers.into();
+            let (batch, group_size) = DBImplInner::<DefaultEnv>::build_batch_group(&mut queue);
+            assert_eq!(batch.count() as usize, group_size);
+            group_size
+        };
+
+        assert_eq!(group(vec![writer(10, false, false)]), 1);
+        assert_eq!(
+            group(vec![
+                writer(10, true, false),
+                writer(10, false, false),
+                writer(10, true, false)
+            ]),
+            3
+        );
+        // a sync write isn't made to wait for a write that doesn't sync
+        assert_eq!(
+            group(vec![
+                writer(10, false, false),
+                writer(10, false, false),
+                writer(10, true, false)
+            ]),
+            2
+        );
+        // batches with their own sequence are written alone
+        assert_eq!(
+            group(vec![writer(10, false, true), writer(10, false, false)]),
+            1
+        );
+        assert_eq!(
+            group(vec![writer(10, false, false), writer(10, false, true)]),
+            1
+        );
+        // a small batch only takes a little more
+        assert_eq!(
+            group(vec![
+                writer(10, false, false),
+                writer(100 << 10, false, false),
+                writer(100 << 10, false, false)
+            ]),
+            2
+        );
+        assert_eq!(
+            group(vec![
+                writer(600 << 10, false, false),
+                writer(300 << 10, false, false),
+                writer(300 << 10, false, false)
+            ]),
+            2
+        );
+    }
+
+    #[derive(Default)]
+    struct ThreadRecorder {
+        tables: Mutex<Vec<String>>,
+        compactions: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for ThreadRecorder {
+        fn on_compaction_completed(&self, _info: &CompactionInfo) {
+            let name = thread::current().name().unwrap_or_default().to_string();
+            self.compactions.lock().unwrap().push(name);
+        }
+        fn on_table_file_created(&self, _info: &TableFileInfo) {
+            let name = thread::current().name().unwrap_or_default().to_string();
+            self.tables.lock().unwrap().push(name);
+        }
+    }
+
+    #[test]
+    fn test_synchronous_mode() {
+        let run = |background_threads| {
+            let listener = Arc::new(ThreadRecorder::default());
+            let mut options = Options {
+                create_if_missing: true,
+                write_buffer_size: 64 << 10,
+                max_file_size: 64 << 10,
+                listener: Some(listener.clone()),
+                ..Default::default()
+            };
+            options.background_threads = background_threads;
+            let env = MemEnv::new();
+            let db = LevelDB::open(options.clone(), "/sync", env.clone()).unwrap();
+            let mut rng = StdRng::seed_from_u64(2019);
+            for i in 0..20000 {
+                let key = format!("key{:05}", rng.gen_range(0..2000));
+                if rng.gen_range(0..10) == 0 {
+                    db.delete(key.as_bytes()).unwrap();
+                } else {
+                    let value = format!("{}{}", i, "v".repeat(rng.gen_range(0..300)));
+                    db.write(key.as_bytes(), value.as_bytes()).unwrap();
+                }
+            }
+            let mut contents = Vec::new();
+            let mut iter = db.iter(&ReadOption::default());
+            iter.seek_to_first();
+            while iter.valid() {
+                contents.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+            drop(iter);
+            drop(db);
+            // what was written survives a reopen
+            let db = LevelDB::open(options, "/sync", env).unwrap();
+            for (key, value) in contents.iter() {
+                assert_eq!(db.get(key).unwrap().as_ref(), Some(value));
+            }
+            let tables = listener.tables.lock().unwrap().clone();
+            let compactions = listener.compactions.lock().unwrap().clone();
+            (contents, tables, compactions)
+        };
+
+        let (threaded, _, _) = run(1);
+        let (synchronous, tables, compactions) = run(0);
+        assert_eq!(synchronous, threaded);
+        // the writing thread flushed and compacted, no worker was started
+        let this = thread::current().name().unwrap().to_string();
+        assert!(!tables.is_empty());
+        assert!(!compactions.is_empty());
+        assert!(tables
+            .iter()
+            .chain(compactions.iter())
+            .all(|name| *name == this));
     }
 }
@@ -0,0 +1,351 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use crate::{
+    cmp::Comparator,
+    error::{Error, Result},
+    format::{extract_user_key, ParsedInternalKey},
+    iterator::{shrink_scratch, DBIterator},
+    types::{SequenceNumber, ValueType},
+    Forward,
+};
+
+// The user view of the merged memtables and tables: internal keys are decoded,
+// entries newer than `sequence` are invisible and only the newest visible entry
+// of each user key is returned, unless that's a deletion.
+//
+// Moving forward, the internal iterator is at the entry returned. Moving backward
+// it's before all entries of the user key returned, which is kept in `saved_key`
+// and `saved_value`.
+pub struct DBIter {
+    user_comparator: Arc<dyn Comparator>,
+    iter: Box<dyn DBIterator>,
+    sequence: SequenceNumber,
+    // the first corrupted internal key, entries around it are still returned
+    status: Option<Error>,
+    // the user key to skip moving forward, the current one moving backward
+    saved_key: Vec<u8>,
+    saved_value: Vec<u8>,
+    direction: Forward,
+    valid: bool,
+}
+
+impl DBIter {
+    pub fn new(
+        user_comparator: Arc<dyn Comparator>,
+        iter: Box<dyn DBIterator>,
+        sequence: SequenceNumber,
+    ) -> Self {
+        DBIter {
+            user_comparator,
+            iter,
+            sequence,
+            status: None,
+            saved_key: Vec::new(),
+            saved_value: Vec::new(),
+            direction: Forward::FORWARD,
+            valid: false,
+        }
+    }
+
+    fn record_error(&mut self, err: Error) {
+        if self.status.is_none() {
+            self.status = Some(err);
+        }
+    }
+
+    fn clear_saved(&mut self) {
+        self.saved_key.clear();
+        shrink_scratch(&mut self.saved_key);
+        self.saved_value.clear();
+        shrink_scratch(&mut self.saved_value);
+    }
+
+    // Moves the internal iterator to the first visible entry of a user key from
+    // where it is on, skipping the entries of `saved_key` with `skipping`.
+    fn find_next_user_entry(&mut self, mut skipping: bool) {
+        while self.iter.valid() {
+            match ParsedInternalKey::try_parse(self.iter.key()) {
+                Ok(ikey) if ikey.sequence <= self.sequence => match ikey.val_type {
+                    // the older entries of the key are hidden by the deletion
+                    ValueType::Deletetion => {
+                        self.saved_key.clear();
+                        self.saved_key.extend_from_slice(ikey.user_key);
+                        skipping = true;
+                    }
+                    ValueType::Value => {
+                        if !skipping
+                            || self.user_comparator.compare(ikey.user_key, &self.saved_key)
+                                == Ordering::Greater
+                        {
+                            self.valid = true;
+                            self.clear_saved();
+                            return;
+                        }
+                    }
+                },
+                Ok(_) => {}
+                Err(err) => self.record_error(err),
+            }
+            self.iter.next();
+        }
+        self.clear_saved();
+        self.valid = false;
+    }
+
+    // Moves the internal iterator back past the entries of the user key before
+    // it, keeping the newest visible one in `saved_key` and `saved_value`.
+    // Deleted keys are skipped.
+    fn find_prev_user_entry(&mut self) {
+        let mut value_type = ValueType::Deletetion;
+        while self.iter.valid() {
+            match ParsedInternalKey::try_parse(self.iter.key()) {
+                Ok(ikey) if ikey.sequence <= self.sequence => {
+                    // a value of the next key back is already saved
+                    if value_type != ValueType::Deletetion
+                        && self.user_comparator.compare(ikey.user_key, &self.saved_key)
+                            == Ordering::Less
+                    {
+                        break;
+                    }
+                    value_type = ikey.val_type;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    if value_type == ValueType::Value {
+                        self.saved_key.extend_from_slice(ikey.user_key);
+                        self.saved_value.extend_from_slice(self.iter.value());
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => self.record_error(err),
+            }
+            self.iter.prev();
+        }
+
+        if value_type == ValueType::Deletetion {
+            // at the start of the db
+            self.valid = false;
+            self.clear_saved();
+            self.direction = Forward::FORWARD;
+        } else {
+            self.valid = true;
+        }
+    }
+}
+
+impl DBIterator for DBIter {
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        self.direction = Forward::FORWARD;
+        self.clear_saved();
+        self.iter.seek_to_first();
+        self.find_next_user_entry(false);
+    }
+
+    fn seek_to_last(&mut self) {
+        self.direction = Forward::BACKWARD;
+        self.clear_saved();
+        self.iter.seek_to_last();
+        self.find_prev_user_entry();
+    }
+
+    // to the first user key >= `target`
+    fn seek(&mut self, target: &[u8]) {
+        self.direction = Forward::FORWARD;
+        self.clear_saved();
+        let lookup = ParsedInternalKey {
+            user_key: target,
+            sequence: self.sequence,
+            val_type: ValueType::Value,
+        };
+        lookup.append(&mut self.saved_key);
+        self.iter.seek(&self.saved_key);
+        self.find_next_user_entry(false);
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid);
+        if self.direction == Forward::BACKWARD {
+            // the internal iterator is before the entries of the current key,
+            // which is in `saved_key` already
+            self.direction = Forward::FORWARD;
+            if self.iter.valid() {
+                self.iter.next();
+            } else {
+                self.iter.seek_to_first();
+            }
+        } else {
+            self.saved_key.clear();
+            self.saved_key
+                .extend_from_slice(extract_user_key(self.iter.key()));
+            self.iter.next();
+        }
+        self.find_next_user_entry(true);
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid);
+        if self.direction == Forward::FORWARD {
+            // back to before the entries of the current key
+            self.saved_key.clear();
+            self.saved_key
+                .extend_from_slice(extract_user_key(self.iter.key()));
+            loop {
+                self.iter.prev();
+                if !self.iter.valid() {
+                    self.valid = false;
+                    self.clear_saved();
+                    return;
+                }
+                let user_key = extract_user_key(self.iter.key());
+                if self.user_comparator.compare(user_key, &self.saved_key) == Ordering::Less {
+                    break;
+                }
+            }
+            self.direction = Forward::BACKWARD;
+        }
+        self.find_prev_user_entry();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Forward::FORWARD => extract_user_key(self.iter.key()),
+            Forward::BACKWARD => &self.saved_key,
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid);
+        match self.direction {
+            Forward::FORWARD => self.iter.value(),
+            Forward::BACKWARD => &self.saved_value,
+        }
+    }
+
+    fn status(&self) -> Result<()> {
+        match self.status {
+            Some(ref err) => Err(err.clone()),
+            None => self.iter.status(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{env::DefaultEnv, LevelDB, Options, ReadOption};
+
+    type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+    fn entry(iter: &DBIter) -> (Vec<u8>, Vec<u8>) {
+        (iter.key().to_vec(), iter.value().to_vec())
+    }
+
+    // compares every way of moving the iterator to the model
+    fn check(mut iter: DBIter, model: &Model, rng: &mut StdRng) {
+        let expected: Vec<_> = model.clone().into_iter().collect();
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(entry(&iter));
+            iter.next();
+        }
+        assert_eq!(forward, expected);
+
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(entry(&iter));
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        for _ in 0..20 {
+            let target = format!("key{:02}", rng.gen_range(0..60)).into_bytes();
+            iter.seek(&target);
+            let mut pos = expected.partition_point(|(k, _)| *k < target);
+            // switch directions at random from the seek on
+            for _ in 0..10 {
+                match expected.get(pos) {
+                    Some(e) => assert_eq!(&entry(&iter), e),
+                    None => {
+                        assert!(!iter.valid());
+                        break;
+                    }
+                }
+                if rng.gen_bool(0.5) {
+                    iter.next();
+                    pos += 1;
+                } else if pos == 0 {
+                    iter.prev();
+                    assert!(!iter.valid());
+                    break;
+                } else {
+                    iter.prev();
+                    pos -= 1;
+                }
+            }
+        }
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_iter_matches_model() {
+        let dir = tempfile::Builder::new()
+            .prefix("db_iter")
+            .tempdir()
+            .unwrap();
+        let options = Options {
+            create_if_missing: true,
+            ..Default::default()
+        };
+        let db = LevelDB::open(options, dir.path(), DefaultEnv::default()).unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut model = Model::new();
+        let mut snapshots = Vec::new();
+
+        check(db.iter(&ReadOption::default()), &model, &mut rng);
+        for op in 0..600 {
+            let key = format!("key{:02}", rng.gen_range(0..50)).into_bytes();
+            if rng.gen_bool(0.7) {
+                let value = format!("v{}", op).into_bytes();
+                db.write(&key, &value).unwrap();
+                model.insert(key, value);
+            } else {
+                db.delete(&key).unwrap();
+                model.remove(&key);
+            }
+            if op % 100 == 99 {
+                snapshots.push((db.get_snapshot(), model.clone()));
+            }
+            if op % 150 == 149 {
+                db.live_files(true).unwrap();
+            }
+        }
+        check(db.iter(&ReadOption::default()), &model, &mut rng);
+        for (snapshot, model) in snapshots {
+            let options = ReadOption {
+                snapshot: Some(snapshot),
+                ..Default::default()
+            };
+            check(db.iter(&options), &model, &mut rng);
+        }
+
+        // an iterator keeps reading the tables it started with
+        let iter = db.iter(&ReadOption::default());
+        for key in model.keys() {
+            db.delete(key).unwrap();
+        }
+        db.live_files(true).unwrap();
+        check(iter, &model, &mut rng);
+        check(db.iter(&ReadOption::default()), &Model::new(), &mut rng);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{DefaultEnv, Env, IoError, IoResult, RandomAccessFile, SequencialFile, WritableFile};
+
+// An env over `E` whose files fail reads or writes with an injected error until
+// the fault is used up or cleared. Clones share the faults. Like a real file
+// error, an injected one names the file.
+//
+// It can also cut the power: the file operations after the cut fail, and
+// `drop_unsynced_data` then leaves the files like a restarted machine would.
+#[derive(Clone)]
+pub struct FaultyEnv<E: Env = DefaultEnv> {
+    inner: E,
+    faults: Arc<Mutex<Faults>>,
+    // the synced length of every file written through the env
+    synced: Arc<Mutex<HashMap<PathBuf, usize>>>,
+    // how often each file was opened for random access
+    opens: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+#[derive(Default)]
+struct Faults {
+    read: Option<Fault>,
+    write: Option<Fault>,
+    // `remaining` counts the bytes appends may still write
+    space: Option<Fault>,
+    // creates, appends, syncs, renames and deletes left before the power goes out
+    power: Option<usize>,
+    power_cut: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Fault {
+    error: fn() -> io::Error,
+    remaining: usize,
+}
+
+fn trigger(fault: &mut Option<Fault>, path: &Path) -> IoResult<()> {
+    match fault {
+        Some(f) if f.remaining > 0 => {
+            f.remaining -= 1;
+            Err(IoError::from((f.error)()).with_path(path))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn use_power(faults: &Mutex<Faults>, path: &Path) -> IoResult<()> {
+    let mut faults = faults.lock().unwrap();
+    match faults.power.as_mut() {
+        Some(0) => {
+            faults.power_cut = true;
+            let err = io::Error::other("power cut");
+            Err(IoError::from(err).with_path(path))
+        }
+        Some(left) => {
+            *left -= 1;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+impl FaultyEnv<DefaultEnv> {
+    pub fn new() -> Self {
+        FaultyEnv::wrap(DefaultEnv::default())
+    }
+}
+
+impl Default for FaultyEnv<DefaultEnv> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Env> FaultyEnv<E> {
+    pub fn wrap(inner: E) -> Self {
+        FaultyEnv {
+            inner,
+            faults: Arc::new(Mutex::new(Faults::default())),
+            synced: Arc::new(Mutex::new(HashMap::new())),
+            opens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // the number of times `name` was opened for random access
+    pub fn open_count(&self, name: &Path) -> usize {
+        self.opens.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+
+    // the next `times` reads of any file fail with `error`
+    pub fn fail_reads(&self, error: fn() -> io::Error, times: usize) {
+        self.faults.lock().unwrap().read = Some(Fault {
+            error,
+            remaining: times,
+        });
+    }
+
+    // the next `times` appends or syncs of any file fail with `error`
+    pub fn fail_writes(&self, error: fn() -> io::Error, times: usize) {
+        self.faults.lock().unwrap().write = Some(Fault {
+            error,
+            remaining: times,
+        });
+    }
+
+    // appends write `bytes` more bytes, then fail with `error` like a full disk. The
+    // append crossing the limit writes the part that fits
+    pub fn fail_writes_after(&self, error: fn() -> io::Error, bytes: usize) {
+        self.faults.lock().unwrap().space = Some(Fault {
+            error,
+            remaining: bytes,
+        });
+    }
+
+    // the next `ops` creates, appends, syncs, renames or deletes go through, the
+    // ones after fail
+    pub fn cut_power_after(&self, ops: usize) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.power = Some(ops);
+        faults.power_cut = false;
+    }
+
+    // whether an operation failed for want of power
+    pub fn power_cut(&self) -> bool {
+        self.faults.lock().unwrap().power_cut
+    }
+
+    // Truncates every file written through the env to its length at its last sync,
+    // a file never synced ends up empty. Renames and deletes are taken as durable.
+    pub fn drop_unsynced_data(&self) -> IoResult<()> {
+        let synced = self.synced.lock().unwrap();
+        for (path, &len) in synced.iter() {
+            if !self.inner.file_exists(path) || self.inner.file_size(path)? <= len {
+                continue;
+            }
+            let mut data = vec![0; len];
+            self.inner
+                .new_sequential_file(path)?
+                .read_exact(&mut data)?;
+            self.inner.delete_file(path)?;
+            let mut file = self.inner.new_writable_file(path)?;
+            file.append(&data)?;
+            file.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_faults(&self) {
+        *self.faults.lock().unwrap() = Faults::default();
+    }
+
+    fn file<F>(&self, inner: F, path: &Path) -> FaultyFile<F> {
+        FaultyFile {
+            inner,
+            path: path.to_path_buf(),
+            faults: self.faults.clone(),
+            written: 0,
+            synced: self.synced.clone(),
+        }
+    }
+}
+
+pub struct FaultyFile<F> {
+    inner: F,
+    path: PathBuf,
+    faults: Arc<Mutex<Faults>>,
+    // bytes appended through this handle
+    written: usize,
+    synced: Arc<Mutex<HashMap<PathBuf, usize>>>,
+}
+
+impl<F> FaultyFile<F> {
+    fn check_read(&self) -> IoResult<()> {
+        trigger(&mut self.faults.lock().unwrap().read, &self.path)
+    }
+
+    fn check_write(&self) -> IoResult<()> {
+        trigger(&mut self.faults.lock().unwrap().write, &self.path)
+    }
+}
+
+impl<F: RandomAccessFile> RandomAccessFile for FaultyFile<F> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+        self.check_read()?;
+        self.inner.read_at(buf, offset)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()> {
+        self.check_read()?;
+        self.inner.read_exact_at(buf, offset)
+    }
+}
+
+impl<F: WritableFile> WritableFile for FaultyFile<F> {
+    fn append(&mut self, data: &[u8]) -> IoResult<()> {
+        self.check_write()?;
+        use_power(&self.faults, &self.path)?;
+        let mut faults = self.faults.lock().unwrap();
+        if let Some(space) = faults.space.as_mut() {
+            if data.len() > space.remaining {
+                let (fits, error) = (space.remaining, space.error);
+                space.remaining = 0;
+                drop(faults);
+                self.inner.append(&data[..fits])?;
+                self.written += fits;
+                return Err(IoError::from(error()).with_path(&self.path));
+            }
+            space.remaining -= data.len();
+        }
+        drop(faults);
+        self.inner.append(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> IoResult<()> {
+        self.check_write()?;
+        use_power(&self.faults, &self.path)?;
+        self.inner.sync()?;
+        self.synced
+            .lock()
+            .unwrap()
+            .insert(self.path.clone(), self.written);
+        Ok(())
+    }
+}
+
+impl<F: SequencialFile> SequencialFile for FaultyFile<F> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        self.check_read()?;
+        self.inner.read_exact(buf)
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> IoResult<()> {
+        self.check_read()?;
+        self.inner.read_to_string(buf)
+    }
+
+    fn skip(&mut self, n: usize) -> IoResult<()> {
+        self.check_read()?;
+        self.inner.skip(n)
+    }
+}
+
+impl<E: Env> Env for FaultyEnv<E> {
+    type RandomAccessFile = FaultyFile<E::RandomAccessFile>;
+    type WritableFile = FaultyFile<E::WritableFile>;
+    type SequencialFile = FaultyFile<E::SequencialFile>;
+    type FileLock = E::FileLock;
+
+    fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile> {
+        let file = self.inner.new_random_access_file(name)?;
+        *self
+            .opens
+            .lock()
+            .unwrap()
+            .entry(name.to_path_buf())
+            .or_default() += 1;
+        Ok(self.file(file, name))
+    }
+
+    fn new_writable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+        use_power(&self.faults, name)?;
+        let file = self.inner.new_writable_file(name)?;
+        self.synced.lock().unwrap().insert(name.to_path_buf(), 0);
+        Ok(self.file(file, name))
+    }
+
+    // what the file held before is taken as synced
+    fn new_appendable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+        use_power(&self.faults, name)?;
+        let file = self.inner.new_appendable_file(name)?;
+        let size = self.inner.file_size(name)?;
+        self.synced
+            .lock()
+            .unwrap()
+            .entry(name.to_path_buf())
+            .or_insert(size);
+        let mut file = self.file(file, name);
+        file.written = size;
+        Ok(file)
+    }
+
+    fn new_sequential_file(&self, name: &Path) -> IoResult<Self::SequencialFile> {
+        Ok(self.file(self.inner.new_sequential_file(name)?, name))
+    }
+
+    fn file_size(&self, path: &Path) -> IoResult<usize> {
+        self.inner.file_size(path)
+    }
+
+    fn file_exists(&self, path: &Path) -> bool {
+        self.inner.file_exists(path)
+    }
+
+    fn delete_file(&self, path: &Path) -> IoResult<()> {
+        use_power(&self.faults, path)?;
+        self.inner.delete_file(path)?;
+        self.synced.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()> {
+        use_power(&self.faults, from)?;
+        self.inner.rename_file(from, to)?;
+        let mut synced = self.synced.lock().unwrap();
+        match synced.remove(from) {
+            Some(len) => synced.insert(to.to_path_buf(), len),
+            None => synced.remove(to),
+        };
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> IoResult<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn delete_dir(&self, path: &Path) -> IoResult<()> {
+        self.inner.delete_dir(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+
+    fn fs_id(&self) -> u64 {
+        self.inner.fs_id()
+    }
+
+    fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()> {
+        self.inner.get_children(path, files)
+    }
+
+    fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock> {
+        self.inner.lock_file(path)
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()> {
+        self.inner.unlock_file(lock)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.inner.now_micros()
+    }
+}
@@ -1,110 +1,429 @@
-// use super::{Env, RandomAccessFile, SequencialFile, WritableFile};
-// use crate::error::Result;
-// use std::path::Path;
+use super::{lock_held, Env, IoError, IoResult, RandomAccessFile, SequencialFile, WritableFile};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
 
-pub struct MemFs {
-    data: Vec<u8>,
+// the bytes of a file, shared by the env and the open handles
+type FileData = Arc<RwLock<Vec<u8>>>;
+
+// A file of `MemEnv`. Like an open file descriptor, a handle keeps the data of
+// a file deleted or replaced after it was opened.
+pub struct MemFile {
+    data: FileData,
+    path: PathBuf,
+    // where a sequential read goes on
+    pos: usize,
+}
+
+fn io_error(kind: io::ErrorKind, msg: &str, path: &Path) -> IoError {
+    IoError::from(io::Error::new(kind, msg)).with_path(path)
+}
+
+fn unexpected_eof(path: &Path) -> IoError {
+    io_error(io::ErrorKind::UnexpectedEof, "failed to fill buffer", path)
+}
+
+fn not_found(path: &Path) -> IoError {
+    io_error(io::ErrorKind::NotFound, "no such file or directory", path)
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+        let data = self.data.read().unwrap();
+        let start = data.len().min(offset as usize);
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()> {
+        if self.read_at(buf, offset)? < buf.len() {
+            return Err(unexpected_eof(&self.path));
+        }
+        Ok(())
+    }
+}
+
+impl WritableFile for MemFile {
+    fn append(&mut self, data: &[u8]) -> IoResult<()> {
+        self.data.write().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+// A read past the end fails with UnexpectedEof and leaves the file at its end,
+// as a read of a std file does.
+impl SequencialFile for MemFile {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        let data = self.data.read().unwrap();
+        let start = self.pos.min(data.len());
+        if data.len() - start < buf.len() {
+            self.pos = data.len();
+            return Err(unexpected_eof(&self.path));
+        }
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+        self.pos = start + buf.len();
+        Ok(())
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> IoResult<()> {
+        let data = self.data.read().unwrap();
+        let start = self.pos.min(data.len());
+        let s = std::str::from_utf8(&data[start..]).map_err(|_| {
+            io_error(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+                &self.path,
+            )
+        })?;
+        buf.push_str(s);
+        self.pos = data.len();
+        Ok(())
+    }
+
+    fn skip(&mut self, n: usize) -> IoResult<()> {
+        self.pos = self.pos.saturating_add(n);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemFs {
+    files: HashMap<PathBuf, FileData>,
+    dirs: HashSet<PathBuf>,
+    locked: HashSet<PathBuf>,
 }
 
 impl MemFs {
-    pub fn new(data: Vec<u8>) -> Self {
-        MemFs { data }
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path) || self.dirs.contains(path)
     }
 
-    pub fn empty() -> Self {
-        MemFs { data: Vec::new() }
+    // the files and directories right inside `dir`
+    fn children<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a PathBuf> {
+        self.files
+            .keys()
+            .chain(self.dirs.iter())
+            .filter(move |p| p.parent() == Some(dir))
     }
+}
+
+// the ids of the file systems of `MemEnv`, 0 is the one of the OS
+static NEXT_FS_ID: AtomicU64 = AtomicU64::new(1);
+
+// An env keeping its files in memory, for tests and dbs that needn't outlive
+// the process. Clones share the files. Paths are taken as absolute and only
+// normalized, without a current directory or links.
+#[derive(Clone)]
+pub struct MemEnv {
+    fs: Arc<Mutex<MemFs>>,
+    fs_id: u64,
+}
 
-    pub fn data(&self) -> &[u8] {
-        &self.data
+impl Default for MemEnv {
+    fn default() -> Self {
+        MemEnv {
+            fs: Default::default(),
+            fs_id: NEXT_FS_ID.fetch_add(1, Ordering::Relaxed),
+        }
     }
 }
 
-// impl RandomAccessFile for MemFs {
-//     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-//         let data = &self.data[offset as usize..offset as usize + buf.len()];
-//         buf.copy_from_slice(data);
-//         Ok(buf.len())
-//     }
+impl MemEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-//     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
-//         self.read_at(buf, offset)?;
-//         Ok(())
-//     }
-// }
+    fn open(&self, path: &Path) -> IoResult<MemFile> {
+        let fs = self.fs.lock().unwrap();
+        let data = fs
+            .files
+            .get(&normalize(path))
+            .ok_or_else(|| not_found(path))?;
+        Ok(MemFile {
+            data: data.clone(),
+            path: path.to_path_buf(),
+            pos: 0,
+        })
+    }
+}
 
-// impl WritableFile for MemFs {
-//     fn append(&mut self, data: &[u8]) -> Result<()> {
-//         self.data.extend_from_slice(data);
-//         Ok(())
-//     }
+// A locked file of `MemEnv`, unlocked when dropped.
+pub struct MemFileLock {
+    fs: Arc<Mutex<MemFs>>,
+    path: PathBuf,
+}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locked.remove(&self.path);
+    }
+}
+
+// `path` below the root, with `.` and `..` resolved
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    normalized
+}
+
+impl Env for MemEnv {
+    type RandomAccessFile = MemFile;
+    type WritableFile = MemFile;
+    type SequencialFile = MemFile;
+    type FileLock = MemFileLock;
+
+    fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile> {
+        self.open(name)
+    }
+
+    // an existing file is truncated
+    fn new_writable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+        let data = FileData::default();
+        let mut fs = self.fs.lock().unwrap();
+        fs.files.insert(normalize(name), data.clone());
+        Ok(MemFile {
+            data,
+            path: name.to_path_buf(),
+            pos: 0,
+        })
+    }
+
+    fn new_appendable_file(&self, name: &Path) -> IoResult<Self::WritableFile> {
+        let mut fs = self.fs.lock().unwrap();
+        let data = fs.files.entry(normalize(name)).or_default();
+        Ok(MemFile {
+            data: data.clone(),
+            path: name.to_path_buf(),
+            pos: 0,
+        })
+    }
+
+    fn new_sequential_file(&self, name: &Path) -> IoResult<Self::SequencialFile> {
+        self.open(name)
+    }
+
+    fn file_size(&self, path: &Path) -> IoResult<usize> {
+        let fs = self.fs.lock().unwrap();
+        let data = fs
+            .files
+            .get(&normalize(path))
+            .ok_or_else(|| not_found(path))?;
+        let size = data.read().unwrap().len();
+        Ok(size)
+    }
 
-//     fn flush(&mut self) -> Result<()> {
-//         Ok(())
-//     }
+    fn file_exists(&self, path: &Path) -> bool {
+        self.fs.lock().unwrap().exists(&normalize(path))
+    }
 
-//     fn sync(&mut self) -> Result<()> {
-//         Ok(())
-//     }
-// }
+    fn delete_file(&self, path: &Path) -> IoResult<()> {
+        let mut fs = self.fs.lock().unwrap();
+        match fs.files.remove(&normalize(path)) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
 
-// impl SequencialFile for MemFs {
-//     fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
-//         todo!()
-//     }
+    fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let data = fs
+            .files
+            .remove(&normalize(from))
+            .ok_or_else(|| not_found(from))?;
+        fs.files.insert(normalize(to), data);
+        Ok(())
+    }
 
-//     fn skip(&mut self, n: usize) -> Result<()> {
-//         todo!()
-//     }
+    fn create_dir(&self, path: &Path) -> IoResult<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let path_buf = normalize(path);
+        if fs.exists(&path_buf) {
+            return Err(io_error(io::ErrorKind::AlreadyExists, "file exists", path));
+        }
+        fs.dirs.insert(path_buf);
+        Ok(())
+    }
 
-//     fn read_all(&mut self, buf: &mut String) -> Result<()> {
-//         todo!()
-//     }
-// }
+    fn delete_dir(&self, path: &Path) -> IoResult<()> {
+        let mut fs = self.fs.lock().unwrap();
+        let path_buf = normalize(path);
+        if !fs.dirs.contains(&path_buf) {
+            return Err(not_found(path));
+        }
+        if fs.children(&path_buf).next().is_some() {
+            return Err(io_error(io::ErrorKind::Other, "directory not empty", path));
+        }
+        fs.dirs.remove(&path_buf);
+        Ok(())
+    }
 
-// #[derive(Clone, Copy)]
-// pub struct MemEnv {}
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        let path_buf = normalize(path);
+        if !self.fs.lock().unwrap().exists(&path_buf) {
+            return Err(not_found(path));
+        }
+        Ok(path_buf)
+    }
 
-// impl Env for MemEnv {
-//     type RandomAccessFile = MemFs;
+    fn fs_id(&self) -> u64 {
+        self.fs_id
+    }
 
-//     type WritableFile = MemFs;
+    fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()> {
+        let fs = self.fs.lock().unwrap();
+        let path_buf = normalize(path);
+        if !fs.dirs.contains(&path_buf) {
+            return Err(not_found(path));
+        }
+        for child in fs.children(&path_buf) {
+            files.extend(child.file_name().map(|name| name.to_os_string()));
+        }
+        Ok(())
+    }
 
-//     type SequencialFile = MemFs;
+    fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock> {
+        let mut fs = self.fs.lock().unwrap();
+        let path_buf = normalize(path);
+        if !fs.locked.insert(path_buf.clone()) {
+            return Err(lock_held(path));
+        }
+        fs.files.entry(path_buf.clone()).or_default();
+        Ok(MemFileLock {
+            fs: self.fs.clone(),
+            path: path_buf,
+        })
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()> {
+        drop(lock);
+        Ok(())
+    }
+}
 
-//     fn new_random_access_file(&self, name: &Path) -> Result<Self::RandomAccessFile> {
-//         Ok(MemFs { data: Vec::new() })
-//     }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DbBuilder;
 
-//     fn new_writable_file(&self, name: &Path) -> Result<Self::WritableFile> {
-//         Ok(MemFs { data: Vec::new() })
-//     }
+    #[test]
+    fn test_files() {
+        let env = MemEnv::new();
+        let dir = Path::new("/db");
+        env.create_dir(dir).unwrap();
+        let path = dir.join("file");
+        let mut file = env.new_writable_file(&path).unwrap();
+        file.append(b"0123456789").unwrap();
+        assert_eq!(env.file_size(&path).unwrap(), 10);
+        assert!(env.file_exists(Path::new("/db/./other/../file")));
 
-//     fn file_size(&self, path: &Path) -> Result<usize> {
-//         todo!()
-//     }
+        let mut seq = env.new_sequential_file(&path).unwrap();
+        let mut buf = [0; 3];
+        seq.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"012");
+        seq.skip(4).unwrap();
+        seq.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"789");
+        // appends show up in the open handles
+        file.append(b"ab").unwrap();
+        let mut content = String::new();
+        seq.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "ab");
+        let err = seq.read_exact(&mut buf).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.path(), Some(path.as_path()));
 
-//     fn new_sequential_file(&self, name: &Path) -> Result<Self::SequencialFile> {
-//         todo!()
-//     }
+        let random = env.new_random_access_file(&path).unwrap();
+        assert_eq!(random.read_at(&mut buf, 10).unwrap(), 2);
+        let err = random.read_exact_at(&mut buf, 10).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
 
-//     fn delete_file(&self, path: &Path) -> Result<()> {
-//         todo!()
-//     }
+        let moved = dir.join("moved");
+        env.rename_file(&path, &moved).unwrap();
+        assert!(!env.file_exists(&path));
+        let err = env.new_sequential_file(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let mut children = Vec::new();
+        env.get_children(dir, &mut children).unwrap();
+        assert_eq!(children, vec![OsString::from("moved")]);
 
-//     fn create_dir(&self, path: &Path) -> Result<()> {
-//         todo!()
-//     }
+        // an open handle keeps the data of a deleted file
+        assert!(env.delete_dir(dir).is_err());
+        env.delete_file(&moved).unwrap();
+        random.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"012");
+        env.delete_dir(dir).unwrap();
+        assert!(!env.file_exists(dir));
+    }
 
-//     fn file_exists(&self, path: &Path) -> bool {
-//         todo!()
-//     }
+    #[test]
+    fn test_lock_file() {
+        let env = MemEnv::new();
+        let path = Path::new("/db/LOCK");
+        let lock = env.lock_file(path).unwrap();
+        assert!(env.file_exists(path));
+        let err = env.lock_file(Path::new("/db/../db/LOCK")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // clones share the locks
+        let other = env.clone();
+        assert!(other.lock_file(path).is_err());
+        other.unlock_file(lock).unwrap();
+        let lock = env.lock_file(path).unwrap();
+        drop(lock);
+        env.lock_file(path).unwrap();
+    }
 
-//     fn rename_file(&self, from: &Path, to: &Path) -> Result<()> {
-//         todo!()
-//     }
+    #[test]
+    fn test_db_in_memory() {
+        let env = MemEnv::new();
+        let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+        let key = |i: usize| format!("key{:03}", i).into_bytes();
+        {
+            let db = builder.open("/mem/db").unwrap();
+            for i in 0..100 {
+                db.write(&key(i), &key(i)).unwrap();
+            }
+            db.live_files(true).unwrap();
+            // these are only in the log
+            for i in 50..150 {
+                db.write(&key(i), b"new").unwrap();
+            }
+        }
+        let db = builder.open("/mem/db").unwrap();
+        assert_eq!(db.get(&key(0)).unwrap(), Some(key(0)));
+        assert_eq!(db.get(&key(50)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(&key(149)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(&key(150)).unwrap(), None);
+        drop(db);
 
-//     fn get_children(&self, path: &Path, files: &mut Vec<String>) -> Result<()> {
-//         todo!()
-//     }
-// }
+        // nothing reached the disk
+        assert!(!Path::new("/mem/db").exists());
+        let mut children = Vec::new();
+        env.get_children(Path::new("/mem/db"), &mut children)
+            .unwrap();
+        assert!(children.contains(&OsString::from("CURRENT")));
+    }
+}
@@ -1,24 +1,64 @@
+#[cfg(test)]
+pub(crate) mod faulty;
 pub mod mem;
+#[cfg(unix)]
 pub mod posix;
+pub mod std_env;
 
+use std::ffi::OsString;
 use std::fmt::Display;
 use std::io;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use thiserror::Error;
 
+// An io error with the path of the file it happened on, when the env knows it.
 #[derive(Error, Debug)]
-pub struct IoError(io::Error);
+pub struct IoError {
+    err: io::Error,
+    path: Option<PathBuf>,
+}
+
+impl IoError {
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
 impl From<io::Error> for IoError {
     fn from(err: io::Error) -> Self {
-        IoError(err)
+        IoError { err, path: None }
+    }
+}
+
+// The copy keeps the os error code, or else the kind and the message.
+impl Clone for IoError {
+    fn clone(&self) -> Self {
+        let err = match self.err.raw_os_error() {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::new(self.err.kind(), self.err.to_string()),
+        };
+        IoError {
+            err,
+            path: self.path.clone(),
+        }
     }
 }
 
 impl Display for IoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.err),
+            None => self.err.fmt(f),
+        }
     }
 }
 
@@ -26,12 +66,34 @@ impl Deref for IoError {
     type Target = io::Error;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.err
     }
 }
 
 pub type IoResult<T> = Result<T, IoError>;
 
+// Attaches the path an io operation worked on to its error.
+pub(crate) trait PathContext<T> {
+    fn with_path(self, path: &Path) -> IoResult<T>;
+}
+
+impl<T> PathContext<T> for io::Result<T> {
+    fn with_path(self, path: &Path) -> IoResult<T> {
+        self.map_err(|e| IoError::from(e).with_path(path))
+    }
+}
+
+// the error of `Env::lock_file` on a file locked already
+pub(crate) fn lock_held(path: &Path) -> IoError {
+    IoError::from(io::Error::new(io::ErrorKind::WouldBlock, "lock held")).with_path(path)
+}
+
+// The env used when none is given: `PosixEnv` where it builds, else `StdEnv`.
+#[cfg(unix)]
+pub type DefaultEnv = posix::PosixEnv;
+#[cfg(not(unix))]
+pub type DefaultEnv = std_env::StdEnv;
+
 pub trait RandomAccessFile {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize>;
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()>;
@@ -46,15 +108,24 @@ pub trait WritableFile {
 pub trait SequencialFile {
     fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()>;
     fn read_to_string(&mut self, buf: &mut String) -> IoResult<()>;
+    // skips `n` bytes, a read after skipping past the end finds the end
+    fn skip(&mut self, n: usize) -> IoResult<()>;
 }
 
 pub trait Env: Send + Sync + Clone + 'static {
-    type RandomAccessFile: RandomAccessFile + 'static;
-    type WritableFile: WritableFile + 'static;
+    // tables are read from any thread, logs and tables are written from the
+    // writers' threads and the compaction worker
+    type RandomAccessFile: RandomAccessFile + Send + Sync + 'static;
+    type WritableFile: WritableFile + Send + 'static;
     type SequencialFile: SequencialFile + 'static;
+    // released by `unlock_file`, or when dropped
+    type FileLock: Send + Sync + 'static;
 
     fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile>;
+    // an existing file is truncated, a stale tail would be read as records
     fn new_writable_file(&self, name: &Path) -> IoResult<Self::WritableFile>;
+    // writes go after the data of an existing file, a missing one is created
+    fn new_appendable_file(&self, name: &Path) -> IoResult<Self::WritableFile>;
     fn new_sequential_file(&self, name: &Path) -> IoResult<Self::SequencialFile>;
 
     fn file_size(&self, path: &Path) -> IoResult<usize>;
@@ -64,7 +135,37 @@ pub trait Env: Send + Sync + Clone + 'static {
     fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()>;
 
     fn create_dir(&self, path: &Path) -> IoResult<()>;
-    fn get_children(&self, path: &Path, files: &mut Vec<String>) -> IoResult<()>;
+    // removes an empty directory
+    fn delete_dir(&self, path: &Path) -> IoResult<()>;
+    // absolute form of an existing path, equal for every way of naming it
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf>;
+    // The file system `canonicalize` names paths of, 0 for the one of the OS.
+    // Envs sharing their files return the same id
+    fn fs_id(&self) -> u64 {
+        0
+    }
+    // names of the entries in `path`, which are not necessarily utf-8
+    fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()>;
+
+    // Locks `path`, created if missing, so no other process and no other lock
+    // in this one gets it until it's released. Fails at once if it's held.
+    fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock>;
+    fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()>;
+
+    fn now_micros(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64)
+    }
+
+    fn sleep_for_micros(&self, micros: u64) {
+        thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+// Destination of informational messages, see `Options::info_log`.
+pub trait Logger: Send + Sync {
+    fn log(&self, msg: &str);
 }
 
 pub fn do_write_string_to_file<E: Env>(
@@ -1,87 +1,124 @@
-use super::{Env, IoResult, RandomAccessFile, SequencialFile, WritableFile};
-#[cfg(unix)]
+use super::{
+    std_env::{lock_file, unlock_file},
+    Env, IoResult, PathContext, RandomAccessFile, SequencialFile, WritableFile,
+};
 use std::os::unix::fs::FileExt;
 use std::{
+    ffi::OsString,
     fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
-pub struct PosixFile(File);
 
-#[cfg(unix)]
+// The path is kept to name the file in errors.
+pub struct PosixFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl PosixFile {
+    fn open(options: &fs::OpenOptions, path: &Path) -> IoResult<Self> {
+        Ok(PosixFile {
+            file: options.open(path).with_path(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
 impl RandomAccessFile for PosixFile {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
-        Ok(self.0.read_at(buf, offset)?)
+        self.file.read_at(buf, offset).with_path(&self.path)
     }
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()> {
-        Ok(self.0.read_exact_at(buf, offset)?)
+        self.file.read_exact_at(buf, offset).with_path(&self.path)
     }
 }
 
-#[cfg(unix)]
 impl WritableFile for PosixFile {
     fn append(&mut self, data: &[u8]) -> IoResult<()> {
-        Ok(self.0.write_all(data)?)
+        self.file.write_all(data).with_path(&self.path)
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        Ok(self.0.flush()?)
+        self.file.flush().with_path(&self.path)
     }
 
     fn sync(&mut self) -> IoResult<()> {
-        Ok(self.0.sync_all()?)
+        self.file.sync_all().with_path(&self.path)
     }
 }
 
-#[cfg(unix)]
 impl SequencialFile for PosixFile {
     fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
-        Ok(self.0.read_exact(buf)?)
+        self.file.read_exact(buf).with_path(&self.path)
     }
     fn read_to_string(&mut self, buf: &mut String) -> IoResult<()> {
-        Ok(self.0.read_to_string(buf).map(|_| ())?)
+        self.file
+            .read_to_string(buf)
+            .map(|_| ())
+            .with_path(&self.path)
+    }
+    fn skip(&mut self, n: usize) -> IoResult<()> {
+        self.file
+            .seek(SeekFrom::Current(n as i64))
+            .map(|_| ())
+            .with_path(&self.path)
     }
 }
 
-#[cfg(unix)]
-#[derive(Clone, Copy)]
+// the locks are std's, flocks on unix
+pub use super::std_env::StdFileLock as PosixFileLock;
+
+#[derive(Clone, Copy, Default)]
 pub struct PosixEnv {}
 
-#[cfg(unix)]
 impl Env for PosixEnv {
     type RandomAccessFile = PosixFile;
     type WritableFile = PosixFile;
     type SequencialFile = PosixFile;
+    type FileLock = PosixFileLock;
 
     fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile> {
-        Ok(PosixFile(fs::OpenOptions::new().read(true).open(name)?))
+        PosixFile::open(fs::OpenOptions::new().read(true), name)
     }
 
     fn file_size(&self, path: &Path) -> IoResult<usize> {
-        let meta = fs::metadata(path)?;
+        let meta = fs::metadata(path).with_path(path)?;
         Ok(meta.len() as usize)
     }
 
     fn new_writable_file(&self, path: &Path) -> IoResult<Self::WritableFile> {
-        let file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(false)
-            .open(path)?;
-        Ok(PosixFile(file))
+        PosixFile::open(
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true),
+            path,
+        )
+    }
+
+    fn new_appendable_file(&self, path: &Path) -> IoResult<Self::WritableFile> {
+        PosixFile::open(fs::OpenOptions::new().create(true).append(true), path)
     }
 
     fn new_sequential_file(&self, path: &Path) -> IoResult<Self::SequencialFile> {
-        let file = fs::OpenOptions::new().read(true).write(false).open(path)?;
-        Ok(PosixFile(file))
+        PosixFile::open(fs::OpenOptions::new().read(true).write(false), path)
     }
 
     fn delete_file(&self, path: &Path) -> IoResult<()> {
-        Ok(fs::remove_file(path)?)
+        fs::remove_file(path).with_path(path)
     }
 
     fn create_dir(&self, path: &Path) -> IoResult<()> {
-        Ok(fs::create_dir(path)?)
+        fs::create_dir(path).with_path(path)
+    }
+
+    fn delete_dir(&self, path: &Path) -> IoResult<()> {
+        fs::remove_dir(path).with_path(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        fs::canonicalize(path).with_path(path)
     }
 
     fn file_exists(&self, path: &Path) -> bool {
@@ -89,22 +126,27 @@ impl Env for PosixEnv {
     }
 
     fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()> {
-        Ok(fs::rename(from, to)?)
+        fs::rename(from, to).with_path(from)
     }
 
-    fn get_children(&self, path: &Path, files: &mut Vec<String>) -> IoResult<()> {
-        for file in fs::read_dir(path)? {
-            if let Ok(f) = file {
-                files.push(f.file_name().into_string().unwrap())
-            }
+    fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()> {
+        for f in fs::read_dir(path).with_path(path)?.flatten() {
+            files.push(f.file_name())
         }
         Ok(())
     }
+
+    fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock> {
+        lock_file(path)
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()> {
+        unlock_file(lock)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use tempdir::TempDir;
 
     use super::*;
 
@@ -114,7 +156,7 @@ mod tests {
         let file_names = &mut ["demo", "demo2", "demo4"];
         file_names.sort();
 
-        let tmp_dir = TempDir::new("example").unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
         for file_name in file_names.iter() {
             let file_path = tmp_dir.path().join(*file_name);
             let mut file = env.new_writable_file(&file_path).unwrap();
@@ -136,11 +178,62 @@ mod tests {
             file.read_to_string(&mut content).unwrap();
 
             assert_eq!(content,*file_name );
+
+            let mut file = env.new_sequential_file(&file_path).unwrap();
+            file.skip(2).unwrap();
+            let mut buf = [0; 2];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, &file_name.as_bytes()[2..4]);
+            file.skip(10).unwrap();
+            assert!(file.read_exact(&mut buf).is_err());
         }
 
         
 
 
 
+    }
+
+    #[test]
+    fn test_error_names_path() {
+        let env = PosixEnv {};
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let missing = tmp_dir.path().join("missing");
+        let err = env.new_sequential_file(&missing).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.path(), Some(missing.as_path()));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}: ", missing.display())));
+    }
+
+    #[test]
+    fn test_writable_file_truncates() {
+        let env = PosixEnv {};
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        let mut file = env.new_writable_file(&path).unwrap();
+        file.append(b"0123456789").unwrap();
+        let mut file = env.new_writable_file(&path).unwrap();
+        file.append(b"ab").unwrap();
+        assert_eq!(env.file_size(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let env = PosixEnv {};
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("LOCK");
+        let lock = env.lock_file(&path).unwrap();
+        assert!(env.file_exists(&path));
+        let err = env.lock_file(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(err.to_string(), format!("{}: lock held", path.display()));
+
+        env.unlock_file(lock).unwrap();
+        let lock = env.lock_file(&path).unwrap();
+        // closing the file releases the lock as well
+        drop(lock);
+        env.unlock_file(env.lock_file(&path).unwrap()).unwrap();
     }
 }
@@ -0,0 +1,303 @@
+use super::{
+    lock_held, Env, IoResult, PathContext, RandomAccessFile, SequencialFile, WritableFile,
+};
+use std::{
+    ffi::OsString,
+    fs::{self, File, TryLockError},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// A file of `StdEnv`. Without a positional read in std, a read at an offset
+// seeks and reads under the lock, on Windows it's a `seek_read`. The path is
+// kept to name the file in errors.
+pub struct StdFile {
+    file: Mutex<File>,
+    path: PathBuf,
+}
+
+impl StdFile {
+    fn open(options: &fs::OpenOptions, path: &Path) -> IoResult<Self> {
+        Ok(StdFile {
+            file: Mutex::new(options.open(path).with_path(path)?),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.get_mut().unwrap()
+    }
+}
+
+#[cfg(windows)]
+fn read_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+#[cfg(not(windows))]
+fn read_at(file: &mut File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buf)
+}
+
+impl RandomAccessFile for StdFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+        let mut file = self.file.lock().unwrap();
+        read_at(&mut file, buf, offset).with_path(&self.path)
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> IoResult<()> {
+        let mut file = self.file.lock().unwrap();
+        while !buf.is_empty() {
+            match read_at(&mut file, buf, offset) {
+                Ok(0) => {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill buffer");
+                    return Err(err).with_path(&self.path);
+                }
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).with_path(&self.path),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl WritableFile for StdFile {
+    fn append(&mut self, data: &[u8]) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file().write_all(data).with_path(&path)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file().flush().with_path(&path)
+    }
+
+    fn sync(&mut self) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file().sync_all().with_path(&path)
+    }
+}
+
+impl SequencialFile for StdFile {
+    fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file().read_exact(buf).with_path(&path)
+    }
+
+    fn read_to_string(&mut self, buf: &mut String) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file().read_to_string(buf).map(|_| ()).with_path(&path)
+    }
+
+    fn skip(&mut self, n: usize) -> IoResult<()> {
+        let path = self.path.clone();
+        self.file()
+            .seek(SeekFrom::Current(n as i64))
+            .map(|_| ())
+            .with_path(&path)
+    }
+}
+
+// A file locked with `File::try_lock`, an flock on unix and a LockFileEx on
+// Windows. The lock belongs to the open file, so it keeps out the other opens of
+// this process too, and goes with the file when it's closed.
+pub struct StdFileLock {
+    file: File,
+    path: PathBuf,
+}
+
+pub(crate) fn lock_file(path: &Path) -> IoResult<StdFileLock> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .with_path(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(StdFileLock {
+            file,
+            path: path.to_path_buf(),
+        }),
+        Err(TryLockError::WouldBlock) => Err(lock_held(path)),
+        Err(TryLockError::Error(err)) => Err(err).with_path(path),
+    }
+}
+
+pub(crate) fn unlock_file(lock: StdFileLock) -> IoResult<()> {
+    lock.file.unlock().with_path(&lock.path)
+}
+
+// An env on std::fs alone, for the platforms `PosixEnv` doesn't build on.
+#[derive(Clone, Copy, Default)]
+pub struct StdEnv {}
+
+impl Env for StdEnv {
+    type RandomAccessFile = StdFile;
+    type WritableFile = StdFile;
+    type SequencialFile = StdFile;
+    type FileLock = StdFileLock;
+
+    fn new_random_access_file(&self, name: &Path) -> IoResult<Self::RandomAccessFile> {
+        StdFile::open(fs::OpenOptions::new().read(true), name)
+    }
+
+    fn new_writable_file(&self, path: &Path) -> IoResult<Self::WritableFile> {
+        StdFile::open(
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true),
+            path,
+        )
+    }
+
+    fn new_appendable_file(&self, path: &Path) -> IoResult<Self::WritableFile> {
+        StdFile::open(fs::OpenOptions::new().create(true).append(true), path)
+    }
+
+    fn new_sequential_file(&self, path: &Path) -> IoResult<Self::SequencialFile> {
+        StdFile::open(fs::OpenOptions::new().read(true), path)
+    }
+
+    fn file_size(&self, path: &Path) -> IoResult<usize> {
+        let meta = fs::metadata(path).with_path(path)?;
+        Ok(meta.len() as usize)
+    }
+
+    fn file_exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn delete_file(&self, path: &Path) -> IoResult<()> {
+        fs::remove_file(path).with_path(path)
+    }
+
+    fn rename_file(&self, from: &Path, to: &Path) -> IoResult<()> {
+        fs::rename(from, to).with_path(from)
+    }
+
+    fn create_dir(&self, path: &Path) -> IoResult<()> {
+        fs::create_dir(path).with_path(path)
+    }
+
+    fn delete_dir(&self, path: &Path) -> IoResult<()> {
+        fs::remove_dir(path).with_path(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> IoResult<PathBuf> {
+        fs::canonicalize(path).with_path(path)
+    }
+
+    fn get_children(&self, path: &Path, files: &mut Vec<OsString>) -> IoResult<()> {
+        for f in fs::read_dir(path).with_path(path)?.flatten() {
+            files.push(f.file_name())
+        }
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> IoResult<Self::FileLock> {
+        lock_file(path)
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> IoResult<()> {
+        unlock_file(lock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{DbBuilder, LevelDB, Options};
+
+    #[test]
+    fn test_files() {
+        let env = StdEnv {};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let mut file = env.new_writable_file(&path).unwrap();
+        file.append(b"0123456789").unwrap();
+        file.sync().unwrap();
+        assert_eq!(env.file_size(&path).unwrap(), 10);
+
+        let mut children = Vec::new();
+        env.get_children(dir.path(), &mut children).unwrap();
+        assert_eq!(children, vec![OsString::from("file")]);
+
+        let mut content = String::new();
+        let mut seq = env.new_sequential_file(&path).unwrap();
+        seq.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "0123456789");
+        let mut seq = env.new_sequential_file(&path).unwrap();
+        seq.skip(4).unwrap();
+        let mut buf = [0; 3];
+        seq.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"456");
+
+        // reads at offsets from several threads don't move each other
+        let file = Arc::new(env.new_random_access_file(&path).unwrap());
+        let readers: Vec<_> = (0..4)
+            .map(|t| {
+                let file = file.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let offset = (t + i) % 8;
+                        let mut buf = [0; 3];
+                        file.read_exact_at(&mut buf, offset as u64).unwrap();
+                        assert_eq!(&buf, &b"0123456789"[offset..offset + 3]);
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let mut buf = [0; 4];
+        assert_eq!(file.read_at(&mut buf, 8).unwrap(), 2);
+        let err = file.read_exact_at(&mut buf, 8).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.path(), Some(path.as_path()));
+
+        // writing the file again drops its old data
+        let mut file = env.new_writable_file(&path).unwrap();
+        file.append(b"ab").unwrap();
+        assert_eq!(env.file_size(&path).unwrap(), 2);
+
+        env.rename_file(&path, &dir.path().join("moved")).unwrap();
+        assert!(!env.file_exists(&path));
+        let err = env.new_sequential_file(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.path(), Some(path.as_path()));
+    }
+
+    #[test]
+    fn test_db_on_std_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = DbBuilder::new().create_if_missing(true).env(StdEnv {});
+        {
+            let db = builder.open(dir.path()).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:03}", i);
+                db.write(key.as_bytes(), key.as_bytes()).unwrap();
+            }
+            db.live_files(true).unwrap();
+            db.write(b"key000", b"new").unwrap();
+        }
+        let db = builder.open(dir.path()).unwrap();
+        assert_eq!(db.get(b"key000").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"key099").unwrap(), Some(b"key099".to_vec()));
+        drop(db);
+
+        let options = Options::default();
+        let db = LevelDB::open_default(options, dir.path()).unwrap();
+        assert_eq!(db.get(b"key050").unwrap(), Some(b"key050".to_vec()));
+    }
+}
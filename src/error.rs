@@ -1,14 +1,13 @@
-use std::{io, result, string::FromUtf8Error};
+use std::{io, result, string::FromUtf8Error, sync::mpsc::RecvError};
 
-use crossbeam::channel::RecvError;
 use thiserror::Error;
 
 use crate::env;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("data store disconnected")]
-    AlreadyExist,
+    #[error("already exist")]
+    AlreadyExist(String),
     #[error("the data for key is not available")]
     Corruption(String),
 
@@ -17,7 +16,7 @@ pub enum Error {
 
     #[error("invalid data")]
     InvalidData(String),
-    #[error("io read error")]
+    #[error("io error: {source}")]
     IOError {
         #[from]
         source: env::IoError,
@@ -36,6 +35,9 @@ pub enum Error {
     },
     #[error("not find")]
     NotFoundError(String),
+    // the read would have needed io its options didn't allow
+    #[error("incomplete")]
+    Incomplete(String),
 
     #[error("custom error")]
     CustomError(String),
@@ -47,4 +49,104 @@ pub enum Error {
     },
 }
 
+// Iterators hand out copies of the error they keep. An io error can't be copied,
+// the copy has the same kind and message but loses the source.
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        let copy_io = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match self {
+            Error::AlreadyExist(s) => Error::AlreadyExist(s.clone()),
+            Error::Corruption(s) => Error::Corruption(s.clone()),
+            Error::InvalidArgument(s) => Error::InvalidArgument(s.clone()),
+            Error::InvalidData(s) => Error::InvalidData(s.clone()),
+            Error::IOError { source } => Error::IOError {
+                source: source.clone(),
+            },
+            Error::FormatError { source } => Error::FormatError {
+                source: copy_io(source),
+            },
+            Error::ReceiveError { source } => Error::ReceiveError { source: *source },
+            Error::NotFoundError(s) => Error::NotFoundError(s.clone()),
+            Error::Incomplete(s) => Error::Incomplete(s.clone()),
+            Error::CustomError(s) => Error::CustomError(s.clone()),
+            Error::FromUtf8Error { source } => Error::FromUtf8Error {
+                source: source.clone(),
+            },
+        }
+    }
+}
+
+impl Error {
+    // The kind of an io error, `None` for every other error.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Error::IOError { source } => Some(source.kind()),
+            Error::FormatError { source } => Some(source.kind()),
+            _ => None,
+        }
+    }
+
+    // Whether the same call may succeed when tried again. Only interrupted, would
+    // block and timed out io errors are transient. Every other io error, a full
+    // disk or a read only file system among them, is permanent, and so is
+    // corruption or an invalid argument.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.io_kind(),
+            Some(io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+        )
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    // ENOSPC and EROFS on linux
+    const NO_SPACE: i32 = 28;
+    const READ_ONLY_FS: i32 = 30;
+
+    #[test]
+    fn test_io_error_classification() {
+        let io_error = |e: io::Error| Error::from(env::IoError::from(e));
+
+        let interrupted = io_error(io::ErrorKind::Interrupted.into());
+        assert_eq!(interrupted.io_kind(), Some(io::ErrorKind::Interrupted));
+        assert!(interrupted.is_retryable());
+        assert!(io_error(io::ErrorKind::WouldBlock.into()).is_retryable());
+        assert!(Error::from(io::Error::from(io::ErrorKind::TimedOut)).is_retryable());
+
+        for code in [NO_SPACE, READ_ONLY_FS] {
+            let err = io_error(io::Error::from_raw_os_error(code));
+            assert!(err.io_kind().is_some());
+            assert!(!err.is_retryable());
+        }
+        assert!(!io_error(io::ErrorKind::NotFound.into()).is_retryable());
+
+        let corruption = Error::Corruption("bad block".into());
+        assert_eq!(corruption.io_kind(), None);
+        assert!(!corruption.is_retryable());
+    }
+
+    #[test]
+    fn test_clone_keeps_os_error_and_path() {
+        let path = Path::new("db/000005.log");
+        let err =
+            Error::from(env::IoError::from(io::Error::from_raw_os_error(NO_SPACE)).with_path(path));
+        let copy = err.clone();
+        assert_eq!(copy.io_kind(), err.io_kind());
+        assert_eq!(copy.to_string(), err.to_string());
+        assert!(copy.to_string().starts_with("io error: db/000005.log: "));
+        match copy {
+            Error::IOError { source } => {
+                assert_eq!(source.raw_os_error(), Some(NO_SPACE));
+                assert_eq!(source.path(), Some(path));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
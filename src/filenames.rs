@@ -15,7 +15,7 @@ pub type FileNum = u64;
 const CURRENT: &str = "CURRENT";
 const LOCK: &str = "LOCK";
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub enum FileType {
     Log,
     DBLock,
@@ -26,8 +26,20 @@ pub enum FileType {
     InfoLog,
 }
 
+// Only plain decimal digits, `from_str_radix` would also take a leading '+'
+fn parse_file_number(s: &str) -> Option<FileNum> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// Names that are not valid utf-8 can't be ours and are reported as unknown.
 pub fn parse_file_name<P: AsRef<Path>>(f: P) -> Result<(FileNum, FileType)> {
-    let f = f.as_ref().to_str().unwrap();
+    let f = match f.as_ref().to_str() {
+        Some(f) => f,
+        None => return Err(Error::InvalidArgument("file name is not utf-8".into())),
+    };
     if f == CURRENT {
         Ok((0, FileType::Current))
     } else if f == LOCK {
@@ -36,7 +48,7 @@ pub fn parse_file_name<P: AsRef<Path>>(f: P) -> Result<(FileNum, FileType)> {
         Ok((0, FileType::InfoLog))
     } else if f.starts_with("MANIFEST-") {
         if let Some(ix) = f.find('-') {
-            if let Ok(num) = FileNum::from_str_radix(&f[ix + 1..], 10) {
+            if let Some(num) = parse_file_number(&f[ix + 1..]) {
                 Ok((num, FileType::Descriptor))
             } else {
                 Err(Error::InvalidArgument(
@@ -47,7 +59,7 @@ pub fn parse_file_name<P: AsRef<Path>>(f: P) -> Result<(FileNum, FileType)> {
             Err(Error::InvalidArgument("manifest file format wrong".into()))
         }
     } else if let Some(ix) = f.find('.') {
-        if let Ok(num) = FileNum::from_str_radix(&f[..ix], 10) {
+        if let Some(num) = parse_file_number(&f[..ix]) {
             match &f[ix + 1..] {
                 "log" => Ok((num, FileType::Log)),
                 "sst" | "ldb" => Ok((num, FileType::Table)),
@@ -62,24 +74,32 @@ pub fn parse_file_name<P: AsRef<Path>>(f: P) -> Result<(FileNum, FileType)> {
     }
 }
 
+// File numbers are zero padded to six digits and, like in C++ leveldb, just grow
+// more digits past 999999. Names of different widths don't sort by number, so
+// order files by their parsed number rather than by name.
+fn numbered_file_name<P: AsRef<Path>>(name: P, num: FileNum, suffix: &str) -> PathBuf {
+    name.as_ref().join(format!("{:06}.{}", num, suffix))
+}
+
 pub fn table_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
     assert!(num > 0);
-    name.as_ref().join(format!("{:06}.ldb", num))
+    numbered_file_name(name, num, "ldb")
 }
 
 pub fn log_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
-    name.as_ref().join(format!("{:0>6}.log", num))
+    numbered_file_name(name, num, "log")
 }
 
 pub fn sst_table_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
-    name.as_ref().join(format!("{:0>6}.sst", num))
+    numbered_file_name(name, num, "sst")
 }
 
 pub fn descriptor_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
-    name.as_ref().join(format!("MANIFEST-{:0>6}", num))
+    name.as_ref().join(format!("MANIFEST-{:06}", num))
 }
+
 pub fn temp_file_name<P: AsRef<Path>>(name: P, num: FileNum) -> PathBuf {
-    name.as_ref().join(format!("{:0>6}.dbtmp", num))
+    numbered_file_name(name, num, "dbtmp")
 }
 
 pub fn current_file_name<P: AsRef<Path>>(name: P) -> PathBuf {
@@ -98,16 +118,105 @@ pub fn old_info_log_file_name<P: AsRef<Path>>(name: P) -> PathBuf {
     name.as_ref().join("LOG.old")
 }
 
-pub fn set_current_file<E: Env>(env: E, db_name: &String, descriptor_num: u64) -> Result<()> {
+pub fn set_current_file<E: Env>(env: E, db_name: &Path, descriptor_num: u64) -> Result<()> {
     let manifest = descriptor_file_name(db_name, descriptor_num);
-    let mut content = manifest.to_str().unwrap()[db_name.len() + 1..].to_owned();
+    // the manifest name itself is always ascii, only db_name may not be
+    let mut content = manifest
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap()
+        .to_owned();
     content.push('\n');
     let tmp = temp_file_name(db_name, descriptor_num);
 
     let res = write_string_to_file_sync(env.clone(), content.as_bytes(), &tmp);
-    if res.is_ok() {
-        Ok(env.rename_file(&tmp, &current_file_name(db_name))?)
-    } else {
-        Ok(env.delete_file(&tmp)?)
+    if let Err(err) = res {
+        let _ = env.delete_file(&tmp);
+        return Err(err.into());
+    }
+    Ok(env.rename_file(&tmp, &current_file_name(db_name))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::env::DefaultEnv;
+
+    fn parse(path: PathBuf) -> (FileNum, FileType) {
+        parse_file_name(path.file_name().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_format_identity() {
+        let mut rng = rand::thread_rng();
+        let mut numbers = vec![
+            1,
+            9,
+            999_999,
+            1_000_000,
+            123_456_789,
+            u64::MAX - 1,
+            u64::MAX,
+        ];
+        numbers.extend((0..1000).map(|_| rng.gen_range(1..=u64::MAX) >> rng.gen_range(0..64)));
+        for num in numbers.into_iter().filter(|&num| num > 0) {
+            let names = [
+                (table_file_name("db", num), FileType::Table),
+                (sst_table_file_name("db", num), FileType::Table),
+                (log_file_name("db", num), FileType::Log),
+                (descriptor_file_name("db", num), FileType::Descriptor),
+                (temp_file_name("db", num), FileType::Temp),
+            ];
+            for (name, file_type) in names {
+                let (parsed, parsed_type) = parse(name.clone());
+                assert_eq!(parsed, num, "{:?}", name);
+                assert!(parsed_type == file_type, "{:?}", name);
+            }
+        }
+        assert_eq!(log_file_name("db", 7), Path::new("db/000007.log"));
+        assert_eq!(log_file_name("db", 1234567), Path::new("db/1234567.log"));
+
+        for name in [
+            "+12.log",
+            "-1.log",
+            ".log",
+            "MANIFEST-",
+            "MANIFEST-+1",
+            "1 .ldb",
+        ] {
+            assert!(parse_file_name(name).is_err(), "{}", name);
+        }
+        // a name longer than the numbers go is not ours
+        assert!(parse_file_name("18446744073709551616.log").is_err());
+    }
+
+    #[test]
+    fn test_sort_by_number() {
+        let dir = tempfile::Builder::new()
+            .prefix("filenames")
+            .tempdir()
+            .unwrap();
+        let mut numbers = vec![5, 999_998, 999_999, 1_000_000, 1_000_001, 20_000_000, 100];
+        for &num in numbers.iter() {
+            std::fs::write(table_file_name(dir.path(), num), b"").unwrap();
+        }
+
+        let env = DefaultEnv::default();
+        let mut children = Vec::new();
+        env.get_children(dir.path(), &mut children).unwrap();
+        children.sort();
+        // by name 1000000.ldb comes before 999999.ldb
+        let by_name: Vec<_> = children
+            .iter()
+            .map(|f| parse_file_name(f).unwrap().0)
+            .collect();
+        numbers.sort_unstable();
+        assert_ne!(by_name, numbers);
+
+        let mut by_number = by_name;
+        by_number.sort_unstable();
+        assert_eq!(by_number, numbers);
     }
 }
@@ -1,7 +1,9 @@
-use crate::{slice::UnsafeSlice, utils::hash::bloom_hash};
+use crate::{
+    format::extract_user_key, slice::UnsafeSlice, types::TAG_SIZE, utils::hash::bloom_hash,
+};
 use std::{cmp, sync::Arc};
 
-pub trait FilterPolicy {
+pub trait FilterPolicy: Send + Sync {
     fn name(&self) -> &'static str;
 
     fn create_filter(&self, keys: &[UnsafeSlice], dst: &mut Vec<u8>);
@@ -78,6 +80,37 @@ impl FilterPolicy for BloomFilterPolicy {
     }
 }
 
+// Builds and probes the filters of a db's tables on the user key part of internal
+// keys, so a lookup at any sequence matches every entry of the key.
+pub struct InternalFilterPolicy {
+    user_policy: Arc<dyn FilterPolicy>,
+}
+
+impl InternalFilterPolicy {
+    pub fn wrap(user_policy: Arc<dyn FilterPolicy>) -> Arc<dyn FilterPolicy> {
+        Arc::new(InternalFilterPolicy { user_policy })
+    }
+}
+
+impl FilterPolicy for InternalFilterPolicy {
+    // tables record the name of the user policy
+    fn name(&self) -> &'static str {
+        self.user_policy.name()
+    }
+
+    fn create_filter(&self, keys: &[UnsafeSlice], dst: &mut Vec<u8>) {
+        let user_keys: Vec<_> = keys
+            .iter()
+            .map(|k| UnsafeSlice::new(k.data(), k.size() - TAG_SIZE))
+            .collect();
+        self.user_policy.create_filter(&user_keys, dst)
+    }
+
+    fn key_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        self.user_policy.key_match(extract_user_key(key), filter)
+    }
+}
+
 struct BloomFilterFactory {
     keys: Vec<UnsafeSlice>,
     policy: Arc<dyn FilterPolicy>,
@@ -102,6 +135,8 @@ impl BloomFilterFactory {
 
 #[cfg(test)]
 mod tests {
+    use crate::{format::InternalKey, types::ValueType};
+
     use super::*;
 
     struct BloomTest {}
@@ -118,4 +153,26 @@ mod tests {
         assert!(policy.key_match("hello".as_bytes(), &filter));
         assert!(policy.key_match("time".as_bytes(), &filter));
     }
+
+    #[test]
+    fn test_internal_filter_policy() {
+        let policy = InternalFilterPolicy::wrap(Arc::new(BloomFilterPolicy::new(10)));
+        assert_eq!(policy.name(), "leveldb.BuiltinBloomFilter2");
+        let keys = [
+            InternalKey::new(b"hello", 7, ValueType::Value),
+            InternalKey::new(b"time", 3, ValueType::Deletetion),
+        ];
+        let slices: Vec<_> = keys
+            .iter()
+            .map(|k| UnsafeSlice::new(k.encode().as_ptr(), k.encode().len()))
+            .collect();
+        let mut filter = Vec::new();
+        policy.create_filter(&slices, &mut filter);
+
+        // any sequence of a key in the filter matches
+        for key in [&b"hello"[..], b"time"] {
+            let lookup = InternalKey::new(key, 100, ValueType::Value);
+            assert!(policy.key_match(lookup.encode(), &filter));
+        }
+    }
 }
@@ -1,8 +1,8 @@
 use std::fmt::Debug;
 
 use crate::{
-    codec::{NumberReader, NumberWriter},
-    types::{SequenceNumber, MAX_SEQUENCE_NUMBER},
+    error::{Error, Result},
+    types::{SequenceNumber, Tag, TAG_SIZE},
     ValueType,
 };
 
@@ -25,7 +25,7 @@ impl InternalKey {
     }
 
     pub fn new(key: &[u8], s: SequenceNumber, t: ValueType) -> Self {
-        let mut rep = Vec::with_capacity(key.len() + 8);
+        let mut rep = Vec::with_capacity(key.len() + TAG_SIZE);
         let parsed_key = ParsedInternalKey {
             user_key: key,
             sequence: s,
@@ -40,8 +40,7 @@ impl InternalKey {
     }
 
     pub fn user_key(&self) -> &[u8] {
-        assert!(self.rep.len() > 8);
-        &self.rep[..(self.rep.len() - 8)]
+        extract_user_key(&self.rep)
     }
     pub fn encode(&self) -> &[u8] {
         self.rep.as_slice()
@@ -52,10 +51,6 @@ impl InternalKey {
     }
 }
 
-pub fn pack_sequence_and_type(seq: u64, t: ValueType) -> u64 {
-    assert!(seq <= MAX_SEQUENCE_NUMBER);
-    (seq << 8) | t as u64
-}
 pub struct ParsedInternalKey<'a> {
     pub user_key: &'a [u8],
     pub sequence: SequenceNumber,
@@ -65,38 +60,64 @@ pub struct ParsedInternalKey<'a> {
 impl<'a> ParsedInternalKey<'a> {
     pub fn append(&self, result: &mut Vec<u8>) {
         result.extend_from_slice(self.user_key);
-        let mut buf = [0u8; 8];
-        buf.as_mut()
-            .write_u64_le(pack_sequence_and_type(self.sequence, self.val_type))
-            .unwrap();
-        result.extend_from_slice(&buf);
+        Tag::new(self.sequence, self.val_type).encode_to(result);
+    }
+
+    // like `parse`, but a short key or an unknown value type is corruption
+    pub fn try_parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < TAG_SIZE {
+            return Err(Error::Corruption("internal key is too short".into()));
+        }
+        Ok(ParsedInternalKey {
+            user_key: extract_user_key(data),
+            sequence: extract_tag(data).seq(),
+            val_type: extract_tag(data).value_type()?,
+        })
     }
 
     pub fn parse(data: &'a [u8]) -> Self {
-        assert!(data.len() >= 8);
-        let user_key = &data[0..data.len() - 8];
-        let mut buf = data;
-        let tag = buf.read_u64_le().unwrap();
-        let sequence = tag >> 8;
-        let val_type = ValueType::try_from((tag & 0xff) as u8).unwrap();
+        assert!(data.len() >= TAG_SIZE);
+        let user_key = extract_user_key(data);
+        let tag = extract_tag(data);
 
         ParsedInternalKey {
             user_key,
-            sequence,
-            val_type,
+            sequence: tag.seq(),
+            val_type: tag.value_type().unwrap(),
         }
     }
 }
 
 pub fn extract_user_key(internal_key: &[u8]) -> &[u8] {
-    let internal_key = internal_key;
-    assert!(internal_key.len() >= 8);
-    &internal_key[..internal_key.len() - 8]
+    assert!(internal_key.len() >= TAG_SIZE);
+    &internal_key[..internal_key.len() - TAG_SIZE]
 }
 
-pub fn extract_sequence_key<T: AsRef<[u8]>>(internal_key: T) -> u64 {
+pub fn extract_tag<T: AsRef<[u8]>>(internal_key: T) -> Tag {
     let internal_key = internal_key.as_ref();
-    assert!(internal_key.len() >= 8);
-    let mut buf = &internal_key[internal_key.len() - 8..];
-    buf.read_u64_le().unwrap()
+    assert!(internal_key.len() >= TAG_SIZE);
+    Tag::decode_from(&internal_key[internal_key.len() - TAG_SIZE..]).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parsed_internal_key_round_trip() {
+        for (key, seq, t) in [
+            (&b""[..], 0, ValueType::Value),
+            (&b"key"[..], 1, ValueType::Deletetion),
+            (&b"a longer user key"[..], 1 << 50, ValueType::Value),
+        ] {
+            let ikey = InternalKey::new(key, seq, t);
+            assert_eq!(ikey.user_key(), key);
+            assert_eq!(extract_tag(ikey.encode()), Tag::new(seq, t));
+
+            let parsed = ParsedInternalKey::parse(ikey.encode());
+            assert_eq!(parsed.user_key, key);
+            assert_eq!(parsed.sequence, seq);
+            assert_eq!(parsed.val_type, t);
+        }
+    }
 }
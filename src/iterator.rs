@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 pub trait DBIterator {
     fn valid(&self) -> bool;
@@ -13,9 +13,225 @@ pub trait DBIterator {
 
     fn prev(&mut self);
 
+    // The entry the iterator is at. Only a valid iterator has one, both panic on
+    // an invalid iterator.
     fn key(&self) -> &[u8];
 
     fn value(&self) -> &[u8];
 
-    fn status(&mut self) -> Result<()>;
+    // The first error the iterator ran into, if any. Errors are sticky, once one is
+    // reported every later call reports it again, wherever the iterator moved.
+    fn status(&self) -> Result<()>;
+}
+
+// Scratch key buffers keep the capacity of the longest key they ever held. Once
+// that is over 4x what the current key needs, and over the floor, the buffer is
+// shrunk back so a few huge keys don't pin memory for the life of the iterator.
+pub const SCRATCH_FLOOR: usize = 4096;
+
+pub fn shrink_scratch(buf: &mut Vec<u8>) {
+    if buf.capacity() > SCRATCH_FLOOR && buf.capacity() > 4 * buf.len() {
+        buf.shrink_to(SCRATCH_FLOOR.max(buf.len()));
+    }
+}
+
+// Keeps `pinned` alive as long as the iterator, like the version whose tables it
+// reads: their files are deleted once no version refers to them.
+pub struct PinnedIterator<T> {
+    iter: Box<dyn DBIterator>,
+    _pinned: T,
+}
+
+impl<T> PinnedIterator<T> {
+    pub fn new(iter: Box<dyn DBIterator>, pinned: T) -> Self {
+        PinnedIterator {
+            iter,
+            _pinned: pinned,
+        }
+    }
+}
+
+impl<T> DBIterator for PinnedIterator<T> {
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        self.iter.seek(target)
+    }
+
+    fn next(&mut self) {
+        self.iter.next()
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+}
+
+// Builds the wrapped iterator on its first positioning, until then it's invalid
+// and costs nothing. A failed build is reported by `status`.
+pub struct LazyIterator<F: FnOnce() -> Result<Box<dyn DBIterator>>> {
+    build: Option<F>,
+    iter: Option<Box<dyn DBIterator>>,
+    err: Option<Error>,
+}
+
+impl<F: FnOnce() -> Result<Box<dyn DBIterator>>> LazyIterator<F> {
+    pub fn new(build: F) -> Self {
+        LazyIterator {
+            build: Some(build),
+            iter: None,
+            err: None,
+        }
+    }
+
+    fn built(&mut self) -> Option<&mut Box<dyn DBIterator>> {
+        if let Some(build) = self.build.take() {
+            match build() {
+                Ok(iter) => self.iter = Some(iter),
+                Err(err) => self.err = Some(err),
+            }
+        }
+        self.iter.as_mut()
+    }
+}
+
+impl<F: FnOnce() -> Result<Box<dyn DBIterator>>> DBIterator for LazyIterator<F> {
+    fn valid(&self) -> bool {
+        self.iter.as_ref().is_some_and(|iter| iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        if let Some(iter) = self.built() {
+            iter.seek_to_first();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if let Some(iter) = self.built() {
+            iter.seek_to_last();
+        }
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        if let Some(iter) = self.built() {
+            iter.seek(target);
+        }
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.iter.as_mut().unwrap().next();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        self.iter.as_mut().unwrap().prev();
+    }
+
+    fn key(&self) -> &[u8] {
+        assert!(self.valid());
+        self.iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        assert!(self.valid());
+        self.iter.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        match (&self.err, &self.iter) {
+            (Some(err), _) => Err(err.clone()),
+            (None, Some(iter)) => iter.status(),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // an empty iterator that failed with `Corruption(msg)`
+    pub(crate) struct ErrorIter(pub &'static str);
+
+    impl DBIterator for ErrorIter {
+        fn valid(&self) -> bool {
+            false
+        }
+        fn seek_to_first(&mut self) {}
+        fn seek_to_last(&mut self) {}
+        fn seek(&mut self, _target: &[u8]) {}
+        fn next(&mut self) {
+            unreachable!()
+        }
+        fn prev(&mut self) {
+            unreachable!()
+        }
+        fn key(&self) -> &[u8] {
+            unreachable!()
+        }
+        fn value(&self) -> &[u8] {
+            unreachable!()
+        }
+        fn status(&self) -> Result<()> {
+            Err(Error::Corruption(self.0.into()))
+        }
+    }
+
+    // checks that `iter` reports `msg`, and keeps reporting it
+    pub(crate) fn assert_sticky_corruption(iter: &dyn DBIterator, msg: &str) {
+        for _ in 0..2 {
+            match iter.status() {
+                Err(Error::Corruption(s)) => assert_eq!(s, msg),
+                other => panic!("expected corruption, got {:?}", other),
+            }
+        }
+    }
+
+    // checks that `iter` is exhausted and that reading its entry panics
+    pub(crate) fn assert_exhausted(iter: &dyn DBIterator) {
+        assert!(!iter.valid());
+        let key = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| iter.key().len()));
+        assert!(key.is_err());
+        let value = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| iter.value().len()));
+        assert!(value.is_err());
+    }
+
+    #[test]
+    fn test_lazy_iterator() {
+        let mut built = false;
+        let mut iter = LazyIterator::new(|| {
+            built = true;
+            Err(Error::Corruption("no table".into()))
+        });
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+        iter.seek(b"a");
+        assert_sticky_corruption(&iter, "no table");
+        assert_exhausted(&iter);
+        drop(iter);
+        assert!(built);
+    }
 }
@@ -11,16 +11,20 @@ mod cmp;
 mod codec;
 mod consts;
 mod db_impl;
+mod db_iter;
 mod env;
 mod error;
 mod filenames;
 mod filter;
 mod format;
 mod iterator;
+mod listener;
 mod options;
+mod rate_limiter;
 mod skiplist;
 mod slice;
 mod sstable;
+mod stats;
 mod table_cache;
 mod types;
 mod utils;
@@ -29,14 +33,46 @@ mod version_edit;
 mod version_set;
 mod write_batch;
 mod merge;
+mod repair;
+mod snapshot;
+#[cfg(test)]
 mod datadriven;
+mod trace;
+#[cfg(feature = "serde")]
+mod typed;
 
-pub use db_impl::LevelDB;
-pub use log::{LogReader, LogWriter};
-pub use memtable::{LookupKey, MemTable};
-pub use options::{Options, ReadOption, WriteOption};
+pub use cmp::{BitWiseComparator, Comparator};
+pub use db::DbBuilder;
+pub use db_impl::{LevelDB, LiveFilesInfo, LiveTableFile, PendingFlush};
+pub use db_iter::DBIter;
+pub use env::Env;
+pub use error::{Error, Result};
+pub use filter::{BloomFilterPolicy, FilterPolicy};
+pub use iterator::DBIterator;
+pub use listener::{CompactionInfo, CompactionKind, EventListener, TableFileInfo};
+pub use log::{LogReader, LogReporter, LogWriter};
+pub use memtable::{LookupKey, MemGet, MemTable};
+pub use options::{Compress, Options, OptionsError, ReadOption, ReadTier, WriteOption};
+pub use rate_limiter::{IoPriority, RateLimiter};
+pub use snapshot::Snapshot;
+pub use sstable::{block::Block, Table, TableBuiler};
 pub use types::ValueType;
+pub use version::CompactionDebt;
+pub use write_batch::WriteBatch;
+#[cfg(unix)]
 pub use env::posix::PosixEnv;
+pub use env::std_env::StdEnv;
+pub use env::mem::MemEnv;
+pub use env::{DefaultEnv, Logger};
+#[cfg(feature = "serde")]
+pub use typed::{
+    BincodeCodec, KeyCodec, OrderedKey, OrderedKeyCodec, TypedBatch, TypedDb, TypedIter, ValueCodec,
+};
+
+// caches a db can share with the rest of a program, see `Options::block_cache`
+pub mod cache {
+    pub use crate::utils::cache::{Cache, EvictionListener, ShardLruCache};
+}
 
 
 #[derive(PartialEq)]
@@ -0,0 +1,36 @@
+// Callbacks for background work. Listeners are invoked from the thread that did
+// the work, with no db lock held, so they must not block for long.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionKind {
+    // the input file was moved to the next level by a version edit only
+    Moved,
+    // inputs were merged and written out as new tables
+    Rewritten,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionInfo {
+    pub kind: CompactionKind,
+    pub level: usize,
+    pub output_level: usize,
+    pub input_files: Vec<u64>,
+    pub output_files: Vec<u64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+// A table written by a memtable flush or a compaction.
+#[derive(Clone, Debug)]
+pub struct TableFileInfo {
+    pub number: u64,
+    pub level: usize,
+    pub file_size: u64,
+    // Env::now_micros when the table was written
+    pub creation_time: u64,
+}
+
+pub trait EventListener: Send + Sync {
+    fn on_compaction_completed(&self, _info: &CompactionInfo) {}
+    fn on_table_file_created(&self, _info: &TableFileInfo) {}
+}
@@ -5,8 +5,16 @@ use crate::codec::{NumberReader, NumberWriter};
 use crate::env::{SequencialFile, WritableFile};
 use crate::error::{Error, Result};
 
-const BLOCK_SIZE: usize = 32 * 1024;
-const HEADER_SIZE: usize = 4 + 2 + 1;
+// The log is a sequence of 32KB blocks, a block tail shorter than a header is
+// zero filled. Each record fragment is written as
+//
+//   checksum: fixed32, length: fixed16, type: u8, data: [u8; length]
+//
+// checksum is the unmasked crc32c of the type byte followed by data, or 0 for a
+// fragment written without one, type is a `RecordType`. Records that don't fit the rest of a block are split into
+// First, Middle.., Last fragments.
+pub const BLOCK_SIZE: usize = 32 * 1024;
+pub const HEADER_SIZE: usize = 4 + 2 + 1;
 
 #[derive(Clone, Copy)]
 pub enum RecordType {
@@ -16,14 +24,14 @@ pub enum RecordType {
     Last = 4,
 }
 
-impl From<u8> for RecordType {
-    fn from(b: u8) -> Self {
+impl RecordType {
+    fn from_u8(b: u8) -> Option<Self> {
         match b {
-            1 => RecordType::Full,
-            2 => RecordType::First,
-            3 => RecordType::Middle,
-            4 => RecordType::Last,
-            _ => panic!("unrecognized record type"),
+            1 => Some(RecordType::Full),
+            2 => Some(RecordType::First),
+            3 => Some(RecordType::Middle),
+            4 => Some(RecordType::Last),
+            _ => None,
         }
     }
 }
@@ -33,6 +41,9 @@ pub struct LogWriter<W: WritableFile> {
     current_block_offset: usize,
     block_size: usize,
     digest: Crc<u32>,
+    checksums: bool,
+    // physical size of the log, headers and padding included
+    bytes_written: u64,
 }
 
 impl<W: WritableFile> LogWriter<W> {
@@ -42,18 +53,42 @@ impl<W: WritableFile> LogWriter<W> {
             current_block_offset: 0,
             block_size: BLOCK_SIZE,
             digest: Crc::<u32>::new(&CRC_32_ISCSI),
+            checksums: true,
+            bytes_written: 0,
         }
     }
 
+    // Writes zero checksums, which readers don't verify.
+    pub fn without_checksums(mut self) -> LogWriter<W> {
+        self.checksums = false;
+        self
+    }
+
+    // A writer appending to a log already `dest_len` bytes long, going on in
+    // the block its last record ended in.
+    pub fn new_with_dest_len(writer: W, dest_len: u64) -> LogWriter<W> {
+        let mut log = Self::new(writer);
+        log.current_block_offset = (dest_len % log.block_size as u64) as usize;
+        log.bytes_written = dest_len;
+        log
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    // An empty record is written as a single Full fragment without data, so the
+    // reader gives it back like any other record.
     pub fn add_record<P: AsRef<[u8]>>(&mut self, record: P) -> Result<()> {
         let mut record = record.as_ref();
         let mut first_frag = true;
-        while !record.is_empty() {
+        loop {
             assert!(self.block_size > HEADER_SIZE);
 
             let left = self.block_size - self.current_block_offset;
             if left < HEADER_SIZE {
                 self.writer.append(&vec![0; left])?;
+                self.bytes_written += left as u64;
                 self.current_block_offset = 0;
             }
 
@@ -79,16 +114,21 @@ impl<W: WritableFile> LogWriter<W> {
 
             record = &record[data_frag_size..];
             first_frag = false;
+            if record.is_empty() {
+                return Ok(());
+            }
         }
-        Ok(())
     }
 
     fn emit_record(&mut self, t: RecordType, data: &[u8], len: usize) -> Result<()> {
-        let mut digest = self.digest.digest();
-        digest.update(&[t as u8]);
-        digest.update(data);
-        let chksum = digest.finalize();
-        // let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        let chksum = if self.checksums {
+            let mut digest = self.digest.digest();
+            digest.update(&[t as u8]);
+            digest.update(data);
+            digest.finalize()
+        } else {
+            0
+        };
 
         let mut buf = Cursor::new([0; HEADER_SIZE]);
         buf.write_u32_le(chksum)?;
@@ -97,10 +137,11 @@ impl<W: WritableFile> LogWriter<W> {
 
         self.writer.append(buf.get_ref())?;
         self.writer.append(data)?;
+        self.bytes_written += (HEADER_SIZE + len) as u64;
 
         self.writer.flush()?;
 
-        self.current_block_offset = HEADER_SIZE + len;
+        self.current_block_offset += HEADER_SIZE + len;
 
         Ok(())
     }
@@ -115,6 +156,10 @@ impl<W: WritableFile> LogWriter<W> {
     }
 }
 
+// Called with the number of bytes a reader drops and why, see
+// `LogReader::new_with_reporter`.
+pub type LogReporter = Box<dyn FnMut(usize, &str)>;
+
 pub struct LogReader<R: SequencialFile> {
     file: R,
     crc: Crc<u32>,
@@ -122,13 +167,13 @@ pub struct LogReader<R: SequencialFile> {
     block_size: usize,
     head_scratch: [u8; HEADER_SIZE],
     checksum: bool,
+    reporter: Option<LogReporter>,
 
     eof: bool,
-    buf: Vec<u8>,
-    buf_length: usize,
 }
 
 impl<R: SequencialFile> LogReader<R> {
+    // A reader failing with a corruption error on the first damaged record.
     pub fn new(file: R, checksum: bool) -> Self {
         LogReader {
             file,
@@ -137,77 +182,174 @@ impl<R: SequencialFile> LogReader<R> {
             block_size: BLOCK_SIZE,
             head_scratch: Default::default(),
             checksum,
+            reporter: None,
 
             eof: false,
-            buf: vec![0; BLOCK_SIZE],
-            buf_length: 0,
         }
     }
 
-    pub fn clear_buf(&mut self) {
-        self.buf = vec![0; BLOCK_SIZE];
-        self.buf_length = 0;
+    // A reader handing damaged records to `reporter` and going on with the next
+    // block, like a reader of leveldb: a torn or flipped write costs the records
+    // of its block only. A record torn at the end of the log is reported too.
+    pub fn new_with_reporter(file: R, checksum: bool, reporter: LogReporter) -> Self {
+        let mut reader = Self::new(file, checksum);
+        reader.reporter = Some(reporter);
+        reader
     }
 
-    pub fn read_physical_record(&mut self, dst: &mut Vec<u8>) -> Result<Option<usize>> {
+    // Reads the next record into `dst` and returns its length, `None` at the end
+    // of the log.
+    pub fn read_record(&mut self, dst: &mut Vec<u8>) -> Result<Option<usize>> {
         dst.clear();
-        let mut dst_offset: usize = 0;
+        // the fragments of the record read so far are in `dst`
+        let mut in_fragmented_record = false;
         loop {
-            if self.block_size - self.blk_off < HEADER_SIZE {
-                self.file
-                    .read_exact(&mut self.head_scratch[0..self.block_size - self.blk_off])?;
+            let left = self.block_size - self.blk_off;
+            if left < HEADER_SIZE {
+                let trailer = &mut self.head_scratch[..left];
+                if !Self::read_fully(&mut self.file, trailer, &mut self.eof)? {
+                    return self.end_of_log(dst, in_fragmented_record);
+                }
                 self.blk_off = 0;
             }
-            let res = self.file.read_exact(&mut self.head_scratch);
-            if let Err(err) = res.as_ref() {
-                if err.kind() == ErrorKind::UnexpectedEof {
-                    return Ok(None);
-                }
+            let mut header = [0; HEADER_SIZE];
+            if !Self::read_fully(&mut self.file, &mut header, &mut self.eof)? {
+                return self.end_of_log(dst, in_fragmented_record);
             }
-            res?;
-
             self.blk_off += HEADER_SIZE;
 
-            let mut buf = &self.head_scratch[..];
+            let mut buf = &header[..];
             let checksum = buf.read_u32_le()?;
-            let length = buf.read_u16_le()?;
+            let length = buf.read_u16_le()? as usize;
             let record_type = buf.read_u8_le()?;
 
-            dst.resize(dst_offset + length as usize, 0);
-
-            self.file
-                .read_exact(&mut dst[dst_offset..dst_offset + length as usize])?;
-            self.blk_off += length as usize;
-
-            self.blk_off %= self.block_size;
+            let avail = self.block_size - self.blk_off;
+            if length > avail {
+                // the data it claims runs into the next block, it's the rest
+                self.drop_block(dst, HEADER_SIZE, "bad record length")?;
+                in_fragmented_record = false;
+                continue;
+            }
+            let start = dst.len();
+            dst.resize(start + length, 0);
+            if !Self::read_fully(&mut self.file, &mut dst[start..], &mut self.eof)? {
+                // the write in flight when the process died
+                self.report(start + HEADER_SIZE + length, "truncated record")?;
+                dst.clear();
+                return Ok(None);
+            }
+            // like the writer's, a full block is skipped as an empty trailer
+            self.blk_off += length;
 
-            if self.checksum {
+            if self.checksum && checksum != 0 {
                 let mut digest = self.crc.digest();
                 digest.update(&[record_type]);
-                digest.update(&dst[dst_offset..dst_offset + length as usize]);
+                digest.update(&dst[start..]);
                 if digest.finalize() != checksum {
-                    return Err(Error::Corruption("digest check failed".into()));
+                    dst.truncate(start);
+                    self.drop_block(dst, HEADER_SIZE + length, "digest check failed")?;
+                    in_fragmented_record = false;
+                    continue;
                 }
             }
 
-            dst_offset += length as usize;
-            match RecordType::from(record_type) {
-                RecordType::Full => {
-                    return Ok(Some(dst_offset));
-                }
-                RecordType::First | RecordType::Middle => {
+            let record_type = match RecordType::from_u8(record_type) {
+                Some(record_type) => record_type,
+                None => {
+                    dst.truncate(start);
+                    self.drop_block(dst, HEADER_SIZE + length, "unknown record type")?;
+                    in_fragmented_record = false;
                     continue;
                 }
-                RecordType::Last => {
-                    return Ok(Some(dst_offset));
+            };
+            match record_type {
+                RecordType::Full | RecordType::First => {
+                    if in_fragmented_record {
+                        self.report(start, "partial record without end")?;
+                        dst.drain(..start);
+                    }
+                    if let RecordType::Full = record_type {
+                        return Ok(Some(dst.len()));
+                    }
+                    in_fragmented_record = true;
+                }
+                RecordType::Middle | RecordType::Last => {
+                    if !in_fragmented_record {
+                        let reason = "missing start of fragmented record";
+                        self.report(HEADER_SIZE + length, reason)?;
+                        dst.clear();
+                    } else if let RecordType::Last = record_type {
+                        return Ok(Some(dst.len()));
+                    }
                 }
             }
-            // self.src.read(buf);
         }
     }
 
-    pub fn read_record(&mut self, dst: &mut Vec<u8>) -> Result<Option<usize>> {
-        self.read_physical_record(dst)
+    // Whether the end of the file was reached. Used after a failed read to tell a
+    // record torn by a crash, which is always the last one, from corruption in the
+    // middle of the log. May consume input, don't read records afterwards.
+    pub fn at_eof(&mut self) -> bool {
+        if !self.eof {
+            let mut byte = [0; 1];
+            if let Err(err) = self.file.read_exact(&mut byte) {
+                self.eof = err.kind() == ErrorKind::UnexpectedEof;
+            }
+        }
+        self.eof
+    }
+
+    // false if the file ends before `buf` is filled
+    fn read_fully(file: &mut R, buf: &mut [u8], eof: &mut bool) -> Result<bool> {
+        match file.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                *eof = true;
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // A file cut in a header or a block trailer ends the log, a record with
+    // fragments before the cut is reported.
+    fn end_of_log(
+        &mut self,
+        dst: &mut Vec<u8>,
+        in_fragmented_record: bool,
+    ) -> Result<Option<usize>> {
+        if in_fragmented_record {
+            self.report(dst.len(), "partial record without end")?;
+        }
+        dst.clear();
+        Ok(None)
+    }
+
+    // Reports the fragment just read, `bytes` long, along with the fragments
+    // before it in `dst` and the rest of its block, and skips to the next block.
+    fn drop_block(&mut self, dst: &mut Vec<u8>, bytes: usize, reason: &str) -> Result<()> {
+        let partial = dst.len();
+        let rest = self.block_size - self.blk_off;
+        self.report(partial + bytes + rest, reason)?;
+        dst.clear();
+        if rest > 0 {
+            let mut skipped = vec![0; rest];
+            if Self::read_fully(&mut self.file, &mut skipped, &mut self.eof)? {
+                self.blk_off = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // Without a reporter a damaged record fails the read.
+    fn report(&mut self, bytes: usize, reason: &str) -> Result<()> {
+        match self.reporter.as_mut() {
+            Some(reporter) => {
+                reporter(bytes, reason);
+                Ok(())
+            }
+            None => Err(Error::Corruption(reason.into())),
+        }
     }
 }
 
@@ -216,12 +358,16 @@ mod tests {
     use tempfile::{self, TempDir};
 
     use crate::{
-        env::{posix::PosixEnv, Env},
+        env::{DefaultEnv, Env},
+        utils::hex::check_golden,
         LogReader,
     };
 
-    use super::LogWriter;
+    use super::{LogWriter, RecordType, BLOCK_SIZE, HEADER_SIZE};
     use std::str;
+    use std::sync::{Arc, Mutex};
+
+    use crc::{Crc, CRC_32_ISCSI};
 
     fn create_tmp_file() -> TempDir {
         tempfile::Builder::new()
@@ -236,7 +382,7 @@ mod tests {
         let dir = create_tmp_file();
         let file_path = dir.path().join("test.log");
 
-        let env = PosixEnv {};
+        let env = DefaultEnv::default();
         eprintln!("file path{:?}", file_path);
         let file = env.new_writable_file(&file_path).unwrap();
 
@@ -261,7 +407,7 @@ mod tests {
         let dir = create_tmp_file();
         let file_path = dir.path().join("test.log");
 
-        let env = PosixEnv {};
+        let env = DefaultEnv::default();
         {
             let file = env.new_writable_file(&file_path).unwrap();
 
@@ -270,16 +416,314 @@ mod tests {
                 writer.add_record(*data).unwrap();
             }
             writer.flush().unwrap();
+            let file_size = std::fs::metadata(&file_path).unwrap().len();
+            assert_eq!(writer.bytes_written(), file_size);
         }
 
         let file = env.new_sequential_file(&file_path).unwrap();
         let mut reader = LogReader::new(file, true);
-        for i in 0..datas.len() {
+        for (i, data) in datas.iter().enumerate() {
             let mut dst = Vec::new();
             reader.read_record(&mut dst).unwrap();
             // println!("===={}", String::from_utf8(dst).unwrap());
             println!("read: {}", i);
-            assert_eq!(String::from_utf8(dst).unwrap(), datas[i]);
+            assert_eq!(String::from_utf8(dst).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn test_log_golden() {
+        let datas = ["foo", "bar", "hello leveldb"];
+
+        let dir = create_tmp_file();
+        let file_path = dir.path().join("golden.log");
+        let env = DefaultEnv::default();
+        {
+            let file = env.new_writable_file(&file_path).unwrap();
+            let mut writer = LogWriter::new(file);
+            for data in datas {
+                writer.add_record(data).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        let written = std::fs::read(&file_path).unwrap();
+        let expected = check_golden("format/wal.hex", &written);
+
+        // parse the fixture back
+        std::fs::write(&file_path, expected).unwrap();
+        let file = env.new_sequential_file(&file_path).unwrap();
+        let mut reader = LogReader::new(file, true);
+        for data in datas {
+            let mut dst = Vec::new();
+            reader.read_record(&mut dst).unwrap();
+            assert_eq!(dst, data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let datas = ["foo", "bar", "a record torn by a crash"];
+        let dir = create_tmp_file();
+        let file_path = dir.path().join("torn.log");
+        let env = DefaultEnv::default();
+        {
+            let file = env.new_writable_file(&file_path).unwrap();
+            let mut writer = LogWriter::new(file);
+            for data in datas {
+                writer.add_record(data).unwrap();
+            }
+        }
+        let written = std::fs::read(&file_path).unwrap();
+
+        let read_all = |data: &[u8]| {
+            std::fs::write(&file_path, data).unwrap();
+            let file = env.new_sequential_file(&file_path).unwrap();
+            let mut reader = LogReader::new(file, true);
+            let mut records = Vec::new();
+            loop {
+                let mut dst = Vec::new();
+                match reader.read_record(&mut dst) {
+                    Ok(Some(_)) => records.push(dst),
+                    Ok(None) => return (records, None),
+                    Err(_) => return (records, Some(reader.at_eof())),
+                }
+            }
+        };
+
+        // cut inside the data of the last record
+        let (records, torn) = read_all(&written[..written.len() - 5]);
+        assert_eq!(records, vec![b"foo".to_vec(), b"bar".to_vec()]);
+        assert_eq!(torn, Some(true));
+
+        // a complete but garbled last record
+        let mut garbled = written.clone();
+        *garbled.last_mut().unwrap() ^= 0xff;
+        assert_eq!(read_all(&garbled).1, Some(true));
+
+        // garbage followed by more records
+        let mut garbled = written.clone();
+        garbled[HEADER_SIZE] ^= 0xff;
+        let (records, torn) = read_all(&garbled);
+        assert!(records.is_empty());
+        assert_eq!(torn, Some(false));
+
+        // a cut right after a record is a clean end
+        let end = 2 * HEADER_SIZE + 6;
+        assert_eq!(read_all(&written[..end]).1, None);
+    }
+
+    fn write_records(path: &std::path::Path, records: &[Vec<u8>]) -> u64 {
+        let file = DefaultEnv::default().new_writable_file(path).unwrap();
+        let mut writer = LogWriter::new(file);
+        for record in records {
+            writer.add_record(record).unwrap();
+        }
+        writer.flush().unwrap();
+        writer.bytes_written()
+    }
+
+    fn read_records(path: &std::path::Path) -> Vec<Vec<u8>> {
+        let file = DefaultEnv::default().new_sequential_file(path).unwrap();
+        let mut reader = LogReader::new(file, true);
+        let mut records = Vec::new();
+        loop {
+            let mut dst = vec![b'x'; 3];
+            match reader.read_record(&mut dst).unwrap() {
+                Some(len) => {
+                    assert_eq!(len, dst.len());
+                    records.push(dst);
+                }
+                None => return records,
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_records() {
+        let dir = create_tmp_file();
+        let path = dir.path().join("empty.log");
+        let records = vec![vec![], vec![]];
+        assert_eq!(write_records(&path, &records), 2 * HEADER_SIZE as u64);
+        assert_eq!(read_records(&path), records);
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[4..HEADER_SIZE], &[0, 0, RecordType::Full as u8]);
+
+        // interleaved, around a record spanning blocks and last before the end
+        let records = vec![
+            vec![],
+            b"foo".to_vec(),
+            vec![],
+            vec![],
+            vec![b'a'; BLOCK_SIZE + 100],
+            vec![],
+            b"bar".to_vec(),
+            vec![],
+        ];
+        let path = dir.path().join("interleaved.log");
+        write_records(&path, &records);
+        assert_eq!(read_records(&path), records);
+
+        // an empty record opening a new block, and one filling a block tail exactly
+        // as long as a header
+        for first in [BLOCK_SIZE - HEADER_SIZE, BLOCK_SIZE - 2 * HEADER_SIZE] {
+            let records = vec![vec![b'b'; first], vec![], b"baz".to_vec(), vec![]];
+            let path = dir.path().join(format!("block{}.log", first));
+            let size = write_records(&path, &records);
+            assert_eq!(size, (first + 4 * HEADER_SIZE + 3) as u64);
+            let written = std::fs::read(&path).unwrap();
+            let empty = HEADER_SIZE + first;
+            assert_eq!(&written[empty + 4..empty + HEADER_SIZE], &[0, 0, 1]);
+            assert_eq!(read_records(&path), records);
+        }
+    }
+
+    // every block ends in a fragment or padding, however the records fall
+    #[test]
+    fn test_many_records_across_blocks() {
+        let dir = create_tmp_file();
+        let path = dir.path().join("many.log");
+        let records: Vec<Vec<u8>> = (0..2000).map(|i| vec![i as u8; i % 300]).collect();
+        let size = write_records(&path, &records);
+        assert!(size > 8 * BLOCK_SIZE as u64);
+        assert_eq!(read_records(&path), records);
+    }
+
+    #[test]
+    fn test_round_trip_varying_sizes() {
+        let dir = create_tmp_file();
+        let path = dir.path().join("varying.log");
+        let mut seed = 301u32;
+        let mut records: Vec<Vec<u8>> = (0..5000)
+            .map(|i| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let len = match seed >> 28 {
+                    0 => (seed >> 8) as usize % (3 * BLOCK_SIZE),
+                    1..=3 => (seed >> 8) as usize % 1000,
+                    _ => (seed >> 8) as usize % 20,
+                };
+                (0..len).map(|j| (i + j) as u8).collect()
+            })
+            .collect();
+        // records ending exactly at a block boundary, or leaving a trailer
+        for rest in 0..=HEADER_SIZE {
+            let used: usize = write_records(&path, &records) as usize % BLOCK_SIZE;
+            let len = (2 * BLOCK_SIZE - used - HEADER_SIZE - rest) % BLOCK_SIZE;
+            records.push(vec![rest as u8; len]);
+        }
+        records.push(b"last".to_vec());
+        let size = write_records(&path, &records);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert!(size > 100 * BLOCK_SIZE as u64);
+        assert_eq!(read_records(&path), records);
+    }
+
+    // reads all records of `data` with a reporter, and what it reported
+    fn read_reported(path: &std::path::Path, data: &[u8]) -> (Vec<Vec<u8>>, Vec<(usize, String)>) {
+        std::fs::write(path, data).unwrap();
+        let file = DefaultEnv::default().new_sequential_file(path).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reporter = {
+            let reports = reports.clone();
+            Box::new(move |bytes: usize, reason: &str| {
+                reports.lock().unwrap().push((bytes, reason.to_string()))
+            })
+        };
+        let mut reader = LogReader::new_with_reporter(file, true, reporter);
+        let mut records = Vec::new();
+        let mut dst = Vec::new();
+        while reader.read_record(&mut dst).unwrap().is_some() {
+            records.push(dst.clone());
+        }
+        let reports = reports.lock().unwrap().clone();
+        (records, reports)
+    }
+
+    #[test]
+    fn test_reporter_skips_damaged_blocks() {
+        let dir = create_tmp_file();
+        let path = dir.path().join("damaged.log");
+        // 32 records and the first fragment of the 33rd fill the first block
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; 1000]).collect();
+        write_records(&path, &records);
+        let written = std::fs::read(&path).unwrap();
+        let record_size = HEADER_SIZE + 1000;
+        let spanning = BLOCK_SIZE / record_size;
+        let after_first_block = || records[..5].iter().chain(&records[spanning + 1..]);
+
+        // a flipped bit costs the rest of the block and the record running into
+        // the next one
+        let mut flipped = written.clone();
+        flipped[5 * record_size + HEADER_SIZE + 10] ^= 0x10;
+        let (read, reports) = read_reported(&path, &flipped);
+        assert!(read.iter().eq(after_first_block()));
+        let rest_of_record = 1000 - (BLOCK_SIZE - spanning * record_size - HEADER_SIZE);
+        assert_eq!(
+            reports,
+            vec![
+                (BLOCK_SIZE - 5 * record_size, "digest check failed".into()),
+                (
+                    HEADER_SIZE + rest_of_record,
+                    "missing start of fragmented record".into()
+                )
+            ]
+        );
+
+        // so does a length running past the block
+        let mut bad_length = written.clone();
+        bad_length[5 * record_size + 4..5 * record_size + 6].copy_from_slice(&[0xff, 0xff]);
+        let (read, reports) = read_reported(&path, &bad_length);
+        assert!(read.iter().eq(after_first_block()));
+        assert_eq!(
+            reports[0],
+            (BLOCK_SIZE - 5 * record_size, "bad record length".into())
+        );
+
+        // and an unknown record type with a valid checksum
+        let mut bad_type = written.clone();
+        let at = 5 * record_size;
+        bad_type[at + 6] = 9;
+        let checksum = Crc::<u32>::new(&CRC_32_ISCSI).checksum(&bad_type[at + 6..at + record_size]);
+        bad_type[at..at + 4].copy_from_slice(&checksum.to_le_bytes());
+        let (read, reports) = read_reported(&path, &bad_type);
+        assert!(read.iter().eq(after_first_block()));
+        assert_eq!(reports[0].1, "unknown record type");
+
+        // a cut in the last record drops it alone
+        let (read, reports) = read_reported(&path, &written[..written.len() - 10]);
+        assert_eq!(read, records[..99]);
+        assert_eq!(reports, vec![(record_size, "truncated record".into())]);
+        let (read, reports) = read_reported(&path, &written);
+        assert_eq!(read, records);
+        assert!(reports.is_empty());
+    }
+
+    #[test]
+    fn test_records_without_checksums() {
+        let dir = create_tmp_file();
+        let path = dir.path().join("unchecked.log");
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; 1000]).collect();
+        let file = DefaultEnv::default().new_writable_file(&path).unwrap();
+        let mut writer = LogWriter::new(file).without_checksums();
+        for record in records.iter() {
+            writer.add_record(record).unwrap();
         }
+        writer.flush().unwrap();
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[..4], &[0; 4]);
+        assert_eq!(read_records(&path), records);
+
+        // a flip goes unnoticed in a record without a checksum, but not in one
+        // with a checksum
+        let record_size = HEADER_SIZE + 1000;
+        let mut flipped = written;
+        flipped[5 * record_size + HEADER_SIZE + 10] ^= 0x10;
+        let (read, reports) = read_reported(&path, &flipped);
+        assert_eq!(read.len(), records.len());
+        assert!(reports.is_empty());
+        write_records(&path, &records);
+        let mut flipped = std::fs::read(&path).unwrap();
+        flipped[5 * record_size + HEADER_SIZE + 10] ^= 0x10;
+        let (_, reports) = read_reported(&path, &flipped);
+        assert_eq!(reports[0].1, "digest check failed");
     }
 }
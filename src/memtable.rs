@@ -1,7 +1,6 @@
 use std::{
     cmp::Ordering,
     io::Write,
-    rc::Rc,
     sync::{
         atomic::{self, AtomicUsize},
         Arc,
@@ -10,31 +9,59 @@ use std::{
 
 use crate::{
     cmp::{Comparator, InternalKeyComparator, KeyComparator},
-    codec::{self, NumberReader, NumberWriter, VarIntReader, VarIntWriter},
+    codec::{self, VarIntReader, VarIntWriter},
     error::{Error, Result},
+    format::extract_tag,
     iterator::DBIterator,
     skiplist::{SkipList, SkipListIter},
-    types::{SequenceNumber, ValueType},
-    utils::buffer::BufferReader,
+    types::{SequenceNumber, Tag, ValueType, TAG_SIZE},
+    utils::{buffer::BufferReader, hash::CRC32},
 };
 
-// use integer_encoding::{FixedIntWriter, VarInt, VarIntWriter};
+// What a memtable knows of a key. Only a `Miss` lets a read go on to older
+// memtables and the tables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemGet {
+    Hit(Vec<u8>),
+    Deleted,
+    Miss,
+}
 
 pub struct MemTable {
     table: Arc<SkipList<Vec<u8>>>,
     comparator: Arc<dyn Comparator>,
     memory_usage: AtomicUsize,
+    // every entry ends with a crc32 of the bytes before it, checked on reads
+    checksums: bool,
+}
+
+const CHECKSUM_SIZE: usize = 4;
+
+// an entry that doesn't match its trailing crc32 is corrupted
+fn verify_entry(entry: &[u8]) -> Result<()> {
+    if entry.len() >= CHECKSUM_SIZE {
+        let (data, checksum) = entry.split_at(entry.len() - CHECKSUM_SIZE);
+        if CRC32.checksum(data).to_le_bytes() == checksum {
+            return Ok(());
+        }
+    }
+    Err(Error::Corruption("memtable entry checksum mismatch".into()))
 }
 
 impl MemTable {
     pub fn new(internal_comparator: InternalKeyComparator) -> MemTable {
+        Self::with_checksums(internal_comparator, false)
+    }
+
+    pub fn with_checksums(internal_comparator: InternalKeyComparator, checksums: bool) -> MemTable {
         let comparator = internal_comparator.user_comparator();
         let key_comparator = KeyComparator::new(internal_comparator);
 
         MemTable {
-            table: Arc::new(SkipList::new(Rc::new(key_comparator))),
+            table: Arc::new(SkipList::new(Arc::new(key_comparator))),
             comparator,
             memory_usage: AtomicUsize::new(0),
+            checksums,
         }
     }
 
@@ -45,23 +72,29 @@ impl MemTable {
         //  tag          : uint64((sequence << 8) | type)
         //  value_size   : varint32 of value.size()
         //  value bytes  : char[value.size()]
+        //  checksum     : crc32 of the above, only with checksums
 
         let key = key.as_ref();
         let value = value.as_ref();
 
-        let key_size = key.len() + 8;
+        let key_size = key.len() + TAG_SIZE;
         let value_size = value.len();
         let size = key_size
             + value_size
             + codec::required_space(key_size as u64)
-            + codec::required_space(value_size as u64);
+            + codec::required_space(value_size as u64)
+            + if self.checksums { CHECKSUM_SIZE } else { 0 };
 
         let mut buf = Vec::with_capacity(size);
         buf.write_var_u32(key_size as u32).unwrap();
         buf.write_all(key).unwrap();
-        buf.write_u64_le(t as u64 | (seq << 8)).unwrap();
+        Tag::new(seq, t).encode_to(&mut buf);
         buf.write_var_u32(value_size as u32).unwrap();
         buf.write_all(value).unwrap();
+        if self.checksums {
+            let checksum = CRC32.checksum(&buf);
+            buf.write_all(&checksum.to_le_bytes()).unwrap();
+        }
 
         self.memory_usage
             .fetch_add(buf.len(), atomic::Ordering::Relaxed);
@@ -71,32 +104,44 @@ impl MemTable {
         self.table.insert(buf);
     }
 
-    pub fn get(&self, search_key: LookupKey) -> Result<Option<Vec<u8>>> {
+    // The newest entry of the lookup's user key with a sequence no larger than
+    // the lookup's. The skiplist orders entries of a user key newest first, so
+    // the seek lands on it when there is one.
+    pub fn get(&self, search_key: LookupKey) -> Result<MemGet> {
         let mut iter = SkipListIter::new(self.table.clone());
         iter.seek(search_key.memtable_key());
-
-        if iter.valid() {
-            let mut seek_key = iter.key();
-
-            let (internal_key_len,_) = seek_key.read_var_u32().unwrap();
-            let mut internal_key = seek_key.read_bytes(internal_key_len as usize).unwrap();
-            let seek_user_key = internal_key.read_bytes(internal_key.len() - 8).unwrap();
-            if self
-                .comparator
-                .compare(search_key.user_key(), seek_user_key)
-                == Ordering::Equal
-            {
-                let record_type = internal_key.read_u64_le().unwrap() & 0xff;
-                if record_type == ValueType::Value as u64 {
-                    let (value_len,_) = seek_key.read_var_u32().unwrap();
-                    let user_value = seek_key.read_bytes(value_len as usize).unwrap();
-                    return Ok(Some(user_value.into()));
-                } else if record_type == ValueType::Deletetion as u64 {
-                    return Ok(None);
-                }
+        if !iter.valid() {
+            return Ok(MemGet::Miss);
+        }
+        if self.checksums {
+            verify_entry(iter.key())?;
+        }
+        let mut seek_key = iter.key();
+
+        let (internal_key_len, _) = seek_key.read_var_u32().unwrap();
+        let mut internal_key = seek_key.read_bytes(internal_key_len as usize).unwrap();
+        let seek_user_key = internal_key
+            .read_bytes(internal_key.len() - TAG_SIZE)
+            .unwrap();
+        // the seek went past every entry of the key
+        if self
+            .comparator
+            .compare(search_key.user_key(), seek_user_key)
+            != Ordering::Equal
+        {
+            return Ok(MemGet::Miss);
+        }
+        let tag = Tag::decode_from(internal_key)?;
+        // the seek skips the entries newer than the lookup
+        debug_assert!(tag.seq() <= extract_tag(search_key.internal_key()).seq());
+        match tag.value_type()? {
+            ValueType::Value => {
+                let (value_len, _) = seek_key.read_var_u32().unwrap();
+                let user_value = seek_key.read_bytes(value_len as usize).unwrap();
+                Ok(MemGet::Hit(user_value.into()))
             }
+            ValueType::Deletetion => Ok(MemGet::Deleted),
         }
-        Err(Error::NotFoundError("no key".into()))
     }
 
     pub fn approximate_memory_usage(&self) -> usize {
@@ -104,20 +149,46 @@ impl MemTable {
     }
 
     pub fn iter(&self) -> Box<dyn DBIterator> {
-        Box::new(MemTableIterator::new(SkipListIter::new(self.table.clone())))
+        let iter = SkipListIter::new(self.table.clone());
+        Box::new(MemTableIterator::new(iter, self.checksums))
+    }
+
+    // flips the last value byte of the newest entry of `user_key`
+    #[cfg(test)]
+    pub fn corrupt_value(&self, user_key: &[u8]) {
+        use crate::types::MAX_SEQUENCE_NUMBER;
+
+        let lookup = LookupKey::new(user_key, MAX_SEQUENCE_NUMBER, ValueType::Value);
+        let mut iter = SkipListIter::new(self.table.clone());
+        iter.seek(lookup.memtable_key());
+        let trailer = if self.checksums { CHECKSUM_SIZE } else { 0 };
+        let entry = iter.key_mut();
+        let at = entry.len() - trailer - 1;
+        entry[at] ^= 0x1;
     }
 }
 
 pub struct MemTableIterator {
     iter: SkipListIter<Vec<u8>>,
     tmp: Vec<u8>,
+    checksums: bool,
+    // the first corrupted entry the iterator was positioned at
+    status: Result<()>,
 }
 
 impl MemTableIterator {
-    pub fn new(iter: SkipListIter<Vec<u8>>) -> Self {
+    pub fn new(iter: SkipListIter<Vec<u8>>, checksums: bool) -> Self {
         MemTableIterator {
             iter,
             tmp: Vec::new(),
+            checksums,
+            status: Ok(()),
+        }
+    }
+
+    fn verify(&mut self) {
+        if self.checksums && self.status.is_ok() && self.iter.valid() {
+            self.status = verify_entry(self.iter.key());
         }
     }
 }
@@ -129,44 +200,59 @@ impl DBIterator for MemTableIterator {
 
     fn seek_to_first(&mut self) {
         self.iter.seek_to_first();
+        self.verify();
     }
 
     fn seek_to_last(&mut self) {
         self.iter.seek_to_last();
+        self.verify();
     }
 
+    // `target` is an internal key, the entries of the skiplist are memtable keys
     fn seek(&mut self, target: &[u8]) {
+        debug_assert!(target.len() >= TAG_SIZE, "not an internal key");
         self.tmp.clear();
         codec::write_length_prefixed_slice(&mut self.tmp, target).unwrap();
         self.iter.seek(&self.tmp);
+        self.verify();
     }
 
     fn next(&mut self) {
         self.iter.next();
+        self.verify();
     }
 
     fn prev(&mut self) {
         self.iter.prev();
+        self.verify();
     }
 
     fn key(&self) -> &[u8] {
+        assert!(self.valid());
         let mut raw = self.iter.key();
         codec::read_length_prefixed_slice(&mut raw).unwrap()
         // result
     }
 
     fn value(&self) -> &[u8] {
+        assert!(self.valid());
         let mut raw = self.iter.key();
         let _ = codec::read_length_prefixed_slice(&mut raw).unwrap();
         let result = codec::read_length_prefixed_slice(&mut raw).unwrap();
         result
     }
 
-    fn status(&mut self) -> Result<()> {
-        todo!()
+    fn status(&self) -> Result<()> {
+        self.status.clone()
     }
 }
 
+// The key of a lookup in the three forms it's searched with:
+//
+//   memtable_key := key_size:varint32 internal_key, the prefix of a memtable entry
+//   internal_key := user_key tag:fixed64, what tables and iterators take
+//
+// `key_offset` is the length of the varint, 1 to 5 bytes.
 pub struct LookupKey {
     key: Vec<u8>,
     key_offset: usize,
@@ -175,27 +261,32 @@ pub struct LookupKey {
 impl LookupKey {
     pub fn new(key: impl AsRef<[u8]>, seq: SequenceNumber, t: ValueType) -> Self {
         let key = key.as_ref();
-        let key_size = key.len() + 8;
+        let key_size = key.len() + TAG_SIZE;
         let size = key_size + codec::required_space(key_size as u64);
 
         let mut buf = Vec::with_capacity(size);
         buf.write_var_u32(key_size as u32).unwrap();
+        let key_offset = buf.len();
         buf.write_all(key).unwrap();
-        buf.write_u64_le(seq << 8 | t as u64).unwrap();
+        Tag::new(seq, t).encode_to(&mut buf);
+        debug_assert_eq!(buf.len(), size);
 
         LookupKey {
             key: buf,
-            key_offset: codec::required_space(key_size as u64),
+            key_offset,
         }
     }
 
+    // for seeking the skiplist of a memtable directly, not a `MemTableIterator`
     pub fn memtable_key(&self) -> &[u8] {
         self.key.as_slice()
     }
+
     pub fn user_key(&self) -> &[u8] {
-        &self.key[self.key_offset..self.key.len() - 8]
+        &self.key[self.key_offset..self.key.len() - TAG_SIZE]
     }
 
+    // for `DBIterator::seek` of any iterator, the memtable's included
     pub fn internal_key(&self) -> &[u8] {
         &self.key[self.key_offset..]
     }
@@ -226,12 +317,132 @@ mod tests {
             let lookup_key = LookupKey::new(key, seq, typ);
             let result = table.get(lookup_key);
             if typ == ValueType::Value {
-                assert!(result.is_ok(), "delete key");
-                assert_eq!(result.unwrap().unwrap().as_slice(), val.as_bytes());
+                assert_eq!(result.unwrap(), MemGet::Hit(val.into()));
             } else {
-                assert!(result.is_ok(), "delete key");
-                assert!(result.unwrap().is_none());
+                assert_eq!(result.unwrap(), MemGet::Deleted);
             }
         }
     }
+
+    #[test]
+    fn test_get_at_snapshot() {
+        let comparator = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let table = MemTable::new(comparator);
+        table.add(5, ValueType::Value, "key", "v5");
+        table.add(10, ValueType::Value, "key", "v10");
+        table.add(7, ValueType::Value, "kez", "other");
+        table.add(4, ValueType::Value, "gone", "v4");
+        table.add(8, ValueType::Deletetion, "gone", "");
+
+        let get = |key: &str, seq| {
+            table
+                .get(LookupKey::new(key, seq, ValueType::Value))
+                .unwrap()
+        };
+        let hit = |value: &str| MemGet::Hit(value.into());
+        // a `Miss` for a key the snapshot doesn't have
+        assert_eq!(get("key", 3), MemGet::Miss);
+        assert_eq!(get("key", 5), hit("v5"));
+        assert_eq!(get("key", 7), hit("v5"));
+        assert_eq!(get("key", 10), hit("v10"));
+        assert_eq!(get("key", 12), hit("v10"));
+        assert_eq!(get("ke", 12), MemGet::Miss);
+        assert_eq!(get("a", 12), MemGet::Miss);
+        assert_eq!(get("zzz", 12), MemGet::Miss);
+
+        // the deletion hides older values only from the snapshots that see it
+        assert_eq!(get("gone", 3), MemGet::Miss);
+        assert_eq!(get("gone", 7), hit("v4"));
+        assert_eq!(get("gone", 8), MemGet::Deleted);
+        assert_eq!(get("gone", 9), MemGet::Deleted);
+    }
+
+    #[test]
+    fn test_entry_checksums() {
+        let comparator = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let table = MemTable::with_checksums(comparator, true);
+        table.add(1, ValueType::Value, "a", "va");
+        table.add(2, ValueType::Value, "b", "vb");
+        table.add(3, ValueType::Value, "c", "vc");
+
+        let get = |key: &str| table.get(LookupKey::new(key, 10, ValueType::Value));
+        // the checksum isn't part of the values read
+        assert_eq!(get("b").unwrap(), MemGet::Hit(b"vb".to_vec()));
+        let mut iter = table.iter();
+        iter.seek_to_first();
+        let mut values = Vec::new();
+        while iter.valid() {
+            values.push(iter.value().to_vec());
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        assert_eq!(values, vec![b"va".to_vec(), b"vb".to_vec(), b"vc".to_vec()]);
+
+        table.corrupt_value(b"b");
+        assert!(matches!(get("b"), Err(Error::Corruption(_))));
+        assert_eq!(get("a").unwrap(), MemGet::Hit(b"va".to_vec()));
+        let mut iter = table.iter();
+        iter.seek_to_first();
+        assert!(iter.status().is_ok());
+        iter.next();
+        assert!(matches!(iter.status(), Err(Error::Corruption(_))));
+        let mut iter = table.iter();
+        iter.seek_to_last();
+        iter.prev();
+        assert!(matches!(iter.status(), Err(Error::Corruption(_))));
+
+        // without checksums the flipped byte goes unnoticed
+        let comparator = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let table = MemTable::new(comparator);
+        table.add(1, ValueType::Value, "b", "vb");
+        table.corrupt_value(b"b");
+        let value = table.get(LookupKey::new("b", 10, ValueType::Value));
+        assert_eq!(value.unwrap(), MemGet::Hit(b"vc".to_vec()));
+    }
+
+    // the varint in front of the internal key takes 1, 2 or 3 bytes
+    #[test]
+    fn test_lookup_key_prefix_lengths() {
+        let table = MemTable::new(InternalKeyComparator::new(Arc::new(BitWiseComparator {})));
+        let lengths = [0, 1, 119, 120, 121, 200, 16375, 16376, 20000];
+        let user_key = |len: usize| vec![b'a' + (len % 26) as u8; len];
+        for &len in lengths.iter() {
+            table.add(
+                10,
+                ValueType::Value,
+                user_key(len),
+                format!("old{}", len).into_bytes(),
+            );
+            table.add(
+                20,
+                ValueType::Value,
+                user_key(len),
+                format!("new{}", len).into_bytes(),
+            );
+        }
+
+        let mut iter = table.iter();
+        for &len in lengths.iter() {
+            let key = user_key(len);
+            let varint_len = codec::required_space((len + TAG_SIZE) as u64);
+            let lookup = LookupKey::new(&key, 15, ValueType::Value);
+            assert_eq!(lookup.user_key(), key.as_slice());
+            assert_eq!(lookup.internal_key().len(), len + TAG_SIZE);
+            assert_eq!(&lookup.internal_key()[..len], key.as_slice());
+            assert_eq!(lookup.memtable_key().len(), varint_len + len + TAG_SIZE);
+            assert_eq!(&lookup.memtable_key()[varint_len..], lookup.internal_key());
+
+            // both see the entry older than the lookup
+            let old = format!("old{}", len).into_bytes();
+            assert_eq!(table.get(lookup).unwrap(), MemGet::Hit(old.clone()));
+            let lookup = LookupKey::new(&key, 15, ValueType::Value);
+            iter.seek(lookup.internal_key());
+            assert!(iter.valid());
+            assert_eq!(extract_tag(iter.key()).seq(), 10);
+            assert_eq!(iter.key().len(), len + TAG_SIZE);
+            assert_eq!(&iter.key()[..len], key.as_slice());
+            assert_eq!(iter.value(), old.as_slice());
+            iter.status().unwrap();
+        }
+    }
 }
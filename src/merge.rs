@@ -1,13 +1,19 @@
 use std::cmp::Ordering;
 
-use crate::{cmp::Comparator, error::Result, iterator::DBIterator, slice::UnsafeSlice, Forward};
-
+use crate::{
+    cmp::Comparator,
+    error::Result,
+    iterator::{shrink_scratch, DBIterator},
+    Forward,
+};
 
 pub struct MergingIterator<C: Comparator> {
     children: Vec<Box<dyn DBIterator>>,
     current: Option<usize>,
     comparator: C,
     direction: Forward,
+    // the current key while the children are repositioned around it
+    saved_key: Vec<u8>,
 }
 
 impl<C: Comparator> MergingIterator<C> {
@@ -17,6 +23,7 @@ impl<C: Comparator> MergingIterator<C> {
             current: None,
             comparator,
             direction: Forward::FORWARD,
+            saved_key: Vec::new(),
         }
     }
 
@@ -87,22 +94,23 @@ impl<C: Comparator> DBIterator for MergingIterator<C> {
             .iter_mut()
             .for_each(|child| child.seek(target));
         self.find_smallest();
+        self.direction = Forward::FORWARD;
     }
 
     fn next(&mut self) {
         assert!(self.valid());
 
         let current = self.current.unwrap();
-        let current_key = UnsafeSlice::new(self.key().as_ptr(), self.key().len());
         if self.direction == Forward::BACKWARD {
+            self.saved_key.clear();
+            shrink_scratch(&mut self.saved_key);
+            self.saved_key
+                .extend_from_slice(self.children[current].key());
             for (pos, child) in self.children.iter_mut().enumerate() {
                 if pos != current {
-                    child.seek(unsafe { current_key.as_ref() });
+                    child.seek(&self.saved_key);
                     if child.valid()
-                        && self
-                            .comparator
-                            .compare(unsafe { current_key.as_ref() }, child.key())
-                            == Ordering::Equal
+                        && self.comparator.compare(&self.saved_key, child.key()) == Ordering::Equal
                     {
                         child.next();
                     }
@@ -118,14 +126,18 @@ impl<C: Comparator> DBIterator for MergingIterator<C> {
         assert!(self.valid());
 
         let current = self.current.unwrap();
-        let current_key = UnsafeSlice::new(self.key().as_ptr(), self.key().len());
         if self.direction == Forward::FORWARD {
+            self.saved_key.clear();
+            shrink_scratch(&mut self.saved_key);
+            self.saved_key
+                .extend_from_slice(self.children[current].key());
+            let saved_key = &self.saved_key;
             self.children
                 .iter_mut()
                 .enumerate()
                 .for_each(|(pos, child)| {
                     if pos != current {
-                        child.seek(unsafe { current_key.as_ref() });
+                        child.seek(saved_key);
                         if child.valid() {
                             child.prev();
                         } else {
@@ -145,13 +157,104 @@ impl<C: Comparator> DBIterator for MergingIterator<C> {
     }
 
     fn value(&self) -> &[u8] {
+        assert!(self.valid());
         self.children[self.current.unwrap()].value()
     }
 
-    fn status(&mut self) -> Result<()> {
-        for i in 0..self.children.len() {
-           self.children[i].status()?;
+    fn status(&self) -> Result<()> {
+        for child in self.children.iter() {
+            child.status()?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        cmp::{BitWiseComparator, InternalKeyComparator},
+        iterator::{
+            tests::{assert_exhausted, assert_sticky_corruption, ErrorIter},
+            SCRATCH_FLOOR,
+        },
+        types::ValueType,
+        MemTable,
+    };
+
+    fn icmp() -> InternalKeyComparator {
+        InternalKeyComparator::new(Arc::new(BitWiseComparator {}))
+    }
+
+    fn memtable_iter(entries: &[(&str, u64)]) -> Box<dyn DBIterator> {
+        let mem = MemTable::new(icmp());
+        for (key, seq) in entries {
+            mem.add(*seq, ValueType::Value, key.as_bytes(), key.as_bytes());
+        }
+        mem.iter()
+    }
+
+    #[test]
+    fn miri_direction_changes() {
+        let children = vec![
+            memtable_iter(&[("a", 1), ("c", 3), ("e", 5)]),
+            memtable_iter(&[("b", 2), ("d", 4)]),
+        ];
+        let mut iter = MergingIterator::new(icmp(), children);
+        let value = |iter: &MergingIterator<_>| String::from_utf8(iter.value().to_vec()).unwrap();
+
+        iter.seek_to_first();
+        let mut seen = vec![];
+        for step in ["next", "next", "prev", "next", "next", "prev", "prev"] {
+            seen.push(value(&iter));
+            match step {
+                "next" => iter.next(),
+                _ => iter.prev(),
+            }
+        }
+        seen.push(value(&iter));
+        assert_eq!(seen, ["a", "b", "c", "b", "c", "d", "c", "b"]);
+
+        iter.seek_to_last();
+        iter.next();
+        assert_exhausted(&iter);
+        iter.seek_to_first();
+        iter.prev();
+        assert_exhausted(&iter);
+    }
+
+    #[test]
+    fn test_child_error() {
+        let children: Vec<Box<dyn DBIterator>> =
+            vec![Box::new(ErrorIter("first")), Box::new(ErrorIter("second"))];
+        let mut iter = MergingIterator::new(BitWiseComparator {}, children);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_sticky_corruption(&iter, "first");
+    }
+
+    #[test]
+    fn test_saved_key_shrinks() {
+        let giant = "a".repeat(1 << 20);
+        let children = vec![
+            memtable_iter(&[(giant.as_str(), 1), ("c", 3)]),
+            memtable_iter(&[("b", 2), ("d", 4)]),
+        ];
+        let mut iter = MergingIterator::new(icmp(), children);
+        iter.seek_to_last();
+        iter.prev();
+        iter.prev();
+        iter.prev();
+        assert_eq!(iter.value(), giant.as_bytes());
+        // turning around saves the current key
+        iter.next();
+        assert_eq!(iter.value(), b"b");
+        assert!(iter.saved_key.capacity() >= giant.len());
+        iter.next();
+        iter.prev();
+        assert_eq!(iter.value(), b"b");
+        assert!(iter.saved_key.capacity() <= SCRATCH_FLOOR);
+    }
+}
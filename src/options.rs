@@ -1,9 +1,13 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc, time::Duration};
 
 use crate::{
     cmp::{BitWiseComparator, Comparator},
+    env::Logger,
     error::Error,
     filter::FilterPolicy,
+    listener::EventListener,
+    rate_limiter::RateLimiter,
+    snapshot::Snapshot,
     sstable::block::Block,
     utils::cache::Cache,
 };
@@ -31,25 +35,84 @@ impl TryFrom<u8> for Compress {
     }
 }
 
+// Build options from `Options::default()` or with `DbBuilder`, new fields are
+// added without notice. `validate` reports every setting a db can't open with.
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct Options {
     pub comparator: Arc<dyn Comparator>,
     pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    // per level override of `filter_policy`, indexed by the level a table is written to.
+    // levels beyond the end of the vec fall back to `filter_policy`, a `None` entry
+    // disables the filter for that level (e.g. the last level, where most reads hit)
+    pub filter_policy_per_level: Option<Vec<Option<Arc<dyn FilterPolicy>>>>,
 
     pub block_restart_interval: u32,
+    // the uncompressed size a data block is cut at. An entry is never split, so a
+    // value larger than this gets a block of its own
     pub block_size: usize,
     pub max_open_files: u64,
     pub max_file_size: usize,
+    // the manifest is replaced by a new one starting with a snapshot of the
+    // current state once it grows past this many bytes
+    pub max_manifest_file_size: u64,
     pub write_buffer_size: u64,
+    // writes of a larger key or value fail with InvalidArgument, 0 disables the
+    // check. Every key is kept whole in the memtable, the index block stores
+    // separators as long as the keys and the filter builder copies them, so
+    // large keys cost far more than large values
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    // keep all the data in the memtable and the WAL. No table is ever written and
+    // there is no compaction worker, the logs are replayed into the memtable on open
+    pub memtable_only: bool,
+    // with `memtable_only`, writes fail once the memtable uses this many bytes
+    pub memtable_only_max_bytes: Option<usize>,
+    // Flushes and compactions run on a worker thread, leveldb's one background
+    // thread, for any number above 0. With 0 no thread is started: the writer
+    // that fills the memtable flushes it and does at most one level compaction
+    // itself, for targets without threads
+    pub background_threads: u32,
 
     pub compression_type: Compress,
     // pub env: Rc<Box<dyn Env>>,
     pub paranoid_checks: bool,
+    // memtable entries carry a crc32 that reads and flushes verify, so memory
+    // corruption fails them with Corruption instead of reaching a table. Costs
+    // a few percent of cpu and 4 bytes per entry
+    pub paranoid_memory_checks: bool,
+    // Blocks of new tables and records of the WAL get a zero checksum instead of
+    // their crc, for a bulk load into a db that can be thrown away. Readers don't
+    // verify zero checksums, so corruption of these files goes unnoticed, and
+    // neither is a block or record whose crc happens to be 0. Checksums that
+    // were written are verified as always.
+    pub disable_data_checksums: bool,
+    // an open goes on appending to the last log instead of writing its
+    // entries to a level-0 table and starting a new one, when the entries
+    // fit in the memtable. The manifest is appended to as well while it is
+    // smaller than `max_file_size`
     pub reuse_log: bool,
     pub error_if_exists: bool,
     pub create_if_missing: bool,
 
     pub block_cache: Option<Arc<dyn Cache<Vec<u8>, Block>>>,
+    // A table evicted from the table cache leaves its index and filter blocks
+    // behind, up to `metadata_cache_capacity` bytes of them, so opening it again
+    // reads neither. Scans opening many tables then cost point lookups at most
+    // the reopen, not the reads of the index and filter
+    pub pin_table_metadata_in_cache: bool,
+    pub metadata_cache_capacity: usize,
+    // each table remembers the index entry of its last get, so a get of a key
+    // in the same entry skips the search of the index block
+    pub table_lookup_hint: bool,
+    // paces the table writes of flushes and compactions, so they don't take the
+    // disk from foreground reads and writes
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub listener: Option<Arc<dyn EventListener>>,
+
+    pub info_log: Option<Arc<dyn Logger>>,
+    // write the `leveldb.stats` property to `info_log` this often
+    pub stats_dump_period: Option<Duration>,
 }
 
 impl Default for Options {
@@ -59,26 +122,242 @@ impl Default for Options {
             block_size: 4 * 1024,
             block_restart_interval: 16,
             max_file_size: 2 * 1024 * 1024,
+            max_manifest_file_size: 64 * 1024 * 1024,
             max_open_files: 1000,
             compression_type: Compress::NO,
             paranoid_checks: false,
+            paranoid_memory_checks: false,
+            disable_data_checksums: false,
             block_cache: None,
+            pin_table_metadata_in_cache: true,
+            metadata_cache_capacity: 8 * 1024 * 1024,
+            table_lookup_hint: true,
+            rate_limiter: None,
             filter_policy: None,
+            filter_policy_per_level: None,
             write_buffer_size: 4 * 1024 * 1024,
+            max_key_size: 8 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+            memtable_only: false,
+            memtable_only_max_bytes: None,
+            background_threads: 1,
             reuse_log: false,
             error_if_exists: false,
             create_if_missing: false,
+            listener: None,
+            info_log: None,
+            stats_dump_period: None,
+        }
+    }
+}
+
+pub const MIN_WRITE_BUFFER_SIZE: u64 = 64 * 1024;
+pub const MIN_BLOCK_SIZE: usize = 1024;
+pub const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_FILTER_BITS_PER_KEY: usize = 64;
+
+// A setting a db can't be opened with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OptionsError {
+    WriteBufferTooSmall { min: u64, got: u64 },
+    BlockSizeOutOfRange { min: usize, max: usize, got: usize },
+    MaxFileSizeZero,
+    EmptyComparatorName,
+    // `memtable_only_max_bytes` has no effect without `memtable_only`
+    MemtableOnlyMaxBytesWithoutMemtableOnly,
+    // the settings of `DbBuilder`
+    FilterBitsOutOfRange { min: usize, max: usize, got: usize },
+    BlockCacheCapacityZero,
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionsError::WriteBufferTooSmall { min, got } => {
+                write!(
+                    f,
+                    "write_buffer_size {} is below the minimum of {}",
+                    got, min
+                )
+            }
+            OptionsError::BlockSizeOutOfRange { min, max, got } => {
+                write!(f, "block_size {} is out of [{}, {}]", got, min, max)
+            }
+            OptionsError::MaxFileSizeZero => write!(f, "max_file_size must not be 0"),
+            OptionsError::EmptyComparatorName => write!(f, "the comparator has no name"),
+            OptionsError::MemtableOnlyMaxBytesWithoutMemtableOnly => {
+                write!(f, "memtable_only_max_bytes is set without memtable_only")
+            }
+            OptionsError::FilterBitsOutOfRange { min, max, got } => {
+                write!(
+                    f,
+                    "filter_bits_per_key {} is out of [{}, {}]",
+                    got, min, max
+                )
+            }
+            OptionsError::BlockCacheCapacityZero => write!(f, "block_cache_capacity must not be 0"),
         }
     }
 }
 
-#[derive(Clone, Default)]
+// one InvalidArgument listing all of `errors`
+pub(crate) fn invalid_options(errors: &[OptionsError]) -> Error {
+    let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+    Error::InvalidArgument(format!("invalid options: {}", errors.join("; ")))
+}
+
+impl Options {
+    // Every setting the db can't open with, not just the first. Settings that
+    // are only out of range are clamped on open instead, with a warning to
+    // `info_log`.
+    pub fn validate(&self) -> Result<(), Vec<OptionsError>> {
+        let mut errors = Vec::new();
+        if self.write_buffer_size < MIN_WRITE_BUFFER_SIZE {
+            errors.push(OptionsError::WriteBufferTooSmall {
+                min: MIN_WRITE_BUFFER_SIZE,
+                got: self.write_buffer_size,
+            });
+        }
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            errors.push(OptionsError::BlockSizeOutOfRange {
+                min: MIN_BLOCK_SIZE,
+                max: MAX_BLOCK_SIZE,
+                got: self.block_size,
+            });
+        }
+        if self.max_file_size == 0 {
+            errors.push(OptionsError::MaxFileSizeZero);
+        }
+        if self.comparator.name().is_empty() {
+            errors.push(OptionsError::EmptyComparatorName);
+        }
+        if self.memtable_only_max_bytes.is_some() && !self.memtable_only {
+            errors.push(OptionsError::MemtableOnlyMaxBytesWithoutMemtableOnly);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn filter_policy_for_level(&self, level: usize) -> Option<Arc<dyn FilterPolicy>> {
+        match self.filter_policy_per_level {
+            Some(ref policies) if level < policies.len() => policies[level].clone(),
+            _ => self.filter_policy.clone(),
+        }
+    }
+
+    // options used by the table builder when writing a table destined for `level`
+    pub fn table_options_for_level(self: &Arc<Self>, level: usize) -> Arc<Options> {
+        if self.filter_policy_per_level.is_none() {
+            return self.clone();
+        }
+        Arc::new(Options {
+            filter_policy: self.filter_policy_for_level(level),
+            ..Options::clone(self)
+        })
+    }
+}
+
+// Where a read may look for a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadTier {
+    // the memtables and the tables
+    #[default]
+    All,
+    // only the memtables, so the read does no disk io. A key the memtables don't
+    // have fails with `Error::Incomplete`
+    MemtableOnly,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct ReadOption {
     pub verify_checksum: bool,
     pub fill_cache: bool,
+    // read the db as of this snapshot instead of its latest state
+    pub snapshot: Option<Snapshot>,
+    // honored by get, iterators always read everything
+    pub read_tier: ReadTier,
+    // a scan ends once the keys and values it returned would pass this many
+    // bytes, see `limit_reached` of the iterators
+    pub scan_limit_bytes: Option<u64>,
 }
 
-#[derive(Clone, Default)]
+impl ReadOption {
+    // checksums verified and the block cache left alone, for reading the metadata
+    // of a table or checking a whole one
+    pub const VERIFY: ReadOption = ReadOption {
+        verify_checksum: true,
+        fill_cache: false,
+        snapshot: None,
+        read_tier: ReadTier::All,
+        scan_limit_bytes: None,
+    };
+
+    // the reads of the db itself, like the inputs of a compaction. They don't fill
+    // the cache and verify checksums with `paranoid_checks`
+    pub fn internal(paranoid_checks: bool) -> ReadOption {
+        ReadOption {
+            verify_checksum: paranoid_checks,
+            ..ReadOption::VERIFY
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct WriteOption {
     pub sync: bool,
+    // keep the sequence the batch already has, for batches read from the log of
+    // another db. It must be after the last sequence of this db, the batch goes
+    // to the log unchanged
+    pub preserve_sequence: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reports_every_error() {
+        assert_eq!(Options::default().validate(), Ok(()));
+
+        let mut options = Options {
+            write_buffer_size: 1024,
+            block_size: 0,
+            max_file_size: 0,
+            memtable_only_max_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let errors = options.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                OptionsError::WriteBufferTooSmall {
+                    min: MIN_WRITE_BUFFER_SIZE,
+                    got: 1024
+                },
+                OptionsError::BlockSizeOutOfRange {
+                    min: MIN_BLOCK_SIZE,
+                    max: MAX_BLOCK_SIZE,
+                    got: 0
+                },
+                OptionsError::MaxFileSizeZero,
+                OptionsError::MemtableOnlyMaxBytesWithoutMemtableOnly,
+            ]
+        );
+        let msg = match invalid_options(&errors) {
+            Error::InvalidArgument(msg) => msg,
+            err => panic!("{:?}", err),
+        };
+        for e in errors {
+            assert!(msg.contains(&e.to_string()), "{}", msg);
+        }
+
+        options.memtable_only = true;
+        options.write_buffer_size = MIN_WRITE_BUFFER_SIZE;
+        options.block_size = MAX_BLOCK_SIZE;
+        options.max_file_size = 1;
+        assert_eq!(options.validate(), Ok(()));
+    }
 }
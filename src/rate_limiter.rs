@@ -0,0 +1,78 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use crate::env::Env;
+
+// Who a write is done for. Background writes, the tables of flushes and
+// compactions, are paced by `Options::rate_limiter`, foreground ones like the
+// WAL never are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    Foreground,
+    Background,
+}
+
+// A token bucket refilled with `bytes_per_sec` bytes a second, holding at most a
+// second worth of them. A request larger than what the bucket holds takes the
+// rest on credit and sleeps until it's paid back.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+    total_bytes: AtomicU64,
+}
+
+struct Bucket {
+    // negative while in debt
+    available: f64,
+    // Env::now_micros of the last refill, none before the first request
+    last_refill_micros: Option<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "a rate limit must be positive");
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                last_refill_micros: None,
+            }),
+            total_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    // Takes `bytes` from the bucket, sleeping with `env` until they're available.
+    pub fn request<E: Env>(&self, env: &E, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let wait_micros = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = env.now_micros();
+            if let Some(last) = bucket.last_refill_micros {
+                let refill = now.saturating_sub(last) as f64 * rate / 1e6;
+                bucket.available = (bucket.available + refill).min(rate);
+            }
+            bucket.last_refill_micros = Some(now);
+            bucket.available -= bytes as f64;
+            if bucket.available < 0.0 {
+                (-bucket.available * 1e6 / rate).ceil() as u64
+            } else {
+                0
+            }
+        };
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if wait_micros > 0 {
+            env.sleep_for_micros(wait_micros);
+        }
+    }
+
+    // the bytes requested so far
+    pub fn total_bytes_through(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,225 @@
+use std::{ffi::OsString, path::Path, sync::Arc};
+
+use crate::{
+    builder::build_table,
+    cmp::{Comparator, InternalKeyComparator},
+    db_impl::{sanitize_options, OpenDb},
+    env::Env,
+    error::{Error, Result},
+    filenames::{
+        descriptor_file_name, log_file_name, parse_file_name, set_current_file,
+        sst_table_file_name, table_file_name, FileType,
+    },
+    format::{extract_tag, InternalKey},
+    iterator::DBIterator,
+    options::{Options, ReadOption},
+    rate_limiter::IoPriority,
+    sstable::Table,
+    table_cache::TableCache,
+    types::{SequenceNumber, TAG_SIZE},
+    version::FileMetaData,
+    version_edit::VersionEdit,
+    write_batch::WriteBatch,
+    LogReader, LogWriter, MemTable,
+};
+
+// Rebuilds the MANIFEST of the db at `db_name` from the files in its directory.
+// Logs are converted to tables and every readable table is put at level 0. Files
+// that can't be used, and the old logs and manifests, are moved to `lost/`. The
+// creation times of the recovered tables are unknown.
+pub fn repair_db<E: Env>(options: Options, db_name: &Path, env: E) -> Result<()> {
+    let _registration = OpenDb::register(&env, db_name)?;
+    let icmp = InternalKeyComparator::new(options.comparator.clone());
+    let options = Arc::new(sanitize_options(options));
+    let mut repairer = Repairer {
+        db_name,
+        table_cache: TableCache::new(
+            db_name.to_path_buf(),
+            options.clone(),
+            env.clone(),
+            options.max_open_files,
+        ),
+        icmp,
+        env,
+        options,
+        next_file_number: 1,
+        max_sequence: 0,
+    };
+    repairer.run()
+}
+
+struct Repairer<'a, E: Env> {
+    db_name: &'a Path,
+    env: E,
+    options: Arc<Options>,
+    icmp: InternalKeyComparator,
+    table_cache: TableCache<E>,
+    next_file_number: u64,
+    max_sequence: SequenceNumber,
+}
+
+impl<'a, E: Env> Repairer<'a, E> {
+    fn run(&mut self) -> Result<()> {
+        let mut files = Vec::new();
+        self.env.get_children(self.db_name, &mut files)?;
+        let mut logs = Vec::new();
+        let mut tables = Vec::new();
+        let mut manifests = Vec::new();
+        for f in files {
+            if let Ok((number, file_type)) = parse_file_name(&f) {
+                self.next_file_number = self.next_file_number.max(number + 1);
+                match file_type {
+                    FileType::Log => logs.push((number, f)),
+                    FileType::Table => tables.push((number, f)),
+                    FileType::Descriptor => manifests.push(f),
+                    _ => {}
+                }
+            }
+        }
+        logs.sort_unstable();
+        tables.sort_unstable();
+
+        for (number, name) in logs {
+            if let Ok(Some(table)) = self.convert_log_to_table(number) {
+                let table_name = table_file_name(self.db_name, table);
+                tables.push((table, table_name.file_name().unwrap().to_owned()));
+            }
+            self.archive_file(&name);
+        }
+
+        let mut edit = VersionEdit::default();
+        for (number, name) in tables {
+            match self.scan_table(number) {
+                Ok(Some(meta)) => edit.add_new_file(
+                    0,
+                    meta.number,
+                    meta.file_size,
+                    meta.smallest,
+                    meta.largest,
+                    meta.creation_time,
+                ),
+                _ => self.archive_file(&name),
+            }
+        }
+
+        let manifest_number = self.next_file_number;
+        edit.set_comparator(self.icmp.user_comparator_name());
+        edit.set_log_number(0);
+        edit.set_next_file_number(manifest_number + 1);
+        edit.set_last_sequence(self.max_sequence);
+
+        let manifest = descriptor_file_name(self.db_name, manifest_number);
+        let mut record = Vec::new();
+        edit.encode(&mut record);
+        let mut log = LogWriter::new(self.env.new_writable_file(&manifest)?);
+        let mut res = log.add_record(record).and_then(|_| log.sync());
+        drop(log);
+        if res.is_ok() {
+            for name in manifests {
+                self.archive_file(&name);
+            }
+            res = set_current_file(self.env.clone(), self.db_name, manifest_number);
+        }
+        if res.is_err() {
+            let _ = self.env.delete_file(&manifest);
+        }
+        res
+    }
+
+    // Returns the number of the new table, if the log had any records. A damaged
+    // log still gives up the records before the damage.
+    fn convert_log_to_table(&mut self, log_number: u64) -> Result<Option<u64>> {
+        let file = self
+            .env
+            .new_sequential_file(&log_file_name(self.db_name, log_number))?;
+        let mut reader = LogReader::new(file, true);
+        let checksums = self.options.paranoid_memory_checks;
+        let mem = Arc::new(MemTable::with_checksums(self.icmp.clone(), checksums));
+        loop {
+            let mut record = Vec::new();
+            match reader.read_record(&mut record) {
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+            let batch = match WriteBatch::from_content(record) {
+                Ok(batch) => batch,
+                Err(_) => break,
+            };
+            if batch.insert_into(mem.clone()).is_err() {
+                break;
+            }
+            let last_sequence =
+                (batch.sequence() + batch.count() as SequenceNumber).saturating_sub(1);
+            self.max_sequence = self.max_sequence.max(last_sequence);
+        }
+
+        let mut meta = FileMetaData {
+            number: self.next_file_number,
+            ..Default::default()
+        };
+        self.next_file_number += 1;
+        build_table(
+            self.db_name,
+            self.env.clone(),
+            &self.options,
+            self.table_cache.clone(),
+            mem.iter(),
+            &mut meta,
+            IoPriority::Foreground,
+        )?;
+        Ok(if meta.file_size > 0 {
+            Some(meta.number)
+        } else {
+            None
+        })
+    }
+
+    // Reads the whole table to find its key range, an empty table is `None`.
+    fn scan_table(&mut self, number: u64) -> Result<Option<FileMetaData>> {
+        let file_size = match self.env.file_size(&table_file_name(self.db_name, number)) {
+            Ok(size) => size,
+            Err(_) => self
+                .env
+                .file_size(&sst_table_file_name(self.db_name, number))?,
+        } as u64;
+        let table = self.table_cache.find_table(number, file_size)?;
+        let read_option = ReadOption::VERIFY;
+        let mut iter = Table::iter(table, &read_option);
+        let mut meta = FileMetaData {
+            number,
+            file_size,
+            smallest: InternalKey::empty(),
+            largest: InternalKey::empty(),
+            ..Default::default()
+        };
+        let mut max_sequence = self.max_sequence;
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            if key.len() < TAG_SIZE {
+                return Err(Error::Corruption("internal key is too short".into()));
+            }
+            if meta.smallest.encode().is_empty() {
+                meta.smallest.decode(key);
+            }
+            meta.largest.decode(key);
+            max_sequence = max_sequence.max(extract_tag(key).seq());
+            iter.next();
+        }
+        iter.status()?;
+
+        if meta.smallest.encode().is_empty() {
+            return Ok(None);
+        }
+        self.max_sequence = max_sequence;
+        Ok(Some(meta))
+    }
+
+    fn archive_file(&self, name: &OsString) {
+        let lost = self.db_name.join("lost");
+        let _ = self.env.create_dir(&lost);
+        let _ = self
+            .env
+            .rename_file(&self.db_name.join(name), &lost.join(name));
+    }
+}
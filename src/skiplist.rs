@@ -1,46 +1,68 @@
 use rand::{thread_rng, Rng};
-use std::{cmp::Ordering, mem::replace, rc::Rc, sync::Arc, vec};
+use std::{
+    cmp::Ordering,
+    ptr,
+    sync::{
+        atomic::{self, AtomicPtr},
+        Arc, Mutex,
+    },
+};
 
 use crate::cmp::Comparator;
 
 const MAX_HEIGHT: usize = 12;
 const BRANCHING_FACTOR: usize = 4;
 
+// Nodes are owned by the list through raw pointers, linked at level 0 from the
+// head, and only freed when the list is dropped. Iterators hold the list, so the
+// nodes they point at stay alive. A null link is the end of a level.
 pub struct Node<T> {
-    skips: Vec<Option<*mut Node<T>>>,
-    next: Option<Box<Node<T>>>,
+    skips: Vec<AtomicPtr<Node<T>>>,
     key: T,
 }
+
 impl<T> Node<T> {
-    pub fn next(&self) -> Option<*const Node<T>> {
-        if self.next.is_some() {
-            Some(self.next.as_ref().unwrap().as_ref() as *const Node<T>)
-        } else {
-            None
+    fn new(key: T, height: usize) -> Node<T> {
+        Node {
+            skips: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            key,
         }
     }
-}
 
-impl<T: Default + AsRef<[u8]>> Node<T> {
-    fn empty() -> Node<T> {
-        Node {
-            skips: vec![None; MAX_HEIGHT],
-            next: None,
-            key: T::default(),
-        }
+    // A node is fully initialized before it's published with a release store,
+    // so a reader that loads a link with acquire sees all of it.
+    fn next(&self, level: usize) -> Option<*mut Node<T>> {
+        let next = self.skips[level].load(atomic::Ordering::Acquire);
+        (!next.is_null()).then_some(next)
+    }
+
+    fn set_next(&self, level: usize, next: *mut Node<T>) {
+        self.skips[level].store(next, atomic::Ordering::Release);
     }
 }
 
+// Any number of readers may search and iterate the list while a writer
+// inserts. Inserts are serialized by the list, and readers never see a node
+// that isn't fully linked below the level they found it at.
 pub struct SkipList<T> {
-    head: Box<Node<T>>,
-    comparator: Rc<dyn Comparator>,
+    head: *mut Node<T>,
+    comparator: Arc<dyn Comparator>,
+    insert_lock: Mutex<()>,
 }
 
+// the nodes are only reached through the list, which never hands out a
+// mutable reference to them
+unsafe impl<T: Send + Sync> Send for SkipList<T> {}
+unsafe impl<T: Send + Sync> Sync for SkipList<T> {}
+
 impl<T: Default + AsRef<[u8]>> SkipList<T> {
-    pub fn new(comparator: Rc<dyn Comparator>) -> Self {
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
         SkipList {
-            comparator: comparator.clone(),
-            head: Box::new(Node::empty()),
+            comparator,
+            head: Box::into_raw(Box::new(Node::new(T::default(), MAX_HEIGHT))),
+            insert_lock: Mutex::new(()),
         }
     }
 
@@ -52,157 +74,103 @@ impl<T: Default + AsRef<[u8]>> SkipList<T> {
         height
     }
 
-    // fn contains(&self, key: impl AsRef<[u8]>) -> bool {
-    //     let key = key.as_ref();
-    //     if let Some(n) = self.get_greater_or_equal(key) {
-    //         n.key.starts_with(key.as_ref())
-    //     } else {
-    //         false
-    //     }
-    // }
-
     pub fn find_last(&self) -> Option<*const Node<T>> {
-        let head = self.head.as_ref() as *const Node<T>;
+        let mut current = self.head;
         let mut level = MAX_HEIGHT - 1;
-        let mut current = head;
         loop {
-            let next = unsafe { (*current).next.as_ref() };
-            if let Some(n) = next {
-                current = n.as_ref() as *const Node<T>;
-            } else {
-                if level == 0 {
-                    if current == head {
-                        return None;
-                    } else {
-                        return Some(current);
-                    }
+            unsafe {
+                match (*current).next(level) {
+                    Some(next) => current = next,
+                    None if level > 0 => level -= 1,
+                    None => return (current != self.head).then_some(current),
                 }
-                level -= 1;
             }
         }
     }
 
+    // the last node before `key`, `None` if the first node isn't
     pub fn find_less_than(&self, key: impl AsRef<[u8]>) -> Option<*const Node<T>> {
         let key = key.as_ref();
-        let mut current = self.head.as_ref() as *const Node<T>;
-        let mut level = self.head.skips.len() - 1;
+        let mut current = self.head;
+        let mut level = MAX_HEIGHT - 1;
         loop {
             unsafe {
-                if let Some(next) = (*current).skips[level] {
-                    let ord = self.comparator.compare((*next).key.as_ref(), key);
-                    match ord {
-                        Ordering::Less => {
-                            current = next;
-                        }
-                        Ordering::Equal | Ordering::Greater => return Some(current),
+                match (*current).next(level) {
+                    Some(next)
+                        if self.comparator.compare((*next).key.as_ref(), key) == Ordering::Less =>
+                    {
+                        current = next
                     }
-                } else {
-                    if level == 0 {
-                        return Some(current);
-                    }
-                    level -= 1
+                    _ if level > 0 => level -= 1,
+                    _ => return (current != self.head).then_some(current),
                 }
             }
         }
     }
+
     pub fn get_greater_or_equal(&self, key: impl AsRef<[u8]>) -> Option<*const Node<T>> {
         let key = key.as_ref();
-
-        let mut current = self.head.as_ref() as *const Node<T>;
-        let mut level = self.head.skips.len() - 1;
+        let mut current = self.head;
+        let mut level = MAX_HEIGHT - 1;
         loop {
             unsafe {
-                if let Some(next) = (*current).skips[level] {
-                    let ord = self.comparator.compare((*next).key.as_ref(), key);
-                    match ord {
-                        Ordering::Less => {
-                            current = next;
-                            continue;
-                        }
-                        Ordering::Equal => return Some(&*next),
-                        Ordering::Greater => {
-                            if level == 0 {
-                                return Some(&*next);
-                            }
-                        }
+                match (*current).next(level) {
+                    Some(next)
+                        if self.comparator.compare((*next).key.as_ref(), key) == Ordering::Less =>
+                    {
+                        current = next
                     }
+                    next if level == 0 => return next.map(|n| n as *const Node<T>),
+                    _ => level -= 1,
                 }
             }
-            if level == 0 {
-                break;
-            }
-            level -= 1;
-        }
-
-        unsafe {
-            if current.is_null()
-                || current == self.head.as_ref() as *const Node<T>
-                || self.comparator.compare((*current).key.as_ref(), key) == Ordering::Less
-            {
-                None
-            } else {
-                Some(current)
-            }
         }
     }
 
     pub fn insert(&self, key: T) {
-        let bytes = key.as_ref();
-        let mut prevs: [Option<*mut Node<T>>; MAX_HEIGHT] = [None; MAX_HEIGHT];
+        let _insert = self.insert_lock.lock().unwrap();
         let new_height = self.random_height();
-
-        let prevs = &mut prevs[0..new_height];
+        // the last node before `key` at every level the new node is linked in
+        let mut prevs = [self.head; MAX_HEIGHT];
+        let mut current = self.head;
         let mut level = MAX_HEIGHT - 1;
-        let mut current = self.head.as_ref() as *const Node<T> as *mut Node<T>;
-
-        for item in prevs.iter_mut() {
-            *item = Some(current);
-        }
-
         loop {
             unsafe {
-                if let Some(next) = (*current).skips[level] {
-                    let next_key = (*next).key.as_ref();
-                    let ord = self.comparator.compare(next_key, bytes);
+                if let Some(next) = (*current).next(level) {
+                    let ord = self.comparator.compare((*next).key.as_ref(), key.as_ref());
                     assert!(ord != Ordering::Equal);
-
                     if ord == Ordering::Less {
                         current = next;
                         continue;
                     }
                 }
             }
-
-            if level < new_height {
-                prevs[level] = Some(current);
-            }
-
+            prevs[level] = current;
             if level == 0 {
                 break;
             }
             level -= 1;
         }
 
-        let new_skips = vec![None; new_height];
-
-        let mut new_node = Box::new(Node {
-            skips: new_skips,
-            next: None,
-            key,
-        });
-
-        let new_ptr = new_node.as_mut() as *mut Node<T>;
-        for (i, item) in prevs.iter().enumerate().take(new_height) {
-            if let Some(prev) = *item {
-                unsafe {
-                    new_node.skips[i] = (*prev).skips[i];
-                    (*prev).skips[i] = Some(new_ptr);
-                }
+        // Linked bottom up, each level only once the node is complete below it.
+        // Its own links are set before it's published, so they need no ordering.
+        let new_node = Box::into_raw(Box::new(Node::new(key, new_height)));
+        for (i, &prev) in prevs.iter().enumerate().take(new_height) {
+            unsafe {
+                let next = (&(*prev).skips)[i].load(atomic::Ordering::Relaxed);
+                (&(*new_node).skips)[i].store(next, atomic::Ordering::Relaxed);
+                (*prev).set_next(i, new_node);
             }
         }
-        unsafe {
-            new_node.next = replace(&mut (*current).next, None);
-            let _ = replace(&mut (*current).next, Some(new_node));
+    }
+}
+
+impl<T> Drop for SkipList<T> {
+    fn drop(&mut self) {
+        let mut node = self.head;
+        while !node.is_null() {
+            let n = unsafe { Box::from_raw(node) };
+            node = n.skips[0].load(atomic::Ordering::Relaxed);
         }
     }
 }
@@ -223,8 +191,7 @@ impl<T: Default + AsRef<[u8]>> SkipListIter<T> {
     }
 
     pub fn seek_to_first(&mut self) {
-        // self.current = Some(self.map.head.as_ref() as *const Node<T>);
-        self.current = self.map.head.next()
+        self.current = unsafe { (*self.map.head).next(0).map(|n| n as *const Node<T>) };
     }
 
     pub fn seek_to_last(&mut self) {
@@ -239,10 +206,9 @@ impl<T: Default + AsRef<[u8]>> SkipListIter<T> {
         assert!(self.valid());
         self.current = unsafe {
             (*self.current.unwrap())
-                .next
-                .as_ref()
-                .map(|r| r.as_ref() as *const Node<T>)
-        }
+                .next(0)
+                .map(|n| n as *const Node<T>)
+        };
     }
 
     pub fn prev(&mut self) {
@@ -255,46 +221,116 @@ impl<T: Default + AsRef<[u8]>> SkipListIter<T> {
         let node = self.current.unwrap();
         unsafe { (*node).key.as_ref() }
     }
+
+    // lets tests corrupt a key in place, the list must not be read meanwhile
+    #[cfg(test)]
+    pub fn key_mut(&mut self) -> &mut T {
+        assert!(self.valid());
+        let node = self.current.unwrap() as *mut Node<T>;
+        unsafe { &mut (*node).key }
+    }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-
-//     #[test]
-//     fn test_skipmapinner_insert() {
-//         let mut m = SkipMapInner::new();
-//         let datas = &["liuzhenzhong", "xinghua"];
-
-//         for i in datas {
-//             m.insert(*i, *i);
-//         }
-
-//         for i in datas {
-//             let v = m.get_greater_or_equal(*i);
-//             assert!(matches!(v, Some(_)));
-
-//             assert!((*i).as_bytes().cmp(v.unwrap().key.as_slice()) == Ordering::Equal);
-
-//             // assert!(m.contains(*i));
-//         }
-//     }
-//     #[test]
-//     fn test_skipmap_iter() {
-//         let m = SkipMap::new();
-//         let datas = &["liuzhenzhong", "xinghua"];
-
-//         for i in datas {
-//             m.insert(*i, *i);
-//         }
-
-//         let mut i = 0;
-//         let mut iter = m.iter();
-//         while let Some(v) = iter.next() {
-//             assert!(v.0.as_slice().cmp(datas[i].as_bytes()) == Ordering::Equal);
-//             i += 1;
-//         }
-
-//         assert!(i == datas.len());
-//     }
-// }
+// The `miri_` tests across the crate cover the unsafe code without any file IO,
+// so they can run under `cargo miri test --lib -- miri_`.
+#[cfg(test)]
+mod tests {
+    use crate::cmp::BitWiseComparator;
+
+    use super::*;
+
+    fn collect(iter: &mut SkipListIter<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(iter.key().to_vec());
+            iter.next();
+        }
+        keys
+    }
+
+    #[test]
+    fn miri_skiplist_insert_and_iterate() {
+        let list = Arc::new(SkipList::new(Arc::new(BitWiseComparator {})));
+        let mut iter = SkipListIter::new(list.clone());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+
+        // an iterator sees the inserts made while it's positioned
+        let mut expected = Vec::new();
+        for i in [5u8, 1, 9, 3, 7, 0, 8, 2, 6, 4] {
+            list.insert(vec![i; i as usize + 1]);
+            expected.push(vec![i; i as usize + 1]);
+            iter.seek(&[]);
+            assert!(iter.valid());
+        }
+        expected.sort();
+        assert_eq!(collect(&mut iter), expected);
+
+        iter.seek_to_last();
+        let mut backward = Vec::new();
+        while iter.valid() {
+            backward.push(iter.key().to_vec());
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        iter.seek(&[4, 4]);
+        assert_eq!(iter.key(), &[4; 5]);
+        iter.seek(&[10]);
+        assert!(!iter.valid());
+        drop(list);
+        // the iterator keeps the list alive
+        assert_eq!(collect(&mut iter), expected);
+    }
+
+    // one writer and readers seeking and iterating while it inserts
+    #[test]
+    fn test_concurrent_insert_and_read() {
+        const KEYS: u64 = 20000;
+        let list = Arc::new(SkipList::new(Arc::new(BitWiseComparator {})));
+        let done = Arc::new(atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (list, done) = (list.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let mut iter = SkipListIter::new(list);
+                    let mut rng = thread_rng();
+                    while !done.load(atomic::Ordering::Acquire) {
+                        let target = rng.gen_range(0..KEYS * 2).to_be_bytes();
+                        iter.seek(&target);
+                        // whatever is found is a whole key in order
+                        let mut last = target.to_vec();
+                        for _ in 0..20 {
+                            if !iter.valid() {
+                                break;
+                            }
+                            assert_eq!(iter.key().len(), 8);
+                            assert!(iter.key() >= last.as_slice());
+                            last = iter.key().to_vec();
+                            iter.next();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // the even numbers in random order
+        let mut keys: Vec<u64> = (0..KEYS).map(|i| i * 2).collect();
+        keys.sort_by_key(|&k| (k.wrapping_mul(0x9e37_79b9_7f4a_7c15), k));
+        for &key in keys.iter() {
+            list.insert(key.to_be_bytes().to_vec());
+        }
+        done.store(true, atomic::Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let mut iter = SkipListIter::new(list);
+        let expected: Vec<Vec<u8>> = (0..KEYS).map(|i| (i * 2).to_be_bytes().to_vec()).collect();
+        assert_eq!(collect(&mut iter), expected);
+    }
+}
@@ -65,3 +65,21 @@ impl From<&str> for UnsafeSlice {
 //         unsafe { from_raw_parts(self.data, self.size) }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn miri_unsafe_slice() {
+        assert!(UnsafeSlice::default().is_empty());
+        let data = b"slice".to_vec();
+        let slice = UnsafeSlice::from(data.as_slice());
+        assert!(!slice.is_empty());
+        assert_eq!(slice.at(4), b'e');
+        assert_eq!(unsafe { slice.as_ref() }, b"slice");
+        let empty = UnsafeSlice::from(&data[5..]);
+        assert!(empty.is_empty());
+        assert_eq!(unsafe { empty.as_ref() }, b"");
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::types::SequenceNumber;
+
+// A read view of the db pinned at a sequence number: a read at a snapshot sees
+// exactly the writes made before the snapshot was taken. Snapshots can't be made
+// up from a raw sequence outside the crate, only the db hands them out, so they
+// never name a sequence the db hasn't reached. Reads clamp one that does to the
+// last sequence anyway. A snapshot is released once it and all its clones are
+// dropped.
+pub struct Snapshot {
+    sequence: SequenceNumber,
+    // the list of the db that handed it out, `None` for one not tracked
+    list: Option<Arc<Mutex<SnapshotList>>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(sequence: SequenceNumber) -> Self {
+        Snapshot {
+            sequence,
+            list: None,
+        }
+    }
+
+    pub(crate) fn acquire(list: &Arc<Mutex<SnapshotList>>, sequence: SequenceNumber) -> Self {
+        list.lock().unwrap().add(sequence);
+        Snapshot {
+            sequence,
+            list: Some(list.clone()),
+        }
+    }
+
+    pub(crate) fn sequence(&self) -> SequenceNumber {
+        self.sequence
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        match self.list {
+            Some(ref list) => Snapshot::acquire(list, self.sequence),
+            None => Snapshot::new(self.sequence),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(ref list) = self.list {
+            list.lock().unwrap().remove(self.sequence);
+        }
+    }
+}
+
+impl PartialEq for Snapshot {
+    fn eq(&self, other: &Self) -> bool {
+        self.sequence == other.sequence
+    }
+}
+
+impl Eq for Snapshot {}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+// The sequences of the snapshots handed out and not released yet. Compactions
+// keep every entry the oldest of them can read.
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    // snapshots taken at the same sequence share an entry
+    counts: BTreeMap<SequenceNumber, usize>,
+}
+
+impl SnapshotList {
+    fn add(&mut self, sequence: SequenceNumber) {
+        *self.counts.entry(sequence).or_default() += 1;
+    }
+
+    fn remove(&mut self, sequence: SequenceNumber) {
+        if let Some(count) = self.counts.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&sequence);
+            }
+        }
+    }
+
+    pub(crate) fn oldest(&self) -> Option<SequenceNumber> {
+        self.counts.keys().next().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let list = Arc::new(Mutex::new(SnapshotList::default()));
+        let oldest = || list.lock().unwrap().oldest();
+        assert_eq!(oldest(), None);
+        let s1 = Snapshot::acquire(&list, 5);
+        let s2 = Snapshot::acquire(&list, 5);
+        let s3 = Snapshot::acquire(&list, 9);
+        assert_eq!(oldest(), Some(5));
+        drop(s1);
+        assert_eq!(oldest(), Some(5));
+        drop(s2);
+        assert_eq!(oldest(), Some(9));
+        // a clone keeps the sequence alive after the original is dropped
+        let s4 = s3.clone();
+        drop(s3);
+        assert_eq!(oldest(), Some(9));
+        drop(s4);
+        assert_eq!(oldest(), None);
+
+        // untracked snapshots don't touch any list
+        let untracked = Snapshot::new(3);
+        assert_eq!(untracked.clone(), untracked);
+        drop(untracked);
+        assert_eq!(oldest(), None);
+    }
+}
@@ -1,14 +1,14 @@
-use super::format::BlockContent;
+use super::format::{BlockContent, NUM_RESTARTS_SIZE, RESTART_ENTRY_SIZE};
 use crate::{
     cmp::Comparator,
     codec::{NumberReader, VarIntReader},
     error::{Error, Result},
-    iterator::DBIterator,
-    slice::UnsafeSlice,
+    iterator::{shrink_scratch, DBIterator},
 };
 use std::{cmp::Ordering, sync::Arc};
 
-const RESTART_SIZE: usize = 4;
+// Clones share the content.
+#[derive(Clone)]
 pub struct Block {
     content: Arc<BlockContent>,
     restart_offset: u32,
@@ -18,22 +18,49 @@ pub struct Block {
 impl Block {
     pub fn from_raw(content: BlockContent) -> Result<Self> {
         let n = content.len();
-        if n < RESTART_SIZE {
+        if n < NUM_RESTARTS_SIZE {
             return Err(Error::Corruption(
                 "bad block contents, size too small".into(),
             ));
         }
         let num_restarts = (&content[n - 4..]).read_u32_le().unwrap();
-        let max_restart_allowed = (n - RESTART_SIZE) / RESTART_SIZE;
-        if num_restarts as usize > max_restart_allowed {
-            Err(Error::Corruption("bac block contents".into()))
-        } else {
-            Ok(Block {
-                content: Arc::new(content),
-                restart_offset: n as u32 - num_restarts * RESTART_SIZE as u32 - RESTART_SIZE as u32,
-                num_restarts,
-            })
+        let max_restart_allowed = (n - NUM_RESTARTS_SIZE) / RESTART_ENTRY_SIZE;
+        if num_restarts == 0 || num_restarts as usize > max_restart_allowed {
+            return Err(Error::Corruption(format!(
+                "bad block contents, {} restarts in {} bytes",
+                num_restarts, n
+            )));
+        }
+        let restart_offset =
+            n as u32 - num_restarts * RESTART_ENTRY_SIZE as u32 - NUM_RESTARTS_SIZE as u32;
+
+        // the iterator trusts every restart point to start an entry, only the single
+        // restart of an empty block points at the restart array
+        let mut restarts = &content[restart_offset as usize..n - NUM_RESTARTS_SIZE];
+        let mut last = None;
+        for i in 0..num_restarts {
+            let offset = restarts.read_u32_le().unwrap();
+            if (offset >= restart_offset && restart_offset != 0)
+                || last.is_some_and(|last| offset <= last)
+            {
+                return Err(Error::Corruption(format!(
+                    "bad block contents, restart {} at offset {} of {}",
+                    i, offset, restart_offset
+                )));
+            }
+            last = Some(offset);
         }
+
+        Ok(Block {
+            content: Arc::new(content),
+            restart_offset,
+            num_restarts,
+        })
+    }
+
+    // the bytes of the block, its restart array included
+    pub fn size(&self) -> usize {
+        self.content.len()
     }
 
     pub fn iter(&self, comparator: Arc<dyn Comparator>) -> BlockIter {
@@ -51,7 +78,9 @@ pub struct BlockIter {
     current: u32,
     restart_index: u32,
     key: Vec<u8>,
-    value: UnsafeSlice,
+    // where the value of the current entry is in the block
+    value_offset: u32,
+    value_len: u32,
     err: Option<Error>,
 }
 
@@ -68,28 +97,22 @@ impl BlockIter {
             restart_index: block.num_restarts,
 
             key: Vec::new(),
-            value: Default::default(),
+            value_offset: 0,
+            value_len: 0,
             err: None,
         }
     }
 
     pub fn next_entry_offset(&self) -> u32 {
-        let offset = unsafe {
-            self.value
-                .data()
-                .add(self.value.size())
-                // .offset(self.value.size() as isize)
-                .offset_from(self.block_content.data.as_ptr())
-        };
-        offset as u32
+        self.value_offset + self.value_len
     }
 
     pub fn seek_to_restart_point(&mut self, index: u32) {
         self.key.clear();
         self.restart_index = index;
         self.current = self.get_restart_point(index);
-        let ptr = unsafe { self.block_content.as_ptr().offset(self.current as isize) };
-        self.value = UnsafeSlice::new(ptr, 0)
+        self.value_offset = self.current;
+        self.value_len = 0;
     }
 
     pub fn decode_entry(&self, offset: u32) -> Result<(u32, u32, u32, u32)> {
@@ -122,7 +145,7 @@ impl BlockIter {
 
     fn get_restart_point(&self, index: u32) -> u32 {
         assert!(index < self.num_restarts);
-        let offset = self.restarts as usize + RESTART_SIZE * index as usize;
+        let offset = self.restarts as usize + RESTART_ENTRY_SIZE * index as usize;
         let mut buf = &self.block_content.data[offset..];
         buf.read_u32_le().unwrap()
     }
@@ -137,14 +160,12 @@ impl BlockIter {
 
         if let Ok((shared, non_shared, value_len, step)) = self.decode_entry(self.current) {
             let offset = (self.current + step) as usize;
-            let mut buf = self.block_content[offset..].as_ref();
-
-            let non_shared_key = &buf[..non_shared as usize];
+            let non_shared_key = &self.block_content[offset..offset + non_shared as usize];
             self.key.truncate(shared as usize);
             self.key.extend_from_slice(non_shared_key);
-
-            buf = &buf[non_shared as usize..];
-            self.value = buf[..value_len as usize].into();
+            shrink_scratch(&mut self.key);
+            self.value_offset = (offset + non_shared as usize) as u32;
+            self.value_len = value_len;
             while self.restart_index + 1 < self.num_restarts
                 && self.get_restart_point(self.restart_index + 1) < self.current
             {
@@ -158,6 +179,8 @@ impl BlockIter {
     }
 
     fn corruption_err(&mut self) {
+        self.current = self.restarts;
+        self.restart_index = self.num_restarts;
         self.err
             .get_or_insert(Error::Corruption("bad entry in block".into()));
     }
@@ -189,7 +212,8 @@ impl DBIterator for BlockIter {
     fn seek(&mut self, target: &[u8]) {
         let (mut left, mut right) = (0, self.num_restarts - 1);
         while left < right {
-            let mid = (left + right) / 2;
+            // round up, `left = mid` must make progress
+            let mid = (left + right).div_ceil(2);
             let region_offset = self.get_restart_point(mid);
             if let Ok((shared, non_shared, _, step)) = self.decode_entry(region_offset) {
                 if shared != 0 {
@@ -231,8 +255,10 @@ impl DBIterator for BlockIter {
         let origin = self.current;
         while self.get_restart_point(self.restart_index) >= origin {
             if self.restart_index == 0 {
+                // no entry before the first one
                 self.current = self.restarts;
                 self.restart_index = self.num_restarts;
+                return;
             }
             self.restart_index -= 1;
         }
@@ -251,20 +277,24 @@ impl DBIterator for BlockIter {
 
     fn value(&self) -> &[u8] {
         assert!(self.valid());
-        unsafe { self.value.as_ref() }
+        &self.block_content[self.value_offset as usize..self.next_entry_offset() as usize]
     }
 
-    fn status(&mut self) -> Result<()> {
-        if self.err.is_some() {
-            return Err(self.err.take().unwrap());
+    fn status(&self) -> Result<()> {
+        match &self.err {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{cmp::BitWiseComparator, sstable::block_builder::BlockBuilder};
+    use crate::{
+        cmp::BitWiseComparator,
+        iterator::{tests::assert_sticky_corruption, SCRATCH_FLOOR},
+        sstable::block_builder::BlockBuilder,
+    };
 
     use super::*;
 
@@ -295,7 +325,7 @@ mod tests {
         let contents = builder.finish();
         let block_content = BlockContent::new(contents);
         let block = Block::from_raw(block_content).unwrap();
-        let mut iter = block.iter(comparator.clone());
+        let mut iter = block.iter(comparator);
 
         iter.seek_to_first();
         for (_idx, &(key, val)) in datas.iter().enumerate() {
@@ -305,4 +335,199 @@ mod tests {
             iter.next();
         }
     }
+
+    #[test]
+    fn test_corrupted_entry() {
+        let comparator = Arc::new(BitWiseComparator {});
+        let mut builder = BlockBuilder::new(comparator.clone(), 3);
+        for &(k, v) in get_data().iter() {
+            builder.add(k, v);
+        }
+        let mut contents = builder.finish();
+        // the first entry runs past the end of the block
+        contents[1] = 127;
+        contents[2] = 127;
+        let block = Block::from_raw(BlockContent::new(contents)).unwrap();
+
+        let mut iter = block.iter(comparator);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_sticky_corruption(&iter, "bad entry in block");
+        // the later entries are fine, the error stays anyway
+        iter.seek(b"prefix_key2");
+        assert_eq!(iter.key(), b"prefix_key2");
+        assert_sticky_corruption(&iter, "bad entry in block");
+    }
+
+    // a block of the test data with restarts at every third entry, and the offset
+    // of its restart array
+    fn block_contents() -> (Vec<u8>, usize) {
+        let mut builder = BlockBuilder::new(Arc::new(BitWiseComparator {}), 3);
+        for &(k, v) in get_data().iter() {
+            builder.add(k, v);
+        }
+        let contents = builder.finish();
+        let restart_offset = contents.len() - NUM_RESTARTS_SIZE - 2 * RESTART_ENTRY_SIZE;
+        (contents, restart_offset)
+    }
+
+    fn assert_corrupted(contents: Vec<u8>, msg: &str) {
+        match Block::from_raw(BlockContent::new(contents)) {
+            Err(Error::Corruption(s)) => assert!(s.contains(msg), "{}", s),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("corrupted block accepted"),
+        }
+    }
+
+    fn set_u32(contents: &mut [u8], offset: usize, value: u32) {
+        contents[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_corrupted_restarts() {
+        let (contents, restart_offset) = block_contents();
+        let n = contents.len();
+        assert!(Block::from_raw(BlockContent::new(contents.clone())).is_ok());
+
+        // no restarts, the entries are taken for the restart array
+        let mut zero = contents[..restart_offset].to_vec();
+        zero.extend_from_slice(&0u32.to_le_bytes());
+        assert_corrupted(zero, "0 restarts");
+        assert_corrupted(0u32.to_le_bytes().to_vec(), "0 restarts");
+
+        let mut descending = contents.clone();
+        let second = u32::from_le_bytes(
+            contents[restart_offset + 4..restart_offset + 8]
+                .try_into()
+                .unwrap(),
+        );
+        set_u32(&mut descending, restart_offset, second);
+        set_u32(&mut descending, restart_offset + 4, 0);
+        assert_corrupted(descending, "restart 1 at offset 0");
+
+        let mut past_end = contents.clone();
+        set_u32(
+            &mut past_end,
+            restart_offset + 4,
+            restart_offset as u32 + 10,
+        );
+        assert_corrupted(past_end, "restart 1 at offset");
+        let mut into_restarts = contents.clone();
+        set_u32(
+            &mut into_restarts,
+            restart_offset + 4,
+            restart_offset as u32,
+        );
+        assert_corrupted(into_restarts, "restart 1 at offset");
+
+        let mut too_many = contents;
+        set_u32(&mut too_many, n - 4, u32::MAX);
+        assert_corrupted(too_many, "restarts in");
+    }
+
+    #[test]
+    fn test_empty_block() {
+        let builder = BlockBuilder::new(Arc::new(BitWiseComparator {}), 3);
+        let block = Block::from_raw(BlockContent::new(builder.finish())).unwrap();
+        let mut iter = block.iter(Arc::new(BitWiseComparator {}));
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek(b"key");
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
+    // the key buffer grows for a giant key and shrinks back on the small ones after
+    #[test]
+    fn test_key_buffer_shrinks() {
+        let comparator = Arc::new(BitWiseComparator {});
+        let mut builder = BlockBuilder::new(comparator.clone(), 16);
+        let giant = vec![b'a'; 1 << 20];
+        builder.add(giant.as_slice(), b"giant");
+        let keys: Vec<_> = (0..1000).map(|i| format!("b{:05}", i)).collect();
+        for key in keys.iter() {
+            builder.add(key.as_bytes(), b"small");
+        }
+        let block = Block::from_raw(BlockContent::new(builder.finish())).unwrap();
+        let mut iter = block.iter(comparator);
+
+        iter.seek_to_first();
+        assert_eq!(iter.key(), giant.as_slice());
+        assert!(iter.key.capacity() >= giant.len());
+        iter.next();
+        for key in keys.iter() {
+            assert_eq!(iter.key(), key.as_bytes());
+            assert!(iter.key.capacity() <= SCRATCH_FLOOR);
+            iter.next();
+        }
+        assert!(!iter.valid());
+
+        // going back over the giant key grows it again, only for as long as needed
+        iter.seek(b"b00000");
+        iter.prev();
+        assert_eq!(iter.key(), giant.as_slice());
+        iter.next();
+        assert_eq!(iter.key(), b"b00000");
+        assert!(iter.key.capacity() <= SCRATCH_FLOOR);
+    }
+
+    // the iterator shares the contents, it outlives the block
+    #[test]
+    fn miri_iter_outlives_block() {
+        let block = Block::from_raw(BlockContent::new(block_contents().0)).unwrap();
+        let mut iter = block.iter(Arc::new(BitWiseComparator {}));
+        drop(block);
+        let datas = get_data();
+        iter.seek_to_last();
+        for &(key, val) in datas.iter().rev() {
+            assert_eq!((iter.key(), iter.value()), (key, val));
+            iter.prev();
+        }
+        assert!(!iter.valid());
+        iter.seek(b"medium");
+        assert_eq!(iter.value(), b"some value 2");
+    }
+
+    // every target against the first key not before it, for several restart
+    // intervals: before the first key, on and between restart keys, past the end
+    #[test]
+    fn test_seek_targets() {
+        let comparator = Arc::new(BitWiseComparator {});
+        let keys: Vec<_> = (0..20).map(|i| format!("key{:03}", i * 2)).collect();
+        let mut targets: Vec<_> = (0..42).map(|i| format!("key{:03}", i)).collect();
+        targets.extend(["", "a", "key", "key0000", "z"].map(String::from));
+        for interval in 1..=5 {
+            let mut builder = BlockBuilder::new(comparator.clone(), interval);
+            for key in keys.iter() {
+                builder.add(key.as_bytes(), key.as_bytes());
+            }
+            let block = Block::from_raw(BlockContent::new(builder.finish())).unwrap();
+            let mut iter = block.iter(comparator.clone());
+            for target in targets.iter() {
+                iter.seek(target.as_bytes());
+                match keys.iter().position(|key| key >= target) {
+                    Some(i) => {
+                        assert_eq!(iter.key(), keys[i].as_bytes());
+                        iter.prev();
+                        match i {
+                            0 => assert!(!iter.valid()),
+                            _ => assert_eq!(iter.key(), keys[i - 1].as_bytes()),
+                        }
+                    }
+                    None => assert!(!iter.valid()),
+                }
+                assert!(iter.status().is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_prev_from_first_entry() {
+        let block = Block::from_raw(BlockContent::new(block_contents().0)).unwrap();
+        let mut iter = block.iter(Arc::new(BitWiseComparator {}));
+        iter.seek_to_first();
+        iter.prev();
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
 }
@@ -1,19 +1,78 @@
-use std::{io::Read, ops::Deref};
-
-use snap::read::FrameDecoder;
+//! On-disk layout of the table files written by this crate.
+//!
+//! All fixed width integers are little endian, varints use the leveldb
+//! base-128 encoding (`codec::VarIntWriter`).
+//!
+//! ```text
+//! table      := data_block* filter_block? properties_block meta_index_block
+//!               index_block footer
+//! block      := block_data type:u8 checksum:fixed32
+//! footer     := meta_index_handle index_handle padding magic:fixed64
+//! handle     := offset:varint64 size:varint64
+//! ```
+//!
+//! * every block is followed by a `BLOCK_TRAILER_SIZE` trailer. `type` is a
+//!   `Compress` byte, `checksum` is the unmasked crc32 (IEEE) of
+//!   `block_data` followed by the type byte, or 0 for a block written without
+//!   one (`Options::disable_data_checksums`). `handle.size` excludes the trailer.
+//! * the footer is always `FULL_FOOTER_LENGTH` bytes: both handles, zero padded to
+//!   `FOOTER_LENGTH`, followed by `TABLE_MAGIC_NUMBER`.
+//! * a block is a sequence of prefix compressed entries followed by the restart array:
+//!
+//! ```text
+//! entry         := shared:varint32 non_shared:varint32 value_len:varint32
+//!                  key_delta:[u8; non_shared] value:[u8; value_len]
+//! restart_array := restart_offset:fixed32 * num_restarts num_restarts:fixed32
+//! ```
+//!
+//!   `shared` is always 0 at a restart point. data blocks restart every
+//!   `Options::block_restart_interval` entries, the index block every entry.
+//! * index block entries map a separator key (>= every key of the block, < every
+//!   key of the next block) to the encoded handle of a data block.
+//! * the meta index block maps `FILTER_META_KEY_PREFIX` + filter policy name to the
+//!   handle of the filter block, and `PROPERTIES_META_KEY` to the handle of the
+//!   properties block. Its keys are in bytewise order, entries of other names are
+//!   skipped. The names in use or set aside are `consts::RESERVED_META_KEYS`.
+//! * the properties block maps property names to values, in bytewise order. The
+//!   `COMPARATOR_PROPERTY` is the name of the user comparator the keys are ordered
+//!   by. Tables of older versions have no properties block.
+//! * filter block, see `filter_block::FilterBlockBuilder`:
+//!
+//! ```text
+//! filter_block := filter* filter_offset:fixed32 * n array_offset:fixed32 base_lg:u8
+//! ```
+
+use std::{io, ops::Deref};
+
+#[cfg(feature = "snappy")]
+use {snap::read::FrameDecoder, std::io::Read};
 
 use crate::{
-    codec::{self, NumberReader, VarIntReader, VarIntWriter},
+    codec::{self, NumberReader, VarIntWriter},
     env::RandomAccessFile,
     error::{Error, Result},
     options::{Compress, ReadOption},
+    utils::hash::CRC32,
 };
 
-pub const FOOTER_LENGTH: usize = 40;
+// two varint64
+pub const MAX_ENCODED_BLOCK_HANDLE_LENGTH: usize = 10 + 10;
+pub const FOOTER_LENGTH: usize = 2 * MAX_ENCODED_BLOCK_HANDLE_LENGTH;
 pub const FULL_FOOTER_LENGTH: usize = FOOTER_LENGTH + 8;
-pub const MAGIC_FOOTER_ENCODED: [u8; 8] = [0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb];
+pub const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+pub const MAGIC_FOOTER_ENCODED: [u8; 8] = TABLE_MAGIC_NUMBER.to_le_bytes();
+
+pub const BLOCK_TYPE_SIZE: usize = 1;
+pub const BLOCK_CHECKSUM_SIZE: usize = 4;
 // 1-byte type + 32-bit crc
-pub const BLOCK_TRAILER_SIZE: usize = 5;
+pub const BLOCK_TRAILER_SIZE: usize = BLOCK_TYPE_SIZE + BLOCK_CHECKSUM_SIZE;
+
+// each restart point is a fixed32 offset, the array ends with a fixed32 count
+pub const RESTART_ENTRY_SIZE: usize = 4;
+pub const NUM_RESTARTS_SIZE: usize = 4;
+
+pub use crate::consts::{FILTER_META_KEY_PREFIX, PROPERTIES_META_KEY};
+pub const COMPARATOR_PROPERTY: &str = "comparator";
 
 #[derive(Clone, Copy, Default)]
 pub struct BlockHandle {
@@ -46,33 +105,19 @@ impl BlockHandle {
     }
 
     pub fn encode(&self, dst: &mut [u8]) -> usize {
-        assert!(
-            dst.len()
-                >= codec::required_space(self.offset as u64)
-                    + codec::required_space(self.size as u64)
-        );
+        assert!(dst.len() >= codec::required_space(self.offset) + codec::required_space(self.size));
         let mut dst = dst;
-        // let offset = self.offset.encode_var(dst);
-        // self.size.encode_var(&mut dst[offset..]) + offset
         dst.write_var_u64(self.offset).unwrap() + dst.write_var_u64(self.size).unwrap()
     }
 
     pub fn decode(&mut self, data: &[u8]) -> Result<usize> {
-        let mut data = data;
-        let (offset, offset_len) = data.read_var_u64()?;
-        let (size, size_len) = data.read_var_u64()?;
+        let (offset, offset_len) = codec::decode_var_u64(data)?;
+        let (size, size_len) = codec::decode_var_u64(&data[offset_len..])?;
         self.offset = offset;
         self.size = size;
 
         Ok(offset_len + size_len)
     }
-
-    // pub fn decode_from(data: &[u8]) -> Option<(BlockHandle, usize)> {
-    //     let (offset, offset_len) = u64::decode_var(data)?;
-    //     let (size, size_len) = u64::decode_var(&data[offset_len..])?;
-
-    //     Some((BlockHandle { offset, size }, offset_len + size_len))
-    // }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -133,17 +178,19 @@ impl BlockContent {
     ) -> Result<Self> {
         let n = handle.size as usize;
         let mut buf = vec![0; n + BLOCK_TRAILER_SIZE];
+        // a short read means the file was cut, any other error is passed on
         file.read_exact_at(buf.as_mut(), handle.offset)
-            .map_err(|_| Error::Corruption("truncated block read".into()))?;
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => Error::Corruption("truncated block read".into()),
+                _ => err.into(),
+            })?;
 
         let data = buf.as_slice();
         if option.verify_checksum {
-            // let (checksum, _) = data[n + 1..].decode_u32_le()?;
-            // let checksum = u32::decode_fixed(&data[n + 1..]);
+            // a block written without a checksum has a zero one, see
+            // `Options::disable_data_checksums`
             let checksum = (&data[n + 1..]).read_u32_le()?;
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.update(&data[0..n + 1]);
-            if checksum != hasher.finalize() {
+            if checksum != 0 && checksum != CRC32.checksum(&data[0..n + 1]) {
                 return Err(Error::Corruption("block check sum mismatch".into()));
             }
         }
@@ -157,6 +204,7 @@ impl BlockContent {
                     // cachable: true,
                 })
             }
+            #[cfg(feature = "snappy")]
             Compress::Snappy => {
                 let mut uncompressed_data = Vec::new();
                 let mut reader = FrameDecoder::new(&data[..n]);
@@ -167,6 +215,10 @@ impl BlockContent {
                     data: uncompressed_data,
                 })
             }
+            #[cfg(not(feature = "snappy"))]
+            Compress::Snappy => Err(Error::InvalidData(
+                "snappy compressed block, built without the snappy feature".into(),
+            )),
         }
     }
 }
@@ -6,4 +6,4 @@ mod table;
 pub mod table_cache;
 pub mod two_level_iterator;
 
-pub use table::{Table,TableBuiler,TableBlockIterBuilder};
+pub use table::{Table,TableBuiler,TableBlockIterBuilder,TableMetadata};
@@ -1,21 +1,32 @@
-use std::{cmp::Ordering, io::Write, sync::Arc};
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+};
 
-use snap::write::FrameEncoder;
+#[cfg(feature = "snappy")]
+use {snap::write::FrameEncoder, std::io::Write};
 
 use crate::{
     cmp::{BitWiseComparator, Comparator},
     codec::NumberWriter,
-    env::{RandomAccessFile, WritableFile},
+    env::{Env, RandomAccessFile, WritableFile},
     error::{Error, Result},
+    filter::FilterPolicy,
     iterator::DBIterator,
     options::{Compress, Options, ReadOption},
+    rate_limiter::IoPriority,
+    utils::{cache::Cache, hash::CRC32},
 };
 
 use super::{
     block::{Block, BlockIter},
     block_builder::BlockBuilder,
     filter_block::FilterBlockBuilder,
-    format::{BlockContent, BlockHandle, Footer, BLOCK_TRAILER_SIZE, FULL_FOOTER_LENGTH},
+    filter_block::FilterBlockReader,
+    format::{
+        BlockContent, BlockHandle, Footer, BLOCK_TRAILER_SIZE, COMPARATOR_PROPERTY,
+        FILTER_META_KEY_PREFIX, FULL_FOOTER_LENGTH, PROPERTIES_META_KEY,
+    },
     two_level_iterator::{BlockIterBuilder, TwoLevelIterator},
 };
 
@@ -25,7 +36,39 @@ pub struct Table<R: RandomAccessFile> {
 
     meta_index_handle: BlockHandle,
     index_block: Block,
-    filter_block_data: Option<BlockContent>,
+    filter_policy: Option<Arc<dyn FilterPolicy>>,
+    filter_block_data: Option<Arc<BlockContent>>,
+
+    lookup_hint: Mutex<Option<LookupHint>>,
+
+    // the data blocks are cached under the id of the table and their offset
+    block_cache: Option<Arc<dyn Cache<Vec<u8>, Block>>>,
+    cache_id: u64,
+}
+
+// What a table reads and parses on open besides its footer and data blocks. The
+// table cache keeps it once the table is evicted, so reopening the table reads
+// neither again, see `Options::pin_table_metadata_in_cache`.
+pub struct TableMetadata {
+    meta_index_handle: BlockHandle,
+    index_block: Block,
+    filter: Option<(Arc<dyn FilterPolicy>, Arc<BlockContent>)>,
+}
+
+impl TableMetadata {
+    // the bytes of the index and filter blocks
+    pub fn charge(&self) -> usize {
+        self.index_block.size() + self.filter.as_ref().map_or(0, |(_, data)| data.data.len())
+    }
+}
+
+// The index entry used by the last get. Every key in [lower, separator] maps to
+// `handle`, lower starts at the looked up key and widens as more keys of the same
+// entry are looked up. Only a hint, entries are never shared with the caller.
+struct LookupHint {
+    lower: Vec<u8>,
+    separator: Vec<u8>,
+    handle: BlockHandle,
 }
 
 impl<R: RandomAccessFile> Table<R> {
@@ -39,47 +82,93 @@ impl<R: RandomAccessFile> Table<R> {
         let mut footer = Footer::default();
         footer.decode(&scratch)?;
 
-        let read_options = ReadOption {
-            verify_checksum: true,
-            fill_cache: false,
-        };
+        let read_options = ReadOption::VERIFY;
         let index_content =
             BlockContent::read_block_from_file(&file, &footer.index_handle, &read_options)?;
         let index_block = Block::from_raw(index_content)?;
 
-        // read meta , ignore error
-        let filter_meta_data = if let Ok(meta_data) = Self::read_meta(&file, &options, &footer) {
-            meta_data
-        } else {
-            None
+        // the table can be read without its filter, but a filter lost to a corrupted
+        // meta index should be found out about
+        let filter = match Self::read_meta(&file, &options, &footer) {
+            Ok(filter) => filter.map(|(policy, data)| (policy, Arc::new(data))),
+            Err(err) => {
+                if let Some(logger) = options.info_log.as_ref() {
+                    logger.log(&format!(
+                        "reading the table without its filter, the meta index is unreadable: {}",
+                        err
+                    ));
+                }
+                None
+            }
         };
-        // TODO cache
-        let table = Table {
-            file,
-            options,
+        let metadata = TableMetadata {
             meta_index_handle: footer.meta_index_handle,
             index_block,
-            filter_block_data: filter_meta_data,
+            filter,
+        };
+        Ok(Self::from_metadata(options, file, &metadata))
+    }
+
+    // Opens the table with the index and filter of an earlier open of the same
+    // file, reading nothing.
+    pub fn from_metadata(options: Arc<Options>, file: R, metadata: &TableMetadata) -> Self {
+        let (filter_policy, filter_block_data) = match &metadata.filter {
+            Some((policy, data)) => (Some(policy.clone()), Some(data.clone())),
+            None => (None, None),
         };
-        Ok(table)
+        let block_cache = options.block_cache.clone();
+        let cache_id = block_cache.as_ref().map_or(0, |cache| cache.new_id());
+        Table {
+            file,
+            options,
+            meta_index_handle: metadata.meta_index_handle,
+            index_block: metadata.index_block.clone(),
+            filter_policy,
+            filter_block_data,
+            lookup_hint: Mutex::new(None),
+            block_cache,
+            cache_id,
+        }
     }
 
+    // shares the blocks of the table, which stay valid after it's dropped
+    pub fn metadata(&self) -> TableMetadata {
+        let filter = match (&self.filter_policy, &self.filter_block_data) {
+            (Some(policy), Some(data)) => Some((policy.clone(), data.clone())),
+            _ => None,
+        };
+        TableMetadata {
+            meta_index_handle: self.meta_index_handle,
+            index_block: self.index_block.clone(),
+            filter,
+        }
+    }
+
+    // the table may have been written with any of the configured policies (or none at
+    // all, see `Options::filter_policy_per_level`), so look the filter up by each policy
+    // name. a filter written by an unknown policy is skipped rather than reported.
     fn read_meta(
         file: &R,
         options: &Arc<Options>,
         footer: &Footer,
-    ) -> Result<Option<BlockContent>> {
-        if let Some(ref policy) = options.filter_policy {
-            let read_option = ReadOption {
-                verify_checksum: true,
-                fill_cache: false,
-            };
-            let meta_block_content =
-                BlockContent::read_block_from_file(file, &footer.meta_index_handle, &read_option)?;
-            let meta_block = Block::from_raw(meta_block_content)?;
-            let comparator = BitWiseComparator {};
-            let mut iter = meta_block.iter(Arc::new(comparator));
-            let mut key = Vec::from("filter");
+    ) -> Result<Option<(Arc<dyn FilterPolicy>, BlockContent)>> {
+        let mut policies: Vec<Arc<dyn FilterPolicy>> = Vec::new();
+        policies.extend(options.filter_policy.iter().cloned());
+        if let Some(ref per_level) = options.filter_policy_per_level {
+            policies.extend(per_level.iter().flatten().cloned());
+        }
+        if policies.is_empty() {
+            return Ok(None);
+        }
+
+        let read_option = ReadOption::VERIFY;
+        let meta_block_content =
+            BlockContent::read_block_from_file(file, &footer.meta_index_handle, &read_option)?;
+        let meta_block = Block::from_raw(meta_block_content)?;
+        let comparator = BitWiseComparator {};
+        let mut iter = meta_block.iter(Arc::new(comparator));
+        for policy in policies {
+            let mut key = Vec::from(FILTER_META_KEY_PREFIX);
             key.extend_from_slice(policy.name().as_bytes());
 
             iter.seek(&key);
@@ -88,24 +177,179 @@ impl<R: RandomAccessFile> Table<R> {
                 handle.decode(iter.value())?;
                 let filter_block_content =
                     BlockContent::read_block_from_file(file, &handle, &read_option)?;
-                return Ok(Some(filter_block_content));
+                return Ok(Some((policy, filter_block_content)));
             }
         }
         Ok(None)
     }
 
+    // The entries of the meta index block: the name of each meta block and its
+    // encoded handle.
+    pub fn meta_index_iter(&self) -> Result<BlockIter> {
+        let read_option = ReadOption::VERIFY;
+        let content =
+            BlockContent::read_block_from_file(&self.file, &self.meta_index_handle, &read_option)?;
+        Ok(Block::from_raw(content)?.iter(Arc::new(BitWiseComparator {})))
+    }
+
+    // The name of the user comparator the table was written with, `None` for a
+    // table without a properties block.
+    pub fn comparator_name(&self) -> Result<Option<String>> {
+        let mut meta_index = self.meta_index_iter()?;
+        meta_index.seek(PROPERTIES_META_KEY.as_bytes());
+        if !meta_index.valid() || meta_index.key() != PROPERTIES_META_KEY.as_bytes() {
+            meta_index.status()?;
+            return Ok(None);
+        }
+        let handle = BlockHandle::from_raw(meta_index.value())?;
+        let content = BlockContent::read_block_from_file(&self.file, &handle, &ReadOption::VERIFY)?;
+        let mut properties = Block::from_raw(content)?.iter(Arc::new(BitWiseComparator {}));
+        properties.seek(COMPARATOR_PROPERTY.as_bytes());
+        if properties.valid() && properties.key() == COMPARATOR_PROPERTY.as_bytes() {
+            return Ok(Some(
+                String::from_utf8_lossy(properties.value()).into_owned(),
+            ));
+        }
+        properties.status()?;
+        Ok(None)
+    }
+
+    // The filter block, if the table has one written by a configured policy.
+    pub fn filter_data(&self) -> Option<&[u8]> {
+        self.filter_block_data
+            .as_ref()
+            .map(|data| data.data.as_slice())
+    }
+
+    // The name of the policy the table's filter was written with, whether or not
+    // it is configured. `None` for a table without a filter or whose meta index
+    // can't be read.
+    pub fn filter_policy_name(&self) -> Option<String> {
+        let mut iter = self.meta_index_iter().ok()?;
+        iter.seek(FILTER_META_KEY_PREFIX.as_bytes());
+        if !iter.valid() {
+            return None;
+        }
+        let name = iter.key().strip_prefix(FILTER_META_KEY_PREFIX.as_bytes())?;
+        Some(String::from_utf8_lossy(name).into_owned())
+    }
+
     fn block_iter_from_index(
         &self,
         read_option: &ReadOption,
         index_value: &[u8],
     ) -> Result<BlockIter> {
         let block_handle = BlockHandle::from_raw(index_value)?;
-        let block_content =
-            BlockContent::read_block_from_file(&self.file, &block_handle, read_option)?;
-        let block = Block::from_raw(block_content)?;
-        Ok(block.iter(self.options.comparator.clone()))
+        self.read_data_block(read_option, &block_handle)
     }
-    pub(crate) fn iter(
+
+    // Iterates the data block of `handle`, from the block cache if it has the
+    // block. A block read from the file is cached if `fill_cache` is set.
+    fn read_data_block(&self, read_option: &ReadOption, handle: &BlockHandle) -> Result<BlockIter> {
+        let comparator = self.options.comparator.clone();
+        let cache = match self.block_cache {
+            Some(ref cache) => cache,
+            None => {
+                let content = BlockContent::read_block_from_file(&self.file, handle, read_option)?;
+                return Ok(Block::from_raw(content)?.iter(comparator));
+            }
+        };
+        let mut cache_key = Vec::with_capacity(16);
+        cache_key.write_u64_le(self.cache_id)?;
+        cache_key.write_u64_le(handle.offset())?;
+        if let Some(block) = cache.lookup(&cache_key) {
+            return Ok(block.iter(comparator));
+        }
+
+        let content = BlockContent::read_block_from_file(&self.file, handle, read_option)?;
+        let charge = content.len() as u64;
+        let block = Block::from_raw(content)?;
+        // the iterator shares the content with the cached block
+        let iter = block.iter(comparator);
+        if read_option.fill_cache {
+            cache.insert(cache_key, block, charge);
+        }
+        Ok(iter)
+    }
+    // Calls `handle_result` with the first entry >= key, if the block that may hold it
+    // passes the filter.
+    pub(crate) fn internal_get<F: FnMut(&[u8], &[u8])>(
+        &self,
+        option: &ReadOption,
+        key: &[u8],
+        mut handle_result: F,
+    ) -> Result<()> {
+        let handle = match self.hinted_block(key) {
+            Some(handle) => handle,
+            None => match self.seek_index(key)? {
+                Some(handle) => handle,
+                None => return Ok(()),
+            },
+        };
+
+        if let (Some(policy), Some(data)) = (&self.filter_policy, &self.filter_block_data) {
+            let filter = FilterBlockReader::new(policy.clone(), data);
+            if !filter.key_may_match(handle.offset() as usize, key) {
+                return Ok(());
+            }
+        }
+
+        let mut block_iter = self.read_data_block(option, &handle)?;
+        block_iter.seek(key);
+        if block_iter.valid() {
+            handle_result(block_iter.key(), block_iter.value());
+        }
+        block_iter.status()
+    }
+
+    fn hinted_block(&self, key: &[u8]) -> Option<BlockHandle> {
+        if !self.options.table_lookup_hint {
+            return None;
+        }
+        let hint = self.lookup_hint.lock().unwrap();
+        let hint = hint.as_ref()?;
+        let comparator = &self.options.comparator;
+        if comparator.compare(&hint.lower, key) != Ordering::Greater
+            && comparator.compare(key, &hint.separator) != Ordering::Greater
+        {
+            Some(hint.handle)
+        } else {
+            None
+        }
+    }
+
+    fn seek_index(&self, key: &[u8]) -> Result<Option<BlockHandle>> {
+        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+        index_iter.seek(key);
+        if !index_iter.valid() {
+            index_iter.status()?;
+            return Ok(None);
+        }
+        let handle = BlockHandle::from_raw(index_iter.value())?;
+        if !self.options.table_lookup_hint {
+            return Ok(Some(handle));
+        }
+
+        let mut hint = self.lookup_hint.lock().unwrap();
+        match hint.as_mut() {
+            // same entry as last time, extend the range downwards
+            Some(h) if h.handle.offset() == handle.offset() => {
+                if self.options.comparator.compare(key, &h.lower) == Ordering::Less {
+                    h.lower = key.to_vec();
+                }
+            }
+            _ => {
+                *hint = Some(LookupHint {
+                    lower: key.to_vec(),
+                    separator: index_iter.key().to_vec(),
+                    handle,
+                })
+            }
+        }
+        Ok(Some(handle))
+    }
+
+    pub fn iter(
         self: Arc<Table<R>>,
         option: &ReadOption,
     ) -> TwoLevelIterator<BlockIter, TableBlockIterBuilder<R>> {
@@ -115,6 +359,74 @@ impl<R: RandomAccessFile> Table<R> {
         TwoLevelIterator::new(index_iter, block_iter_builder, option.clone())
     }
 
+    // The separator key and handle of every data block, in key order. A block
+    // holds the keys after the separator of the one before it, up to its own.
+    pub fn block_ranges(&self) -> Result<Vec<(Vec<u8>, BlockHandle)>> {
+        let mut ranges = Vec::new();
+        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            let handle = BlockHandle::from_raw(index_iter.value())?;
+            ranges.push((index_iter.key().to_vec(), handle));
+            index_iter.next();
+        }
+        index_iter.status()?;
+        Ok(ranges)
+    }
+
+    // About the bytes of the file before the data of `key`: the offset of the block
+    // that would hold it. Keys past the last block map to the end of the data,
+    // where the meta blocks start.
+    pub fn approximate_offset_of(&self, key: &[u8]) -> u64 {
+        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+        index_iter.seek(key);
+        if index_iter.valid() {
+            if let Ok(handle) = BlockHandle::from_raw(index_iter.value()) {
+                return handle.offset();
+            }
+        }
+        self.meta_index_handle.offset()
+    }
+
+    // Like `iter`, but over the data blocks `start_block..end_block` of
+    // `block_ranges` only. Iterators over disjoint block ranges can scan the table
+    // from several threads.
+    pub fn iter_range(
+        self: &Arc<Table<R>>,
+        option: &ReadOption,
+        start_block: usize,
+        end_block: usize,
+    ) -> Result<TwoLevelIterator<BlockIter, TableBlockIterBuilder<R>>> {
+        let mut index = BlockBuilder::new(self.options.comparator.clone(), 1);
+        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+        index_iter.seek_to_first();
+        for _ in 0..start_block {
+            if !index_iter.valid() {
+                break;
+            }
+            index_iter.next();
+        }
+        for _ in start_block..end_block {
+            if !index_iter.valid() {
+                break;
+            }
+            index.add(index_iter.key(), index_iter.value());
+            index_iter.next();
+        }
+        index_iter.status()?;
+
+        let index_block = Block::from_raw(BlockContent::new(index.finish()))?;
+        let index_iter = index_block.iter(self.options.comparator.clone());
+        let block_iter_builder = TableBlockIterBuilder {
+            table: self.clone(),
+        };
+        Ok(TwoLevelIterator::new(
+            index_iter,
+            block_iter_builder,
+            option.clone(),
+        ))
+    }
+
     pub(crate) fn print_indexes(&self) {
         let mut index_iter = self.index_block.iter(self.options.comparator.clone());
         index_iter.seek_to_first();
@@ -165,6 +477,10 @@ pub struct TableBuiler<W: WritableFile> {
     pending_index_entry: bool,
     pending_handle: BlockHandle,
     compress_out: Vec<u8>,
+
+    // requests the bytes written from the rate limiter, see `set_io_priority`
+    rate_limit: Option<Box<dyn Fn(u64) + Send>>,
+    charged: u64,
 }
 
 impl<W: WritableFile> TableBuiler<W> {
@@ -189,9 +505,31 @@ impl<W: WritableFile> TableBuiler<W> {
             pending_index_entry: false,
             pending_handle: Default::default(),
             compress_out: Vec::new(),
+            rate_limit: None,
+            charged: 0,
         }
     }
 
+    // Background tables are written at the pace of `Options::rate_limiter`,
+    // which sleeps with `env`. Foreground ones, the default, aren't limited.
+    pub fn set_io_priority<E: Env>(&mut self, priority: IoPriority, env: E) {
+        self.rate_limit = match (priority, &self.options.rate_limiter) {
+            (IoPriority::Background, Some(limiter)) => {
+                let limiter = limiter.clone();
+                Some(Box::new(move |bytes| limiter.request(&env, bytes)))
+            }
+            _ => None,
+        };
+    }
+
+    // charges the bytes written since the last charge
+    fn charge_written(&mut self) {
+        if let Some(ref rate_limit) = self.rate_limit {
+            rate_limit(self.offset - self.charged);
+        }
+        self.charged = self.offset;
+    }
+
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         assert!(self.data_block.is_some());
         assert!(self.index_block.is_some());
@@ -232,6 +570,16 @@ impl<W: WritableFile> TableBuiler<W> {
         }
         Ok(())
     }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    // bytes written to the file so far, the pending data block is not included
+    pub fn file_size(&self) -> u64 {
+        self.offset
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         assert!(self.data_block.is_some());
 
@@ -254,9 +602,11 @@ impl<W: WritableFile> TableBuiler<W> {
             self.options.compression_type,
             &mut self.compress_out,
             self.offset,
+            !self.options.disable_data_checksums,
         )?;
         self.offset = offset;
         self.pending_index_entry = true;
+        self.charge_written();
         self.file.flush()?;
 
         if let Some(b) = self.filter_block.as_mut() {
@@ -269,12 +619,12 @@ impl<W: WritableFile> TableBuiler<W> {
     pub fn finish(mut self, sync: bool) -> Result<u64> {
         self.flush()?;
 
-        let mut meta_index_block = BlockBuilder::new(
-            self.options.comparator.clone(),
-            self.options.block_restart_interval,
-        );
+        // the meta blocks are keyed by name, whatever the table's comparator
+        let bytewise = Arc::new(BitWiseComparator {});
+        let mut meta_index_block =
+            BlockBuilder::new(bytewise.clone(), self.options.block_restart_interval);
         let mut meta_index_block_handle: BlockHandle = Default::default();
-        if let Some(filter_builder) = self.filter_block {
+        if let Some(filter_builder) = self.filter_block.take() {
             let mut filter_block_handle = BlockHandle::new(0, 0);
             let block_content = filter_builder.finish();
             self.offset = write_raw_block(
@@ -283,8 +633,9 @@ impl<W: WritableFile> TableBuiler<W> {
                 self.options.compression_type,
                 &mut filter_block_handle,
                 self.offset,
+                !self.options.disable_data_checksums,
             )?;
-            let mut key = Vec::from("filter".as_bytes());
+            let mut key = Vec::from(FILTER_META_KEY_PREFIX);
             if let Some(policy) = &self.options.filter_policy {
                 key.extend_from_slice(policy.name().as_bytes());
                 let mut handle_encoding = vec![0; 16];
@@ -293,6 +644,25 @@ impl<W: WritableFile> TableBuiler<W> {
             }
         }
 
+        let mut properties_block = BlockBuilder::new(bytewise, 1);
+        properties_block.add(
+            COMPARATOR_PROPERTY.as_bytes(),
+            self.options.comparator.user_comparator_name().as_bytes(),
+        );
+        let mut properties_block_handle = BlockHandle::default();
+        self.offset = write_block(
+            &mut self.file,
+            properties_block,
+            &mut properties_block_handle,
+            self.options.compression_type,
+            &mut self.compress_out,
+            self.offset,
+            !self.options.disable_data_checksums,
+        )?;
+        let mut handle_encoding = vec![0; 16];
+        let off = properties_block_handle.encode(&mut handle_encoding);
+        meta_index_block.add(PROPERTIES_META_KEY.as_bytes(), &handle_encoding[..off]);
+
         self.offset = write_block(
             &mut self.file,
             meta_index_block,
@@ -300,6 +670,7 @@ impl<W: WritableFile> TableBuiler<W> {
             self.options.compression_type,
             &mut self.compress_out,
             self.offset,
+            !self.options.disable_data_checksums,
         )?;
 
         let mut index_block_handle = BlockHandle::default();
@@ -320,6 +691,7 @@ impl<W: WritableFile> TableBuiler<W> {
             self.options.compression_type,
             &mut self.compress_out,
             self.offset,
+            !self.options.disable_data_checksums,
         )?;
 
         let footer = Footer::new(meta_index_block_handle, index_block_handle);
@@ -328,6 +700,7 @@ impl<W: WritableFile> TableBuiler<W> {
 
         self.file.append(&buf)?;
         self.offset += buf.len() as u64;
+        self.charge_written();
 
         if sync {
             self.file.sync()?;
@@ -337,6 +710,8 @@ impl<W: WritableFile> TableBuiler<W> {
     }
 }
 
+// `compress_out` is only needed by snappy
+#[cfg_attr(not(feature = "snappy"), allow(unused_variables, clippy::ptr_arg))]
 fn write_block<W: WritableFile>(
     file: &mut W,
     block: BlockBuilder,
@@ -344,10 +719,12 @@ fn write_block<W: WritableFile>(
     compress_type: Compress,
     compress_out: &mut Vec<u8>,
     offset: u64,
+    checksum: bool,
 ) -> Result<u64> {
     let raw = block.finish();
     let (compress_type, block_content) = match compress_type {
         Compress::NO => (Compress::NO, raw.as_slice()),
+        #[cfg(feature = "snappy")]
         Compress::Snappy => {
             compress_out.clear();
             {
@@ -360,9 +737,12 @@ fn write_block<W: WritableFile>(
                 (Compress::NO, raw.as_slice())
             }
         }
+        // stored uncompressed like a block snappy doesn't shrink
+        #[cfg(not(feature = "snappy"))]
+        Compress::Snappy => (Compress::NO, raw.as_slice()),
     };
 
-    let offset = write_raw_block(file, block_content, compress_type, handle, offset)?;
+    let offset = write_raw_block(file, block_content, compress_type, handle, offset, checksum)?;
     Ok(offset)
 }
 
@@ -372,14 +752,20 @@ fn write_raw_block<W: WritableFile>(
     compress_type: Compress,
     handle: &mut BlockHandle,
     offset: u64,
+    checksum: bool,
 ) -> Result<u64> {
     handle.set_offset(offset);
     handle.set_size(block_content.len() as u64);
 
-    let mut hasher = crc32fast::Hasher::new();
-    hasher.update(block_content);
-    hasher.update(&[compress_type.as_byte()]);
-    let checksum = hasher.finalize();
+    // a zero checksum is not verified, see `Options::disable_data_checksums`
+    let checksum = if checksum {
+        let mut digest = CRC32.digest();
+        digest.update(block_content);
+        digest.update(&[compress_type.as_byte()]);
+        digest.finalize()
+    } else {
+        0
+    };
 
     let mut trailer = [0u8; BLOCK_TRAILER_SIZE];
     let mut buf = trailer.as_mut();
@@ -394,28 +780,36 @@ fn write_raw_block<W: WritableFile>(
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, rc::Rc};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        RwLock,
+    };
 
     use crate::{
         cmp::BitWiseComparator,
-        env::{IoResult, RandomAccessFile},
+        consts::{NUM_LEVELS, RANGE_DEL_META_KEY, RESERVED_META_KEYS},
+        env::{IoResult, Logger, RandomAccessFile},
         filter::BloomFilterPolicy,
+        slice::UnsafeSlice,
+        utils::cache::ShardLruCache,
     };
 
     use super::*;
+    use crate::sstable::format::MAGIC_FOOTER_ENCODED;
+
     pub struct MemFs {
-        data: Rc<RefCell<Vec<u8>>>,
+        data: Arc<RwLock<Vec<u8>>>,
     }
 
     impl MemFs {
-        pub fn new(data: Rc<RefCell<Vec<u8>>>) -> Self {
-            MemFs { data: data }
+        pub fn new(data: Arc<RwLock<Vec<u8>>>) -> Self {
+            MemFs { data }
         }
     }
 
     impl RandomAccessFile for MemFs {
         fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
-            let data = self.data.borrow();
+            let data = self.data.read().unwrap();
             let data = &data[offset as usize..offset as usize + buf.len()];
             buf.copy_from_slice(data);
             Ok(buf.len())
@@ -429,7 +823,7 @@ mod tests {
 
     impl WritableFile for MemFs {
         fn append(&mut self, data: &[u8]) -> IoResult<()> {
-            let mut v = self.data.as_ref().borrow_mut();
+            let mut v = self.data.write().unwrap();
             v.extend_from_slice(data);
             Ok(())
         }
@@ -452,7 +846,7 @@ mod tests {
             datas.push((key, value));
         }
 
-        let data = Rc::new(RefCell::new(Vec::new()));
+        let data = Arc::new(RwLock::new(Vec::new()));
         let file = MemFs::new(data.clone());
         let options = Arc::new(Options {
             comparator: Arc::new(BitWiseComparator {}),
@@ -469,16 +863,13 @@ mod tests {
         }
 
         table_builder.finish(true).unwrap();
-        let v = data.as_ref().borrow();
+        let v = data.read().unwrap();
         // assert_eq!(v.len(), 20);
 
         let file = MemFs::new(data.clone());
-        let table = Arc::new(Table::open(options.clone(), file, v.len() as u64).unwrap());
+        let table = Arc::new(Table::open(options, file, v.len() as u64).unwrap());
 
-        let read_option = ReadOption {
-            verify_checksum: true,
-            fill_cache: false,
-        };
+        let read_option = ReadOption::VERIFY;
 
         table.print_indexes();
 
@@ -498,4 +889,951 @@ mod tests {
             iter.next();
         }
     }
+
+    fn build_mem_table(options: Arc<Options>, datas: &[(String, String)]) -> Table<MemFs> {
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut table_builder = TableBuiler::new(options, MemFs::new(data.clone()));
+        for (k, v) in datas.iter() {
+            table_builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        let size = table_builder.finish(true).unwrap();
+        assert_eq!(size, data.read().unwrap().len() as u64);
+
+        let read_options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            block_size: 1024,
+            ..Default::default()
+        });
+        Table::open(read_options, MemFs::new(data), size).unwrap()
+    }
+
+    fn check_table_content(table: Table<MemFs>, datas: &[(String, String)]) {
+        let mut iter = Arc::new(table).iter(&ReadOption::default());
+        iter.seek_to_first();
+        for (k, v) in datas.iter() {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), k.as_bytes());
+            assert_eq!(iter.value(), v.as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_snappy_compression() {
+        let datas: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:06}", i), "v".repeat(100)))
+            .collect();
+        let build = |compression_type: Compress| {
+            let options = Arc::new(Options {
+                block_size: 1024,
+                compression_type,
+                ..Default::default()
+            });
+            let data = Arc::new(RwLock::new(Vec::new()));
+            let mut table_builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+            for (k, v) in datas.iter() {
+                table_builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            let size = table_builder.finish(true).unwrap();
+            check_table_content(
+                Table::open(options, MemFs::new(data), size).unwrap(),
+                &datas,
+            );
+            size
+        };
+        let uncompressed = build(Compress::NO);
+        let compressed = build(Compress::Snappy);
+        if cfg!(feature = "snappy") {
+            assert!(compressed < uncompressed / 2);
+        } else {
+            // written uncompressed instead
+            assert_eq!(compressed, uncompressed);
+        }
+    }
+
+    #[cfg(not(feature = "snappy"))]
+    #[test]
+    fn test_snappy_block_without_feature() {
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut file = MemFs::new(data);
+        let mut handle = BlockHandle::default();
+        write_raw_block(&mut file, b"data", Compress::Snappy, &mut handle, 0, true).unwrap();
+        let res = BlockContent::read_block_from_file(&file, &handle, &ReadOption::VERIFY);
+        assert!(matches!(res, Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_filter_policy_per_level() {
+        let datas: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+
+        let bloom: Arc<dyn FilterPolicy> = Arc::new(BloomFilterPolicy::new(10));
+        let mut per_level = vec![Some(bloom.clone()); NUM_LEVELS - 1];
+        per_level.push(None);
+        let options = Arc::new(Options {
+            filter_policy: Some(bloom),
+            filter_policy_per_level: Some(per_level),
+            block_size: 1024,
+            ..Default::default()
+        });
+
+        assert!(options.filter_policy_for_level(0).is_some());
+        assert!(options.filter_policy_for_level(NUM_LEVELS - 1).is_none());
+
+        let table = build_mem_table(options.table_options_for_level(0), &datas);
+        assert!(table.filter_block_data.is_some());
+        check_table_content(table, &datas);
+
+        let table = build_mem_table(options.table_options_for_level(NUM_LEVELS - 1), &datas);
+        assert!(table.filter_block_data.is_none());
+        assert!(table.filter_policy.is_none());
+        check_table_content(table, &datas);
+    }
+
+    fn meta_index_keys(table: &Table<MemFs>) -> Vec<String> {
+        let mut iter = table.meta_index_iter().unwrap();
+        let mut keys = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(String::from_utf8(iter.key().to_vec()).unwrap());
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        keys
+    }
+
+    #[test]
+    fn test_meta_index() {
+        let datas: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+
+        let table = build_mem_table(Arc::new(Options::default()), &datas);
+        assert_eq!(meta_index_keys(&table), vec!["rleveldb.properties"]);
+        assert_eq!(table.filter_policy_name(), None);
+        assert_eq!(
+            table.comparator_name().unwrap().as_deref(),
+            Some("leveldb.BytewiseComparator")
+        );
+        assert_eq!(table.filter_data(), None);
+
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..Default::default()
+        });
+        let table = build_mem_table(options, &datas);
+        assert_eq!(
+            meta_index_keys(&table),
+            vec!["filterleveldb.BuiltinBloomFilter2", "rleveldb.properties"]
+        );
+        assert_eq!(
+            table.filter_policy_name().as_deref(),
+            Some("leveldb.BuiltinBloomFilter2")
+        );
+        assert!(!table.filter_data().unwrap().is_empty());
+
+        // the name is known even when the policy isn't configured
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(RenamedFilterPolicy(BloomFilterPolicy::new(10)))),
+            ..Default::default()
+        });
+        let table = build_mem_table(options, &datas);
+        assert_eq!(
+            table.filter_policy_name().as_deref(),
+            Some("rleveldb.test.RenamedFilter")
+        );
+        assert_eq!(table.filter_data(), None);
+    }
+
+    // Replaces the meta index of the table in `data` by one that also has the
+    // `extra` entries, like a table written by a later version.
+    fn add_meta_entries(data: &Arc<RwLock<Vec<u8>>>, extra: &[(&str, &[u8])]) -> u64 {
+        let size = data.read().unwrap().len() as u64;
+        let table =
+            Table::open(Arc::new(Options::default()), MemFs::new(data.clone()), size).unwrap();
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = extra
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.to_vec()))
+            .collect();
+        let mut iter = table.meta_index_iter().unwrap();
+        iter.seek_to_first();
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        entries.sort();
+
+        let mut footer = Footer::default();
+        footer
+            .decode(&data.read().unwrap()[size as usize - FULL_FOOTER_LENGTH..])
+            .unwrap();
+        let mut meta_index = BlockBuilder::new(Arc::new(BitWiseComparator {}), 1);
+        for (k, v) in entries.iter() {
+            meta_index.add(k, v);
+        }
+        data.write()
+            .unwrap()
+            .truncate(size as usize - FULL_FOOTER_LENGTH);
+        let mut file = MemFs::new(data.clone());
+        let offset = write_raw_block(
+            &mut file,
+            &meta_index.finish(),
+            Compress::NO,
+            &mut footer.meta_index_handle,
+            size - FULL_FOOTER_LENGTH as u64,
+            true,
+        )
+        .unwrap();
+        let mut encoded = [0; FULL_FOOTER_LENGTH];
+        footer.encode(&mut encoded);
+        file.append(&encoded).unwrap();
+        offset + FULL_FOOTER_LENGTH as u64
+    }
+
+    #[test]
+    fn test_unknown_meta_entries_are_skipped() {
+        let datas: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..Default::default()
+        });
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+        for (k, v) in datas.iter() {
+            builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        builder.finish(true).unwrap();
+        // entries of blocks this version doesn't write, with handles that
+        // don't point to blocks
+        let garbage = [0xff; 8];
+        let size = add_meta_entries(
+            &data,
+            &[
+                (RANGE_DEL_META_KEY, &garbage),
+                ("rleveldb.prefix-filter.fixed4", &garbage),
+                ("zzz.unknown", &garbage),
+            ],
+        );
+
+        let table = Table::open(options, MemFs::new(data), size).unwrap();
+        assert_eq!(
+            meta_index_keys(&table),
+            vec![
+                "filterleveldb.BuiltinBloomFilter2",
+                "rleveldb.prefix-filter.fixed4",
+                "rleveldb.properties",
+                "rleveldb.range-del",
+                "zzz.unknown"
+            ]
+        );
+        assert!(table.filter_data().is_some());
+        assert_eq!(
+            table.comparator_name().unwrap().as_deref(),
+            Some("leveldb.BytewiseComparator")
+        );
+        check_table_content(table, &datas);
+    }
+
+    #[test]
+    fn test_reserved_meta_keys_dont_collide() {
+        for (i, a) in RESERVED_META_KEYS.iter().enumerate() {
+            for b in RESERVED_META_KEYS.iter().skip(i + 1) {
+                assert!(!a.starts_with(b) && !b.starts_with(a), "{} {}", a, b);
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct CapturingLogger {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Logger for CapturingLogger {
+        fn log(&self, msg: &str) {
+            self.lines.lock().unwrap().push(msg.to_string());
+        }
+    }
+
+    #[test]
+    fn test_unreadable_meta_index_is_logged() {
+        let datas: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let logger = Arc::new(CapturingLogger::default());
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            info_log: Some(logger.clone()),
+            ..Default::default()
+        });
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+        for (k, v) in datas.iter() {
+            builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        let size = builder.finish(true).unwrap();
+        let table = Table::open(options.clone(), MemFs::new(data.clone()), size).unwrap();
+        assert!(table.filter_data().is_some());
+        assert!(logger.lines.lock().unwrap().is_empty());
+
+        // a flipped byte fails the checksum of the meta index
+        let offset = table.meta_index_handle.offset() as usize;
+        data.write().unwrap()[offset] ^= 0xff;
+        let table = Table::open(options, MemFs::new(data), size).unwrap();
+        assert_eq!(table.filter_data(), None);
+        let lines = logger.lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("meta index"), "{}", lines[0]);
+        drop(lines);
+        check_table_content(table, &datas);
+    }
+
+    struct RenamedFilterPolicy(BloomFilterPolicy);
+
+    impl FilterPolicy for RenamedFilterPolicy {
+        fn name(&self) -> &'static str {
+            "rleveldb.test.RenamedFilter"
+        }
+
+        fn create_filter(&self, keys: &[UnsafeSlice], dst: &mut Vec<u8>) {
+            self.0.create_filter(keys, dst)
+        }
+
+        fn key_match(&self, key: &[u8], filter: &[u8]) -> bool {
+            self.0.key_match(key, filter)
+        }
+    }
+
+    #[test]
+    fn test_filter_policy_name_mismatch() {
+        let datas: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(RenamedFilterPolicy(BloomFilterPolicy::new(10)))),
+            block_size: 1024,
+            ..Default::default()
+        });
+
+        // the reader is configured with the builtin bloom filter only, the filter block
+        // written under another name is ignored instead of failing the open
+        let table = build_mem_table(options, &datas);
+        assert!(table.filter_block_data.is_none());
+        check_table_content(table, &datas);
+    }
+
+    fn golden_table_options() -> Arc<Options> {
+        Arc::new(Options {
+            comparator: Arc::new(BitWiseComparator {}),
+            filter_policy: None,
+            block_restart_interval: 16,
+            block_size: 4096,
+            compression_type: Compress::NO,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_table_golden() {
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut table_builder = TableBuiler::new(golden_table_options(), MemFs::new(data.clone()));
+        table_builder.add(b"k1", b"v1").unwrap();
+        table_builder.add(b"k2", b"v2").unwrap();
+        table_builder.finish(true).unwrap();
+
+        let data = data.read().unwrap();
+        crate::utils::hex::check_golden("format/table.hex", &data);
+
+        let n = data.len();
+        assert_eq!(&data[n - 8..], &MAGIC_FOOTER_ENCODED);
+        let mut footer = Footer::default();
+        footer.decode(&data[n - FULL_FOOTER_LENGTH..]).unwrap();
+        // the first data block starts the file, the index block comes right before the footer
+        let index = footer.index_handle;
+        assert_eq!(
+            index.offset() + index.size() + BLOCK_TRAILER_SIZE as u64,
+            (n - FULL_FOOTER_LENGTH) as u64
+        );
+    }
+
+    // tables written before the properties block existed are still read
+    #[test]
+    fn test_table_golden_parse() {
+        for (name, comparator) in [
+            ("table.hex", Some("leveldb.BytewiseComparator")),
+            // the misspelled name of the bytewise comparator tables used to record
+            (
+                "table_legacy_comparator.hex",
+                Some("Leveldb.BitwiseComaparator"),
+            ),
+            ("table_no_properties.hex", None),
+        ] {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata/format")
+                .join(name);
+            let data =
+                crate::utils::hex::from_hex(&std::fs::read_to_string(path).unwrap()).unwrap();
+            let size = data.len() as u64;
+            let table = Table::open(
+                golden_table_options(),
+                MemFs::new(Arc::new(RwLock::new(data))),
+                size,
+            )
+            .unwrap();
+            assert_eq!(table.comparator_name().unwrap().as_deref(), comparator);
+            let datas = vec![
+                ("k1".to_string(), "v1".to_string()),
+                ("k2".to_string(), "v2".to_string()),
+            ];
+            check_table_content(table, &datas);
+        }
+    }
+
+    struct CountingComparator {
+        count: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Comparator for CountingComparator {
+        fn compare(&self, left: &[u8], right: &[u8]) -> Ordering {
+            self.count
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            left.cmp(right)
+        }
+
+        fn name(&self) -> &'static str {
+            "rleveldb.test.CountingComparator"
+        }
+
+        fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]) {
+            BitWiseComparator {}.find_shortest_separator(start, limit)
+        }
+
+        fn find_shortest_successor(&self, key: &mut Vec<u8>) {
+            BitWiseComparator {}.find_shortest_successor(key)
+        }
+    }
+
+    fn table_get(table: &Table<MemFs>, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut result = None;
+        table
+            .internal_get(&ReadOption::default(), key, |k, v| {
+                result = Some((k.to_vec(), v.to_vec()))
+            })
+            .unwrap();
+        result
+    }
+
+    #[test]
+    fn test_internal_get_matches_iterator() {
+        use rand::{Rng, SeedableRng};
+
+        let datas: Vec<(String, String)> = (0..3000)
+            .map(|i| (format!("key{:06}", i * 2), format!("value{:06}", i)))
+            .collect();
+        let options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            block_size: 1024,
+            ..Default::default()
+        });
+        let table = Arc::new(build_mem_table(options, &datas));
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(301);
+        let mut iter = table.clone().iter(&ReadOption::default());
+        for _ in 0..5000 {
+            // mostly hit a small hot range, sometimes jump anywhere, odd keys are absent
+            let n: u32 = if rng.gen_bool(0.8) {
+                rng.gen_range(1000..1100)
+            } else {
+                rng.gen_range(0..6100)
+            };
+            let key = format!("key{:06}", n);
+
+            iter.seek(key.as_bytes());
+            let expected = if iter.valid() {
+                Some((iter.key().to_vec(), iter.value().to_vec()))
+            } else {
+                None
+            };
+            // the table was built with a filter, absent keys may be filtered out
+            let got = table_get(&table, key.as_bytes());
+            match got {
+                Some(ref entry) => assert_eq!(Some(entry), expected.as_ref()),
+                None => assert!(n % 2 == 1 || n >= 6000, "missing {}", key),
+            }
+            if n.is_multiple_of(2) && n < 6000 {
+                assert_eq!(expected.unwrap().0, key.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_internal_get_lookup_hint() {
+        let datas: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let comparator = Arc::new(CountingComparator {
+            count: Default::default(),
+        });
+        let options = Arc::new(Options {
+            comparator: comparator.clone(),
+            block_size: 1024,
+            ..Default::default()
+        });
+
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut table_builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+        for (k, v) in datas.iter() {
+            table_builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        let size = table_builder.finish(true).unwrap();
+        let table = Table::open(options.clone(), MemFs::new(data.clone()), size).unwrap();
+        let unhinted = Table::open(
+            Arc::new(Options {
+                table_lookup_hint: false,
+                ..(*options).clone()
+            }),
+            MemFs::new(data),
+            size,
+        )
+        .unwrap();
+
+        // repeated lookups of keys in one data block
+        let hot: Vec<_> = (1000..1010).map(|i| format!("key{:06}", i)).collect();
+        let compares = |table: &Table<MemFs>, clear_hint: bool| {
+            comparator
+                .count
+                .store(0, std::sync::atomic::Ordering::Relaxed);
+            for _ in 0..10 {
+                for key in hot.iter() {
+                    if clear_hint {
+                        *table.lookup_hint.lock().unwrap() = None;
+                    }
+                    let (k, _) = table_get(table, key.as_bytes()).unwrap();
+                    assert_eq!(k, key.as_bytes());
+                }
+            }
+            comparator.count.load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let without_hint = compares(&table, true);
+        let with_hint = compares(&table, false);
+        assert!(
+            with_hint < without_hint,
+            "{} >= {}",
+            with_hint,
+            without_hint
+        );
+        // turned off no hint is kept
+        assert_eq!(compares(&unhinted, false), without_hint);
+        assert!(unhinted.lookup_hint.lock().unwrap().is_none());
+
+        // the hint must not leak into lookups outside its range
+        assert_eq!(
+            table_get(&table, b"key000001").unwrap().0,
+            b"key000001".to_vec()
+        );
+        assert_eq!(
+            table_get(&table, b"key001999").unwrap().0,
+            b"key001999".to_vec()
+        );
+        assert!(table_get(&table, b"key999999").is_none());
+    }
+
+    #[test]
+    fn test_parallel_block_range_scans() {
+        let datas: Vec<(String, String)> = (0..10000)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        // a block per entry
+        let options = Arc::new(Options {
+            block_size: 1,
+            ..Default::default()
+        });
+        let table = Arc::new(build_mem_table(options, &datas));
+        let blocks = table.block_ranges().unwrap();
+        assert_eq!(blocks.len(), 10000);
+        assert!(blocks
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1.offset() + w[0].1.size() < w[1].1.offset()));
+
+        let scan = |mut iter: TwoLevelIterator<_, _>| {
+            let mut entries = Vec::new();
+            iter.seek_to_first();
+            while iter.valid() {
+                entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                iter.next();
+            }
+            iter.status().unwrap();
+            entries
+        };
+        let option = ReadOption::default();
+        let full = scan(table.clone().iter(&option));
+        assert_eq!(full.len(), datas.len());
+
+        let parts = [0, 1, 2500, 7777, 10000];
+        let scanned: Vec<_> = std::thread::scope(|s| {
+            let threads: Vec<_> = parts
+                .windows(2)
+                .map(|w| {
+                    let table = &table;
+                    let option = &option;
+                    let (start, end) = (w[0], w[1]);
+                    s.spawn(move || scan(table.iter_range(option, start, end).unwrap()))
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(
+            scanned.iter().map(|part| part.len()).collect::<Vec<_>>(),
+            [1, 2499, 5277, 2223]
+        );
+        assert_eq!(scanned.concat(), full);
+
+        // a range may run past the last block
+        assert_eq!(
+            scan(table.iter_range(&option, 9999, 20000).unwrap()).len(),
+            1
+        );
+        assert!(scan(table.iter_range(&option, 20000, 30000).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_approximate_offset_of() {
+        let datas: Vec<(String, String)> = (0..1000)
+            .map(|i| (format!("key{:06}", i), "v".repeat(100)))
+            .collect();
+        let table = build_mem_table(Arc::new(Options::default()), &datas);
+        let offset_of = |key: &str| table.approximate_offset_of(key.as_bytes());
+
+        assert_eq!(offset_of(""), 0);
+        assert_eq!(offset_of("key000000"), 0);
+        // past the last key is the end of the data
+        let end = offset_of("zzz");
+        assert_eq!(end, table.meta_index_handle.offset());
+        assert!(end > 100 * 1000);
+
+        let mut last = 0;
+        for i in (0..1000).step_by(50) {
+            let offset = offset_of(&format!("key{:06}", i));
+            assert!(offset >= last);
+            // a block of 4KB off at most
+            let expected = end * i / 1000;
+            assert!(offset.abs_diff(expected) < 5000, "{} {}", offset, expected);
+            last = offset;
+        }
+    }
+
+    #[test]
+    fn test_restart_intervals() {
+        let datas: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:06}", i * 2), format!("value{:06}", i)))
+            .collect();
+        for block_restart_interval in [1, 2, 16, 128] {
+            let options = Arc::new(Options {
+                block_restart_interval,
+                block_size: 1024,
+                ..Default::default()
+            });
+            let table = Arc::new(build_mem_table(options, &datas));
+            let mut iter = table.iter(&ReadOption::default());
+
+            iter.seek_to_first();
+            for (k, v) in datas.iter() {
+                assert_eq!((iter.key(), iter.value()), (k.as_bytes(), v.as_bytes()));
+                iter.next();
+            }
+            assert!(!iter.valid());
+            iter.seek_to_last();
+            for (k, v) in datas.iter().rev() {
+                assert!(iter.valid());
+                assert_eq!((iter.key(), iter.value()), (k.as_bytes(), v.as_bytes()));
+                iter.prev();
+            }
+            assert!(!iter.valid());
+
+            // every key and every gap between keys, restart points included
+            for n in 0..4001usize {
+                let target = format!("key{:06}", n);
+                iter.seek(target.as_bytes());
+                match datas.get(n.div_ceil(2)) {
+                    Some((k, _)) => {
+                        assert!(iter.valid(), "{} at {}", target, block_restart_interval);
+                        assert_eq!(iter.key(), k.as_bytes());
+                    }
+                    None => assert!(!iter.valid()),
+                }
+            }
+            iter.status().unwrap();
+        }
+    }
+
+    // counts the reads of the file
+    struct CountingFile {
+        file: MemFs,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl RandomAccessFile for CountingFile {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+            self.reads.fetch_add(1, AtomicOrdering::SeqCst);
+            self.file.read_at(buf, offset)
+        }
+
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> IoResult<()> {
+            self.reads.fetch_add(1, AtomicOrdering::SeqCst);
+            self.file.read_exact_at(buf, offset)
+        }
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let datas: Vec<(String, String)> = (0..2000)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let options = Arc::new(Options {
+            block_size: 1024,
+            compression_type: Compress::NO,
+            block_cache: Some(Arc::new(ShardLruCache::new(1 << 20))),
+            ..Default::default()
+        });
+        let data = Arc::new(RwLock::new(Vec::new()));
+        let mut table_builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+        for (k, v) in datas.iter() {
+            table_builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+        }
+        let size = table_builder.finish(true).unwrap();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let open = || {
+            let file = CountingFile {
+                file: MemFs::new(data.clone()),
+                reads: reads.clone(),
+            };
+            Arc::new(Table::open(options.clone(), file, size).unwrap())
+        };
+        let table = open();
+        let scan = |table: &Arc<Table<CountingFile>>, read_option: &ReadOption| {
+            let before = reads.load(AtomicOrdering::SeqCst);
+            let mut iter = table.clone().iter(read_option);
+            iter.seek_to_first();
+            let mut n = 0;
+            while iter.valid() {
+                assert_eq!(iter.key(), datas[n].0.as_bytes());
+                n += 1;
+                iter.next();
+            }
+            assert_eq!(n, datas.len());
+            reads.load(AtomicOrdering::SeqCst) - before
+        };
+
+        let ranges = table.block_ranges().unwrap();
+        let num_blocks = ranges.len();
+        assert!(num_blocks > 10);
+        // without fill_cache the blocks are read every time
+        assert_eq!(scan(&table, &ReadOption::default()), num_blocks);
+        assert_eq!(scan(&table, &ReadOption::default()), num_blocks);
+        let fill = ReadOption {
+            fill_cache: true,
+            ..Default::default()
+        };
+        assert_eq!(scan(&table, &fill), num_blocks);
+        assert_eq!(scan(&table, &fill), 0);
+        assert_eq!(scan(&table, &ReadOption::default()), 0);
+        // charged the data length of the blocks
+        let cache = options.block_cache.as_ref().unwrap();
+        let data_len: u64 = ranges.iter().map(|(_, handle)| handle.size()).sum();
+        assert_eq!(cache.total_charge(), data_len);
+
+        // gets hit the cache too
+        let before = reads.load(AtomicOrdering::SeqCst);
+        let mut found = None;
+        table
+            .internal_get(&ReadOption::default(), b"key000100", |_, v| {
+                found = Some(v.to_vec())
+            })
+            .unwrap();
+        assert_eq!(found.unwrap(), b"value000100");
+        assert_eq!(reads.load(AtomicOrdering::SeqCst), before);
+
+        // the same file opened again gets its own cache id
+        let reopened = open();
+        assert_eq!(scan(&reopened, &fill), num_blocks);
+        assert_eq!(cache.total_charge(), data_len * 2);
+    }
+
+    fn user_key_of(key: &[u8], internal: bool) -> &[u8] {
+        if internal {
+            &key[..key.len() - 8]
+        } else {
+            key
+        }
+    }
+
+    // Seeks next to the index separators of a two block table, for the user and
+    // the internal key order.
+    #[test]
+    fn test_seek_block_boundaries() {
+        use crate::{
+            cmp::InternalKeyComparator,
+            types::{Tag, ValueType, MAX_SEQUENCE_NUMBER},
+        };
+
+        // the last key is all 0xff but for its last byte
+        let user_keys: [&[u8]; 5] = [b"abc0", b"abc1", b"abcd", b"abf", &[0xff, 0xff, 0x01]];
+        let internal_key = |user_key: &[u8], sequence| {
+            let mut key = user_key.to_vec();
+            Tag::new(sequence, ValueType::Value).encode_to(&mut key);
+            key
+        };
+        for internal in [false, true] {
+            let (comparator, keys): (Arc<dyn Comparator>, Vec<Vec<u8>>) = if internal {
+                (
+                    Arc::new(InternalKeyComparator::new(Arc::new(BitWiseComparator {}))),
+                    user_keys.iter().map(|k| internal_key(k, 100)).collect(),
+                )
+            } else {
+                (
+                    Arc::new(BitWiseComparator {}),
+                    user_keys.iter().map(|k| k.to_vec()).collect(),
+                )
+            };
+            let options = Arc::new(Options {
+                comparator: comparator.clone(),
+                // three entries fill the first block
+                block_size: 250,
+                compression_type: Compress::NO,
+                ..Default::default()
+            });
+            let data = Arc::new(RwLock::new(Vec::new()));
+            let mut table_builder = TableBuiler::new(options.clone(), MemFs::new(data.clone()));
+            for key in keys.iter() {
+                table_builder.add(key, &[b'v'; 100]).unwrap();
+            }
+            let size = table_builder.finish(true).unwrap();
+            let table = Arc::new(Table::open(options, MemFs::new(data), size).unwrap());
+            let ranges = table.block_ranges().unwrap();
+            assert_eq!(ranges.len(), 2, "{}", comparator.name());
+            // a separator is at or after the last key of its block, and before
+            // the next block
+            let (first, second) = (&ranges[0].0, &ranges[1].0);
+            assert_ne!(comparator.compare(&keys[2], first), Ordering::Greater);
+            assert_eq!(comparator.compare(first, &keys[3]), Ordering::Less);
+            assert_ne!(comparator.compare(&keys[4], second), Ordering::Greater);
+
+            // each boundary key and separator, a byte below and above it, and the
+            // keys right after it
+            let mut targets = Vec::new();
+            let bounds = [&keys[2], &keys[3], &keys[4], first, second];
+            for bound in bounds {
+                let user_key = user_key_of(bound, internal);
+                let last = user_key.len() - 1;
+                let mut below = user_key.to_vec();
+                below[last] -= 1;
+                let mut above = user_key.to_vec();
+                above[last] = above[last].wrapping_add(1);
+                let mut longer = user_key.to_vec();
+                longer.push(0);
+                for target in [user_key.to_vec(), below, above, longer] {
+                    if internal {
+                        targets.push(internal_key(&target, MAX_SEQUENCE_NUMBER));
+                        targets.push(internal_key(&target, 0));
+                    } else {
+                        targets.push(target);
+                    }
+                }
+                targets.push(bound.clone());
+            }
+
+            let mut iter = table.clone().iter(&ReadOption::default());
+            for target in targets {
+                let expected = keys
+                    .iter()
+                    .find(|k| comparator.compare(k, &target) != Ordering::Less);
+                iter.seek(&target);
+                assert_eq!(
+                    iter.valid().then(|| iter.key()),
+                    expected.map(|k| k.as_slice()),
+                    "{} seek {:?}",
+                    comparator.name(),
+                    target
+                );
+                // a get only looks in the block the index points it to, which
+                // holds the key if the table does
+                let mut found = None;
+                table
+                    .internal_get(&ReadOption::default(), &target, |k, _| {
+                        found = Some(k.to_vec())
+                    })
+                    .unwrap();
+                let user_key = user_key_of(&target, internal);
+                if found.is_some() || expected.map(|k| user_key_of(k, internal)) == Some(user_key) {
+                    assert_eq!(found.as_ref(), expected, "get {:?}", target);
+                }
+            }
+            iter.status().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_blocks_without_checksums() {
+        let datas: Vec<(String, String)> = (0..200)
+            .map(|i| (format!("key{:06}", i), format!("value{:06}", i)))
+            .collect();
+        let build = |disable_data_checksums| {
+            let options = Arc::new(Options {
+                block_size: 1024,
+                disable_data_checksums,
+                ..Default::default()
+            });
+            let data = Arc::new(RwLock::new(Vec::new()));
+            let mut table_builder = TableBuiler::new(options, MemFs::new(data.clone()));
+            for (k, v) in datas.iter() {
+                table_builder.add(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            table_builder.finish(true).unwrap();
+            let data = data.read().unwrap().clone();
+            data
+        };
+        // reads with the option set, which only changes what is written
+        let options = Arc::new(Options {
+            disable_data_checksums: true,
+            ..Default::default()
+        });
+        let open = |data: Vec<u8>| {
+            let size = data.len() as u64;
+            Table::open(
+                options.clone(),
+                MemFs::new(Arc::new(RwLock::new(data))),
+                size,
+            )
+        };
+        let scan = |table: Table<MemFs>| {
+            let mut iter = Arc::new(table).iter(&ReadOption::VERIFY);
+            iter.seek_to_first();
+            while iter.valid() {
+                iter.next();
+            }
+            iter.status()
+        };
+
+        let unchecked = build(true);
+        let table = open(unchecked.clone()).unwrap();
+        let (_, first) = &table.block_ranges().unwrap()[0];
+        let trailer = (first.offset() + first.size()) as usize;
+        assert_eq!(&unchecked[trailer + 1..trailer + 5], &[0; 4]);
+        check_table_content(open(unchecked.clone()).unwrap(), &datas);
+        scan(open(unchecked.clone()).unwrap()).unwrap();
+
+        // a flipped byte of the first key goes unnoticed without a checksum
+        let at = first.offset() as usize + 8;
+        let mut flipped = unchecked;
+        flipped[at] ^= 0x01;
+        scan(open(flipped).unwrap()).unwrap();
+        let mut flipped = build(false);
+        flipped[at] ^= 0x01;
+        let err = scan(open(flipped).unwrap()).err().unwrap();
+        assert!(matches!(err, Error::Corruption(_)), "{:?}", err);
+    }
 }
@@ -3,7 +3,7 @@ use std::cmp::Ordering;
 use crate::{
     cmp::{BitWiseComparator, Comparator},
     error::{Error, Result},
-    iterator::DBIterator,
+    iterator::{shrink_scratch, DBIterator},
     options::ReadOption,
 };
 
@@ -47,6 +47,7 @@ impl<I: DBIterator, B: BlockIterBuilder> TwoLevelIterator<I, B> {
                 match self.block_builder.build(&self.option, handle) {
                     Ok(data_iter) => {
                         self.data_block_handle.clear();
+                        shrink_scratch(&mut self.data_block_handle);
                         self.data_block_handle.extend_from_slice(handle);
                         self.set_data_iterator(Some(data_iter));
                     }
@@ -60,7 +61,7 @@ impl<I: DBIterator, B: BlockIterBuilder> TwoLevelIterator<I, B> {
     }
 
     fn set_data_iterator(&mut self, data_iter: Option<B::Iter>) {
-        if let Some(ref mut iter) = self.data_iter {
+        if let Some(ref iter) = self.data_iter {
             if let Err(err) = iter.status() {
                 self.save_err(err);
             }
@@ -181,14 +182,60 @@ impl<I: DBIterator, B: BlockIterBuilder> DBIterator for TwoLevelIterator<I, B> {
         iter.value()
     }
 
-    fn status(&mut self) -> Result<()> {
+    fn status(&self) -> Result<()> {
         self.index_iter.status()?;
-        if let Some(ref mut data_iter) = self.data_iter {
+        if let Some(ref data_iter) = self.data_iter {
             data_iter.status()?;
         };
-        if self.status.is_some() {
-            return Err(self.status.take().unwrap());
+        match &self.status {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        iterator::tests::{assert_sticky_corruption, ErrorIter},
+        sstable::{
+            block::{Block, BlockIter},
+            block_builder::BlockBuilder,
+            format::BlockContent,
+        },
+    };
+    use std::sync::Arc;
+
+    // every data block is missing
+    struct FailingBuilder;
+
+    impl BlockIterBuilder for FailingBuilder {
+        type Iter = ErrorIter;
+        fn build(&self, _option: &ReadOption, index_val: &[u8]) -> Result<Self::Iter> {
+            Err(Error::Corruption(
+                String::from_utf8_lossy(index_val).into_owned(),
+            ))
         }
-        Ok(())
+    }
+
+    fn index_iter() -> BlockIter {
+        let comparator = Arc::new(BitWiseComparator {});
+        let mut builder = BlockBuilder::new(comparator.clone(), 16);
+        builder.add(b"a".as_ref(), b"block1".as_ref());
+        builder.add(b"b".as_ref(), b"block2".as_ref());
+        let block = Block::from_raw(BlockContent::new(builder.finish())).unwrap();
+        block.iter(comparator)
+    }
+
+    #[test]
+    fn test_block_error() {
+        let mut iter = TwoLevelIterator::new(index_iter(), FailingBuilder, ReadOption::default());
+        iter.seek_to_first();
+        // the first failure is kept, the iterator went on to the next block
+        assert!(!iter.valid());
+        assert_sticky_corruption(&iter, "block1");
+        iter.seek(b"b");
+        assert_sticky_corruption(&iter, "block1");
     }
 }
@@ -0,0 +1,186 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Bytes written to disk by the db compared to the bytes handed to it by the
+// application. Counters only grow, readers may see them slightly out of sync
+// with each other.
+#[derive(Default)]
+pub struct WriteStats {
+    // key + value bytes of committed batches
+    user_bytes: AtomicU64,
+    // physical log bytes, record headers and block padding included
+    wal_bytes: AtomicU64,
+    // tables written by memtable flushes
+    flush_bytes: AtomicU64,
+    // tables written by compactions
+    compaction_bytes: AtomicU64,
+}
+
+impl WriteStats {
+    pub fn add_user_bytes(&self, n: u64) {
+        self.user_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_wal_bytes(&self, n: u64) {
+        self.wal_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_flush_bytes(&self, n: u64) {
+        self.flush_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_compaction_bytes(&self, n: u64) {
+        self.compaction_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn user_bytes(&self) -> u64 {
+        self.user_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn wal_bytes(&self) -> u64 {
+        self.wal_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn flush_bytes(&self) -> u64 {
+        self.flush_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn compaction_bytes(&self) -> u64 {
+        self.compaction_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn disk_bytes(&self) -> u64 {
+        self.wal_bytes() + self.flush_bytes() + self.compaction_bytes()
+    }
+
+    // 0 until the application has written anything
+    pub fn write_amplification(&self) -> f64 {
+        let user = self.user_bytes();
+        if user == 0 {
+            0.0
+        } else {
+            self.disk_bytes() as f64 / user as f64
+        }
+    }
+
+    pub fn reset(&self) {
+        self.user_bytes.store(0, Ordering::Relaxed);
+        self.wal_bytes.store(0, Ordering::Relaxed);
+        self.flush_bytes.store(0, Ordering::Relaxed);
+        self.compaction_bytes.store(0, Ordering::Relaxed);
+    }
+
+    pub fn write_amplification_property(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "write-amplification: {:.3}", self.write_amplification()).unwrap();
+        writeln!(s, "user-bytes: {}", self.user_bytes()).unwrap();
+        writeln!(s, "wal-bytes: {}", self.wal_bytes()).unwrap();
+        writeln!(s, "flush-bytes: {}", self.flush_bytes()).unwrap();
+        writeln!(s, "compaction-bytes: {}", self.compaction_bytes()).unwrap();
+        s
+    }
+}
+
+// Work done by flushes and compactions producing files of one level.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub micros: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl CompactionStats {
+    pub fn add(&mut self, other: &CompactionStats) {
+        self.micros += other.micros;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+// Renders the `leveldb.stats` property from (file count, total file size) and the
+// compaction stats of each level.
+pub fn level_stats_property(files: &[(usize, u64)], stats: &[CompactionStats]) -> String {
+    const MB: f64 = 1048576.0;
+    let mut s = String::new();
+    s.push_str("                               Compactions\n");
+    s.push_str("Level  Files Size(MB) Time(sec) Read(MB) Write(MB)\n");
+    s.push_str("--------------------------------------------------\n");
+    for (level, (&(num_files, size), stat)) in files.iter().zip(stats.iter()).enumerate() {
+        if num_files == 0 && stat.micros == 0 {
+            continue;
+        }
+        writeln!(
+            s,
+            "{:3} {:8} {:8.0} {:9.0} {:8.0} {:9.0}",
+            level,
+            num_files,
+            size as f64 / MB,
+            stat.micros as f64 / 1e6,
+            stat.bytes_read as f64 / MB,
+            stat.bytes_written as f64 / MB
+        )
+        .unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_amplification() {
+        let stats = WriteStats::default();
+        assert_eq!(stats.write_amplification(), 0.0);
+
+        stats.add_user_bytes(100);
+        stats.add_wal_bytes(110);
+        stats.add_flush_bytes(90);
+        assert_eq!(stats.write_amplification(), 2.0);
+
+        stats.add_compaction_bytes(100);
+        assert_eq!(stats.disk_bytes(), 300);
+        assert_eq!(stats.write_amplification(), 3.0);
+        assert_eq!(
+            stats.write_amplification_property(),
+            "write-amplification: 3.000\nuser-bytes: 100\nwal-bytes: 110\n\
+             flush-bytes: 90\ncompaction-bytes: 100\n"
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let stats = WriteStats::default();
+        stats.add_user_bytes(10);
+        stats.add_wal_bytes(20);
+        stats.add_flush_bytes(30);
+        stats.add_compaction_bytes(40);
+        stats.reset();
+        assert_eq!(stats.user_bytes(), 0);
+        assert_eq!(stats.disk_bytes(), 0);
+    }
+
+    #[test]
+    fn test_level_stats_property() {
+        let mut stats = [CompactionStats::default(); 3];
+        stats[0].add(&CompactionStats {
+            micros: 2_000_000,
+            bytes_read: 0,
+            bytes_written: 3 << 20,
+        });
+        let files = [(2, 3 << 20), (0, 0), (1, 10 << 20)];
+        let property = level_stats_property(&files, &stats);
+        let lines: Vec<_> = property.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[3],
+            "  0        2        3         2        0         3"
+        );
+        assert_eq!(
+            lines[4],
+            "  2        1       10         0        0         0"
+        );
+    }
+}
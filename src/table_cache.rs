@@ -1,31 +1,162 @@
-use std::sync::Arc;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use lru::LruCache;
 
 use crate::{
-    env::Env,
-    error::Result,
+    cmp::is_same_comparator,
+    env::{Env, RandomAccessFile},
+    error::{Error, Result},
     filenames::{sst_table_file_name, table_file_name},
-    options::Options,
-    sstable::Table,
+    format::InternalKey,
+    iterator::DBIterator,
+    options::{Options, ReadOption},
+    sstable::{Table, TableMetadata},
+    version::FileMetaData,
 };
 
-#[derive(Clone)]
-pub struct TableCache<E> {
+// The open tables of a db, at most `size` of them besides the pinned ones. Clones
+// share the tables.
+pub struct TableCache<E: Env> {
     env: E,
-    dbname: String,
+    dbname: PathBuf,
     options: Arc<Options>,
     size: u64,
+    tables: Arc<Mutex<CachedTables<E::RandomAccessFile>>>,
+}
+
+impl<E: Env> Clone for TableCache<E> {
+    fn clone(&self) -> Self {
+        TableCache {
+            env: self.env.clone(),
+            dbname: self.dbname.clone(),
+            options: self.options.clone(),
+            size: self.size,
+            tables: self.tables.clone(),
+        }
+    }
+}
+
+struct CachedTables<R: RandomAccessFile> {
+    lru: LruCache<u64, Arc<Table<R>>>,
+    // out of the lru while pinned, they can't be evicted
+    pinned: HashMap<u64, PinnedTable<R>>,
+    // The index and filter of tables evicted from the lru, charging at most
+    // `metadata_capacity` bytes. A table reopened takes its entry back
+    metadata: LruCache<u64, TableMetadata>,
+    metadata_charge: usize,
+    metadata_capacity: usize,
+    // tables opened by reading their index and filter, and by taking them from
+    // `metadata`
+    metadata_rebuilds: u64,
+    metadata_reuses: u64,
+}
+
+struct PinnedTable<R: RandomAccessFile> {
+    table: Arc<Table<R>>,
+    pins: usize,
+    // the file is gone, the table isn't cached once unpinned
+    evicted: bool,
+}
+
+impl<R: RandomAccessFile> CachedTables<R> {
+    fn lookup(&mut self, file_number: u64) -> Option<Arc<Table<R>>> {
+        match self.pinned.get(&file_number) {
+            Some(pinned) => Some(pinned.table.clone()),
+            None => self.lru.get(&file_number).cloned(),
+        }
+    }
+
+    fn insert(&mut self, file_number: u64, table: Arc<Table<R>>, size: u64) {
+        if size == 0 {
+            self.keep_metadata(file_number, &table);
+            return;
+        }
+        while self.lru.len() as u64 >= size {
+            if let Some((evicted_number, evicted)) = self.lru.pop_lru() {
+                self.keep_metadata(evicted_number, &evicted);
+            }
+        }
+        self.lru.push(file_number, table);
+    }
+
+    fn keep_metadata(&mut self, file_number: u64, table: &Table<R>) {
+        let metadata = table.metadata();
+        if metadata.charge() > self.metadata_capacity {
+            return;
+        }
+        self.metadata_charge += metadata.charge();
+        if let Some((_, replaced)) = self.metadata.push(file_number, metadata) {
+            self.metadata_charge -= replaced.charge();
+        }
+        while self.metadata_charge > self.metadata_capacity {
+            let (_, evicted) = self.metadata.pop_lru().unwrap();
+            self.metadata_charge -= evicted.charge();
+        }
+    }
+
+    fn take_metadata(&mut self, file_number: u64) -> Option<TableMetadata> {
+        let metadata = self.metadata.pop(&file_number);
+        match &metadata {
+            Some(metadata) => {
+                self.metadata_charge -= metadata.charge();
+                self.metadata_reuses += 1;
+            }
+            None => self.metadata_rebuilds += 1,
+        }
+        metadata
+    }
+}
+
+// Keeps a table open and out of reach of the eviction of the cache until it's
+// dropped, see `TableCache::pin`.
+pub struct PinGuard<R: RandomAccessFile> {
+    tables: Arc<Mutex<CachedTables<R>>>,
+    file_number: u64,
+    size: u64,
+}
+
+impl<R: RandomAccessFile> Drop for PinGuard<R> {
+    fn drop(&mut self) {
+        let mut tables = self.tables.lock().unwrap();
+        let pinned = tables.pinned.get_mut(&self.file_number).unwrap();
+        pinned.pins -= 1;
+        if pinned.pins == 0 {
+            let pinned = tables.pinned.remove(&self.file_number).unwrap();
+            if !pinned.evicted {
+                tables.insert(self.file_number, pinned.table, self.size);
+            }
+        }
+    }
 }
 
 impl<E: Env> TableCache<E> {
     /// Creates a new [`TableCache<E>`].
-    pub fn new(dbname: String, options: Arc<Options>, env: E, size: u64) -> Self {
+    pub fn new(dbname: PathBuf, options: Arc<Options>, env: E, size: u64) -> Self {
+        let metadata_capacity = if options.pin_table_metadata_in_cache {
+            options.metadata_cache_capacity
+        } else {
+            0
+        };
         TableCache {
             dbname,
             env,
             options,
             size,
+            tables: Arc::new(Mutex::new(CachedTables {
+                lru: LruCache::unbounded(),
+                pinned: HashMap::new(),
+                metadata: LruCache::unbounded(),
+                metadata_charge: 0,
+                metadata_capacity,
+                metadata_rebuilds: 0,
+                metadata_reuses: 0,
+            })),
         }
     }
 
@@ -34,21 +165,509 @@ impl<E: Env> TableCache<E> {
         file_number: u64,
         file_size: u64,
     ) -> Result<Arc<Table<E::RandomAccessFile>>> {
-        let mut key = Vec::with_capacity(8);
-        key.write_u64::<LittleEndian>(file_number).unwrap();
+        if let Some(table) = self.tables.lock().unwrap().lookup(file_number) {
+            return Ok(table);
+        }
+        // opened unlocked, a table opened twice meanwhile is only cached once
+        let table = self.open_table(file_number, file_size)?;
+        self.tables
+            .lock()
+            .unwrap()
+            .insert(file_number, table.clone(), self.size);
+        Ok(table)
+    }
+
+    // Opens the table if needed and keeps it cached while the guard lives, however
+    // many other tables are read meanwhile. For the inputs of a compaction, which
+    // would otherwise be evicted and reopened under the load of reads.
+    pub fn pin(&self, file_number: u64, file_size: u64) -> Result<PinGuard<E::RandomAccessFile>> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(pinned) = tables.pinned.get_mut(&file_number) {
+            pinned.pins += 1;
+        } else {
+            let table = match tables.lru.pop(&file_number) {
+                Some(table) => table,
+                None => {
+                    drop(tables);
+                    let table = self.open_table(file_number, file_size)?;
+                    tables = self.tables.lock().unwrap();
+                    tables.lru.pop(&file_number);
+                    table
+                }
+            };
+            let pinned = tables.pinned.entry(file_number).or_insert(PinnedTable {
+                table,
+                pins: 0,
+                evicted: false,
+            });
+            pinned.pins += 1;
+        }
+        Ok(PinGuard {
+            tables: self.tables.clone(),
+            file_number,
+            size: self.size,
+        })
+    }
+
+    // the number of tables pinned now
+    pub fn pinned_count(&self) -> usize {
+        self.tables.lock().unwrap().pinned.len()
+    }
+
+    // The `leveldb.table-metadata` property: how often tables were opened by
+    // reading their index and filter blocks or by reusing those of an evicted
+    // table, and the bytes kept for reuse.
+    pub fn metadata_property(&self) -> String {
+        let tables = self.tables.lock().unwrap();
+        format!(
+            "metadata-rebuilds: {}\nmetadata-reuses: {}\nmetadata-cached-bytes: {}\n",
+            tables.metadata_rebuilds, tables.metadata_reuses, tables.metadata_charge
+        )
+    }
 
+    // Drops the table of a deleted file. A pinned one is dropped once unpinned.
+    pub fn evict(&self, file_number: u64) {
+        let mut tables = self.tables.lock().unwrap();
+        tables.lru.pop(&file_number);
+        if let Some(metadata) = tables.metadata.pop(&file_number) {
+            tables.metadata_charge -= metadata.charge();
+        }
+        if let Some(pinned) = tables.pinned.get_mut(&file_number) {
+            pinned.evicted = true;
+        }
+    }
+
+    fn open_table(
+        &self,
+        file_number: u64,
+        file_size: u64,
+    ) -> Result<Arc<Table<E::RandomAccessFile>>> {
         let file = self.open_table_file(file_number)?;
+        // the comparator of a reopened table was checked on its first open
+        let metadata = self.tables.lock().unwrap().take_metadata(file_number);
+        if let Some(metadata) = metadata {
+            let table = Table::from_metadata(self.options.clone(), file, &metadata);
+            return Ok(Arc::new(table));
+        }
         let table = Table::open(self.options.clone(), file, file_size)?;
+        if self.options.paranoid_checks {
+            self.check_comparator(&table, file_number)?;
+        }
         Ok(Arc::new(table))
     }
 
+    // A table ordered by another comparator would be searched in the wrong order.
+    // Tables without the comparator property pass
+    fn check_comparator<R: RandomAccessFile>(
+        &self,
+        table: &Table<R>,
+        file_number: u64,
+    ) -> Result<()> {
+        let expected = self.options.comparator.user_comparator_name();
+        match table.comparator_name()? {
+            Some(name) if !is_same_comparator(&name, expected) => {
+                let file_name = table_file_name(&self.dbname, file_number);
+                Err(Error::Corruption(format!(
+                    "table {} was written with comparator {} but the db is configured with {}",
+                    file_name.file_name().unwrap().to_string_lossy(),
+                    name,
+                    expected
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Calls `handle_result` with the first entry >= key in the table of `file`,
+    // see `Table::internal_get`. A lookup failing on an interrupted read is tried
+    // once more. With paranoid checks the table must hold the key range its
+    // manifest entry records.
+    pub fn get<F: FnMut(&[u8], &[u8])>(
+        &self,
+        option: &ReadOption,
+        file: &FileMetaData,
+        key: &[u8],
+        mut handle_result: F,
+    ) -> Result<()> {
+        let mut lookup = || {
+            let table = self.find_table(file.number, file.file_size)?;
+            if self.options.paranoid_checks {
+                self.check_key_range(table.clone(), file)?;
+            }
+            table.internal_get(option, key, &mut handle_result)
+        };
+        match lookup() {
+            Err(e) if e.io_kind() == Some(io::ErrorKind::Interrupted) => lookup(),
+            res => res,
+        }
+    }
+
+    fn check_key_range<R: RandomAccessFile>(
+        &self,
+        table: Arc<Table<R>>,
+        file: &FileMetaData,
+    ) -> Result<()> {
+        let mut iter = Table::iter(table, &ReadOption::default());
+        iter.seek_to_first();
+        let first = iter.valid().then(|| iter.key().to_vec());
+        iter.seek_to_last();
+        let last = iter.valid().then(|| iter.key().to_vec());
+        iter.status()?;
+
+        let is = |key: &Option<Vec<u8>>, bound: &InternalKey| {
+            key.as_ref().is_some_and(|k| {
+                self.options.comparator.compare(k, bound.encode()) == Ordering::Equal
+            })
+        };
+        if !is(&first, &file.smallest) || !is(&last, &file.largest) {
+            return Err(Error::Corruption(format!(
+                "table #{} doesn't hold the key range of its manifest entry",
+                file.number
+            )));
+        }
+        Ok(())
+    }
+
+    // Opens the table by its .ldb name, or else by the .sst name of older versions.
+    // A live table under neither name means the db is inconsistent, that's
+    // reported as corruption. Any other error is passed on with its path.
     pub fn open_table_file(&self, file_number: u64) -> Result<E::RandomAccessFile> {
         let file_name = table_file_name(&self.dbname, file_number);
-        if let Ok(file) = self.env.new_random_access_file(&file_name) {
-            Ok(file)
-        } else {
-            let old_file_name = sst_table_file_name(&self.dbname, file_number);
-            Ok(self.env.new_random_access_file(&old_file_name)?)
+        match self.env.new_random_access_file(&file_name) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => return Ok(res?),
+        }
+        let old_file_name = sst_table_file_name(&self.dbname, file_number);
+        match self.env.new_random_access_file(&old_file_name) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Err(Error::Corruption(format!(
+                "missing table file {:06} (looked for {} and {})",
+                file_number,
+                file_name.file_name().unwrap().to_string_lossy(),
+                old_file_name.file_name().unwrap().to_string_lossy()
+            ))),
+            res => Ok(res?),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{self, AtomicBool},
+        Barrier,
+    };
+
+    use crate::{
+        cmp::{BitWiseComparator, Comparator, InternalKeyComparator},
+        env::{faulty::FaultyEnv, DefaultEnv},
+        filter::BloomFilterPolicy,
+        sstable::TableBuiler,
+        ValueType,
+    };
+
+    use super::*;
+
+    fn interrupted() -> io::Error {
+        io::ErrorKind::Interrupted.into()
+    }
+
+    #[test]
+    fn test_get_retries_interrupted_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = FaultyEnv::new();
+        let options = Arc::new(Options::default());
+        let file = env
+            .new_writable_file(&table_file_name(dir.path(), 7))
+            .unwrap();
+        let mut builder = TableBuiler::new(options.clone(), file);
+        builder.add(b"key", b"value").unwrap();
+        let size = builder.finish(true).unwrap();
+
+        let file = FileMetaData {
+            number: 7,
+            file_size: size,
+            ..Default::default()
+        };
+        let cache = TableCache::new(dir.path().to_path_buf(), options, env.clone(), 10);
+        let get = || {
+            let mut found = None;
+            cache
+                .get(&ReadOption::default(), &file, b"key", |k, v| {
+                    found = Some((k.to_vec(), v.to_vec()))
+                })
+                .map(|_| found)
+        };
+
+        env.fail_reads(interrupted, 1);
+        assert_eq!(get().unwrap(), Some((b"key".to_vec(), b"value".to_vec())));
+
+        // only one retry
+        env.fail_reads(interrupted, 2);
+        let err = get().unwrap_err();
+        assert_eq!(err.io_kind(), Some(io::ErrorKind::Interrupted));
+        assert!(err.is_retryable());
+        assert!(get().unwrap().is_some());
+
+        // a permanent error is returned at once
+        env.fail_reads(|| io::ErrorKind::PermissionDenied.into(), 2);
+        assert!(!get().unwrap_err().is_retryable());
+        assert!(get().is_err());
+        assert!(get().unwrap().is_some());
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, left: &[u8], right: &[u8]) -> Ordering {
+            right.cmp(left)
+        }
+
+        fn name(&self) -> &'static str {
+            "rleveldb.test.ReverseComparator"
+        }
+
+        fn find_shortest_separator(&self, _start: &mut Vec<u8>, _limit: &[u8]) {}
+
+        fn find_shortest_successor(&self, _key: &mut Vec<u8>) {}
+    }
+
+    #[test]
+    fn test_comparator_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = DefaultEnv::default();
+        let internal = |user: Arc<dyn Comparator>| -> Arc<dyn Comparator> {
+            Arc::new(InternalKeyComparator::new(user))
+        };
+        let file = env
+            .new_writable_file(&table_file_name(dir.path(), 123))
+            .unwrap();
+        let options = Arc::new(Options {
+            comparator: internal(Arc::new(ReverseComparator)),
+            ..Default::default()
+        });
+        let mut builder = TableBuiler::new(options, file);
+        for key in [b"b", b"a"] {
+            let key = InternalKey::new(key, 1, ValueType::Value);
+            builder.add(key.encode(), b"value").unwrap();
+        }
+        let size = builder.finish(true).unwrap();
+
+        let cache = |paranoid_checks| {
+            let options = Arc::new(Options {
+                comparator: internal(Arc::new(BitWiseComparator {})),
+                paranoid_checks,
+                ..Default::default()
+            });
+            TableCache::new(dir.path().to_path_buf(), options, env, 10)
+        };
+        let table = cache(false).find_table(123, size).unwrap();
+        assert_eq!(
+            table.comparator_name().unwrap().as_deref(),
+            Some("rleveldb.test.ReverseComparator")
+        );
+        let err = cache(true).find_table(123, size).err().unwrap();
+        assert!(
+            matches!(&err, Error::Corruption(msg) if msg == "table 000123.ldb was written with \
+                comparator rleveldb.test.ReverseComparator but the db is configured with \
+                leveldb.BytewiseComparator"),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_missing_table_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = DefaultEnv::default();
+        let options = Arc::new(Options::default());
+        let cache = TableCache::new(dir.path().to_path_buf(), options.clone(), env, 10);
+        let err = cache.find_table(123, 100).err().unwrap();
+        assert!(
+            matches!(&err, Error::Corruption(msg) if msg == "missing table file 000123 \
+                (looked for 000123.ldb and 000123.sst)"),
+            "{:?}",
+            err
+        );
+
+        // a table by the old name is found
+        let file = env
+            .new_writable_file(&sst_table_file_name(dir.path(), 123))
+            .unwrap();
+        let mut builder = TableBuiler::new(options, file);
+        builder.add(b"key", b"value").unwrap();
+        let size = builder.finish(true).unwrap();
+        cache.find_table(123, size).unwrap();
+    }
+
+    #[test]
+    fn test_tables_are_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = FaultyEnv::new();
+        let options = Arc::new(Options::default());
+        let mut size = 0;
+        for number in 1..=3 {
+            let file = env
+                .new_writable_file(&table_file_name(dir.path(), number))
+                .unwrap();
+            let mut builder = TableBuiler::new(options.clone(), file);
+            builder.add(b"key", b"value").unwrap();
+            size = builder.finish(true).unwrap();
+        }
+        let cache = TableCache::new(dir.path().to_path_buf(), options, env.clone(), 2);
+
+        let first = cache.find_table(1, size).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.find_table(1, size).unwrap()));
+        // the least recently used table goes once the cache is full
+        cache.find_table(2, size).unwrap();
+        cache.find_table(3, size).unwrap();
+        let reopened = cache.find_table(1, size).unwrap();
+        assert!(!Arc::ptr_eq(&first, &reopened));
+        assert_eq!(env.open_count(&table_file_name(dir.path(), 1)), 2);
+        assert!(Arc::ptr_eq(&reopened, &cache.find_table(1, size).unwrap()));
+
+        cache.evict(1);
+        assert!(!Arc::ptr_eq(&reopened, &cache.find_table(1, size).unwrap()));
+    }
+
+    #[test]
+    fn test_table_metadata_outlives_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = FaultyEnv::new();
+        let write_options = Arc::new(Options {
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..Default::default()
+        });
+        let mut size = 0;
+        for number in 1..=2 {
+            let file = env
+                .new_writable_file(&table_file_name(dir.path(), number))
+                .unwrap();
+            let mut builder = TableBuiler::new(write_options.clone(), file);
+            builder.add(b"key", b"value").unwrap();
+            size = builder.finish(true).unwrap();
+        }
+
+        let open = |pin_table_metadata_in_cache, metadata_cache_capacity| {
+            let options = Options {
+                filter_policy: write_options.filter_policy.clone(),
+                pin_table_metadata_in_cache,
+                metadata_cache_capacity,
+                ..Default::default()
+            };
+            let cache =
+                TableCache::new(dir.path().to_path_buf(), Arc::new(options), env.clone(), 1);
+            // table 1 is evicted by table 2, then opened again while reads fail
+            cache.find_table(1, size).unwrap();
+            cache.find_table(2, size).unwrap();
+            env.fail_reads(interrupted, usize::MAX);
+            let reopened = cache.find_table(1, size);
+            env.clear_faults();
+            (cache, reopened)
+        };
+
+        let (cache, reopened) = open(true, 1 << 20);
+        // the reopened table read nothing, and still has its index and filter
+        let table = reopened.unwrap();
+        assert!(table.filter_data().is_some());
+        let mut iter = Table::iter(table, &ReadOption::default());
+        iter.seek_to_first();
+        assert_eq!((iter.key(), iter.value()), (&b"key"[..], &b"value"[..]));
+        let property = cache.metadata_property();
+        assert!(
+            property
+                .starts_with("metadata-rebuilds: 2\nmetadata-reuses: 1\nmetadata-cached-bytes: "),
+            "{}",
+            property
+        );
+        // table 2 was evicted in turn, and is deleted now
+        assert!(!property.ends_with("metadata-cached-bytes: 0\n"));
+        cache.evict(2);
+        assert!(cache
+            .metadata_property()
+            .ends_with("metadata-cached-bytes: 0\n"));
+
+        // without the option, or room for the blocks, the index is read again
+        for (pin, capacity) in [(false, 1 << 20), (true, 10)] {
+            let (cache, reopened) = open(pin, capacity);
+            assert!(reopened.is_err());
+            assert_eq!(
+                cache.metadata_property(),
+                "metadata-rebuilds: 3\nmetadata-reuses: 0\nmetadata-cached-bytes: 0\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_pinned_tables_survive_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = FaultyEnv::new();
+        let options = Arc::new(Options::default());
+        let sizes: Vec<u64> = (1..=8)
+            .map(|number| {
+                let file = env
+                    .new_writable_file(&table_file_name(dir.path(), number))
+                    .unwrap();
+                let mut builder = TableBuiler::new(options.clone(), file);
+                builder.add(b"key", b"value").unwrap();
+                builder.finish(true).unwrap()
+            })
+            .collect();
+        let size = |number: u64| sizes[number as usize - 1];
+        let opens = |number| env.open_count(&table_file_name(dir.path(), number));
+        let cache = TableCache::new(dir.path().to_path_buf(), options, env.clone(), 2);
+
+        // a compaction of tables 1 and 2 reads them while a reader floods the cache
+        // with the others until the compaction is done
+        let pins = vec![
+            cache.pin(1, size(1)).unwrap(),
+            cache.pin(2, size(2)).unwrap(),
+        ];
+        assert_eq!(cache.pinned_count(), 2);
+        let started = Barrier::new(2);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                started.wait();
+                let mut i = 0;
+                while i < 12 || !done.load(atomic::Ordering::Acquire) {
+                    let number = 3 + i % 6;
+                    cache.find_table(number, size(number)).unwrap();
+                    i += 1;
+                }
+            });
+            started.wait();
+            for _ in 0..100 {
+                for number in [1, 2] {
+                    cache.find_table(number, size(number)).unwrap();
+                }
+            }
+            done.store(true, atomic::Ordering::Release);
+        });
+        assert_eq!((opens(1), opens(2)), (1, 1));
+        assert!(opens(3) > 1);
+
+        // unpinned they're cached like the others, a pin taken twice holds until
+        // both are dropped
+        let again = cache.pin(1, size(1)).unwrap();
+        drop(pins);
+        assert_eq!(cache.pinned_count(), 1);
+        cache.find_table(2, size(2)).unwrap();
+        assert_eq!(opens(2), 1);
+        for number in 3..5 {
+            cache.find_table(number, size(number)).unwrap();
+        }
+        cache.find_table(2, size(2)).unwrap();
+        assert_eq!(opens(2), 2);
+        cache.find_table(1, size(1)).unwrap();
+        assert_eq!(opens(1), 1);
+
+        // the table of a deleted file isn't cached once unpinned
+        cache.evict(1);
+        cache.find_table(1, size(1)).unwrap();
+        assert_eq!(opens(1), 1);
+        drop(again);
+        assert_eq!(cache.pinned_count(), 0);
+        cache.find_table(1, size(1)).unwrap();
+        assert_eq!(opens(1), 2);
+    }
+}
@@ -0,0 +1,108 @@
+// Spans of the major operations for `tracing` subscribers. Without the `tracing`
+// feature `trace_span!` expands to a unit struct and its fields are never
+// evaluated.
+
+#[cfg(feature = "tracing")]
+pub(crate) type Span = tracing::span::EnteredSpan;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    #[inline(always)]
+    pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+}
+
+// Enters an info span until the returned guard is dropped. Fields recorded later
+// are declared with `tracing::field::Empty`.
+macro_rules! trace_span {
+    ($name:literal $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "tracing")]
+        let span: $crate::trace::Span = tracing::info_span!($name $(, $($fields)*)?).entered();
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::trace::Span;
+        span
+    }};
+}
+
+pub(crate) use trace_span;
+
+// Keeps the spans created on the thread it is the default subscriber of, with
+// their fields formatted.
+#[cfg(all(test, feature = "tracing"))]
+pub(crate) mod testing {
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    #[derive(Clone, Debug)]
+    pub(crate) struct SpanRecord {
+        pub name: &'static str,
+        pub fields: HashMap<&'static str, String>,
+    }
+
+    #[derive(Clone, Default)]
+    pub(crate) struct SpanCollector {
+        spans: Arc<Mutex<Vec<SpanRecord>>>,
+    }
+
+    impl SpanCollector {
+        pub(crate) fn spans(&self, name: &str) -> Vec<SpanRecord> {
+            let spans = self.spans.lock().unwrap();
+            spans.iter().filter(|s| s.name == name).cloned().collect()
+        }
+    }
+
+    struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for SpanCollector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = HashMap::new();
+            attrs.record(&mut Fields(&mut fields));
+            spans.push(SpanRecord {
+                name: attrs.metadata().name(),
+                fields,
+            });
+            span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let span = &mut spans[id.into_u64() as usize - 1];
+            values.record(&mut Fields(&mut span.fields));
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+}
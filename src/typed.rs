@@ -0,0 +1,701 @@
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    cmp::{BitWiseComparator, Comparator},
+    env::{DefaultEnv, Env},
+    error::{Error, Result},
+    format::{InternalKey, ParsedInternalKey},
+    iterator::DBIterator,
+    options::{ReadOption, WriteOption},
+    types::{SequenceNumber, ValueType},
+    write_batch::WriteBatch,
+    LevelDB,
+};
+
+// A db of typed keys and values. Keys and values are turned into bytes by a
+// `KeyCodec` and a `ValueCodec`, by default `OrderedKeyCodec` and `BincodeCodec`.
+//
+// ORDERING: scans return entries in the order of the encoded keys. Only an
+// `OrderedKey` encoding sorts like the keys themselves. A key type with just a
+// serde encoding, like `BincodeCodec` as key codec, gets a valid db but its scans
+// come out in an unrelated order and range bounds select unrelated keys. Use such
+// codecs for point lookups only, or implement `OrderedKey` for the type.
+//
+// The db must use the default bytewise comparator, and a db has to be reopened
+// with the codecs it was written with.
+//
+//     let db = TypedDb::<u64, String>::new(DbBuilder::new().open("demo")?)?;
+//     db.put(&1, &"one".to_string())?;
+//     for entry in db.range(&ReadOption::default(), 1..10) {
+//         let (id, name) = entry?;
+//     }
+pub struct TypedDb<K, V, E: Env = DefaultEnv, KC = OrderedKeyCodec, VC = BincodeCodec> {
+    db: LevelDB<E>,
+    key_codec: KC,
+    value_codec: VC,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+pub trait KeyCodec<K> {
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>>;
+
+    fn decode_key(&self, data: &[u8]) -> Result<K>;
+}
+
+pub trait ValueCodec<V> {
+    fn encode_value(&self, value: &V) -> Result<Vec<u8>>;
+
+    fn decode_value(&self, data: &[u8]) -> Result<V>;
+}
+
+// A key encoding whose bytes sort like the keys: `a < b` exactly when the
+// encoding of `a` is bytewise less than the one of `b`. Encodings must also be
+// self delimiting, so tuples of ordered keys can just concatenate them.
+pub trait OrderedKey: Sized {
+    fn encode_ordered(&self, dst: &mut Vec<u8>);
+
+    // decodes a key from the front of `src` and advances it past the key
+    fn decode_ordered(src: &mut &[u8]) -> Result<Self>;
+}
+
+// Keys by their `OrderedKey` encoding
+#[derive(Clone, Copy, Default)]
+pub struct OrderedKeyCodec;
+
+// bincode's default encoding. As a key codec it doesn't keep the order of the
+// keys, see `TypedDb`
+#[derive(Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl<K, V, E: Env> TypedDb<K, V, E>
+where
+    OrderedKeyCodec: KeyCodec<K>,
+    BincodeCodec: ValueCodec<V>,
+{
+    pub fn new(db: LevelDB<E>) -> Result<Self> {
+        Self::with_codecs(db, OrderedKeyCodec, BincodeCodec)
+    }
+}
+
+impl<K, V, E: Env, KC: KeyCodec<K>, VC: ValueCodec<V>> TypedDb<K, V, E, KC, VC> {
+    pub fn with_codecs(db: LevelDB<E>, key_codec: KC, value_codec: VC) -> Result<Self> {
+        if db.user_comparator().name() != (BitWiseComparator {}).name() {
+            return Err(Error::InvalidArgument(
+                "a typed db needs the bytewise comparator".into(),
+            ));
+        }
+        Ok(TypedDb {
+            db,
+            key_codec,
+            value_codec,
+            _types: PhantomData,
+        })
+    }
+
+    pub fn db(&self) -> &LevelDB<E> {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> LevelDB<E> {
+        self.db
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        let mut batch = self.batch();
+        batch.put(key, value)?;
+        self.write(&WriteOption::default(), batch)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        let mut batch = self.batch();
+        batch.delete(key)?;
+        self.write(&WriteOption::default(), batch)
+    }
+
+    pub fn get(&self, options: &ReadOption, key: &K) -> Result<Option<V>> {
+        let key = self.key_codec.encode_key(key)?;
+        let (mut iter, sequence) = self.db.new_internal_iterator(options);
+        iter.seek(InternalKey::new(&key, sequence, ValueType::Value).encode());
+        if iter.valid() {
+            let ikey = ParsedInternalKey::parse(iter.key());
+            if ikey.user_key == key.as_slice() {
+                return match ikey.val_type {
+                    ValueType::Value => self.value_codec.decode_value(iter.value()).map(Some),
+                    ValueType::Deletetion => Ok(None),
+                };
+            }
+        }
+        iter.status()?;
+        Ok(None)
+    }
+
+    // a batch to fill and apply with `write`
+    pub fn batch(&self) -> TypedBatch<'_, K, V, KC, VC> {
+        TypedBatch {
+            batch: WriteBatch::new(),
+            key_codec: &self.key_codec,
+            value_codec: &self.value_codec,
+            _types: PhantomData,
+        }
+    }
+
+    // applies all updates of the batch at once
+    pub fn write(&self, options: &WriteOption, batch: TypedBatch<'_, K, V, KC, VC>) -> Result<()> {
+        self.db.write_batch(options, batch.batch)
+    }
+
+    pub fn iter(&self, options: &ReadOption) -> TypedIter<'_, K, V, KC, VC> {
+        self.scan(options, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    // the entries with keys in `range`, in the order of their encoding
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        options: &ReadOption,
+        range: R,
+    ) -> TypedIter<'_, K, V, KC, VC> {
+        let encode = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(self.key_codec.encode_key(key)?),
+                Bound::Excluded(key) => Bound::Excluded(self.key_codec.encode_key(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let bounds = encode(range.start_bound()).and_then(|start| {
+            let end = encode(range.end_bound())?;
+            Ok((start, end))
+        });
+        match bounds {
+            Ok((start, end)) => self.scan(options, start, end),
+            Err(err) => TypedIter::failed(self, err),
+        }
+    }
+
+    fn scan(
+        &self,
+        options: &ReadOption,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> TypedIter<'_, K, V, KC, VC> {
+        let (mut iter, sequence) = self.db.new_internal_iterator(options);
+        let mut skip = None;
+        match start {
+            Bound::Included(key) => {
+                iter.seek(InternalKey::new(&key, sequence, ValueType::Value).encode())
+            }
+            Bound::Excluded(key) => {
+                iter.seek(InternalKey::new(&key, sequence, ValueType::Value).encode());
+                skip = Some(key);
+            }
+            Bound::Unbounded => iter.seek_to_first(),
+        }
+        TypedIter {
+            iter: Some(iter),
+            sequence,
+            last_key: skip,
+            end,
+            error: None,
+            scan_limit_bytes: options.scan_limit_bytes,
+            bytes_scanned: 0,
+            entries_scanned: 0,
+            limit_reached: false,
+            key_codec: &self.key_codec,
+            value_codec: &self.value_codec,
+            _types: PhantomData,
+        }
+    }
+}
+
+// Updates to apply at once with `TypedDb::write`
+pub struct TypedBatch<'a, K, V, KC, VC> {
+    batch: WriteBatch,
+    key_codec: &'a KC,
+    value_codec: &'a VC,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K, V, KC: KeyCodec<K>, VC: ValueCodec<V>> TypedBatch<'a, K, V, KC, VC> {
+    pub fn put(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = self.key_codec.encode_key(key)?;
+        let value = self.value_codec.encode_value(value)?;
+        self.batch.put(&key, &value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        let key = self.key_codec.encode_key(key)?;
+        self.batch.delete(&key);
+        Ok(())
+    }
+
+    pub fn count(&self) -> u32 {
+        self.batch.count()
+    }
+}
+
+// The entries of a `TypedDb` in key order. A key or value that doesn't decode is
+// an error item, the scan goes on after it. A read error ends the scan.
+//
+// With `ReadOption::scan_limit_bytes` the scan also ends before the entry that
+// would take the encoded keys and values returned past the limit, and
+// `limit_reached` tells it from the end of the range. The first entry is
+// returned whatever its size, so paging with `range` from the last key returned
+// always makes progress.
+pub struct TypedIter<'a, K, V, KC, VC> {
+    iter: Option<Box<dyn DBIterator>>,
+    sequence: SequenceNumber,
+    // the user key last seen, older entries of it are hidden
+    last_key: Option<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    error: Option<Error>,
+    scan_limit_bytes: Option<u64>,
+    bytes_scanned: u64,
+    entries_scanned: u64,
+    limit_reached: bool,
+    key_codec: &'a KC,
+    value_codec: &'a VC,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K, V, KC: KeyCodec<K>, VC: ValueCodec<V>> TypedIter<'a, K, V, KC, VC> {
+    fn failed<E: Env>(db: &'a TypedDb<K, V, E, KC, VC>, err: Error) -> Self {
+        TypedIter {
+            iter: None,
+            sequence: 0,
+            last_key: None,
+            end: Bound::Unbounded,
+            error: Some(err),
+            scan_limit_bytes: None,
+            bytes_scanned: 0,
+            entries_scanned: 0,
+            limit_reached: false,
+            key_codec: &db.key_codec,
+            value_codec: &db.value_codec,
+            _types: PhantomData,
+        }
+    }
+
+    // the encoded key and value bytes of the entries returned so far
+    pub fn bytes_scanned(&self) -> u64 {
+        self.bytes_scanned
+    }
+
+    pub fn entries_scanned(&self) -> u64 {
+        self.entries_scanned
+    }
+
+    // whether the scan ended at `scan_limit_bytes` rather than the end of its
+    // range
+    pub fn limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    fn past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl<'a, K, V, KC: KeyCodec<K>, VC: ValueCodec<V>> Iterator for TypedIter<'a, K, V, KC, VC> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut iter = match self.iter.take() {
+            Some(iter) => iter,
+            None => return self.error.take().map(Err),
+        };
+        while iter.valid() {
+            let ikey = ParsedInternalKey::parse(iter.key());
+            if ikey.sequence > self.sequence || self.last_key.as_deref() == Some(ikey.user_key) {
+                iter.next();
+                continue;
+            }
+            if self.past_end(ikey.user_key) {
+                return None;
+            }
+            self.last_key = Some(ikey.user_key.to_vec());
+            if ikey.val_type == ValueType::Deletetion {
+                iter.next();
+                continue;
+            }
+            let bytes = (ikey.user_key.len() + iter.value().len()) as u64;
+            if let Some(limit) = self.scan_limit_bytes {
+                if self.entries_scanned > 0 && self.bytes_scanned + bytes > limit {
+                    self.limit_reached = true;
+                    return None;
+                }
+            }
+            self.bytes_scanned += bytes;
+            self.entries_scanned += 1;
+            let entry = self.key_codec.decode_key(ikey.user_key).and_then(|key| {
+                let value = self.value_codec.decode_value(iter.value())?;
+                Ok((key, value))
+            });
+            iter.next();
+            self.iter = Some(iter);
+            return Some(entry);
+        }
+        iter.status().err().map(Err)
+    }
+}
+
+impl<K: OrderedKey> KeyCodec<K> for OrderedKeyCodec {
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>> {
+        let mut dst = Vec::new();
+        key.encode_ordered(&mut dst);
+        Ok(dst)
+    }
+
+    fn decode_key(&self, mut data: &[u8]) -> Result<K> {
+        let key = K::decode_ordered(&mut data)?;
+        if !data.is_empty() {
+            return Err(Error::InvalidData("trailing bytes after the key".into()));
+        }
+        Ok(key)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> KeyCodec<T> for BincodeCodec {
+    fn encode_key(&self, key: &T) -> Result<Vec<u8>> {
+        self.encode_value(key)
+    }
+
+    fn decode_key(&self, data: &[u8]) -> Result<T> {
+        self.decode_value(data)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> ValueCodec<T> for BincodeCodec {
+    fn encode_value(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::InvalidData(e.to_string()))
+    }
+
+    fn decode_value(&self, data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(|e| Error::InvalidData(e.to_string()))
+    }
+}
+
+fn take<const N: usize>(src: &mut &[u8]) -> Result<[u8; N]> {
+    if src.len() < N {
+        return Err(Error::InvalidData("key is too short".into()));
+    }
+    let (head, rest) = src.split_at(N);
+    *src = rest;
+    Ok(head.try_into().unwrap())
+}
+
+// unsigned integers are big endian
+macro_rules! ordered_unsigned {
+    ($($t:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn encode_ordered(&self, dst: &mut Vec<u8>) {
+                dst.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_ordered(src: &mut &[u8]) -> Result<Self> {
+                Ok(<$t>::from_be_bytes(take(src)?))
+            }
+        }
+    )*};
+}
+
+// signed integers are big endian with the sign bit flipped, so negative numbers
+// sort first
+macro_rules! ordered_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl OrderedKey for $t {
+            fn encode_ordered(&self, dst: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                dst.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn decode_ordered(src: &mut &[u8]) -> Result<Self> {
+                let flipped = <$u>::from_be_bytes(take(src)?);
+                Ok((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+ordered_unsigned!(u8, u16, u32, u64);
+ordered_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+// Byte strings escape 0 as 0 0xff and end with 0 1. The end sorts before any
+// other byte, so a string sorts before the strings it is a prefix of.
+impl OrderedKey for Vec<u8> {
+    fn encode_ordered(&self, dst: &mut Vec<u8>) {
+        for &b in self {
+            dst.push(b);
+            if b == 0 {
+                dst.push(0xff);
+            }
+        }
+        dst.extend_from_slice(&[0, 1]);
+    }
+
+    fn decode_ordered(src: &mut &[u8]) -> Result<Self> {
+        let mut key = Vec::new();
+        loop {
+            match take::<1>(src)? {
+                [0] => match take::<1>(src)? {
+                    [0xff] => key.push(0),
+                    [1] => return Ok(key),
+                    _ => return Err(Error::InvalidData("bad escape in key".into())),
+                },
+                [b] => key.push(b),
+            }
+        }
+    }
+}
+
+impl OrderedKey for String {
+    fn encode_ordered(&self, dst: &mut Vec<u8>) {
+        // same as the bytes, without the copy
+        for &b in self.as_bytes() {
+            dst.push(b);
+            if b == 0 {
+                dst.push(0xff);
+            }
+        }
+        dst.extend_from_slice(&[0, 1]);
+    }
+
+    fn decode_ordered(src: &mut &[u8]) -> Result<Self> {
+        Ok(String::from_utf8(Vec::decode_ordered(src)?)?)
+    }
+}
+
+macro_rules! ordered_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: OrderedKey),+> OrderedKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_ordered(&self, dst: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_ordered(dst);)+
+            }
+
+            fn decode_ordered(src: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_ordered(src)?,)+))
+            }
+        }
+    };
+}
+
+ordered_tuple!(A B);
+ordered_tuple!(A B C);
+ordered_tuple!(A B C D);
+
+#[cfg(test)]
+mod tests {
+    use crate::DbBuilder;
+
+    use super::*;
+
+    fn encoded<K: OrderedKey>(key: &K) -> Vec<u8> {
+        OrderedKeyCodec.encode_key(key).unwrap()
+    }
+
+    #[test]
+    fn test_ordered_keys() {
+        let unsigned = [
+            0,
+            1,
+            255,
+            256,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+            u64::MAX,
+        ];
+        let signed = [i64::MIN, -256, -1, 0, 1, 255, i64::MAX];
+        let strings = ["", "\0", "\0\0", "\0a", "a", "a\0", "a\0b", "ab", "b"];
+        let tuples = [("", 5u64), ("a", 0), ("a", 1), ("a\0", 0), ("b", 0)];
+        fn check<K: OrderedKey + PartialEq + std::fmt::Debug>(sorted: &[K]) {
+            for pair in sorted.windows(2) {
+                assert!(encoded(&pair[0]) < encoded(&pair[1]), "{:?}", pair);
+            }
+            for key in sorted {
+                let decoded: K = OrderedKeyCodec.decode_key(&encoded(key)).unwrap();
+                assert_eq!(&decoded, key);
+            }
+        }
+        check(&unsigned);
+        check(&signed);
+        check(&strings.map(String::from));
+        check(&tuples.map(|(s, n)| (s.to_string(), n)));
+        check(&[-1i32, 0, 1]);
+
+        let bad: [&[u8]; 3] = [&[0, 0, 0], b"abc", &[b'a', 0, 2]];
+        for data in bad {
+            let res: Result<String> = OrderedKeyCodec.decode_key(data);
+            assert!(matches!(res, Err(Error::InvalidData(_))), "{:?}", data);
+        }
+        let res: Result<u32> = OrderedKeyCodec.decode_key(&[0, 0, 0, 1, 0]);
+        assert!(matches!(res, Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_typed_db() {
+        let dir = tempfile::Builder::new().prefix("typed").tempdir().unwrap();
+        let builder = DbBuilder::new().create_if_missing(true);
+        let read = ReadOption::default();
+        let keys = [0, 1, 2, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX];
+        let value = |key: u64| (format!("user{}", key), vec![key as u32; 3]);
+        {
+            let db =
+                TypedDb::<u64, (String, Vec<u32>)>::new(builder.open(dir.path()).unwrap()).unwrap();
+            for key in keys.iter().rev() {
+                db.put(key, &value(*key)).unwrap();
+            }
+            assert_eq!(db.get(&read, &1).unwrap(), Some(value(1)));
+            assert_eq!(db.get(&read, &3).unwrap(), None);
+
+            db.put(&2, &("two".to_string(), vec![])).unwrap();
+            db.delete(&0).unwrap();
+            assert_eq!(db.get(&read, &0).unwrap(), None);
+            assert_eq!(db.get(&read, &2).unwrap().unwrap().0, "two");
+
+            let mut batch = db.batch();
+            batch.put(&2, &value(2)).unwrap();
+            batch.put(&0, &value(0)).unwrap();
+            batch.delete(&1).unwrap();
+            assert_eq!(batch.count(), 3);
+            db.write(&WriteOption::default(), batch).unwrap();
+        }
+
+        let db =
+            TypedDb::<u64, (String, Vec<u32>)>::new(builder.open(dir.path()).unwrap()).unwrap();
+        let scan = |iter: TypedIter<_, _, _, _>| -> Vec<u64> {
+            iter.map(|entry| {
+                let (key, v) = entry.unwrap();
+                assert_eq!(v, value(key));
+                key
+            })
+            .collect()
+        };
+        assert_eq!(
+            scan(db.iter(&read)),
+            vec![0, 2, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX]
+        );
+        assert_eq!(
+            scan(db.range(&read, 1..u32::MAX as u64 + 1)),
+            vec![2, u32::MAX as u64]
+        );
+        assert_eq!(scan(db.range(&read, u32::MAX as u64..)), keys[3..].to_vec());
+        assert_eq!(scan(db.range(&read, ..=0)), vec![0]);
+        assert_eq!(
+            scan(db.range(
+                &read,
+                (Bound::Excluded(0), Bound::Included(u32::MAX as u64))
+            )),
+            vec![2, u32::MAX as u64]
+        );
+        assert!(scan(db.range(&read, 3..u32::MAX as u64)).is_empty());
+
+        // the same bytes under another value type
+        let db = TypedDb::<u64, Vec<u64>>::new(db.into_inner()).unwrap();
+        let res = db.get(&read, &2);
+        assert!(matches!(res, Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_signed_and_tuple_keys() {
+        let dir = tempfile::Builder::new()
+            .prefix("typed_keys")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .open(dir.path())
+            .unwrap();
+        let db = TypedDb::<(String, i64), ()>::new(db).unwrap();
+        let keys = [("a", 5), ("b", -7), ("a", -1), ("b", 0), ("ab", i64::MIN)];
+        for (name, n) in keys {
+            db.put(&(name.to_string(), n), &()).unwrap();
+        }
+        let names: Vec<_> = db
+            .range(
+                &ReadOption::default(),
+                ("a".to_string(), i64::MIN)..("b".to_string(), 0),
+            )
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        let expected = [("a", -1), ("a", 5), ("ab", i64::MIN), ("b", -7)];
+        assert_eq!(names, expected.map(|(s, n)| (s.to_string(), n)));
+    }
+
+    #[test]
+    fn test_bounded_scan() {
+        let dir = tempfile::Builder::new()
+            .prefix("typed_paging")
+            .tempdir()
+            .unwrap();
+        let db = DbBuilder::new()
+            .create_if_missing(true)
+            .write_buffer_size(64 * 1024)
+            .open(dir.path())
+            .unwrap();
+        let db = TypedDb::<u32, Vec<u8>>::new(db).unwrap();
+        let value = |key: u32| vec![key as u8; (key % 50) as usize];
+        for key in 0..5000 {
+            db.put(&key, &value(key)).unwrap();
+        }
+        for key in (0..5000).step_by(7) {
+            db.delete(&key).unwrap();
+        }
+        let expected: Vec<u32> = (0..5000).filter(|key| key % 7 != 0).collect();
+
+        let limit = 4096;
+        let options = ReadOption {
+            scan_limit_bytes: Some(limit),
+            ..Default::default()
+        };
+        let mut scanned = Vec::new();
+        let mut pages = 0;
+        loop {
+            let mut iter = match scanned.last() {
+                Some(&last) => db.range(&options, (Bound::Excluded(last), Bound::Unbounded)),
+                None => db.iter(&options),
+            };
+            let page: Vec<_> = iter.by_ref().map(|entry| entry.unwrap()).collect();
+            for (key, v) in page.iter() {
+                assert_eq!(v, &value(*key));
+            }
+            assert_eq!(iter.entries_scanned(), page.len() as u64);
+            assert!(iter.bytes_scanned() <= limit);
+            scanned.extend(page.into_iter().map(|(key, _)| key));
+            pages += 1;
+            if !iter.limit_reached() {
+                break;
+            }
+            // the next entry, a u32 key and a length prefixed value, wouldn't have fit
+            let next = expected[scanned.len()];
+            let next_bytes = 4 + 8 + value(next).len() as u64;
+            assert!(iter.bytes_scanned() + next_bytes > limit);
+        }
+        assert_eq!(scanned, expected);
+        assert!(pages > 10, "{}", pages);
+
+        // an entry over the limit alone still makes progress
+        let options = ReadOption {
+            scan_limit_bytes: Some(1),
+            ..Default::default()
+        };
+        let mut iter = db.iter(&options);
+        assert_eq!(iter.next().unwrap().unwrap().0, 1);
+        assert!(iter.next().is_none());
+        assert!(iter.limit_reached());
+        assert_eq!(iter.bytes_scanned(), 4 + 8 + 1);
+
+        let mut iter = db.iter(&ReadOption::default());
+        assert_eq!(iter.by_ref().count(), expected.len());
+        assert!(!iter.limit_reached());
+    }
+}
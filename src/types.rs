@@ -1,6 +1,6 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::error::Error;
+use crate::error::{Error, Result};
 
 pub type SequenceNumber = u64;
 pub const MAX_SEQUENCE_NUMBER: SequenceNumber = (1 << 56) - 1;
@@ -10,7 +10,7 @@ pub fn shared<T>(t: T) -> Rc<RefCell<T>> {
     Rc::new(RefCell::new(t))
 }
 
-#[derive(Copy, Clone,PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ValueType {
     Deletetion = 0,
     Value = 1,
@@ -19,7 +19,7 @@ pub enum ValueType {
 impl TryFrom<u8> for ValueType {
     type Error = Error;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
         match value {
             0u8 => Ok(ValueType::Deletetion),
             1u8 => Ok(ValueType::Value),
@@ -27,3 +27,110 @@ impl TryFrom<u8> for ValueType {
         }
     }
 }
+
+pub const TAG_SIZE: usize = 8;
+
+// The last 8 bytes of an internal key, `sequence << 8 | value type` stored as a
+// little endian fixed64 like the C++ implementation. Comparing tags orders by
+// sequence first, then by type.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Tag(u64);
+
+// the type lives in the low byte, the sequence in the upper 7 bytes
+const _: () = assert!(Tag::new(0x0102, ValueType::Value).0.to_le_bytes()[0] == 1);
+const _: () = assert!(Tag::new(0x0102, ValueType::Deletetion).0.to_le_bytes()[0] == 0);
+const _: () = assert!(Tag::new(0x0102, ValueType::Value).0.to_le_bytes()[1] == 0x02);
+const _: () = assert!(Tag::new(0x0102, ValueType::Value).0.to_le_bytes()[2] == 0x01);
+const _: () = assert!(Tag::new(MAX_SEQUENCE_NUMBER, ValueType::Value).seq() == MAX_SEQUENCE_NUMBER);
+
+impl Tag {
+    pub const fn new(seq: SequenceNumber, t: ValueType) -> Self {
+        assert!(seq <= MAX_SEQUENCE_NUMBER);
+        Tag(seq << 8 | t as u64)
+    }
+
+    pub const fn from_raw(raw: u64) -> Self {
+        Tag(raw)
+    }
+
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn seq(&self) -> SequenceNumber {
+        self.0 >> 8
+    }
+
+    pub fn value_type(&self) -> Result<ValueType> {
+        ValueType::try_from((self.0 & 0xff) as u8)
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(&self.0.to_le_bytes());
+    }
+
+    // decodes the tag from the first TAG_SIZE bytes of `src`
+    pub fn decode_from(src: &[u8]) -> Result<Self> {
+        if src.len() < TAG_SIZE {
+            return Err(Error::Corruption("internal key tag too short".into()));
+        }
+        let mut buf = [0u8; TAG_SIZE];
+        buf.copy_from_slice(&src[..TAG_SIZE]);
+        Ok(Tag(u64::from_le_bytes(buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_round_trip() {
+        let seqs = [0, 1, 255, 256, 1 << 40, MAX_SEQUENCE_NUMBER - 1];
+        for seq in seqs.into_iter().chain([MAX_SEQUENCE_NUMBER]) {
+            for t in [ValueType::Value, ValueType::Deletetion] {
+                let tag = Tag::new(seq, t);
+                assert_eq!(tag.seq(), seq);
+                assert_eq!(tag.value_type().unwrap(), t);
+
+                let mut buf = Vec::new();
+                tag.encode_to(&mut buf);
+                assert_eq!(buf.len(), TAG_SIZE);
+                assert_eq!(buf[0], t as u8);
+                assert_eq!(Tag::decode_from(&buf).unwrap(), tag);
+                assert_eq!(Tag::from_raw(tag.as_u64()), tag);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tag_layout() {
+        let mut buf = Vec::new();
+        Tag::new(0x0102_0304, ValueType::Value).encode_to(&mut buf);
+        assert_eq!(buf, [0x01, 0x04, 0x03, 0x02, 0x01, 0, 0, 0]);
+
+        buf.clear();
+        Tag::new(MAX_SEQUENCE_NUMBER, ValueType::Deletetion).encode_to(&mut buf);
+        assert_eq!(buf, [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_tag_order() {
+        let tag = Tag::new(5, ValueType::Value);
+        assert!(tag > Tag::new(5, ValueType::Deletetion));
+        assert!(tag > Tag::new(4, ValueType::Value));
+        assert!(tag < Tag::new(6, ValueType::Deletetion));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_tag_sequence_overflow() {
+        Tag::new(MAX_SEQUENCE_NUMBER + 1, ValueType::Value);
+    }
+
+    #[test]
+    fn test_tag_decode_errors() {
+        assert!(Tag::decode_from(&[1, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Tag::from_raw(0x0102).value_type().is_err());
+    }
+}
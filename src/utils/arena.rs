@@ -11,10 +11,19 @@ pub struct Arena {
     p: *mut u8,
     remain: usize,
     usage: AtomicUsize,
-    blocks: Vec<Vec<u8>>,
+    blocks: Vec<Vec<u64>>,
 }
 
 impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            p: std::ptr::null_mut(),
+            remain: 0,
+            usage: AtomicUsize::new(0),
+            blocks: Vec::new(),
+        }
+    }
+
     pub fn allocate(&mut self, n: usize) -> *mut u8 {
         if n < self.remain {
             let result = self.p;
@@ -73,15 +82,13 @@ impl Arena {
         }
     }
 
+    // blocks are made of u64 so they start aligned for `allocated_aligned`
     fn allocate_new_block(&mut self, block_bytes: usize) -> *mut u8 {
-        let mut v: Vec<u8> = Vec::with_capacity(block_bytes);
-        // unsafe {
-        //     v.set_len(block_bytes);
-        // }
-        let r = v.as_mut_ptr();
+        let mut v: Vec<u64> = Vec::with_capacity(block_bytes.div_ceil(8));
+        let r = v.as_mut_ptr() as *mut u8;
         self.blocks.push(v);
         self.usage
-            .fetch_add(block_bytes + size_of::<Vec<u8>>(), Ordering::SeqCst);
+            .fetch_add(block_bytes + size_of::<Vec<u64>>(), Ordering::SeqCst);
         r
     }
 }
@@ -94,4 +101,32 @@ mod tests {
     fn test_vec_len() {
         assert_eq!(size_of::<Vec<u8>>(), EXTRA_VEC_LEN);
     }
+
+    #[test]
+    fn miri_arena_allocate() {
+        let mut arena = Arena::new();
+        let mut allocated: Vec<(*mut u8, usize)> = Vec::new();
+        // small ones fill a block, large ones get their own, aligned ones skip slop
+        for (i, n) in [1, 7, 100, 1000, 2000, 3, 5000, 17, 0, 4095]
+            .into_iter()
+            .cycle()
+            .take(40)
+            .enumerate()
+        {
+            let p = if i % 3 == 0 {
+                let p = arena.allocated_aligned(n);
+                assert_eq!(p as usize % 8, 0, "allocation {} of {}", i, n);
+                p
+            } else {
+                arena.allocate(n)
+            };
+            unsafe { std::ptr::write_bytes(p, i as u8, n) };
+            allocated.push((p, n));
+        }
+        assert!(arena.memory_usage() >= allocated.iter().map(|(_, n)| n).sum());
+        for (i, &(p, n)) in allocated.iter().enumerate() {
+            let data = unsafe { std::slice::from_raw_parts(p, n) };
+            assert!(data.iter().all(|&b| b == i as u8));
+        }
+    }
 }
@@ -77,3 +77,33 @@ impl<'a> BufferWriter for &'a mut [u8] {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn miri_buffer_edges() {
+        let data = [1u8, 2, 3, 4];
+        let mut reader = &data[..];
+        assert_eq!(reader.read_bytes(0).unwrap(), &[] as &[u8]);
+        assert_eq!(reader.read_bytes(3).unwrap(), &[1, 2, 3]);
+        assert!(reader.read_bytes(2).is_err());
+        assert_eq!(reader.read_bytes(1).unwrap(), &[4]);
+        assert!(reader.bytes().is_empty());
+        assert!(reader.read_bytes(1).is_err());
+
+        let mut buf = [0u8; 4];
+        let mut writer = &mut buf[..];
+        writer.write_bytes(&[1, 2]).unwrap();
+        assert!(writer.write_bytes(&[3, 4, 5]).is_err());
+        unsafe {
+            writer.bytes_mut(1)[0] = 3;
+            writer.advance_mut(1);
+        }
+        writer.write_bytes(&[4]).unwrap();
+        assert!(writer.is_empty());
+        writer.write_bytes(&[]).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+}
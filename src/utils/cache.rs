@@ -13,15 +13,28 @@ use std::hash::Hash;
 const NUM_SHARD_BITS: u32 = 4;
 const NUM_SHARDS: u32 = 1 << NUM_SHARD_BITS;
 
-pub trait Cache<K: Sized, V: Sized> {
+// A cache shared by all the threads of a db, e.g. `Options::block_cache`. Each
+// entry is charged against the capacity of the cache, what a charge measures is
+// up to the user (the block cache charges bytes). Values are handed out as `Arc`s,
+// so an entry dropped from the cache stays alive while a reader still holds it.
+pub trait Cache<K: Sized, V: Sized>: Send + Sync {
+    // Caches `value` under `key`, replacing any entry of the key, and returns it.
+    // `None` if the cache keeps nothing.
     fn insert(&self, key: K, value: V, charge: u64) -> Option<Arc<V>>;
     fn lookup(&self, key: &K) -> Option<Arc<V>>;
     fn erase(&self, key: &K);
 
+    // A new id for a user of the cache to prefix its keys with.
     fn new_id(&self) -> u64;
+    // The sum of the charges of the cached entries.
     fn total_charge(&self) -> u64;
 }
 
+// Called with the key, value and charge of every entry that leaves a cache, by
+// eviction, erase or replacement. It runs with part of the cache locked and must
+// not use the cache.
+pub type EvictionListener<K, V> = Arc<dyn Fn(&K, &V, u64) + Send + Sync>;
+
 type CacheShard<K, V> = Arc<Mutex<LruCacheInner<K, V>>>;
 pub struct ShardLruCache<K, V>
 where
@@ -36,10 +49,18 @@ where
     K: Eq + Hash,
 {
     pub fn new(capacity: u64) -> Self {
+        Self::build(capacity, None)
+    }
+
+    pub fn with_eviction_listener(capacity: u64, listener: EvictionListener<K, V>) -> Self {
+        Self::build(capacity, Some(listener))
+    }
+
+    fn build(capacity: u64, listener: Option<EvictionListener<K, V>>) -> Self {
         let per_shard = (capacity + NUM_SHARDS as u64 - 1) / NUM_SHARDS as u64;
         let mut cache = Vec::with_capacity(NUM_SHARDS as usize);
         for _ in 0..NUM_SHARDS {
-            let shard = Arc::new(Mutex::new(LruCacheInner::new(per_shard)));
+            let shard = Arc::new(Mutex::new(LruCacheInner::new(per_shard, listener.clone())));
             cache.push(shard);
         }
         ShardLruCache {
@@ -64,7 +85,8 @@ where
 
 impl<K, V> Cache<K, V> for ShardLruCache<K, V>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Send,
+    V: Send + Sync,
 {
     fn insert(&self, key: K, value: V, charge: u64) -> Option<Arc<V>> {
         let mut lru = self.get_shard(&key);
@@ -103,15 +125,17 @@ struct LruCacheInner<K: Eq + Hash, V> {
     lru: LruCache<K, LruValue<V>>,
     usage: u64,
     capacity: u64,
+    listener: Option<EvictionListener<K, V>>,
 }
 
 impl<K: Eq + Hash, V> LruCacheInner<K, V> {
-    pub fn new(capacity: u64) -> Self {
+    pub fn new(capacity: u64, listener: Option<EvictionListener<K, V>>) -> Self {
         let lru = LruCache::unbounded();
         LruCacheInner {
             lru,
             usage: 0,
             capacity,
+            listener,
         }
     }
 
@@ -122,18 +146,18 @@ impl<K: Eq + Hash, V> LruCacheInner<K, V> {
 
         self.usage += charge;
         while self.usage > self.capacity && !self.lru.is_empty() {
-            let (_, evicted_val) = self.lru.pop_lru().unwrap();
-            self.usage -= evicted_val.charge;
+            let (evicted_key, evicted_val) = self.lru.pop_lru().unwrap();
+            self.removed(&evicted_key, evicted_val);
         }
         let value = Arc::new(value);
 
-        self.lru.put(
-            key,
-            LruValue {
-                value: value.clone(),
-                charge,
-            },
-        );
+        let entry = LruValue {
+            value: value.clone(),
+            charge,
+        };
+        if let Some((old_key, old_val)) = self.lru.push(key, entry) {
+            self.removed(&old_key, old_val);
+        }
 
         Some(value)
     }
@@ -144,7 +168,14 @@ impl<K: Eq + Hash, V> LruCacheInner<K, V> {
 
     pub fn erase(&mut self, key: &K) {
         if let Some(v) = self.lru.pop(key) {
-            self.usage -= v.charge;
+            self.removed(key, v);
+        }
+    }
+
+    fn removed(&mut self, key: &K, v: LruValue<V>) {
+        self.usage -= v.charge;
+        if let Some(listener) = self.listener.as_ref() {
+            listener(key, &v.value, v.charge);
         }
     }
     pub fn clear(&mut self) {
@@ -171,4 +202,96 @@ mod tests {
         assert!(ret.is_some());
         assert_eq!(*ret.unwrap(), 200);
     }
+
+    #[test]
+    fn test_eviction_listener() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let evicted = evicted.clone();
+            Arc::new(move |k: &u32, v: &u32, charge| evicted.lock().unwrap().push((*k, *v, charge)))
+        };
+        // a charge of 1 per shard
+        let cache = ShardLruCache::with_eviction_listener(NUM_SHARDS as u64, listener);
+        for i in 0..100 {
+            cache.insert(i, i * 10, 1);
+        }
+        let live: Vec<u32> = (0..100).filter(|i| cache.lookup(i).is_some()).collect();
+        {
+            let evicted = evicted.lock().unwrap();
+            assert_eq!(evicted.len() + live.len(), 100);
+            assert!(evicted
+                .iter()
+                .all(|&(k, v, charge)| v == k * 10 && charge == 1));
+        }
+        assert_eq!(cache.total_charge(), live.len() as u64);
+
+        evicted.lock().unwrap().clear();
+        cache.erase(&live[0]);
+        cache.insert(live[1], 7, 1);
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [(live[0], live[0] * 10, 1), (live[1], live[1] * 10, 1)]
+        );
+        assert_eq!(*cache.lookup(&live[1]).unwrap(), 7);
+        assert_eq!(cache.total_charge(), live.len() as u64 - 1);
+    }
+
+    // a user supplied cache counting its hits and misses
+    struct CountingCache<K: Eq + Hash, V> {
+        inner: ShardLruCache<K, V>,
+        hits: AtomicU64,
+        misses: AtomicU64,
+    }
+
+    impl<K: Eq + Hash + Send, V: Send + Sync> Cache<K, V> for CountingCache<K, V> {
+        fn insert(&self, key: K, value: V, charge: u64) -> Option<Arc<V>> {
+            self.inner.insert(key, value, charge)
+        }
+
+        fn lookup(&self, key: &K) -> Option<Arc<V>> {
+            let value = self.inner.lookup(key);
+            let counter = if value.is_some() {
+                &self.hits
+            } else {
+                &self.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            value
+        }
+
+        fn erase(&self, key: &K) {
+            self.inner.erase(key)
+        }
+
+        fn new_id(&self) -> u64 {
+            self.inner.new_id()
+        }
+
+        fn total_charge(&self) -> u64 {
+            self.inner.total_charge()
+        }
+    }
+
+    #[test]
+    fn test_custom_block_cache() {
+        use crate::{options::Options, sstable::format::BlockContent, Block};
+
+        let counting = Arc::new(CountingCache {
+            inner: ShardLruCache::new(1 << 20),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        let options = Options {
+            block_cache: Some(counting.clone()),
+            ..Default::default()
+        };
+        let cache = options.block_cache.unwrap();
+        let block = Block::from_raw(BlockContent::new(vec![0, 0, 0, 0, 1, 0, 0, 0])).unwrap();
+        assert!(cache.lookup(&b"block".to_vec()).is_none());
+        cache.insert(b"block".to_vec(), block, 8);
+        assert!(cache.lookup(&b"block".to_vec()).is_some());
+        assert_eq!(counting.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(counting.misses.load(Ordering::Relaxed), 1);
+        assert_eq!(counting.total_charge(), 8);
+    }
 }
@@ -1,5 +1,9 @@
+use crc::{Crc, CRC_32_ISO_HDLC};
 use murmur3::murmur3_32;
 
+// the IEEE crc32 of table blocks and memtable entries, the log has its own
+pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
 pub fn bloom_hash(key: &[u8]) -> u32 {
     let mut key = key;
     murmur3_32(&mut key, 0xbc9f1d34).unwrap()
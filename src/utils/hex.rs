@@ -0,0 +1,63 @@
+use std::fmt::Write;
+
+pub fn to_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+// whitespace is ignored so fixtures can be wrapped
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+        .collect()
+}
+
+// Compares `data` against the checked in fixture `testdata/<name>`, set
+// REWRITE=1 to regenerate the fixture after an intended format change.
+#[cfg(test)]
+pub fn check_golden(name: &str, data: &[u8]) -> Vec<u8> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(name);
+    if std::env::var("REWRITE").is_ok() {
+        let mut s = String::new();
+        for line in data.chunks(32) {
+            s.push_str(&to_hex(line));
+            s.push('\n');
+        }
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, s).unwrap();
+    }
+    let content = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("read golden file {:?}: {}", path, e));
+    let expected = from_hex(&content).unwrap_or_else(|| panic!("bad hex in {:?}", path));
+    assert_eq!(
+        to_hex(data),
+        to_hex(&expected),
+        "{} changed, rerun with REWRITE=1 if intended",
+        name
+    );
+    expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let data = [0x00, 0x01, 0x7f, 0x80, 0xff];
+        assert_eq!(to_hex(&data), "00017f80ff");
+        assert_eq!(from_hex("00017f\n80ff").unwrap(), data);
+        assert!(from_hex("0").is_none());
+        assert!(from_hex("zz").is_none());
+    }
+}
@@ -1,5 +1,6 @@
+pub mod arena;
 pub mod buffer;
 pub mod cache;
-pub mod arena;
 pub mod hash;
-pub mod release;
\ No newline at end of file
+pub mod hex;
+pub mod release;
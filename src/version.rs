@@ -1,35 +1,88 @@
 use crate::{
     cmp::{Comparator, InternalKeyComparator},
-    consts::{MAX_MEM_COMPACT_LEVEL, NUM_LEVELS},
+    consts::{L0_COMPACTION_TRIGGER, MAX_MEM_COMPACT_LEVEL, NUM_LEVELS},
     env::Env,
-    error::Result,
-    format::InternalKey,
-    iterator::DBIterator,
+    error::{Error, Result},
+    format::{extract_tag, extract_user_key, InternalKey, ParsedInternalKey},
+    iterator::{DBIterator, LazyIterator},
     options::Options,
     sstable::{two_level_iterator::TwoLevelIterator, Table},
     table_cache::TableCache,
-    types::MAX_SEQUENCE_NUMBER,
+    types::{MAX_SEQUENCE_NUMBER, TAG_SIZE},
     version_edit::VersionEdit,
     version_set::{LevelFileNumIterator, LevelTableIterBuilder},
-    ReadOption, ValueType,
+    LookupKey, ReadOption, ValueType,
 };
 use std::{
-    cmp::Ordering,
+    cmp::{Ordering, Reverse},
     collections::HashSet,
     fmt::Debug,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicI32, Ordering as AtomicOrdering},
+        Arc, RwLock,
+    },
 };
 
-#[derive(Default, Clone, Debug)]
+fn debug_key(key: &[u8]) -> String {
+    let parsed = ParsedInternalKey::parse(key);
+    let user_key: Vec<u8> = parsed
+        .user_key
+        .iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .collect();
+    format!(
+        "'{}' @ {} : {}",
+        String::from_utf8_lossy(&user_key),
+        parsed.sequence,
+        parsed.val_type as u8
+    )
+}
+
+#[derive(Default, Debug)]
 pub struct FileMetaData {
-    pub allowed_seeks: i32,
+    // gets that had to look past this file before it is compacted, see
+    // `Version::update_stats`
+    pub allowed_seeks: AtomicI32,
     pub number: u64,
     pub file_size: u64,
     pub smallest: InternalKey,
     pub largest: InternalKey,
+    // Env::now_micros when the table was written, 0 if unknown
+    pub creation_time: u64,
 }
 
-pub struct Version<E> {
+impl Clone for FileMetaData {
+    fn clone(&self) -> Self {
+        FileMetaData {
+            allowed_seeks: AtomicI32::new(self.allowed_seeks.load(AtomicOrdering::Relaxed)),
+            number: self.number,
+            file_size: self.file_size,
+            smallest: self.smallest.clone(),
+            largest: self.largest.clone(),
+            creation_time: self.creation_time,
+        }
+    }
+}
+
+// The first file a get read, when it had to read more than one, and the level
+// of the file that had the key.
+#[derive(Default)]
+pub struct GetStats {
+    pub seek_file: Option<(Arc<FileMetaData>, usize)>,
+    pub level_served: Option<usize>,
+}
+
+// How far compaction is behind, in bytes: what it has to move out of each level
+// to bring the level back under its limit. Level 0 is limited by its number of
+// files, its files past the compaction trigger count at their average size. The
+// last level has no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionDebt {
+    pub levels: [u64; NUM_LEVELS],
+    pub total_bytes: u64,
+}
+
+pub struct Version<E: Env> {
     pub table_cache: TableCache<E>,
     pub options: Arc<Options>,
     pub files: [Vec<Arc<FileMetaData>>; NUM_LEVELS],
@@ -39,7 +92,7 @@ pub struct Version<E> {
     pub compaction_level: i32,
 }
 
-impl<E> Debug for Version<E> {
+impl<E: Env> Debug for Version<E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Version")
             .field("files", &self.files)
@@ -67,15 +120,96 @@ impl<E: Env> Version<E> {
         }
     }
 
-    // pub fn get(
-    //     &self,
-    //     option: &ReadOption,
-    //     k: &LookupKey,
-    //     val: &mut Vec<u8>,
-    // ) -> Result<(Arc<FileMetaData>, usize)> {
-    // }
+    // The value of the key in the tables: the level-0 files holding it newest
+    // first, then the one file of each other level whose range holds it. The first
+    // entry of the key at or below the lookup's sequence decides, a deletion is
+    // `Ok(None)`. Fails with `Error::NotFoundError` when no table has the key.
+    // `stats` gets the file to charge the seek to, see `update_stats`.
+    pub fn get(
+        &self,
+        option: &ReadOption,
+        key: &LookupKey,
+        stats: &mut GetStats,
+    ) -> Result<Option<Vec<u8>>> {
+        let ucmp = self.cmp.user_comparator();
+        let mut last_file_read: Option<(&Arc<FileMetaData>, usize)> = None;
+        let (user_key, internal_key) = (key.user_key(), key.internal_key());
+        for level in 0..NUM_LEVELS {
+            let files = &self.files[level];
+            let candidates: Vec<&Arc<FileMetaData>> = if level == 0 {
+                let mut candidates: Vec<_> = files
+                    .iter()
+                    .filter(|f| {
+                        ucmp.compare(user_key, f.smallest.user_key()) != Ordering::Less
+                            && ucmp.compare(user_key, f.largest.user_key()) != Ordering::Greater
+                    })
+                    .collect();
+                candidates.sort_by_key(|f| Reverse(f.number));
+                candidates
+            } else {
+                // the first file whose largest key is >= the lookup
+                let index = files.partition_point(|f| {
+                    self.cmp.compare(f.largest.encode(), internal_key) == Ordering::Less
+                });
+                files
+                    .get(index)
+                    .filter(|f| ucmp.compare(user_key, f.smallest.user_key()) != Ordering::Less)
+                    .into_iter()
+                    .collect()
+            };
+
+            for f in candidates {
+                if let Some((file, level)) = last_file_read {
+                    if stats.seek_file.is_none() {
+                        stats.seek_file = Some((file.clone(), level));
+                    }
+                }
+                last_file_read = Some((f, level));
+
+                let mut found = None;
+                self.table_cache.get(option, f, internal_key, |k, v| {
+                    if k.len() < TAG_SIZE {
+                        found = Some(Err(Error::Corruption("internal key is too short".into())));
+                        return;
+                    }
+                    if ucmp.compare(extract_user_key(k), user_key) != Ordering::Equal {
+                        return;
+                    }
+                    found = Some(match extract_tag(k).value_type() {
+                        Ok(ValueType::Value) => Ok(Some(v.to_vec())),
+                        Ok(ValueType::Deletetion) => Ok(None),
+                        Err(err) => Err(err),
+                    });
+                })?;
+                if let Some(found) = found {
+                    stats.level_served = Some(level);
+                    return found;
+                }
+            }
+        }
+        Err(Error::NotFoundError("no key".into()))
+    }
 
-    fn get_overlapping_inputs(
+    // Charges a get that read more than one file to the first of them. A file
+    // out of seeks becomes the file to compact, returns whether it did: the
+    // seeks it costs are more than compacting it into the next level would.
+    pub fn update_stats(&self, stats: &GetStats) -> bool {
+        let (file, level) = match stats.seek_file.as_ref() {
+            Some(seek_file) => seek_file,
+            None => return false,
+        };
+        if file.allowed_seeks.fetch_sub(1, AtomicOrdering::Relaxed) > 1 {
+            return false;
+        }
+        let mut file_to_compact = self.file_to_compact.write().unwrap();
+        if file_to_compact.is_some() {
+            return false;
+        }
+        *file_to_compact = Some((file.clone(), *level));
+        true
+    }
+
+    pub fn get_overlapping_inputs(
         &self,
         level: usize,
         begin: &Option<InternalKey>,
@@ -125,7 +259,7 @@ impl<E: Env> Version<E> {
             let start = smallest_user_key
                 .as_ref()
                 .map(|&f| InternalKey::new(f, MAX_SEQUENCE_NUMBER, ValueType::Value));
-            let limit = smallest_user_key
+            let limit = largest_user_key
                 .as_ref()
                 .map(|&f| InternalKey::new(f, 0, ValueType::Deletetion));
 
@@ -147,7 +281,46 @@ impl<E: Env> Version<E> {
         level
     }
 
-    fn overlap_in_level(
+    // How far `level` is over its limit, it needs a compaction at 1 or more: the
+    // number of files for level 0, the bytes for the others.
+    pub fn level_score(&self, level: usize) -> f64 {
+        if level == 0 {
+            self.files[0].len() as f64 / L0_COMPACTION_TRIGGER as f64
+        } else {
+            self.level_total_file_size(level) as f64 / max_bytes_for_level(level)
+        }
+    }
+
+    // Every file's smallest key is at most its largest, and the files of a level
+    // above 0 are sorted and disjoint, else a read would miss the keys of files
+    // hidden behind others.
+    pub fn check_invariants(&self) -> Result<()> {
+        for (level, files) in self.files.iter().enumerate() {
+            for f in files.iter() {
+                if self.cmp.compare(f.smallest.encode(), f.largest.encode()) == Ordering::Greater {
+                    return Err(Error::Corruption(format!(
+                        "file #{} at level {} has its smallest key after its largest",
+                        f.number, level
+                    )));
+                }
+            }
+            if level == 0 {
+                continue;
+            }
+            for pair in files.windows(2) {
+                if self
+                    .cmp
+                    .compare(pair[0].largest.encode(), pair[1].smallest.encode())
+                    != Ordering::Less
+                {
+                    return Err(overlap_error(&pair[0], &pair[1], level));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn overlap_in_level(
         &self,
         level: usize,
         smallest: &Option<&[u8]>,
@@ -166,15 +339,10 @@ impl<E: Env> Version<E> {
     ) -> bool {
         let ucmp = self.cmp.user_comparator();
         if !disjoint_sorted_files {
-            for file in files {
-                if self.before_file(&ucmp, smallest, file) || self.after_file(&ucmp, largest, file)
-                {
-                    continue;
-                } else {
-                    return true;
-                }
-            }
-            false
+            // a file overlaps unless the range ends before it or starts after it
+            files.iter().any(|file| {
+                !self.after_file(&ucmp, smallest, file) && !self.before_file(&ucmp, largest, file)
+            })
         } else {
             let mut index = 0;
             if let &Some(k) = smallest {
@@ -236,6 +404,41 @@ impl<E: Env> Version<E> {
         files.iter().map(|m| m.file_size).sum()
     }
 
+    pub fn compaction_debt(&self) -> CompactionDebt {
+        let mut debt = CompactionDebt::default();
+        let level0_files = self.files[0].len() as u64;
+        if level0_files > L0_COMPACTION_TRIGGER as u64 {
+            let average = self.level_total_file_size(0) / level0_files;
+            debt.levels[0] = (level0_files - L0_COMPACTION_TRIGGER as u64) * average;
+        }
+        for level in 1..NUM_LEVELS - 1 {
+            let size = self.level_total_file_size(level);
+            debt.levels[level] = size.saturating_sub(max_bytes_for_level(level) as u64);
+        }
+        debt.total_bytes = debt.levels.iter().sum();
+        debt
+    }
+
+    // Renders the `leveldb.sstables` property, one line per file:
+    //   number:size['smallest' @ seq : type .. 'largest' @ seq : type] created micros
+    pub fn sstables_property(&self) -> String {
+        let mut out = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            out.push_str(&format!("--- level {} ---\n", level));
+            for f in files.iter() {
+                out.push_str(&format!(
+                    " {}:{}[{} .. {}] created {}\n",
+                    f.number,
+                    f.file_size,
+                    debug_key(f.smallest.encode()),
+                    debug_key(f.largest.encode()),
+                    f.creation_time
+                ));
+            }
+        }
+        out
+    }
+
     pub fn new_concat_iter(
         &self,
         option: &ReadOption,
@@ -249,23 +452,22 @@ impl<E: Env> Version<E> {
         TwoLevelIterator::new(index_iter, builder, option.clone())
     }
 
-    pub fn add_iterators(
-        &self,
-        option: &ReadOption,
-        iters: &mut Vec<Box<dyn DBIterator>>,
-    ) -> Result<()> {
+    // One iterator per level-0 file and one per other level. Tables are opened by
+    // the first positioning of their iterator, not here.
+    pub fn add_iterators(&self, option: &ReadOption, iters: &mut Vec<Box<dyn DBIterator>>) {
         for file in self.files[0].iter() {
-            let table = self.table_cache.find_table(file.number, file.file_size)?;
-            let iter = Table::iter(table, option);
-            iters.push(Box::new(iter));
+            let (table_cache, file, option) =
+                (self.table_cache.clone(), file.clone(), option.clone());
+            iters.push(Box::new(LazyIterator::new(move || {
+                let table = table_cache.find_table(file.number, file.file_size)?;
+                Ok(Box::new(Table::iter(table, &option)) as Box<dyn DBIterator>)
+            })));
         }
 
         for i in 1..self.files.len() {
             let iter = self.new_concat_iter(option, i);
             iters.push(Box::new(iter));
         }
-
-        Ok(())
     }
 }
 
@@ -314,17 +516,21 @@ impl<E: Env> VersionBuilder<E> {
         for (level, file) in edit.new_files.iter() {
             let mut file_meta = file.clone();
 
-            // todo
-            file_meta.allowed_seeks = (file_meta.file_size / 16384) as i32;
-            if file_meta.allowed_seeks < 100 {
-                file_meta.allowed_seeks = 100;
-            }
+            // A seek costs about as much as compacting 40KB, and compacting 1MB
+            // reads and writes ~25MB (the overlapping files of the next level
+            // included). Being conservative, a file is worth compacting once it
+            // caused a seek per 16KB of it.
+            let allowed_seeks = (file_meta.file_size / 16384).max(100);
+            file_meta.allowed_seeks = AtomicI32::new(allowed_seeks.min(i32::MAX as u64) as i32);
             self.deleted_files[*level as usize].remove(&file_meta.number);
             self.added_files[*level as usize].push(Arc::new(file_meta));
         }
     }
 
-    pub fn save_to(&mut self, version: &mut Version<E>) {
+    // Fails with Corruption if the files of a level above 0 would overlap, and in
+    // debug builds or with paranoid checks if the version breaks any of
+    // `Version::check_invariants`. A version like that must not be installed.
+    pub fn save_to(&mut self, version: &mut Version<E>) -> Result<()> {
         let icmp = self.icmp.clone();
         for x in self.added_files.iter_mut() {
             x.sort_by(|f1, f2| icmp.compare(f1.smallest.encode(), f2.smallest.encode()));
@@ -337,51 +543,229 @@ impl<E: Env> VersionBuilder<E> {
                     if icmp.compare(base_file.smallest.encode(), add_file.smallest.encode())
                         == Ordering::Less
                     {
-                        self.maybe_add_file(version, level, base_file.clone());
+                        self.maybe_add_file(version, level, base_file.clone())?;
                         base_iter.next();
                     } else {
                         break;
                     }
                 }
-                self.maybe_add_file(version, level, add_file.clone());
+                self.maybe_add_file(version, level, add_file.clone())?;
+            }
+            for f in base_iter {
+                self.maybe_add_file(version, level, f.clone())?;
             }
-            base_iter.for_each(|f| self.maybe_add_file(version, level, f.clone()));
         }
+        if cfg!(debug_assertions) || version.options.paranoid_checks {
+            version.check_invariants()?;
+        }
+        Ok(())
     }
 
-    fn maybe_add_file(&self, version: &mut Version<E>, level: usize, file_meta: Arc<FileMetaData>) {
+    fn maybe_add_file(
+        &self,
+        version: &mut Version<E>,
+        level: usize,
+        file_meta: Arc<FileMetaData>,
+    ) -> Result<()> {
         if self.deleted_files[level].contains(&file_meta.number) {
-            return;
+            return Ok(());
         }
-
-        let last = &mut version.files[level].last();
-
-        //check last order
-        if level != 0 && last.is_some() {
-            let check_last = self
-                .icmp
-                .compare(last.unwrap().largest.encode(), file_meta.smallest.encode())
-                == Ordering::Less;
-
-            assert!(check_last);
+        // files are added in order of their smallest key, a level above 0 stays
+        // disjoint if each starts after the one before ends
+        if let Some(last) = version.files[level].last() {
+            if level > 0
+                && self
+                    .icmp
+                    .compare(last.largest.encode(), file_meta.smallest.encode())
+                    != Ordering::Less
+            {
+                return Err(overlap_error(last, &file_meta, level));
+            }
         }
-
         version.files[level].push(file_meta);
+        Ok(())
     }
 }
 
-fn target_file_size(options: &Arc<Options>) -> usize {
+fn overlap_error(a: &FileMetaData, b: &FileMetaData, level: usize) -> Error {
+    Error::Corruption(format!(
+        "files #{} and #{} overlap at level {}",
+        a.number, b.number, level
+    ))
+}
+
+pub fn target_file_size(options: &Arc<Options>) -> usize {
     options.max_file_size
 }
 
-fn grand_parent_overlap_bytes(options: &Arc<Options>) -> usize {
+pub fn grand_parent_overlap_bytes(options: &Arc<Options>) -> usize {
     10 * target_file_size(options)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::{env::DefaultEnv, types::ValueType};
+
     use super::*;
 
+    fn empty_version() -> Version<DefaultEnv> {
+        let options = Arc::new(Options::default());
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let table_cache =
+            TableCache::new("debt".into(), options.clone(), DefaultEnv::default(), 10);
+        Version::new(icmp, options, table_cache)
+    }
+
+    fn add_file(version: &mut Version<DefaultEnv>, level: usize, range: (&str, &str), size: u64) {
+        let number = version
+            .files
+            .iter()
+            .map(|files| files.len() as u64)
+            .sum::<u64>()
+            + 1;
+        version.files[level].push(Arc::new(FileMetaData {
+            number,
+            file_size: size,
+            smallest: InternalKey::new(range.0.as_bytes(), 100, ValueType::Value),
+            largest: InternalKey::new(range.1.as_bytes(), 100, ValueType::Value),
+            ..Default::default()
+        }));
+    }
+
     #[test]
-    fn test_pick_memtable_files() {}
+    fn test_pick_memtable_files() {
+        let pick = |version: &Version<DefaultEnv>, smallest: &str, largest: &str| {
+            version.pick_level_for_memtable_output(
+                &Some(smallest.as_bytes()),
+                &Some(largest.as_bytes()),
+            )
+        };
+        let mut version = empty_version();
+        assert_eq!(pick(&version, "a", "m"), MAX_MEM_COMPACT_LEVEL);
+
+        // too much of level 3 overlaps the middle of the range for level 2, the
+        // table would make a costly compaction
+        let overlap_limit = grand_parent_overlap_bytes(&version.options) as u64;
+        add_file(&mut version, 3, ("f", "g"), overlap_limit + 1);
+        assert_eq!(pick(&version, "a", "m"), 1);
+        assert_eq!(pick(&version, "h", "m"), MAX_MEM_COMPACT_LEVEL);
+
+        // a table overlapping the range stops it above
+        add_file(&mut version, 1, ("k", "z"), 100);
+        assert_eq!(pick(&version, "a", "m"), 0);
+        add_file(&mut version, 0, ("a", "b"), 100);
+        assert_eq!(pick(&version, "b", "c"), 0);
+        version.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut version = empty_version();
+        add_file(&mut version, 0, ("a", "k"), 100);
+        add_file(&mut version, 0, ("c", "f"), 100);
+        add_file(&mut version, 1, ("a", "c"), 100);
+        add_file(&mut version, 1, ("d", "f"), 100);
+        version.check_invariants().unwrap();
+
+        add_file(&mut version, 1, ("f", "h"), 100);
+        let err = version.check_invariants().unwrap_err();
+        assert!(
+            matches!(&err, Error::Corruption(msg) if msg == "files #4 and #5 overlap at level 1")
+        );
+
+        let mut version = empty_version();
+        add_file(&mut version, 2, ("b", "a"), 100);
+        let err = version.check_invariants().unwrap_err();
+        assert!(matches!(&err, Error::Corruption(msg)
+            if msg == "file #1 at level 2 has its smallest key after its largest"));
+    }
+
+    #[test]
+    fn test_builder_rejects_overlaps() {
+        let base = Arc::new(empty_version());
+        let key = |k: &str| InternalKey::new(k.as_bytes(), 100, ValueType::Value);
+        let save = |files: &[(usize, u64, &str, &str)]| {
+            let mut edit = VersionEdit::default();
+            for &(level, number, smallest, largest) in files {
+                edit.add_new_file(level as u32, number, 100, key(smallest), key(largest), 0);
+            }
+            let mut builder = VersionBuilder::new(base.clone(), base.cmp.clone());
+            let mut compact_pointers: [Vec<u8>; NUM_LEVELS] = Default::default();
+            builder.apply(&edit, &mut compact_pointers);
+            let mut version = empty_version();
+            builder.save_to(&mut version).map(|_| version)
+        };
+        let overlap = |res: Result<Version<DefaultEnv>>, expected: &str| {
+            assert!(matches!(&res, Err(Error::Corruption(msg)) if msg == expected));
+        };
+
+        // level-0 files may overlap
+        let version = save(&[
+            (0, 1, "a", "k"),
+            (0, 2, "c", "f"),
+            (1, 3, "a", "c"),
+            (1, 4, "d", "f"),
+        ])
+        .unwrap();
+        assert_eq!(version.files[0].len(), 2);
+        assert_eq!(version.files[1].len(), 2);
+
+        // sharing a key, or one within another, in any order of the edit
+        overlap(
+            save(&[(1, 3, "a", "d"), (1, 4, "d", "f")]),
+            "files #3 and #4 overlap at level 1",
+        );
+        overlap(
+            save(&[(2, 6, "m", "n"), (2, 5, "a", "z")]),
+            "files #5 and #6 overlap at level 2",
+        );
+        if cfg!(debug_assertions) {
+            overlap(
+                save(&[(0, 7, "b", "a")]),
+                "file #7 at level 0 has its smallest key after its largest",
+            );
+        }
+    }
+
+    fn version_with_sizes(levels: &[(usize, &[u64])]) -> Version<DefaultEnv> {
+        let mut version = empty_version();
+        let mut number = 0;
+        for &(level, sizes) in levels {
+            for &file_size in sizes {
+                number += 1;
+                version.files[level].push(Arc::new(FileMetaData {
+                    number,
+                    file_size,
+                    ..Default::default()
+                }));
+            }
+        }
+        version
+    }
+
+    #[test]
+    fn test_compaction_debt() {
+        const MB: u64 = 1 << 20;
+        // at the limits there is nothing to do
+        let version = version_with_sizes(&[
+            (0, &[MB; L0_COMPACTION_TRIGGER]),
+            (1, &[4 * MB, 6 * MB]),
+            (2, &[100 * MB]),
+        ]);
+        assert_eq!(version.compaction_debt(), CompactionDebt::default());
+
+        // two level-0 files over the trigger at the 2MB average, 5MB over the
+        // 10MB of level 1, 150MB over the 100MB of level 2. The last level
+        // has no limit
+        let version = version_with_sizes(&[
+            (0, &[MB, MB, MB, 3 * MB, 3 * MB, 3 * MB]),
+            (1, &[15 * MB]),
+            (2, &[200 * MB, 50 * MB]),
+            (NUM_LEVELS - 1, &[1 << 50]),
+        ]);
+        let debt = version.compaction_debt();
+        assert_eq!(debt.levels[..4], [4 * MB, 5 * MB, 150 * MB, 0]);
+        assert_eq!(debt.levels[NUM_LEVELS - 1], 0);
+        assert_eq!(debt.total_bytes, 159 * MB);
+    }
 }
@@ -1,3 +1,19 @@
+// A MANIFEST is a log (see `log`) whose records are encoded VersionEdits. An
+// edit is a sequence of fields, each starting with a varint32 tag:
+//
+//   COMPARATOR          len-prefixed name
+//   LOG_NUMBER          varint64
+//   NEXT_FILE_NUMBER    varint64
+//   LAST_SEQUENCE       varint64
+//   COMPACTION_POINTER  level: varint32, len-prefixed internal key
+//   DELETED_FILES       level: varint32, number: varint64
+//   NEW_FILE            level: varint32, number: varint64, size: varint64,
+//                       len-prefixed smallest and largest internal keys
+//   PREV_LOG_NUMBER     varint64
+//   NEW_FILE_V2         NEW_FILE followed by the creation time: varint64
+//
+// NEW_FILE_V2 is only written for files with a known creation time, so edits
+// without one stay readable by older versions and the C++ leveldb.
 use std::io::Write;
 
 use crate::{
@@ -18,6 +34,7 @@ const DELETED_FILES: u32 = 6;
 const NEW_FILE: u32 = 7;
 // 8 was used for large value refs
 const PREV_LOG_NUMBER: u32 = 9;
+const NEW_FILE_V2: u32 = 10;
 
 #[derive(Default)]
 pub struct VersionEdit {
@@ -71,13 +88,15 @@ impl VersionEdit {
         file_size: u64,
         smallest: InternalKey,
         largest: InternalKey,
+        creation_time: u64,
     ) {
         let file_meta = FileMetaData {
-            allowed_seeks: 0,
+            allowed_seeks: Default::default(),
             number: file_num,
             file_size,
             smallest,
             largest,
+            creation_time,
         };
         self.new_files.push((level, file_meta));
     }
@@ -123,7 +142,12 @@ impl VersionEdit {
         }
 
         for (n, f) in self.new_files.iter() {
-            dst.write_var_u32(NEW_FILE).unwrap();
+            let tag = if f.creation_time == 0 {
+                NEW_FILE
+            } else {
+                NEW_FILE_V2
+            };
+            dst.write_var_u32(tag).unwrap();
             dst.write_var_u32(*n).unwrap();
             dst.write_var_u64(f.number).unwrap();
             dst.write_var_u64(f.file_size).unwrap();
@@ -132,6 +156,9 @@ impl VersionEdit {
             dst.write_all(small).unwrap();
             dst.write_var_u32(large.len() as u32).unwrap();
             dst.write_all(large).unwrap();
+            if f.creation_time != 0 {
+                dst.write_var_u64(f.creation_time).unwrap();
+            }
         }
     }
 
@@ -197,27 +224,30 @@ impl VersionEdit {
                     DELETED_FILES => {
                         let (level_ret, num_res) =
                             (get_level(&mut src, &mut level), src.read_var_u64());
-                        if level_ret.is_ok() && num_res.is_ok() {
-                            self.deleted_files.push((level, num_res.unwrap().0));
+                        if let (Ok(_), Ok((num, _))) = (level_ret, num_res) {
+                            self.deleted_files.push((level, num));
                         } else {
                             msg = Some(String::from("deleted files"));
                         }
                     }
 
-                    NEW_FILE => {
+                    NEW_FILE | NEW_FILE_V2 => {
                         let level_res = get_level(&mut src, &mut level);
                         let num_res = src.read_var_u64();
                         let size_res = src.read_var_u64();
                         let small_res = get_internal_key(&mut src, &mut file_meta.smallest);
                         let large_res = get_internal_key(&mut src, &mut file_meta.largest);
-                        if level_res.is_ok()
-                            && num_res.is_ok()
-                            && size_res.is_ok()
-                            && small_res.is_ok()
-                            && large_res.is_ok()
+                        let time_res = if tag == NEW_FILE_V2 {
+                            src.read_var_u64()
+                        } else {
+                            Ok((0, 0))
+                        };
+                        if let (Ok(_), Ok((num, _)), Ok((size, _)), Ok(_), Ok(_), Ok((time, _))) =
+                            (level_res, num_res, size_res, small_res, large_res, time_res)
                         {
-                            file_meta.number = num_res.unwrap().0;
-                            file_meta.file_size = size_res.unwrap().0;
+                            file_meta.number = num;
+                            file_meta.file_size = size;
+                            file_meta.creation_time = time;
                             self.new_files.push((level, file_meta.clone()))
                         } else {
                             msg = Some(String::from("new files"));
@@ -265,3 +295,87 @@ fn get_internal_key(src: &mut &[u8], dst: &mut InternalKey) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{types::ValueType, utils::hex::check_golden};
+
+    #[test]
+    fn test_version_edit_golden() {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator("leveldb.BytewiseComparator");
+        edit.set_log_number(5);
+        edit.set_prev_log_number(4);
+        edit.set_next_file_number(8);
+        edit.set_last_sequence(100);
+        edit.add_compact_pointer(1, InternalKey::new(b"foo", 10, ValueType::Value));
+        edit.add_delete_file(2, 3);
+        edit.add_new_file(
+            0,
+            7,
+            1024,
+            InternalKey::new(b"a", 1, ValueType::Value),
+            InternalKey::new(b"z", 99, ValueType::Deletetion),
+            0,
+        );
+
+        let mut encoded = Vec::new();
+        edit.encode(&mut encoded);
+        let expected = check_golden("format/manifest.hex", &encoded);
+
+        let mut decoded = VersionEdit::new();
+        decoded.decode(&expected).unwrap();
+        assert_eq!(
+            decoded.comparator.as_deref(),
+            Some("leveldb.BytewiseComparator")
+        );
+        assert_eq!(decoded.log_number, Some(5));
+        assert_eq!(decoded.prev_log_number, Some(4));
+        assert_eq!(decoded.next_file_number, Some(8));
+        assert_eq!(decoded.last_sequence, Some(100));
+        assert_eq!(decoded.compact_pointers.len(), 1);
+        assert_eq!(decoded.compact_pointers[0].0, 1);
+        assert_eq!(
+            decoded.compact_pointers[0].1.encode(),
+            edit.compact_pointers[0].1.encode()
+        );
+        assert_eq!(decoded.deleted_files, vec![(2, 3)]);
+        assert_eq!(decoded.new_files.len(), 1);
+        let (level, f) = &decoded.new_files[0];
+        assert_eq!((*level, f.number, f.file_size), (0, 7, 1024));
+        assert_eq!(f.smallest.encode(), edit.new_files[0].1.smallest.encode());
+        assert_eq!(f.largest.encode(), edit.new_files[0].1.largest.encode());
+    }
+
+    #[test]
+    fn test_new_file_creation_time() {
+        let mut edit = VersionEdit::new();
+        for (number, creation_time) in [(7, 0), (8, 1_600_000_000_000_000)] {
+            edit.add_new_file(
+                1,
+                number,
+                1024,
+                InternalKey::new(b"a", 1, ValueType::Value),
+                InternalKey::new(b"z", 99, ValueType::Value),
+                creation_time,
+            );
+        }
+        let mut encoded = Vec::new();
+        edit.encode(&mut encoded);
+        assert_eq!(encoded[0], NEW_FILE as u8);
+
+        let mut decoded = VersionEdit::new();
+        decoded.decode(&encoded).unwrap();
+        let files: Vec<_> = decoded
+            .new_files
+            .iter()
+            .map(|(level, f)| (*level, f.number, f.creation_time))
+            .collect();
+        assert_eq!(files, vec![(1, 7, 0), (1, 8, 1_600_000_000_000_000)]);
+
+        // a truncated creation time is corruption, not a missing one
+        encoded.pop();
+        assert!(VersionEdit::new().decode(&encoded).is_err());
+    }
+}
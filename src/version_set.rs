@@ -1,21 +1,25 @@
 use std::{
     cell::UnsafeCell,
+    cmp::Ordering,
     collections::{HashSet, LinkedList},
     fmt::Debug,
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 use crate::{
-    cmp::{Comparator, InternalKeyComparator},
+    cmp::{is_same_comparator, Comparator, InternalKeyComparator},
     codec::{NumberReader, NumberWriter},
-    consts::{L0_COMPACTION_TRIGGER, NUM_LEVELS},
+    consts::NUM_LEVELS,
     env::{read_file_to_vec, Env},
     error::{Error, Result},
-    filenames::{current_file_name, descriptor_file_name, set_current_file},
+    filenames::{
+        current_file_name, descriptor_file_name, parse_file_name, set_current_file, FileType,
+    },
     format::InternalKey,
-    iterator::DBIterator,
-    options::Options,
+    iterator::{DBIterator, PinnedIterator},
+    merge::MergingIterator,
+    options::{Options, ReadOption},
     sstable::{
         block::BlockIter,
         Table, TableBlockIterBuilder,
@@ -23,22 +27,29 @@ use crate::{
     },
     table_cache::TableCache,
     types::SequenceNumber,
-    version::{max_bytes_for_level, FileMetaData, Version, VersionBuilder},
+    version::{
+        grand_parent_overlap_bytes, target_file_size, FileMetaData, Version, VersionBuilder,
+    },
     version_edit::VersionEdit,
     LogReader, LogWriter,
 };
 
 pub struct VersionSet<E: Env> {
     env: E,
-    db_name: String,
+    db_name: PathBuf,
     table_cache: TableCache<E>,
     options: Arc<Options>,
     icmp: InternalKeyComparator,
     last_sequence: SequenceNumber,
     next_file_number: u64,
     manifest_file_number: u64,
+    // the manifest CURRENT names, `manifest_file_number` is only used once a new
+    // manifest is started
+    current_manifest_number: u64,
     log_number: u64,
     prev_log_number: u64,
+    // the edits `recover` read from the manifest
+    recovered_edits: usize,
 
     versions: LinkedList<Arc<Version<E>>>,
 
@@ -52,7 +63,7 @@ pub struct VersionSet<E: Env> {
 impl<E: Env> VersionSet<E> {
     pub fn new(
         env: E,
-        db_name: String,
+        db_name: PathBuf,
         options: Arc<Options>,
         table_cache: TableCache<E>,
         icmp: InternalKeyComparator,
@@ -70,8 +81,10 @@ impl<E: Env> VersionSet<E> {
             last_sequence: 0,
             next_file_number: 2,
             manifest_file_number: 0,
+            current_manifest_number: 0,
             log_number: 0,
             prev_log_number: 0,
+            recovered_edits: 0,
             versions,
             compact_pointer: Default::default(),
             descriptor_log: None,
@@ -93,6 +106,10 @@ impl<E: Env> VersionSet<E> {
         self.manifest_file_number
     }
 
+    pub fn current_manifest_number(&self) -> u64 {
+        self.current_manifest_number
+    }
+
     pub fn new_file_number(&mut self) -> u64 {
         let ret = self.next_file_number;
         self.next_file_number += 1;
@@ -104,6 +121,20 @@ impl<E: Env> VersionSet<E> {
     pub fn prev_log_number(&self) -> u64 {
         self.prev_log_number
     }
+    pub fn recovered_edits(&self) -> usize {
+        self.recovered_edits
+    }
+
+    // Drops the versions nothing but the set refers to anymore, the current one
+    // stays.
+    pub fn remove_unused_versions(&mut self) {
+        self.versions = std::mem::take(&mut self.versions)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, v)| *i == 0 || Arc::strong_count(v) > 1)
+            .map(|(_, v)| v)
+            .collect();
+    }
 
     pub fn live_files(&self, live: &mut HashSet<u64>) {
         for v in self.versions.iter() {
@@ -115,6 +146,24 @@ impl<E: Env> VersionSet<E> {
         }
     }
 
+    // The internal key of each level where its last compaction stopped, the next
+    // size triggered one starts after it. Empty before the first one.
+    pub fn compact_pointers(&self) -> &[Vec<u8>; NUM_LEVELS] {
+        &self.compact_pointer
+    }
+
+    // The score of each level of the current version but the last, which is never
+    // compacted, see `Version::level_score`.
+    pub fn level_scores(&self) -> [f64; NUM_LEVELS - 1] {
+        let mut scores = [0.0; NUM_LEVELS - 1];
+        if let Some(current) = self.current() {
+            for (level, score) in scores.iter_mut().enumerate() {
+                *score = current.level_score(level);
+            }
+        }
+        scores
+    }
+
     pub fn mark_file_number_used(&mut self, file_number: u64) {
         if self.next_file_number <= file_number {
             self.next_file_number = file_number + 1;
@@ -123,14 +172,7 @@ impl<E: Env> VersionSet<E> {
 
     pub fn finalize(&self, version: &mut Version<E>) {
         let (best_level, best_score) = (0..NUM_LEVELS - 1)
-            .map(|level| {
-                let score = if level == 0 {
-                    (version.files.len() / L0_COMPACTION_TRIGGER) as f64
-                } else {
-                    version.level_total_file_size(level) as f64 / max_bytes_for_level(level)
-                };
-                (level, score)
-            })
+            .map(|level| (level, version.level_score(level)))
             .fold(
                 (-1i32, -1f64),
                 |(best_level, best_score), (level, score)| {
@@ -145,7 +187,9 @@ impl<E: Env> VersionSet<E> {
         version.compaction_score = best_score;
     }
 
-    pub fn recover(&mut self) -> Result<bool> {
+    // With `reuse_manifest` edits go on being appended to the manifest CURRENT
+    // names, if it is small enough and was read to its end.
+    pub fn recover(&mut self, reuse_manifest: bool) -> Result<bool> {
         let mut current = String::with_capacity(1024);
         let current_file = current_file_name(&self.db_name);
         read_file_to_vec(self.env.clone(), &current_file, &mut current)?;
@@ -155,7 +199,16 @@ impl<E: Env> VersionSet<E> {
             ));
         }
         current.truncate(current.len() - 1);
-        let description_name = Path::new(&self.db_name).join(current);
+        let current_manifest_number = match parse_file_name(&current) {
+            Ok((number, FileType::Descriptor)) => number,
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "CURRENT names {:?}, not a manifest",
+                    current
+                )))
+            }
+        };
+        let description_name = self.db_name.join(current);
         let file = self.env.new_sequential_file(&description_name)?;
         let mut reader = LogReader::new(file, true);
         let mut record = Vec::new();
@@ -165,23 +218,43 @@ impl<E: Env> VersionSet<E> {
         let mut prev_log_number = None;
         let mut next_file_number = None;
         let mut last_sequence = None;
+        let mut comparator_renamed = false;
+        let mut torn = false;
 
         loop {
-            let res = reader.read_record(&mut record)?;
+            let res = match reader.read_record(&mut record) {
+                Ok(res) => res,
+                // a crash in log_and_apply tears the last edit. Dropping it is safe:
+                // tables it added are left unreferenced, and files it deleted are
+                // still on disk and stay live.
+                Err(err) if !self.options.paranoid_checks && reader.at_eof() => {
+                    if let Some(logger) = self.options.info_log.as_ref() {
+                        logger.log(&format!(
+                            "{:?}: ignoring torn final record: {}",
+                            description_name, err
+                        ));
+                    }
+                    torn = true;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
             if res.is_none() {
                 break;
             }
 
             let mut edit = VersionEdit::default();
             edit.decode(record.as_slice())?;
-            if edit.comparator.is_some()
-                && edit.comparator.as_ref().unwrap() != self.icmp.user_comparator().name()
-            {
-                return Err(Error::InvalidArgument(format!(
-                    "{} comparator name does not match with {}",
-                    edit.comparator.as_ref().unwrap(),
-                    self.icmp.user_comparator().name()
-                )));
+            self.recovered_edits += 1;
+            if let Some(stored) = edit.comparator.as_ref() {
+                let name = self.icmp.user_comparator_name();
+                if !is_same_comparator(stored, name) {
+                    return Err(Error::InvalidArgument(format!(
+                        "{} comparator name does not match with {}",
+                        stored, name
+                    )));
+                }
+                comparator_renamed = stored != name;
             }
             builder.apply(&edit, &mut self.compact_pointer);
 
@@ -223,21 +296,23 @@ impl<E: Env> VersionSet<E> {
             self.options.clone(),
             self.table_cache.clone(),
         );
-        builder.save_to(&mut version);
+        builder.save_to(&mut version)?;
         self.finalize(&mut version);
         self.versions.push_front(Arc::new(version));
 
         self.manifest_file_number = next_file_number.unwrap();
+        self.current_manifest_number = current_manifest_number;
         self.next_file_number = next_file_number.unwrap() + 1;
         self.last_sequence = last_sequence.unwrap();
         self.log_number = log_number.unwrap();
         self.prev_log_number = prev_log_number.unwrap();
 
-        let save_manifest = false;
-        // if self.reuse_manifest(description_name, &current_name) {
-        //     save_manifest = true;
-        // }
-        Ok(save_manifest)
+        // a comparator recorded under an old name gets its name in a new manifest,
+        // and an edit appended after a torn one would be dropped with it
+        if reuse_manifest && !comparator_renamed && !torn {
+            self.reuse_manifest(&description_name, current_manifest_number);
+        }
+        Ok(comparator_renamed)
     }
 
     pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Result<()> {
@@ -251,6 +326,15 @@ impl<E: Env> VersionSet<E> {
         if edit.prev_log_number.is_none() {
             edit.set_prev_log_number(self.prev_log_number);
         }
+        // A manifest grown too large is replaced, the edit goes to a new one after
+        // a snapshot of the current version. The old one is garbage once CURRENT
+        // names the new one
+        if let Some(log) = self.descriptor_log.as_ref() {
+            if log.bytes_written() >= self.options.max_manifest_file_size {
+                self.descriptor_log = None;
+                self.manifest_file_number = self.new_file_number();
+            }
+        }
         edit.set_next_file_number(self.next_file_number);
         edit.set_last_sequence(self.last_sequence);
 
@@ -261,13 +345,13 @@ impl<E: Env> VersionSet<E> {
         );
         let mut builder = VersionBuilder::new(self.current().unwrap(), self.icmp.clone());
         builder.apply(edit, &mut self.compact_pointer);
-        builder.save_to(&mut version);
+        builder.save_to(&mut version)?;
         self.finalize(&mut version);
 
         let mut create_new_manifest = false;
+        let manifest_name = descriptor_file_name(&self.db_name, self.manifest_file_number);
         if self.descriptor_log.is_none() {
             create_new_manifest = true;
-            let manifest_name = descriptor_file_name(&self.db_name, self.manifest_file_number);
             let manifest_file = self.env.new_writable_file(&manifest_name)?;
             let mut writer = LogWriter::new(manifest_file);
             match self.write_snapshot(&mut writer) {
@@ -282,14 +366,24 @@ impl<E: Env> VersionSet<E> {
         let mut record = Vec::new();
         edit.encode(&mut record);
         let writer = self.descriptor_log.as_mut().unwrap();
-        writer.add_record(&record)?;
-        writer.sync()?;
-
+        let mut res = writer.add_record(&record).and_then(|_| writer.sync());
+        if res.is_ok() && create_new_manifest {
+            res = set_current_file(self.env.clone(), &self.db_name, self.manifest_file_number);
+        }
+        if let Err(err) = res {
+            // a new manifest CURRENT may not name is started over by the next edit
+            if create_new_manifest {
+                self.descriptor_log = None;
+                let _ = self.env.delete_file(&manifest_name);
+            }
+            return Err(err);
+        }
         if create_new_manifest {
-            set_current_file(self.env.clone(), &self.db_name, self.manifest_file_number)?;
+            self.current_manifest_number = self.manifest_file_number;
         }
 
         self.versions.push_front(Arc::new(version));
+        self.remove_unused_versions();
 
         //TODO??
         self.log_number = edit.log_number.unwrap();
@@ -298,9 +392,15 @@ impl<E: Env> VersionSet<E> {
         Ok(())
     }
 
+    // every edit is synced when it's written, an edit after this starts a new
+    // manifest
+    pub fn close_manifest(&mut self) {
+        self.descriptor_log = None;
+    }
+
     fn write_snapshot(&self, writer: &mut LogWriter<E::WritableFile>) -> Result<()> {
         let mut edit = VersionEdit::default();
-        edit.set_comparator(self.icmp.user_comparator().name());
+        edit.set_comparator(self.icmp.user_comparator_name());
 
         self.compact_pointer.iter().enumerate().for_each(|(i, c)| {
             if !c.is_empty() {
@@ -318,6 +418,7 @@ impl<E: Env> VersionSet<E> {
                     f.file_size,
                     f.smallest.clone(),
                     f.largest.clone(),
+                    f.creation_time,
                 );
             }
         }
@@ -328,8 +429,352 @@ impl<E: Env> VersionSet<E> {
         Ok(())
     }
 
-    fn reuse_manifest(&mut self, _dscname: &str, _dscbase: &str) -> bool {
-        false
+    // Opens the manifest for appending, below `max_file_size`. Otherwise the
+    // first edit starts a new manifest with a snapshot.
+    fn reuse_manifest(&mut self, name: &Path, number: u64) {
+        let size = match self.env.file_size(name) {
+            Ok(size) if size < self.options.max_file_size => size,
+            _ => return,
+        };
+        let file = match self.env.new_appendable_file(name) {
+            Ok(file) => file,
+            Err(err) => {
+                if let Some(logger) = self.options.info_log.as_ref() {
+                    logger.log(&format!("{:?}: reuse failed: {}", name, err));
+                }
+                return;
+            }
+        };
+        if let Some(logger) = self.options.info_log.as_ref() {
+            logger.log(&format!("reusing manifest {:?}", name));
+        }
+        self.descriptor_log = Some(LogWriter::new_with_dest_len(file, size as u64));
+        self.manifest_file_number = number;
+    }
+
+    // whether `pick_compaction` has something to do
+    pub fn needs_compaction(&self) -> bool {
+        let current = match self.current() {
+            Some(current) => current,
+            None => return false,
+        };
+        if current.compaction_score >= 1.0 {
+            return true;
+        }
+        let seek_level = current
+            .file_to_compact
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|f| f.1);
+        matches!(seek_level, Some(level) if level + 1 < NUM_LEVELS)
+    }
+
+    // Size triggered compactions are preferred over seek triggered ones. Files of
+    // the last level are never picked, there is no level below to compact into.
+    pub fn pick_compaction(&mut self) -> Option<Compaction<E>> {
+        let current = self.current()?;
+        if current.compaction_score < 1.0 {
+            let (file, level) = current.file_to_compact.read().unwrap().clone()?;
+            return self.compact_file(level, file);
+        }
+        let level = current.compaction_level as usize;
+        // first file after the key where the last compaction of this level stopped
+        let pointer = &self.compact_pointer[level];
+        let file = current.files[level]
+            .iter()
+            .find(|f| {
+                pointer.is_empty()
+                    || self.icmp.compare(f.largest.encode(), pointer) == Ordering::Greater
+            })
+            .or_else(|| current.files[level].first())?
+            .clone();
+        self.compact_file(level, file)
+    }
+
+    // A compaction of `file` at `level` of the current version into the next level.
+    pub fn compact_file(&mut self, level: usize, file: Arc<FileMetaData>) -> Option<Compaction<E>> {
+        if level + 1 >= NUM_LEVELS {
+            return None;
+        }
+        let mut c = Compaction::new(&self.options, level);
+        c.inputs[0].push(file);
+        c.input_version = self.current();
+        self.setup_inputs(&mut c);
+        Some(c)
+    }
+
+    // A compaction of the files of `level` overlapping [begin, end] into the next
+    // level, `None` if there are none. Above level 0 the inputs stop once they hold
+    // a target file size, the rest of the range is left to another compaction.
+    pub fn compact_range(
+        &mut self,
+        level: usize,
+        begin: &Option<InternalKey>,
+        end: &Option<InternalKey>,
+    ) -> Option<Compaction<E>> {
+        if level + 1 >= NUM_LEVELS {
+            return None;
+        }
+        let current = self.current()?;
+        let mut inputs = Vec::new();
+        current.get_overlapping_inputs(level, begin, end, &mut inputs);
+        if inputs.is_empty() {
+            return None;
+        }
+        // level-0 files overlap each other, all of them have to go at once
+        if level > 0 {
+            let limit = target_file_size(&self.options) as u64;
+            let mut total = 0;
+            if let Some(last) = inputs.iter().position(|f| {
+                total += f.file_size;
+                total >= limit
+            }) {
+                inputs.truncate(last + 1);
+            }
+        }
+        let mut c = Compaction::new(&self.options, level);
+        c.inputs[0] = inputs;
+        c.input_version = Some(current);
+        self.setup_other_inputs(&mut c);
+        Some(c)
+    }
+
+    fn setup_inputs(&mut self, c: &mut Compaction<E>) {
+        // level-0 files may overlap each other, pick up all of them that overlap the range
+        if c.level == 0 {
+            let current = c.input_version.clone().unwrap();
+            let (smallest, largest) = self.get_range(&c.inputs[0]);
+            let mut inputs = Vec::new();
+            current.get_overlapping_inputs(0, &Some(smallest), &Some(largest), &mut inputs);
+            c.inputs[0] = inputs;
+        }
+        self.setup_other_inputs(c);
+    }
+
+    fn setup_other_inputs(&mut self, c: &mut Compaction<E>) {
+        let current = c.input_version.clone().unwrap();
+        let level = c.level;
+        let (smallest, largest) = self.get_range(&c.inputs[0]);
+        let mut inputs = Vec::new();
+        current.get_overlapping_inputs(
+            level + 1,
+            &Some(smallest),
+            &Some(largest.clone()),
+            &mut inputs,
+        );
+        c.inputs[1] = inputs;
+
+        let all: Vec<_> = c.inputs[0]
+            .iter()
+            .chain(c.inputs[1].iter())
+            .cloned()
+            .collect();
+        let (all_start, all_limit) = self.get_range(&all);
+        if level + 2 < NUM_LEVELS {
+            let mut grandparents = Vec::new();
+            current.get_overlapping_inputs(
+                level + 2,
+                &Some(all_start),
+                &Some(all_limit),
+                &mut grandparents,
+            );
+            c.grandparents = grandparents;
+        }
+
+        // the next compaction of this level starts after this one, even if it fails
+        self.compact_pointer[level] = largest.encode().to_vec();
+        c.edit.add_compact_pointer(level as u32, largest);
+    }
+
+    // About the bytes of the tables of `version` before the internal key `ikey`.
+    // Reads only the version, so it's called without holding the version set.
+    // A table that can't be opened counts as empty.
+    pub fn approximate_offset_of(version: &Version<E>, ikey: &[u8]) -> u64 {
+        let mut offset = 0;
+        for (level, files) in version.files.iter().enumerate() {
+            for f in files {
+                if version.cmp.compare(f.largest.encode(), ikey) != Ordering::Greater {
+                    // the whole file is before the key
+                    offset += f.file_size;
+                } else if version.cmp.compare(f.smallest.encode(), ikey) == Ordering::Greater {
+                    // the whole file is after the key, and so are the rest of a
+                    // sorted level
+                    if level > 0 {
+                        break;
+                    }
+                } else if let Ok(table) = version.table_cache.find_table(f.number, f.file_size) {
+                    offset += table.approximate_offset_of(ikey);
+                }
+            }
+        }
+        offset
+    }
+
+    // Level-0 files may overlap each other and get an iterator each, the files of
+    // any other level are disjoint and are read one after another. The input
+    // tables stay pinned in the table cache while the iterator lives.
+    pub fn make_input_iterator(&self, c: &Compaction<E>) -> Result<Box<dyn DBIterator>> {
+        let option = ReadOption::internal(self.options.paranoid_checks);
+        let pins = c
+            .inputs
+            .iter()
+            .flatten()
+            .map(|f| self.table_cache.pin(f.number, f.file_size))
+            .collect::<Result<Vec<_>>>()?;
+        let mut iters: Vec<Box<dyn DBIterator>> = Vec::new();
+        for (which, files) in c.inputs.iter().enumerate() {
+            if files.is_empty() {
+                continue;
+            }
+            if c.level + which == 0 {
+                for f in files.iter() {
+                    let table = self.table_cache.find_table(f.number, f.file_size)?;
+                    iters.push(Box::new(Table::iter(table, &option)));
+                }
+            } else {
+                let index_iter = LevelFileNumIterator::new(self.icmp.clone(), files.clone());
+                let builder = LevelTableIterBuilder {
+                    table_cache: self.table_cache.clone(),
+                };
+                iters.push(Box::new(TwoLevelIterator::new(
+                    index_iter,
+                    builder,
+                    option.clone(),
+                )));
+            }
+        }
+        let iter = Box::new(MergingIterator::new(self.icmp.clone(), iters));
+        Ok(Box::new(PinnedIterator::new(iter, pins)))
+    }
+
+    // smallest and largest key of a non empty set of files
+    fn get_range(&self, inputs: &[Arc<FileMetaData>]) -> (InternalKey, InternalKey) {
+        assert!(!inputs.is_empty());
+        let mut smallest = &inputs[0].smallest;
+        let mut largest = &inputs[0].largest;
+        for f in inputs.iter().skip(1) {
+            if self.icmp.compare(f.smallest.encode(), smallest.encode()) == Ordering::Less {
+                smallest = &f.smallest;
+            }
+            if self.icmp.compare(f.largest.encode(), largest.encode()) == Ordering::Greater {
+                largest = &f.largest;
+            }
+        }
+        (smallest.clone(), largest.clone())
+    }
+}
+
+// A compaction of `inputs[0]` at `level` with the overlapping `inputs[1]` at
+// `level + 1`.
+pub struct Compaction<E: Env> {
+    pub level: usize,
+    pub max_output_file_size: u64,
+    pub input_version: Option<Arc<Version<E>>>,
+    pub edit: VersionEdit,
+    pub inputs: [Vec<Arc<FileMetaData>>; 2],
+    // files of `level + 2` overlapping the compaction
+    pub grandparents: Vec<Arc<FileMetaData>>,
+    max_grandparent_overlap_bytes: u64,
+
+    // state of should_stop_before
+    grandparent_index: usize,
+    seen_key: bool,
+    overlapped_bytes: u64,
+    // state of is_base_level_for_key, keys are asked for in increasing order
+    level_ptrs: [usize; NUM_LEVELS],
+}
+
+impl<E: Env> Compaction<E> {
+    pub fn new(options: &Arc<Options>, level: usize) -> Self {
+        Compaction {
+            level,
+            max_output_file_size: target_file_size(options) as u64,
+            input_version: None,
+            edit: VersionEdit::default(),
+            inputs: Default::default(),
+            grandparents: Vec::new(),
+            max_grandparent_overlap_bytes: grand_parent_overlap_bytes(options) as u64,
+            grandparent_index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            level_ptrs: [0; NUM_LEVELS],
+        }
+    }
+
+    pub fn num_input_files(&self, which: usize) -> usize {
+        self.inputs[which].len()
+    }
+
+    // A single input file with nothing to merge at the next level can be moved down
+    // by editing the version. Too much overlap with the grandparent level would make
+    // the later compaction of the moved file very expensive, so rewrite in that case.
+    pub fn is_trivial_move(&self) -> bool {
+        self.num_input_files(0) == 1
+            && self.num_input_files(1) == 0
+            && Version::<E>::total_file_size(&self.grandparents)
+                <= self.max_grandparent_overlap_bytes
+    }
+
+    // True if the output table should be finished before `internal_key`, so the
+    // table doesn't overlap too much of the grandparent level.
+    pub fn should_stop_before(&mut self, internal_key: &[u8]) -> bool {
+        let icmp = &self.input_version.as_ref().unwrap().cmp;
+        while self.grandparent_index < self.grandparents.len()
+            && icmp.compare(
+                internal_key,
+                self.grandparents[self.grandparent_index].largest.encode(),
+            ) == Ordering::Greater
+        {
+            if self.seen_key {
+                self.overlapped_bytes += self.grandparents[self.grandparent_index].file_size;
+            }
+            self.grandparent_index += 1;
+        }
+        self.seen_key = true;
+
+        if self.overlapped_bytes > self.max_grandparent_overlap_bytes {
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // True if no level below the output level may contain `user_key`, a deletion
+    // of the key has nothing left to shadow then.
+    pub fn is_base_level_for_key(&mut self, user_key: &[u8]) -> bool {
+        let version = self.input_version.as_ref().unwrap();
+        let ucmp = version.cmp.user_comparator();
+        for level in self.level + 2..NUM_LEVELS {
+            let files = &version.files[level];
+            while self.level_ptrs[level] < files.len() {
+                let f = &files[self.level_ptrs[level]];
+                if ucmp.compare(user_key, f.largest.user_key()) != Ordering::Greater {
+                    if ucmp.compare(user_key, f.smallest.user_key()) != Ordering::Less {
+                        return false;
+                    }
+                    break;
+                }
+                self.level_ptrs[level] += 1;
+            }
+        }
+        true
+    }
+
+    // Lets go of the input version once the compaction is installed, it keeps the
+    // input files from being deleted.
+    pub fn release_inputs(&mut self) {
+        self.input_version = None;
+    }
+
+    pub fn add_input_deletions(&mut self) {
+        for (which, files) in self.inputs.iter().enumerate() {
+            for f in files.iter() {
+                self.edit
+                    .add_delete_file((self.level + which) as u32, f.number);
+            }
+        }
     }
 }
 
@@ -406,8 +851,9 @@ impl DBIterator for LevelFileNumIterator {
     }
 
     fn prev(&mut self) {
-        assert!(self.index != 0);
-        self.index -= 1;
+        assert!(self.valid());
+        // before the first file is invalid, like past the last
+        self.index = self.index.checked_sub(1).unwrap_or(self.files.len());
     }
 
     fn key(&self) -> &[u8] {
@@ -424,11 +870,12 @@ impl DBIterator for LevelFileNumIterator {
             let mut write_buf = buf.as_mut();
             write_buf.write_u64_le(num).unwrap();
             write_buf.write_u64_le(size).unwrap();
-            write_buf
+            // the writes advance `write_buf` past the encoding
+            &buf[..]
         }
     }
 
-    fn status(&mut self) -> Result<()> {
+    fn status(&self) -> Result<()> {
         Ok(())
     }
 }
@@ -1,21 +1,29 @@
 use std::sync::Arc;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use bytes::Buf;
-// use integer_encoding::VarIntWriter;
 
 use crate::{
     codec::{self, NumberReader, VarIntWriter},
     error::{Error, Result},
-    types::SequenceNumber,
+    types::{SequenceNumber, MAX_SEQUENCE_NUMBER},
     MemTable, ValueType,
 };
 
+// the sequence as a u64, then the count as a u32
 const HEAD_SIZE: usize = 12;
+
+// The records of a batch after a header. Every batch has a whole header, so
+// reading it never fails, the records are only checked by `iterate`.
 pub struct WriteBatch {
     rep: Vec<u8>,
 }
 
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WriteBatch {
     pub fn new() -> Self {
         WriteBatch {
@@ -23,6 +31,29 @@ impl WriteBatch {
         }
     }
 
+    // room for `bytes` of encoded records before the batch has to grow
+    pub fn with_capacity(bytes: usize) -> Self {
+        let mut rep = Vec::with_capacity(HEAD_SIZE + bytes);
+        rep.resize(HEAD_SIZE, 0);
+        WriteBatch { rep }
+    }
+
+    // A batch of the encoded `content`, like a record of the WAL. Content too
+    // short for the header is corrupted.
+    pub fn from_content(content: Vec<u8>) -> Result<Self> {
+        if content.len() < HEAD_SIZE {
+            return Err(Error::Corruption(format!(
+                "malformed write batch, {} bytes is too small for the header",
+                content.len()
+            )));
+        }
+        Ok(WriteBatch { rep: content })
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.rep.reserve(additional);
+    }
+
     pub fn set_sequence(&mut self, seq: SequenceNumber) {
         self.rep
             .as_mut_slice()
@@ -32,6 +63,57 @@ impl WriteBatch {
 
     pub fn put(&mut self, key: &[u8], value: &[u8]) {
         self.set_count(self.count() + 1);
+        self.encode_put(key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.set_count(self.count() + 1);
+        self.encode_delete(key);
+    }
+
+    // Same as calling `put` for every entry, but the batch grows at most once.
+    // The entries are walked twice, first to size the records and then to encode
+    // them, so pass borrowed entries if cloning them is not cheap.
+    pub fn put_many<I, K, V>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        I::IntoIter: Clone,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let entries = entries.into_iter();
+        let size: usize = entries
+            .clone()
+            .map(|(k, v)| put_size(k.as_ref(), v.as_ref()))
+            .sum();
+        self.rep.reserve(size);
+        let mut count = 0;
+        for (k, v) in entries {
+            self.encode_put(k.as_ref(), v.as_ref());
+            count += 1;
+        }
+        self.set_count(self.count() + count);
+    }
+
+    // `put_many` for deletions
+    pub fn delete_many<I, K>(&mut self, keys: I)
+    where
+        I: IntoIterator<Item = K>,
+        I::IntoIter: Clone,
+        K: AsRef<[u8]>,
+    {
+        let keys = keys.into_iter();
+        let size: usize = keys.clone().map(|k| delete_size(k.as_ref())).sum();
+        self.rep.reserve(size);
+        let mut count = 0;
+        for k in keys {
+            self.encode_delete(k.as_ref());
+            count += 1;
+        }
+        self.set_count(self.count() + count);
+    }
+
+    fn encode_put(&mut self, key: &[u8], value: &[u8]) {
         self.rep.push(ValueType::Value as u8);
         self.rep.write_var_u32(key.len() as u32).unwrap();
         self.rep.extend_from_slice(key);
@@ -39,8 +121,7 @@ impl WriteBatch {
         self.rep.extend_from_slice(value);
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
-        self.set_count(self.count() + 1);
+    fn encode_delete(&mut self, key: &[u8]) {
         self.rep.push(ValueType::Deletetion as u8);
         self.rep.write_var_u32(key.len() as u32).unwrap();
         self.rep.extend_from_slice(key);
@@ -65,6 +146,13 @@ impl WriteBatch {
         self.rep.len()
     }
 
+    // key and value bytes carried by the batch, without any encoding overhead
+    pub fn user_bytes(&self) -> Result<u64> {
+        let mut counter = ByteCounter::default();
+        self.iterate(&mut counter)?;
+        Ok(counter.bytes)
+    }
+
     pub fn append(&mut self, source: &WriteBatch) {
         self.set_count(self.count() + source.count());
         assert!(source.rep.len() >= HEAD_SIZE);
@@ -78,24 +166,15 @@ impl WriteBatch {
         buf.read_u64_le().unwrap()
     }
 
-    pub fn mut_content(&mut self) -> &mut Vec<u8> {
-        &mut self.rep
-    }
     pub fn content(&self) -> &Vec<u8> {
         &self.rep
     }
-    pub fn set_content(&mut self, content: Vec<u8>) {
-        self.rep = content;
-    }
 
+    // Hands the records to `handler` in order. A record that doesn't decode, or a
+    // count other than that of the header, fails with Corruption, after the
+    // records before it were handled.
     pub fn iterate<H: Handler>(&self, mut handler: H) -> Result<()> {
-        let mut buf = self.rep.as_slice();
-        if buf.len() < HEAD_SIZE {
-            return Err(Error::Corruption(
-                "malformed write batch( too small)".into(),
-            ));
-        }
-        buf.advance(HEAD_SIZE);
+        let mut buf = &self.rep[HEAD_SIZE..];
         let mut found = 0;
         while !buf.is_empty() {
             let tag = ValueType::try_from(buf.read_u8_le()?)?;
@@ -106,12 +185,8 @@ impl WriteBatch {
                     handler.delete(key);
                 }
                 ValueType::Value => {
-                    // let (key, key_offset) = codec::decode_length_prefix_slice(&buf)?;
-                    // let (value, value_offset) =
-                    //     codec::decode_length_prefix_slice(&mut &buf[key_offset..])?;
-                    let key = codec::read_length_prefixed_slice(&mut buf).unwrap();
-                    let value = codec::read_length_prefixed_slice(&mut buf).unwrap();
-                    // buf.advance(key_offset + value_offset);
+                    let key = codec::read_length_prefixed_slice(&mut buf)?;
+                    let value = codec::read_length_prefixed_slice(&mut buf)?;
                     handler.put(key, value);
                 }
             }
@@ -123,33 +198,244 @@ impl WriteBatch {
         Ok(())
     }
 
-    pub fn insert_into(&self, mem: Arc<MemTable>) -> Result<()> {
-        let inserter = MemtableInserter {
+    // Fails with InvalidArgument on the first key longer than `max_key_size` or
+    // value longer than `max_value_size`, a limit of 0 is no limit.
+    pub fn check_sizes(&self, max_key_size: usize, max_value_size: usize) -> Result<()> {
+        let mut checker = SizeChecker {
+            max_key_size,
+            max_value_size,
+            error: None,
+        };
+        self.iterate(&mut checker)?;
+        match checker.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Adds the records to `mem`, returning the key and value bytes inserted, the
+    // same count as `user_bytes` without decoding the batch again.
+    pub fn insert_into(&self, mem: Arc<MemTable>) -> Result<u64> {
+        // the sequences of the records must fit in a tag
+        let end = self
+            .sequence()
+            .saturating_add(self.count() as SequenceNumber);
+        if end > MAX_SEQUENCE_NUMBER + 1 {
+            return Err(Error::Corruption(format!(
+                "write batch of {} records at sequence {} runs past the last sequence",
+                self.count(),
+                self.sequence()
+            )));
+        }
+        let mut inserter = MemtableInserter {
             sequence: self.sequence(),
             mem,
+            user_bytes: 0,
         };
-        self.iterate(inserter)
+        self.iterate(&mut inserter)?;
+        Ok(inserter.user_bytes)
     }
 }
 
+fn put_size(key: &[u8], value: &[u8]) -> usize {
+    1 + codec::required_space(key.len() as u64)
+        + key.len()
+        + codec::required_space(value.len() as u64)
+        + value.len()
+}
+
+fn delete_size(key: &[u8]) -> usize {
+    1 + codec::required_space(key.len() as u64) + key.len()
+}
+
 pub trait Handler {
     fn put(&mut self, key: &[u8], value: &[u8]);
     fn delete(&mut self, key: &[u8]);
 }
 
+impl<H: Handler> Handler for &mut H {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        (**self).put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        (**self).delete(key)
+    }
+}
+
+#[derive(Default)]
+struct ByteCounter {
+    bytes: u64,
+}
+
+impl Handler for ByteCounter {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.bytes += (key.len() + value.len()) as u64;
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.bytes += key.len() as u64;
+    }
+}
+
+struct SizeChecker {
+    max_key_size: usize,
+    max_value_size: usize,
+    error: Option<Error>,
+}
+
+impl SizeChecker {
+    fn check(&mut self, what: &str, len: usize, limit: usize) {
+        if self.error.is_none() && limit > 0 && len > limit {
+            self.error = Some(Error::InvalidArgument(format!(
+                "{} of {} bytes exceeds max_{}_size of {} bytes",
+                what, len, what, limit
+            )));
+        }
+    }
+}
+
+impl Handler for SizeChecker {
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.check("key", key.len(), self.max_key_size);
+        self.check("value", value.len(), self.max_value_size);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.check("key", key.len(), self.max_key_size);
+    }
+}
+
 pub struct MemtableInserter {
     sequence: SequenceNumber,
     mem: Arc<MemTable>,
+    user_bytes: u64,
 }
 
 impl Handler for MemtableInserter {
     fn put(&mut self, key: &[u8], value: &[u8]) {
         self.mem.add(self.sequence, ValueType::Value, key, value);
         self.sequence += 1;
+        self.user_bytes += (key.len() + value.len()) as u64;
     }
 
     fn delete(&mut self, key: &[u8]) {
         self.mem.add(self.sequence, ValueType::Deletetion, key, &[]);
         self.sequence += 1;
+        self.user_bytes += key.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmp::{BitWiseComparator, InternalKeyComparator};
+
+    #[test]
+    fn test_user_bytes() {
+        let mut batch = WriteBatch::new();
+        assert_eq!(batch.user_bytes().unwrap(), 0);
+        batch.put(b"key1", b"value1");
+        batch.put(b"k", b"");
+        batch.delete(b"key2");
+        assert_eq!(batch.user_bytes().unwrap(), 10 + 1 + 4);
+        assert!(batch.approximate_size() > 15);
+
+        let comparator = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+        let inserted = batch.insert_into(Arc::new(MemTable::new(comparator)));
+        assert_eq!(inserted.unwrap(), 10 + 1 + 4);
+    }
+
+    #[test]
+    fn test_put_many_matches_put() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..1000)
+            .map(|i| (format!("key{}", i).into_bytes(), vec![b'v'; i % 300]))
+            .collect();
+        let keys = [b"a".as_ref(), b"", &[b'k'; 200]];
+
+        let mut expected = WriteBatch::new();
+        expected.put(b"first", b"");
+        for (k, v) in entries.iter() {
+            expected.put(k, v);
+        }
+        for k in keys.iter() {
+            expected.delete(k);
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"first", b"");
+        batch.put_many(entries.iter().map(|(k, v)| (k, v)));
+        batch.delete_many(keys);
+        assert_eq!(batch.rep, expected.rep);
+        assert_eq!(batch.count(), 1004);
+
+        // nothing to add leaves the batch alone
+        batch.put_many(Vec::<(&[u8], &[u8])>::new());
+        batch.delete_many(Vec::<&[u8]>::new());
+        assert_eq!(batch.rep, expected.rep);
+
+        let mut sized = WriteBatch::with_capacity(expected.rep.len() - HEAD_SIZE);
+        let capacity = sized.rep.capacity();
+        sized.put(b"first", b"");
+        sized.put_many(entries.iter().map(|(k, v)| (k, v)));
+        sized.delete_many(keys);
+        assert_eq!(sized.rep, expected.rep);
+        // sized up front, nothing was reallocated
+        assert_eq!(sized.rep.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_from_content_needs_header() {
+        for len in 0..HEAD_SIZE {
+            let res = WriteBatch::from_content(vec![0; len]);
+            assert!(matches!(res, Err(Error::Corruption(_))));
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", b"value");
+        batch.set_sequence(7);
+        let copy = WriteBatch::from_content(batch.content().clone()).unwrap();
+        assert_eq!(copy.sequence(), 7);
+        assert_eq!(copy.count(), 1);
+        assert_eq!(copy.user_bytes().unwrap(), 8);
+    }
+
+    #[test]
+    fn test_malformed_content_is_an_error() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut valid = WriteBatch::new();
+        valid.put(b"key", b"value");
+        valid.delete(b"gone");
+        valid.set_sequence(100);
+
+        let mut rng = StdRng::seed_from_u64(301);
+        for _ in 0..2000 {
+            // a valid batch with bytes flipped or cut off, or random bytes
+            let content = match rng.gen_range(0..3) {
+                0 => {
+                    let mut content = valid.content().clone();
+                    for _ in 0..rng.gen_range(1..4) {
+                        let i = rng.gen_range(0..content.len());
+                        content[i] = rng.gen();
+                    }
+                    content
+                }
+                1 => valid.content()[..rng.gen_range(0..valid.content().len())].to_vec(),
+                _ => (0..rng.gen_range(0..40)).map(|_| rng.gen()).collect(),
+            };
+            let batch = match WriteBatch::from_content(content) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    assert!(matches!(err, Error::Corruption(_)));
+                    continue;
+                }
+            };
+            let _ = batch.count();
+            let _ = batch.sequence();
+            let _ = batch.user_bytes();
+            let _ = batch.check_sizes(4, 4);
+            let comparator = InternalKeyComparator::new(Arc::new(BitWiseComparator {}));
+            let _ = batch.insert_into(Arc::new(MemTable::new(comparator)));
+        }
     }
 }
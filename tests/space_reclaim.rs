@@ -0,0 +1,73 @@
+//! Overwritten and deleted data gives its space back once its range is
+//! compacted: tombstones are dropped at the last level and the obsolete files
+//! deleted.
+
+use std::path::Path;
+
+use rleveldb::{DBIterator, DbBuilder, Env, LevelDB, MemEnv, ReadOption};
+
+// about 100MB of values
+const KEYS: usize = 100_000;
+const VALUE_SIZE: usize = 1000;
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:08}", i).into_bytes()
+}
+
+// the size of all files of the db directory
+fn db_size(env: &MemEnv, db: &Path) -> u64 {
+    let mut files = Vec::new();
+    env.get_children(db, &mut files).unwrap();
+    files
+        .iter()
+        .map(|name| env.file_size(&db.join(name)).unwrap() as u64)
+        .sum()
+}
+
+fn count_keys<E: Env>(db: &LevelDB<E>) -> usize {
+    let mut iter = db.iter(&ReadOption::default());
+    iter.seek_to_first();
+    let mut count = 0;
+    while iter.valid() {
+        count += 1;
+        iter.next();
+    }
+    iter.status().unwrap();
+    count
+}
+
+#[test]
+fn test_deletes_reclaim_space() {
+    let env = MemEnv::new();
+    let path = Path::new("/space_reclaim");
+    let builder = DbBuilder::new().create_if_missing(true).env(env.clone());
+    let db = builder.open(path).unwrap();
+
+    // every key is written twice, only the second value survives the compaction
+    for value in *b"ab" {
+        for i in 0..KEYS {
+            db.write(&key(i), &[value; VALUE_SIZE]).unwrap();
+        }
+    }
+    db.compact_range(None, None).unwrap();
+    let full = db_size(&env, path);
+    assert!(full > (KEYS * VALUE_SIZE) as u64 / 2, "{}", full);
+    assert!(full < (2 * KEYS * VALUE_SIZE) as u64, "{}", full);
+    assert_eq!(count_keys(&db), KEYS);
+    assert_eq!(db.get(&key(0)).unwrap(), Some(vec![b'b'; VALUE_SIZE]));
+
+    for i in 0..KEYS {
+        db.delete(&key(i)).unwrap();
+    }
+    db.compact_range(None, None).unwrap();
+    // what's left is the manifest, the current log and the like
+    let empty = db_size(&env, path);
+    assert!(empty < 1 << 20, "{} of {}", empty, full);
+    assert_eq!(count_keys(&db), 0);
+    drop(db);
+
+    let db = builder.open(path).unwrap();
+    assert_eq!(count_keys(&db), 0);
+    assert_eq!(db.get(&key(0)).unwrap(), None);
+    assert!(db_size(&env, path) < 1 << 20);
+}